@@ -0,0 +1,522 @@
+use crate::common::{
+    check_entry_name, check_link_target, create_symlink, ensure_store, link_target, read_blob,
+    read_store_config, set_file_mode, DiffBlob, DiffBlobType, Durability, FileParseError,
+    PathNormalization, StoreConfig,
+};
+use crate::delta::apply_binary_patch;
+use crate::diff::{parse_blobs, read_blobs};
+use crate::hash::calculate_file_hash_with;
+use crate::patch::{
+    apply_patchs_in, unpack_patch_from, ApplyOptions, BlobPatch, UnpackLimits, ZipFileError,
+};
+use crate::staging::Staging;
+use bytes::Bytes;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Kind of change made to a file of a working tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Add,
+    Delete,
+    Modify,
+}
+
+/// A file change between two stored roots, resolved to its path below the root.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PathChange {
+    /// Path relative to the root, always using `/` as separator.
+    pub path: String,
+    pub kind: ChangeKind,
+    /// Blob before the change, `None` for additions.
+    pub old: Option<String>,
+    /// Blob after the change, `None` for deletions.
+    pub new: Option<String>,
+    /// Permission bits recorded for the file after the change.
+    pub mode: Option<u32>,
+    /// Whether the file after the change is a symbolic link, whose blob holds the target.
+    pub symlink: bool,
+}
+
+/// Source of the parsed directory blobs walked by [`resolve_changes_with`].
+pub(crate) type DirectoryReader<'a> =
+    dyn Fn(&str) -> Result<HashMap<String, DiffBlob>, FileParseError> + 'a;
+
+/// Resolve the file changes between two stored roots to real paths.
+///
+/// Directories only show up through the files they contain, deletions come first so a file
+/// replaced by a directory of the same name (or the other way round) is removed before the
+/// new entry is written.
+pub(crate) fn resolve_changes(
+    old: &str,
+    new: &str,
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<Vec<PathChange>, FileParseError> {
+    resolve_changes_with(old, new, &|hash| read_blobs(hash, base, store))
+}
+
+/// Like [`resolve_changes`], reading directory blobs through `read`.
+pub(crate) fn resolve_changes_with(
+    old: &str,
+    new: &str,
+    read: &DirectoryReader,
+) -> Result<Vec<PathChange>, FileParseError> {
+    let mut changes = vec![];
+    resolve_dir(Some(old), Some(new), "", read, &mut changes)?;
+    changes.sort_by_key(|c| c.kind != ChangeKind::Delete);
+    Ok(changes)
+}
+
+fn resolve_dir(
+    old: Option<&str>,
+    new: Option<&str>,
+    prefix: &str,
+    read: &DirectoryReader,
+    changes: &mut Vec<PathChange>,
+) -> Result<(), FileParseError> {
+    if old == new {
+        return Ok(());
+    }
+    let old_blobs = match old {
+        Some(hash) => read(hash)?,
+        None => Default::default(),
+    };
+    let new_blobs = match new {
+        Some(hash) => read(hash)?,
+        None => Default::default(),
+    };
+    for (key, b) in &old_blobs {
+        let path = join_path(prefix, check_entry_name(&b.name)?);
+        let new_b = new_blobs.get(key);
+        if new_b.is_some_and(|n| n.hash == b.hash && n.mode == b.mode) {
+            continue;
+        }
+        match (&b.blob_type, new_b) {
+            (DiffBlobType::File | DiffBlobType::Symlink, Some(n)) => changes.push(PathChange {
+                path,
+                kind: ChangeKind::Modify,
+                old: Some(b.hash.clone()),
+                new: Some(n.hash.clone()),
+                mode: n.mode,
+                symlink: n.blob_type == DiffBlobType::Symlink,
+            }),
+            (DiffBlobType::File | DiffBlobType::Symlink, None) => changes.push(PathChange {
+                path,
+                kind: ChangeKind::Delete,
+                old: Some(b.hash.clone()),
+                new: None,
+                mode: None,
+                symlink: false,
+            }),
+            (DiffBlobType::Directory, n) => resolve_dir(
+                Some(&b.hash),
+                n.map(|n| n.hash.as_str()),
+                &path,
+                read,
+                changes,
+            )?,
+        }
+    }
+    for (key, b) in &new_blobs {
+        if old_blobs.contains_key(key) {
+            continue;
+        }
+        let path = join_path(prefix, check_entry_name(&b.name)?);
+        match b.blob_type {
+            DiffBlobType::File | DiffBlobType::Symlink => changes.push(PathChange {
+                path,
+                kind: ChangeKind::Add,
+                old: None,
+                new: Some(b.hash.clone()),
+                mode: b.mode,
+                symlink: b.blob_type == DiffBlobType::Symlink,
+            }),
+            DiffBlobType::Directory => resolve_dir(None, Some(&b.hash), &path, read, changes)?,
+        }
+    }
+    Ok(())
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Apply a patch to the store at `base` and update the working tree checked out from
+/// `old_root` at `working_dir` so it matches `new_root`.
+///
+/// Deleted files are resolved to their paths through the directory blobs of `old_root`, a
+/// file that is already gone from the working tree is skipped. Directories left empty by a
+/// deletion are removed as well.
+///
+/// Every changed file is written to a staging directory next to `working_dir` first and moved
+/// into place once all of them are written, a failing apply leaves the working tree as it was.
+/// An apply interrupted while moving files is rolled back by the next one.
+/// Changes below a symbolic link and links pointing outside `working_dir` fail the apply with
+/// [`ZipFileError::PathTraversal`] before the first file is staged. Recorded modes are restored
+/// without their setuid, setgid and sticky bits.
+pub fn apply_patch_in_place<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    patch_path: P,
+    base: Q,
+    working_dir: R,
+    old_root: &str,
+    new_root: &str,
+) -> Result<(), ZipFileError> {
+    apply_patch_in_place_with_progress(patch_path, base, working_dir, old_root, new_root, |_, _| {})
+}
+
+/// Like [`apply_patch_in_place`], calling `on_change` with the path relative to `working_dir`
+/// and the kind of every file change once it is applied.
+pub fn apply_patch_in_place_with_progress<P, Q, R, F>(
+    patch_path: P,
+    base: Q,
+    working_dir: R,
+    old_root: &str,
+    new_root: &str,
+    on_change: F,
+) -> Result<(), ZipFileError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    F: Fn(&str, ChangeKind),
+{
+    apply_patch_in_place_in(
+        patch_path.as_ref(),
+        base.as_ref(),
+        working_dir.as_ref(),
+        (old_root, new_root),
+        &read_store_config(base.as_ref())?,
+        &ApplyOptions::default(),
+        on_change,
+    )
+}
+
+/// Like [`apply_patch_in_place_with_progress`], going from the first root of `roots` to the
+/// second.
+pub(crate) fn apply_patch_in_place_in<F: Fn(&str, ChangeKind)>(
+    patch_path: &Path,
+    base: &Path,
+    working_dir: &Path,
+    (old_root, new_root): (&str, &str),
+    store: &StoreConfig,
+    options: &ApplyOptions,
+    on_change: F,
+) -> Result<(), ZipFileError> {
+    apply_patchs_in(patch_path, base, store, options)?;
+    let changes = resolve_changes(old_root, new_root, base, store)?;
+    // a directory blob listing a `..` must not reach outside the working tree
+    let paths = changes
+        .iter()
+        .map(
+            |change| match PathNormalization::denormalize(&change.path) {
+                Some(path) => Ok((change.path.as_str(), working_dir.join(path))),
+                None => Err(ZipFileError::PathTraversal(change.path.clone())),
+            },
+        )
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    let path_of = |change: &PathChange| paths[change.path.as_str()].clone();
+    // nothing is written through a link, neither one on disk nor one this apply creates
+    let (removed, links): (HashSet<_>, HashSet<_>) = (
+        changes
+            .iter()
+            .filter(|c| c.new.is_none())
+            .map(path_of)
+            .collect(),
+        changes.iter().filter(|c| c.symlink).map(path_of).collect(),
+    );
+    let is_link = |dir: &Path| links.contains(dir) || !removed.contains(dir) && is_symlink(dir);
+    for change in &changes {
+        let path = path_of(change);
+        check_no_links(working_dir, path.parent().unwrap(), is_link)?;
+        if let (Some(hash), true) = (&change.new, change.symlink) {
+            let target = fs::read(store.path_from_hash(hash, base)?)?;
+            check_link_target(working_dir, &path, &target)?;
+        }
+    }
+    // every file is written before the first one is moved into place
+    // staged next to the working tree, so it never shows up in the tree itself
+    let working = std::path::absolute(working_dir)?;
+    let mut staging = Staging::new(working.parent().unwrap_or(&working), Durability::None)?;
+    for change in &changes {
+        let path = path_of(change);
+        match &change.new {
+            None => staging.remove(&path),
+            Some(hash) if change.symlink => {
+                let target = fs::read(store.path_from_hash(hash, base)?)?;
+                staging.write(&path, |tmp| create_symlink(&target, tmp))?;
+            }
+            Some(hash) => {
+                staging.write(&path, |tmp| {
+                    fs::copy(store.path_from_hash(hash, base)?, tmp)?;
+                    set_file_mode(tmp, change.mode, false)
+                })?;
+            }
+        };
+    }
+    staging.commit()?;
+    for change in &changes {
+        if change.new.is_none() {
+            prune_empty_dirs(working_dir, &path_of(change));
+        }
+        on_change(&change.path, change.kind);
+    }
+    Ok(())
+}
+
+/// Options of [`checkout_with_options`].
+#[derive(Clone, Debug, Default)]
+pub struct CheckoutOptions {
+    /// Leave destination files alone that already hold the content of their blob.
+    ///
+    /// Each existing file is hashed instead of rewritten, which is faster when the destination
+    /// is already close to the version.
+    pub skip_identical: bool,
+    /// Restore the setuid, setgid and sticky bits of recorded modes as well.
+    ///
+    /// Off by default, only the permission bits are restored so a checked out file never gains
+    /// elevated rights.
+    pub special_bits: bool,
+}
+
+/// Number of files a checkout wrote and left alone.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CheckoutReport {
+    pub written: usize,
+    pub skipped: usize,
+}
+
+/// Write the files of the stored root `root_hash` with their real names below `dest`.
+///
+/// Nothing is written through a symbolic link, a link in the way or a link pointing outside
+/// `dest` fails the checkout with [`FileParseError::PathTraversal`].
+pub fn checkout<P: AsRef<Path>, Q: AsRef<Path>>(
+    root_hash: &str,
+    base: P,
+    dest: Q,
+) -> Result<CheckoutReport, FileParseError> {
+    checkout_with_options(root_hash, base, dest, &CheckoutOptions::default())
+}
+
+/// Like [`checkout`], configured by `options`.
+///
+/// Files of `dest` that are not part of the version are left in place.
+pub fn checkout_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    root_hash: &str,
+    base: P,
+    dest: Q,
+    options: &CheckoutOptions,
+) -> Result<CheckoutReport, FileParseError> {
+    checkout_in(
+        root_hash,
+        base.as_ref(),
+        dest.as_ref(),
+        &read_store_config(base.as_ref())?,
+        options,
+    )
+}
+
+pub(crate) fn checkout_in(
+    root_hash: &str,
+    base: &Path,
+    dest: &Path,
+    store: &StoreConfig,
+    options: &CheckoutOptions,
+) -> Result<CheckoutReport, FileParseError> {
+    ensure_store(base)?;
+    let mut report = CheckoutReport::default();
+    let mut stack = vec![(root_hash.to_string(), dest.to_path_buf())];
+    while let Some((hash, dir)) = stack.pop() {
+        // a link of the same name as the directory, checked out or left in `dest`
+        check_no_links(dest, &dir, is_symlink)?;
+        fs::create_dir_all(&dir)?;
+        for blob in read_blobs(&hash, base, store)?.into_values() {
+            let path = dir.join(check_entry_name(&blob.name)?);
+            if blob.blob_type == DiffBlobType::Directory {
+                stack.push((blob.hash, path));
+                continue;
+            }
+            let source = store.path_from_hash(&blob.hash, base)?;
+            if blob.blob_type == DiffBlobType::Symlink {
+                let target = read_blob(store, &blob.hash, base)?;
+                if options.skip_identical && link_target(&path).is_ok_and(|t| t == target) {
+                    report.skipped += 1;
+                    continue;
+                }
+                check_link_target(dest, &path, &target)?;
+                create_symlink(&target, &path)?;
+                report.written += 1;
+                continue;
+            }
+            // a link left at the path would have the copy written through it
+            if is_symlink(&path) {
+                fs::remove_file(&path)?;
+            }
+            if options.skip_identical && holds_blob(&path, &source, &blob.hash, store)? {
+                set_file_mode(&path, blob.mode, options.special_bits)?;
+                report.skipped += 1;
+                continue;
+            }
+            fs::copy(&source, &path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound if !source.exists() => {
+                    FileParseError::MissingBaseBlob(blob.hash.clone())
+                }
+                _ => FileParseError::Io(e),
+            })?;
+            set_file_mode(&path, blob.mode, options.special_bits)?;
+            report.written += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Apply the patch at `patch_path` to the store at `store_base` and write the files of the
+/// patched root `new_root_hash` with their real names below `output_dir`.
+///
+/// This is [`apply_patchs`](crate::prelude::apply_patchs) followed by [`checkout`], the store
+/// keeps the hash named blobs and `output_dir` gets the tree they describe.
+pub fn materialize<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+    patch_path: P,
+    store_base: Q,
+    output_dir: R,
+    new_root_hash: &str,
+) -> Result<CheckoutReport, ZipFileError> {
+    materialize_in(
+        patch_path.as_ref(),
+        store_base.as_ref(),
+        output_dir.as_ref(),
+        new_root_hash,
+        &read_store_config(store_base.as_ref())?,
+        &ApplyOptions::default(),
+    )
+}
+
+pub(crate) fn materialize_in(
+    patch_path: &Path,
+    store_base: &Path,
+    output_dir: &Path,
+    new_root_hash: &str,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+) -> Result<CheckoutReport, ZipFileError> {
+    apply_patchs_in(patch_path, store_base, store, options)?;
+    Ok(checkout_in(
+        new_root_hash,
+        store_base,
+        output_dir,
+        store,
+        &CheckoutOptions::default(),
+    )?)
+}
+
+/// Fail when `dir` or one of its parents below `root` is a symbolic link according to
+/// `is_link`, so nothing is written through it.
+fn check_no_links<F: Fn(&Path) -> bool>(
+    root: &Path,
+    dir: &Path,
+    is_link: F,
+) -> Result<(), FileParseError> {
+    for dir in dir
+        .ancestors()
+        .take_while(|d| *d != root && d.starts_with(root))
+    {
+        if is_link(dir) {
+            return Err(FileParseError::PathTraversal(dir.display().to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Check whether `path` already holds the content of the blob `hash` stored at `source`.
+fn holds_blob(path: &Path, source: &Path, hash: &str, store: &StoreConfig) -> io::Result<bool> {
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(false);
+    };
+    // comparing sizes first avoids hashing most changed files
+    if !metadata.is_file() || fs::metadata(source).map(|m| m.len()).ok() != Some(metadata.len()) {
+        return Ok(false);
+    }
+    Ok(calculate_file_hash_with(path, store.hash_algorithm)? == hash)
+}
+
+/// Apply the patch `patch` to an in-memory store mapping blob hashes to their content and
+/// return the files of the patched version of `old_root`, keyed by their path below the root.
+///
+/// Nothing is read from or written to the file system, `base` is left untouched.
+pub fn apply_patch_in_memory(
+    patch: &[u8],
+    base: &HashMap<String, Bytes>,
+    old_root: &str,
+) -> Result<HashMap<PathBuf, Bytes>, ZipFileError> {
+    let added = RefCell::new(HashMap::new());
+    let patchs = unpack_patch_from(
+        io::Cursor::new(patch),
+        UnpackLimits::default(),
+        |buffer, name| {
+            added
+                .borrow_mut()
+                .insert(name.to_string(), Bytes::from(buffer));
+            Ok(())
+        },
+    )?;
+    let mut blobs = added.into_inner();
+    let blob = |blobs: &HashMap<String, Bytes>, hash: &str| {
+        blobs
+            .get(hash)
+            .or_else(|| base.get(hash))
+            .cloned()
+            .ok_or_else(|| ZipFileError::MissingBaseBlob(hash.to_string()))
+    };
+    let mut new_root = old_root.to_string();
+    for patch in patchs {
+        if let BlobPatch::Replace {
+            old_file,
+            new_file,
+            patch,
+        } = patch
+        {
+            let new = apply_binary_patch(&blob(&blobs, &old_file)?, &patch)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            if old_file == old_root {
+                new_root.clone_from(&new_file);
+            }
+            blobs.insert(new_file, Bytes::from(new));
+        }
+    }
+    let mut files = HashMap::new();
+    let mut stack = vec![(new_root, PathBuf::new())];
+    while let Some((hash, dir)) = stack.pop() {
+        for child in parse_blobs(blob(&blobs, &hash)?.to_vec())?.into_values() {
+            let path = dir.join(check_entry_name(&child.name)?);
+            match child.blob_type {
+                DiffBlobType::Directory => stack.push((child.hash, path)),
+                // a link maps to its target
+                DiffBlobType::File | DiffBlobType::Symlink => {
+                    files.insert(path, blob(&blobs, &child.hash)?);
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Remove the now empty parents of a deleted file up to, but excluding, `root`.
+fn prune_empty_dirs(root: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        // removing fails once a directory still has entries
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
@@ -1,29 +1,288 @@
-#[cfg(feature = "binaryBlob")]
-use serde::{Deserialize, Serialize};
+//! Store layout, directory blob entries and the checks shared by every module.
+//!
+//! A directory blob line keeps the legacy `name hash type NNHHTT` layout whenever each field
+//! fits its two hex digits of length, so a tree blobbed before the versioned layout existed
+//! keeps its directory hashes. Only a line with a longer field uses the versioned layout, a NUL
+//! and a 1-byte version tag followed by the fields. Its lengths are decimal digits ended by a
+//! `:` rather than binary varints: directory blobs are read line by line, and a varint byte can
+//! be a `\n` or `\r` that would end the line inside a length.
+
+use crate::hash::HashAlgorithm;
+use crate::index::META_DIR;
 #[cfg(feature = "binaryBlob")]
 use serde_columnar::{columnar, from_bytes, to_vec};
+use std::borrow::Cow;
 use std::fmt::Display;
-use std::io;
-use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{fs, io};
 use thiserror::Error;
 
-pub(crate) fn split_dir_and_name(hash: &str) -> (&str, &str) {
-    // assert hash length is longer than 2
-    let dir = &hash[0..1];
-    let name = &hash[1..];
-    (dir, name)
+/// File in the meta directory recording the fanout, hash algorithm and extension of a store.
+const LAYOUT_FILE: &str = "layout";
+
+#[cfg(test)]
+pub(crate) fn path_from_hash<P: AsRef<Path>>(
+    hash: &str,
+    base: P,
+) -> Result<PathBuf, DeserializeError> {
+    StoreConfig::default().path_from_hash(hash, base)
+}
+
+/// Layout of a blob store, every operation on a store must use the same config.
+///
+/// The free functions read the layout a store records, see [`read_store_config`].
+#[derive(Clone, Debug)]
+pub struct StoreConfig {
+    pub hash_algorithm: HashAlgorithm,
+    /// Number of leading hash characters used as shard directory name.
+    pub fanout: usize,
+    /// Extension appended to every blob file name, so blobs can be told apart from unrelated
+    /// files when the store shares its directory with other data.
+    pub extension: Option<String>,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            hash_algorithm: HashAlgorithm::XxHash64,
+            fanout: 1,
+            extension: None,
+        }
+    }
+}
+
+impl StoreConfig {
+    /// Split `hash` into shard directory and file name, a hash no longer than the fanout has
+    /// no file name and is rejected.
+    pub(crate) fn split_dir_and_name<'a>(
+        &self,
+        hash: &'a str,
+    ) -> Result<(&'a str, &'a str), DeserializeError> {
+        match (hash.get(..self.fanout), hash.get(self.fanout..)) {
+            (Some(dir), Some(name)) if !name.is_empty() => Ok((dir, name)),
+            _ => Err(DeserializeError::InvalidHashLengthInfo),
+        }
+    }
+
+    pub(crate) fn path_from_hash<P: AsRef<Path>>(
+        &self,
+        hash: &str,
+        base: P,
+    ) -> Result<PathBuf, DeserializeError> {
+        let (dir, name) = self.split_dir_and_name(hash)?;
+        Ok(match &self.extension {
+            Some(extension) => base
+                .as_ref()
+                .join(dir)
+                .join(format!("{}.{}", name, extension)),
+            None => base.as_ref().join(dir).join(name),
+        })
+    }
+
+    /// Record the fanout, hash algorithm and extension of this config in the store at `base`,
+    /// or check them against the layout recorded by an earlier write.
+    ///
+    /// A store without a layout file has the default layout, so only other layouts are
+    /// recorded and stores written with the defaults stay free of it. Another layout is only
+    /// recorded for a store that holds no blobs yet.
+    pub(crate) fn record_layout(&self, base: &Path) -> io::Result<()> {
+        let digest_len = self.hash_algorithm.digest_len();
+        if self.fanout >= digest_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "fanout {} is not shorter than the {} digits of a hash",
+                    self.fanout, digest_len
+                ),
+            ));
+        }
+        if self
+            .extension
+            .as_ref()
+            .is_some_and(|extension| extension.is_empty() || extension.contains(['/', '\n']))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a blob extension must be a non-empty file name part",
+            ));
+        }
+        let default = StoreConfig::default();
+        let recorded = match read_layout(base)? {
+            Some(recorded) => recorded,
+            None if self.same_layout(&default) => return Ok(()),
+            None if base.is_dir() && !default.list_blobs(base)?.is_empty() => default,
+            None => {
+                let file = layout_file(base);
+                fs::create_dir_all(file.parent().unwrap())?;
+                return fs::write(file, self.layout());
+            }
+        };
+        if !recorded.same_layout(self) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "store has layout {:?}, not {:?}",
+                    recorded.layout().trim_end(),
+                    self.layout().trim_end()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn same_layout(&self, other: &StoreConfig) -> bool {
+        self.fanout == other.fanout
+            && self.hash_algorithm == other.hash_algorithm
+            && self.extension == other.extension
+    }
+
+    /// Content of the layout file recording this config.
+    fn layout(&self) -> String {
+        let mut layout = format!(
+            "fanout {}\nhash {}\n",
+            self.fanout,
+            self.hash_algorithm.name()
+        );
+        if let Some(extension) = &self.extension {
+            layout.push_str(&format!("extension {}\n", extension));
+        }
+        layout
+    }
+
+    /// Hash of the blob stored as `file_name` in the shard directory `dir_name`, `None` when
+    /// the file is no blob of this store.
+    pub(crate) fn hash_from_path(&self, dir_name: &str, file_name: &str) -> Option<String> {
+        let name = match &self.extension {
+            Some(extension) => file_name
+                .strip_suffix(extension.as_str())?
+                .strip_suffix('.')?,
+            None => file_name,
+        };
+        let hash = format!("{}{}", dir_name, name);
+        (dir_name.len() == self.fanout && !name.is_empty() && is_valid_hash(&hash)).then_some(hash)
+    }
+
+    /// Every blob of the store at `base` with its path, sorted by hash.
+    ///
+    /// Files that do not look like blobs of this store are left out, so they are never touched
+    /// by operations over the whole store.
+    pub(crate) fn list_blobs(&self, base: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+        let mut blobs = vec![];
+        for shard in fs::read_dir(base)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            let dir_name = shard.file_name().to_string_lossy().to_string();
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if let Some(hash) = self.hash_from_path(&dir_name, &file_name) {
+                    blobs.push((hash, entry.path()));
+                }
+            }
+        }
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    pub(crate) fn blob_exists<P: AsRef<Path>>(&self, hash: &str, base: P) -> bool {
+        self.path_from_hash(hash, base)
+            .is_ok_and(|path| path.is_file())
+    }
+
+    pub(crate) fn blob_size<P: AsRef<Path>>(
+        &self,
+        hash: &str,
+        base: P,
+    ) -> Result<u64, FileParseError> {
+        fs::metadata(self.path_from_hash(hash, base)?)
+            .map(|m| m.len())
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => FileParseError::MissingBaseBlob(hash.to_string()),
+                _ => FileParseError::Io(e),
+            })
+    }
 }
 
-pub(crate) fn path_from_hash<P: AsRef<Path>>(hash: &str, base: P) -> PathBuf {
-    let (dir, name) = split_dir_and_name(hash);
-    base.as_ref().join(dir).join(name)
+/// How far a write is pushed to stable storage before it counts as committed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave flushing to the operating system.
+    #[default]
+    None,
+    /// Sync the content of every written file.
+    Data,
+    /// Also sync the directory holding a written file, so its creation or rename survives
+    /// a power loss.
+    Full,
+}
+
+impl Durability {
+    /// Sync a file that was written in place.
+    pub(crate) fn commit(self, path: &Path) -> io::Result<()> {
+        self.commit_with(path, &mut |file| file.sync_all())
+    }
+
+    fn commit_with(
+        self,
+        path: &Path,
+        sync: &mut dyn FnMut(&File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.sync_data_with(path, sync)?;
+        self.sync_parent_with(path, sync)
+    }
+
+    /// Sync the content of a file before it is renamed into place.
+    pub(crate) fn sync_data(self, path: &Path) -> io::Result<()> {
+        self.sync_data_with(path, &mut |file| file.sync_all())
+    }
+
+    fn sync_data_with(
+        self,
+        path: &Path,
+        sync: &mut dyn FnMut(&File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self == Durability::None {
+            return Ok(());
+        }
+        sync(&File::open(path)?)
+    }
+
+    /// Sync the directory of a file after it was created or renamed into place.
+    pub(crate) fn sync_parent(self, path: &Path) -> io::Result<()> {
+        self.sync_parent_with(path, &mut |file| file.sync_all())
+    }
+
+    fn sync_parent_with(
+        self,
+        path: &Path,
+        sync: &mut dyn FnMut(&File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // only unix can open a directory to sync it
+        if self != Durability::Full || cfg!(not(unix)) {
+            return Ok(());
+        }
+        match path.parent() {
+            Some(parent) => sync(&File::open(parent)?),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum DiffBlobType {
     Directory,
     File,
+    /// A symbolic link, its blob holds the link target instead of the content it points to.
+    Symlink,
 }
 
 impl Display for DiffBlobType {
@@ -31,6 +290,7 @@ impl Display for DiffBlobType {
         match self {
             DiffBlobType::Directory => write!(f, "directory"),
             DiffBlobType::File => write!(f, "file"),
+            DiffBlobType::Symlink => write!(f, "symlink"),
         }
     }
 }
@@ -40,8 +300,26 @@ pub struct DiffBlob {
     pub(crate) name: String,
     pub(crate) hash: String,
     pub(crate) blob_type: DiffBlobType,
+    /// Permission bits of a file, only recorded when blobbing with
+    /// [`BlobOptions::record_mode`](crate::hash::BlobOptions::record_mode).
+    pub(crate) mode: Option<u32>,
 }
 
+/// Start of a directory blob in the columnar format, followed by [`BINARY_BLOB_VERSION`].
+///
+/// A text blob whose first line is in the versioned layout starts with a NUL as well, but the
+/// byte after its NUL is the line version tag, never the `d` that follows the NUL here.
+#[cfg(feature = "binaryBlob")]
+pub(crate) const BINARY_BLOB_MAGIC: &[u8] = b"\0ditiear-columnar\0";
+
+/// Version of the columnar format written by this crate.
+#[cfg(feature = "binaryBlob")]
+const BINARY_BLOB_VERSION: u8 = 1;
+
+/// An entry of a directory blob in the columnar format.
+///
+/// The lengths are stored next to the name and hash, a decoded entry whose fields do not have
+/// the stored lengths is rejected as corrupt.
 #[cfg(feature = "binaryBlob")]
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[columnar(vec, ser, de)]
@@ -51,11 +329,13 @@ pub struct BinaryDiffBlob {
     #[columnar(strategy = "Rle")]
     pub(crate) hash: String,
     #[columnar(strategy = "Rle")]
-    pub(crate) name_len: u8,
+    pub(crate) name_len: u32,
     #[columnar(strategy = "Rle")]
-    pub(crate) hash_len: u8,
+    pub(crate) hash_len: u32,
+    /// `0` for a directory, `1` for a file and `2` for a symbolic link.
     #[columnar(strategy = "Rle")]
-    pub(crate) blob_type_len: u8,
+    pub(crate) blob_type: u8,
+    pub(crate) mode: Option<u32>,
 }
 
 #[cfg(feature = "binaryBlob")]
@@ -68,50 +348,280 @@ pub struct BinaryDiffBlobStore {
 
 #[cfg(feature = "binaryBlob")]
 impl DiffBlob {
-    fn into_binary(self) -> BinaryDiffBlob {
-        let DiffBlob {
-            name,
-            hash,
+    /// Columnar form of this entry.
+    pub(crate) fn into_binary(self) -> Result<BinaryDiffBlob, DeserializeError> {
+        let name_len =
+            u32::try_from(self.name.len()).map_err(|_| DeserializeError::InvalidNameLengthInfo)?;
+        let hash_len =
+            u32::try_from(self.hash.len()).map_err(|_| DeserializeError::InvalidHashLengthInfo)?;
+        let blob_type = match self.blob_type {
+            DiffBlobType::Directory => 0,
+            DiffBlobType::File => 1,
+            DiffBlobType::Symlink => 2,
+        };
+        Ok(BinaryDiffBlob {
+            name: self.name,
+            hash: self.hash,
+            name_len,
+            hash_len,
             blob_type,
-        } = self;
-        let name_len = name.len();
-        let hash_len = hash.len();
-        let blob_type_len = blob_type.to_string().len();
-        assert!(name_len < 256);
-        assert!(hash_len < 256);
-        assert!(blob_type_len < 256);
-        BinaryDiffBlob {
-            name,
-            hash,
-            name_len: name_len as u8,
-            hash_len: hash_len as u8,
-            blob_type_len: blob_type_len as u8,
+            mode: self.mode,
+        })
+    }
+
+    pub(crate) fn from_binary(blob: BinaryDiffBlob) -> Result<Self, DeserializeError> {
+        if blob.name.len() != blob.name_len as usize || blob.name.is_empty() {
+            return Err(DeserializeError::InvalidNameLengthInfo);
         }
+        if blob.hash.len() != blob.hash_len as usize || !is_valid_hash(&blob.hash) {
+            return Err(DeserializeError::InvalidHashLengthInfo);
+        }
+        let blob_type = match blob.blob_type {
+            0 => DiffBlobType::Directory,
+            1 => DiffBlobType::File,
+            2 => DiffBlobType::Symlink,
+            _ => return Err(DeserializeError::InvalidType),
+        };
+        Ok(DiffBlob {
+            name: blob.name,
+            hash: blob.hash,
+            blob_type,
+            mode: blob.mode,
+        })
     }
 }
 
+/// Encode the entries of a directory blob in the columnar format.
+#[cfg(feature = "binaryBlob")]
+pub(crate) fn encode_binary_blobs(blobs: Vec<DiffBlob>) -> Result<Vec<u8>, DeserializeError> {
+    let blobs = blobs
+        .into_iter()
+        .map(DiffBlob::into_binary)
+        .collect::<Result<_, _>>()?;
+    let encoded =
+        to_vec(&BinaryDiffBlobStore { blobs }).map_err(|_| DeserializeError::InvalidLength)?;
+    Ok([BINARY_BLOB_MAGIC, &[BINARY_BLOB_VERSION], &encoded].concat())
+}
+
+/// Decode a directory blob in the columnar format, `None` when `content` is in the text
+/// format.
+#[cfg(feature = "binaryBlob")]
+pub(crate) fn decode_binary_blobs(
+    content: &[u8],
+) -> Option<Result<Vec<DiffBlob>, DeserializeError>> {
+    let encoded = match content.strip_prefix(BINARY_BLOB_MAGIC)?.split_first() {
+        Some((&BINARY_BLOB_VERSION, encoded)) => encoded,
+        Some((version, _)) => return Some(Err(DeserializeError::UnsupportedVersion(*version))),
+        None => return Some(Err(DeserializeError::InvalidLength)),
+    };
+    Some(
+        from_bytes::<BinaryDiffBlobStore>(encoded)
+            .map_err(|_| DeserializeError::InvalidLength)
+            .and_then(|store| store.blobs.into_iter().map(DiffBlob::from_binary).collect()),
+    )
+}
+
 impl Display for DiffBlob {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let name_length = self.name.len();
+        let name = escape_name(&self.name);
+        let name_length = name.len();
         let hash_length = self.hash.len();
-        let type_length = self.blob_type.to_string().len();
-        write!(
+        let blob_type = self.type_field();
+        let type_length = blob_type.len();
+        if [name_length, hash_length, type_length]
+            .iter()
+            .any(|length| *length > LEGACY_MAX_FIELD)
+        {
+            return writeln!(
+                f,
+                "\0{}{}:{}{}:{}{}:{}",
+                LINE_VERSION as char,
+                name_length,
+                name,
+                hash_length,
+                self.hash,
+                type_length,
+                blob_type
+            );
+        }
+        writeln!(
             f,
-            "{} {} {} {:02x}{:02x}{:02x}\n",
-            self.name, self.hash, self.blob_type, name_length, hash_length, type_length
+            "{} {} {} {:02x}{:02x}{:02x}",
+            name, self.hash, blob_type, name_length, hash_length, type_length
         )
     }
 }
 
+impl DiffBlob {
+    /// The type field of the blob line, the mode follows the type as `file:755` and a name
+    /// written escaped is flagged by a trailing `:e`.
+    ///
+    /// Keeping these inside the type field leaves the line layout unchanged, so lines without
+    /// a mode or line breaks in the name are written exactly as before and keep their hashes.
+    fn type_field(&self) -> String {
+        let mut field = self.blob_type.to_string();
+        if let Some(mode) = self.mode {
+            field.push_str(&format!(":{:o}", mode));
+        }
+        if needs_escape(&self.name) {
+            field.push_str(":e");
+        }
+        field
+    }
+}
+
+pub(crate) fn needs_escape(name: &str) -> bool {
+    name.contains(['\n', '\r'])
+}
+
+/// Name as written to a blob line, line breaks would end the line early.
+pub(crate) fn escape_name(name: &str) -> Cow<'_, str> {
+    match needs_escape(name) {
+        true => Cow::Owned(
+            name.replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r"),
+        ),
+        false => Cow::Borrowed(name),
+    }
+}
+
+pub(crate) fn unescape_name(name: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(b) = bytes.next() {
+        if *b != b'\\' {
+            result.push(*b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => result.push(b'\n'),
+            Some(b'r') => result.push(b'\r'),
+            Some(other) => result.push(*other),
+            None => result.push(b'\\'),
+        }
+    }
+    result
+}
+
+/// Permission bits of a file, `None` on platforms without them.
+pub(crate) fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Target of the symbolic link at `path`, as stored in the blob of the link.
+pub(crate) fn link_target(path: &Path) -> io::Result<Vec<u8>> {
+    let target = fs::read_link(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(target.as_os_str().as_bytes().to_vec())
+    }
+    #[cfg(not(unix))]
+    Ok(target.to_string_lossy().replace('\\', "/").into_bytes())
+}
+
+/// Create a symbolic link at `path` pointing to `target`, replacing what is at `path`.
+///
+/// Only unix can restore links, elsewhere this fails with [`io::ErrorKind::Unsupported`].
+pub(crate) fn create_symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        if fs::symlink_metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (target, path);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symbolic links can only be restored on unix",
+        ))
+    }
+}
+
+/// Check that the target `target` of a link to be created at `link`, a path below `root`,
+/// resolves below `root` as well.
+///
+/// Targets are resolved lexically, an absolute target or one climbing above `root` with `..`
+/// fails with [`FileParseError::PathTraversal`].
+pub(crate) fn check_link_target(
+    root: &Path,
+    link: &Path,
+    target: &[u8],
+) -> Result<(), FileParseError> {
+    let text = String::from_utf8_lossy(target);
+    let escapes = || FileParseError::PathTraversal(text.to_string());
+    if text.starts_with(['/', '\\']) || Path::new(text.as_ref()).has_root() {
+        return Err(escapes());
+    }
+    let mut depth = link
+        .parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .ok_or_else(escapes)?
+        .components()
+        .count();
+    for component in text.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => depth = depth.checked_sub(1).ok_or_else(escapes)?,
+            _ => depth += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Set the permission bits recorded for a file, a missing mode leaves the file as it is.
+///
+/// The setuid, setgid and sticky bits of a mode are dropped unless `special_bits` is set, a
+/// patch from elsewhere must not hand out elevated rights. Platforms without permission bits
+/// ignore the mode.
+pub(crate) fn set_file_mode(path: &Path, mode: Option<u32>, special_bits: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = match special_bits {
+            true => mode & 0o7777,
+            false => mode & 0o777,
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode, special_bits);
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 #[error("Deserialize error")]
+#[allow(clippy::enum_variant_names)]
 pub enum DeserializeError {
     InvalidLength,
     InvalidNameLengthInfo,
     InvalidHashLengthInfo,
     InvalidTypeLengthInfo,
     InvalidTotalLength,
+    InvalidSeparator,
     InvalidType,
+    /// A versioned line or columnar directory blob written by a newer version of this crate.
+    UnsupportedVersion(u8),
+}
+
+impl From<DeserializeError> for io::Error {
+    fn from(e: DeserializeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -120,54 +630,568 @@ pub enum FileParseError {
     Io(#[from] io::Error),
     #[error("Parse error")]
     Parse(#[from] DeserializeError),
+    #[error("Store not found: {0}")]
+    StoreNotFound(PathBuf),
+    #[error("Blob {0} is missing from the store")]
+    MissingBaseBlob(String),
+    #[error("Manifest does not match the store: {0}")]
+    ManifestMismatch(String),
+    #[error("Operation was cancelled")]
+    Cancelled,
+    #[error("Entry name {0:?} points outside its directory")]
+    PathTraversal(String),
+}
+
+/// Shared flag to stop long running operations from another thread.
+///
+/// Clones share the flag. Blobbing checks it before every file, diffing before every
+/// directory entry and applying before every record, they stop with a cancellation error once
+/// it is set. Blobs written until then are complete, so a later run picks up where it stopped.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with an [`io::ErrorKind::Interrupted`] error wrapping [`Cancelled`] once cancelled.
+    pub(crate) fn check_io(&self) -> io::Result<()> {
+        match self.is_cancelled() {
+            true => Err(io::Error::new(io::ErrorKind::Interrupted, Cancelled)),
+            false => Ok(()),
+        }
+    }
+
+    /// Fail with [`FileParseError::Cancelled`] once cancelled.
+    pub(crate) fn check(&self) -> Result<(), FileParseError> {
+        match self.is_cancelled() {
+            true => Err(FileParseError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Share a flag the application already owns, setting it cancels like
+/// [`CancellationToken::cancel`].
+impl From<Arc<AtomicBool>> for CancellationToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancellationToken(flag)
+    }
+}
+
+/// Error wrapped by the I/O errors of cancelled operations, see [`CancellationToken`].
+#[derive(Error, Debug)]
+#[error("Operation was cancelled")]
+pub struct Cancelled;
+
+/// Fail with `StoreNotFound` when `base` is not an existing directory.
+pub(crate) fn ensure_store(base: &Path) -> Result<(), FileParseError> {
+    if base.is_dir() {
+        Ok(())
+    } else {
+        Err(FileParseError::StoreNotFound(base.to_path_buf()))
+    }
+}
+
+/// Canonical form of the store directory `base`, with symlinks and `..` components resolved.
+///
+/// Entry points handling untrusted patches work below this path, so nothing they join onto it
+/// can end up in a different directory than the caller meant.
+pub(crate) fn canonical_store(base: &Path) -> Result<PathBuf, FileParseError> {
+    ensure_store(base)?;
+    Ok(base.canonicalize()?)
+}
+
+/// Check that `name`, the name of a directory blob entry, names a child of the directory it
+/// is joined onto: no separator, no `.` or `..`, no NUL and no absolute prefix.
+///
+/// A `\` only counts as separator on Windows, as in [`PathNormalization`].
+pub(crate) fn check_entry_name(name: &str) -> Result<&str, FileParseError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        // a single normal component has no root, prefix, `.` or `..`
+        (Some(Component::Normal(_)), None)
+            if !name.contains(SEPARATORS) && !name.contains('\0') =>
+        {
+            Ok(name)
+        }
+        _ => Err(FileParseError::PathTraversal(name.to_string())),
+    }
+}
+
+/// Path separators of this platform.
+pub(crate) const SEPARATORS: &[char] = if cfg!(windows) { &['/', '\\'] } else { &['/'] };
+
+/// Check that `hash` looks like a hash this crate writes: lowercase hex, long enough to be
+/// split into shard directory and name and no longer than a 256 bit digest.
+pub(crate) fn is_valid_hash(hash: &str) -> bool {
+    (2..=64).contains(&hash.len()) && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Conversion between real paths and the relative paths kept in a store.
+///
+/// Stored paths always use `/` as separator. On Windows a `\` is a separator as well, elsewhere
+/// it is an ordinary character of a file name and kept. Stored paths are turned back into
+/// native paths on checkout.
+pub(crate) struct PathNormalization;
+
+impl PathNormalization {
+    fn components(path: &Path) -> Vec<String> {
+        path.components()
+            .flat_map(|c| {
+                c.as_os_str()
+                    .to_string_lossy()
+                    .split(SEPARATORS)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Stored form of `path` relative to `root`.
+    pub(crate) fn relative(root: &Path, path: &Path) -> String {
+        let (root, path) = (Self::components(root), Self::components(path));
+        path.strip_prefix(root.as_slice())
+            .unwrap_or(&path)
+            .join("/")
+    }
+
+    /// Native form of a stored relative path, `None` when a segment is empty, `.`, `..` or
+    /// otherwise no plain file name on this platform, so the path never leaves the directory
+    /// it is joined to.
+    pub(crate) fn denormalize(stored: &str) -> Option<PathBuf> {
+        stored
+            .split('/')
+            .map(|segment| {
+                let mut components = Path::new(segment).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) => Some(segment),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+fn layout_file(base: &Path) -> PathBuf {
+    base.join(META_DIR).join(LAYOUT_FILE)
+}
+
+/// Layout recorded in the layout file of the store at `base`, `None` without one.
+///
+/// Fields the file leaves out keep their defaults, layout files of earlier versions only
+/// record the fanout.
+fn read_layout(base: &Path) -> io::Result<Option<StoreConfig>> {
+    let content = match fs::read_to_string(layout_file(base)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed store layout file");
+    let mut store = StoreConfig::default();
+    for line in content.lines() {
+        match line.split_once(' ').ok_or_else(malformed)? {
+            ("fanout", fanout) => store.fanout = fanout.parse().map_err(|_| malformed())?,
+            ("hash", name) => {
+                store.hash_algorithm = HashAlgorithm::from_name(name).ok_or_else(malformed)?
+            }
+            ("extension", extension) => store.extension = Some(extension.to_string()),
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(Some(store))
+}
+
+/// Config of the store at `base` with the fanout, hash algorithm and extension it was written
+/// with, the defaults for a store that records no layout.
+pub fn read_store_config<P: AsRef<Path>>(base: P) -> io::Result<StoreConfig> {
+    Ok(read_layout(base.as_ref())?.unwrap_or_default())
+}
+
+/// Check whether the store at `base` holds the blob `hash`, a store whose layout file cannot
+/// be read holds none.
+pub fn blob_exists<P: AsRef<Path>>(hash: &str, base: P) -> bool {
+    read_store_config(base.as_ref()).is_ok_and(|store| store.blob_exists(hash, base))
+}
+
+/// Size in bytes of the blob `hash` in the store at `base`.
+pub fn blob_size<P: AsRef<Path>>(hash: &str, base: P) -> Result<u64, FileParseError> {
+    read_store_config(base.as_ref())?.blob_size(hash, base)
+}
+
+/// Read a blob from a store, a blob that does not exist is reported as `MissingBaseBlob`.
+pub(crate) fn read_blob(
+    store: &StoreConfig,
+    hash: &str,
+    base: &Path,
+) -> Result<Vec<u8>, FileParseError> {
+    fs::read(store.path_from_hash(hash, base)?).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FileParseError::MissingBaseBlob(hash.to_string()),
+        _ => FileParseError::Io(e),
+    })
 }
 
 impl FromStr for DiffBlob {
     type Err = DeserializeError;
+    /// Parse a directory blob line, malformed input is reported as an error and never panics.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.trim();
-        let len = s.len();
-        if len < 6 {
-            return Err(DeserializeError::InvalidLength);
-        }
-        let (s, len_part) = s.split_at(len - 6);
-        let (name_length, hash_length, type_length) = (
-            usize::from_str_radix(&len_part[0..2], 16)
-                .map_err(|_| DeserializeError::InvalidNameLengthInfo)?,
-            usize::from_str_radix(&len_part[2..4], 16)
-                .map_err(|_| DeserializeError::InvalidHashLengthInfo)?,
-            usize::from_str_radix(&len_part[4..6], 16)
-                .map_err(|_| DeserializeError::InvalidTypeLengthInfo)?,
-        );
-        if len < name_length + hash_length + type_length + 3 {
-            return Err(DeserializeError::InvalidTotalLength);
-        }
-        let (name, rest) = s.split_at(name_length);
-        let (hash, rest) = rest[1..].split_at(hash_length);
-        let blob_type_str = rest[1..].split_at(type_length).0;
-        let blob_type = match blob_type_str {
-            "directory" => DiffBlobType::Directory,
-            "file" => DiffBlobType::File,
-            _ => return Err(DeserializeError::InvalidType),
-        };
+        let (name, hash, blob_type, mode) = split_line(s.as_bytes())?;
+        // a field boundary inside a character makes the field invalid UTF-8
+        let text =
+            |field| std::str::from_utf8(field).map_err(|_| DeserializeError::InvalidTotalLength);
         Ok(DiffBlob {
-            name: name.to_string(),
-            hash: hash.to_string(),
+            name: text(&name)?.to_string(),
+            hash: text(hash)?.to_string(),
             blob_type,
+            mode,
         })
     }
 }
 
+impl DiffBlob {
+    /// Parse a directory blob line whose name is not valid UTF-8, invalid sequences in the name
+    /// are replaced with `U+FFFD`.
+    ///
+    /// The recorded field lengths count bytes, so the line is split before the name is decoded.
+    pub(crate) fn from_bytes_lossy(line: &[u8]) -> Result<Self, DeserializeError> {
+        let (name, hash, blob_type, mode) = split_line(line)?;
+        Ok(DiffBlob {
+            name: String::from_utf8_lossy(&name).to_string(),
+            hash: std::str::from_utf8(hash)
+                .map_err(|_| DeserializeError::InvalidTotalLength)?
+                .to_string(),
+            blob_type,
+            mode,
+        })
+    }
+}
+
+/// Fields of a directory blob line: name, hash, type and mode.
+type LineFields<'a> = (Cow<'a, [u8]>, &'a [u8], DiffBlobType, Option<u32>);
+
+/// Longest field the legacy line layout can record, its lengths are two hex digits each.
+const LEGACY_MAX_FIELD: usize = 0xff;
+
+/// Version tag of the versioned line layout written by this crate.
+const LINE_VERSION: u8 = b'1';
+
+/// Split a directory blob line into name, hash, type and mode.
+///
+/// Lines are written in the legacy layout, `name hash type NNHHTT` with the field lengths as
+/// hex digits at the end, whenever every field fits it, so existing directory hashes do not
+/// change. Longer fields are written in the versioned layout: a NUL, which never starts a name,
+/// the 1-byte version tag and then every field prefixed by its decimal length and a `:`, see
+/// the module documentation for why the lengths are no varints.
+fn split_line(s: &[u8]) -> Result<LineFields<'_>, DeserializeError> {
+    let mut s = s;
+    while let Some(rest) = s.strip_suffix(b"\n").or_else(|| s.strip_suffix(b"\r")) {
+        s = rest;
+    }
+    match s.split_first() {
+        Some((0, rest)) => split_versioned_line(rest),
+        _ => split_legacy_line(s),
+    }
+}
+
+/// Split a line in the versioned layout, `s` starts at the version tag.
+fn split_versioned_line(s: &[u8]) -> Result<LineFields<'_>, DeserializeError> {
+    let (version, mut rest) = s.split_first().ok_or(DeserializeError::InvalidLength)?;
+    if *version != LINE_VERSION {
+        return Err(DeserializeError::UnsupportedVersion(*version));
+    }
+    let mut field = |error: DeserializeError| {
+        let digits = rest
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(DeserializeError::InvalidSeparator)?;
+        let length = std::str::from_utf8(&rest[..digits])
+            .ok()
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<usize>().ok())
+            .ok_or(error)?;
+        let value = rest[digits + 1..]
+            .get(..length)
+            .ok_or(DeserializeError::InvalidTotalLength)?;
+        rest = &rest[digits + 1 + length..];
+        Ok::<_, DeserializeError>(value)
+    };
+    let name = field(DeserializeError::InvalidNameLengthInfo)?;
+    let hash = field(DeserializeError::InvalidHashLengthInfo)?;
+    let type_field = field(DeserializeError::InvalidTypeLengthInfo)?;
+    if !rest.is_empty() {
+        return Err(DeserializeError::InvalidTotalLength);
+    }
+    parse_type_field(name, hash, type_field)
+}
+
+/// Split a line in the legacy layout by the lengths recorded at its end.
+fn split_legacy_line(s: &[u8]) -> Result<LineFields<'_>, DeserializeError> {
+    let split = s
+        .len()
+        .checked_sub(6)
+        .ok_or(DeserializeError::InvalidLength)?;
+    let (s, len_part) = s.split_at(split);
+    // a length part starting inside a character is no length part at all
+    if !len_part.is_ascii() {
+        return Err(DeserializeError::InvalidLength);
+    }
+    let length = |range: std::ops::Range<usize>, error: DeserializeError| {
+        len_part
+            .get(range)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+            .ok_or(error)
+    };
+    let name_length = length(0..2, DeserializeError::InvalidNameLengthInfo)?;
+    let hash_length = length(2..4, DeserializeError::InvalidHashLengthInfo)?;
+    let type_length = length(4..6, DeserializeError::InvalidTypeLengthInfo)?;
+    // the three fields are each followed by a single space
+    if s.len() != name_length + hash_length + type_length + 3 {
+        return Err(DeserializeError::InvalidTotalLength);
+    }
+    let mut rest = s;
+    let mut field = |length: usize| {
+        let value = rest
+            .get(..length)
+            .ok_or(DeserializeError::InvalidTotalLength)?;
+        rest = rest[length..]
+            .strip_prefix(b" ")
+            .ok_or(DeserializeError::InvalidSeparator)?;
+        Ok::<_, DeserializeError>(value)
+    };
+    let name = field(name_length)?;
+    let hash = field(hash_length)?;
+    let type_field = field(type_length)?;
+    parse_type_field(name, hash, type_field)
+}
+
+/// Fields of a line from its split name, hash and type field.
+fn parse_type_field<'a>(
+    name: &'a [u8],
+    hash: &'a [u8],
+    type_field: &[u8],
+) -> Result<LineFields<'a>, DeserializeError> {
+    // lines written before modes were recorded carry the bare type
+    let mut parts = type_field.split(|b| *b == b':');
+    let blob_type = match parts.next() {
+        Some(b"directory") => DiffBlobType::Directory,
+        Some(b"file") => DiffBlobType::File,
+        Some(b"symlink") => DiffBlobType::Symlink,
+        _ => return Err(DeserializeError::InvalidType),
+    };
+    let (mut mode, mut escaped) = (None, false);
+    for part in parts {
+        match part {
+            b"e" => escaped = true,
+            part => {
+                mode = Some(
+                    std::str::from_utf8(part)
+                        .ok()
+                        .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                        .ok_or(DeserializeError::InvalidType)?,
+                )
+            }
+        }
+    }
+    let name = match escaped {
+        true => Cow::Owned(unescape_name(name)),
+        false => Cow::Borrowed(name),
+    };
+    Ok((name, hash, blob_type, mode))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_path_normalization() {
+        let unix = PathNormalization::relative(
+            Path::new("/home/me/tree"),
+            Path::new("/home/me/tree/dir/sub/a.txt"),
+        );
+        assert_eq!(unix, "dir/sub/a.txt");
+        #[cfg(windows)]
+        assert_eq!(
+            PathNormalization::relative(
+                Path::new("C:\\Users\\me\\tree"),
+                Path::new("C:\\Users\\me\\tree\\dir\\sub\\a.txt"),
+            ),
+            unix
+        );
+        // elsewhere a backslash is part of the name
+        #[cfg(not(windows))]
+        assert_eq!(
+            PathNormalization::relative(Path::new("/tree"), Path::new("/tree/dir\\a.txt")),
+            "dir\\a.txt"
+        );
+        assert_eq!(
+            PathNormalization::denormalize(&unix),
+            Some(Path::new("dir").join("sub").join("a.txt"))
+        );
+        for escaping in [
+            "../a.txt",
+            "dir/../../a.txt",
+            "dir//a.txt",
+            "/a.txt",
+            "dir/",
+            "",
+        ] {
+            assert_eq!(
+                PathNormalization::denormalize(escaping),
+                None,
+                "{}",
+                escaping
+            );
+        }
+    }
+
+    #[test]
+    fn test_escaped_name_line() {
+        let blob = |name: &str| DiffBlob {
+            name: name.to_string(),
+            hash: "1234abcd".to_string(),
+            blob_type: DiffBlobType::File,
+            mode: Some(0o644),
+        };
+        // names without line breaks are written as before
+        assert_eq!(blob("a\\b").to_string(), "a\\b 1234abcd file:644 030808\n");
+        let line = blob("a\nb\\n").to_string();
+        assert_eq!(line, "a\\nb\\\\n 1234abcd file:644:e 07080a\n");
+        let parsed = DiffBlob::from_str(&line).unwrap();
+        assert_eq!(parsed.name, "a\nb\\n");
+        assert_eq!(parsed.mode, Some(0o644));
+    }
+
+    #[test]
+    fn test_versioned_line() {
+        let blob = DiffBlob {
+            name: "n".repeat(300),
+            hash: "ab".repeat(100),
+            blob_type: DiffBlobType::File,
+            mode: Some(0o644),
+        };
+        let line = blob.to_string();
+        assert!(line.starts_with("\x001300:nnn"));
+        let parsed = DiffBlob::from_str(&line).unwrap();
+        assert_eq!(parsed.name, blob.name);
+        assert_eq!(parsed.hash, blob.hash);
+        assert_eq!(parsed.mode, Some(0o644));
+
+        // a name with trailing whitespace and line breaks survives
+        let spaced = DiffBlob {
+            name: format!("{} \n ", "s".repeat(300)),
+            ..blob.clone()
+        };
+        assert_eq!(
+            DiffBlob::from_str(&spaced.to_string()).unwrap().name,
+            spaced.name
+        );
+
+        // short fields keep the legacy layout
+        let short = DiffBlob {
+            name: "a".to_string(),
+            hash: "1234abcd".to_string(),
+            ..blob
+        };
+        assert_eq!(short.to_string(), "a 1234abcd file:644 010808\n");
+        assert!(matches!(
+            DiffBlob::from_str("\x0029:a8:1234abcd4:file\n"),
+            Err(DeserializeError::UnsupportedVersion(b'2'))
+        ));
+        for broken in ["\x001", "\x0011:a", "\x0011:a8:1234abcd4:filex", "\x001x:a"] {
+            assert!(DiffBlob::from_str(broken).is_err(), "{:?}", broken);
+        }
+    }
+
+    #[test]
+    fn test_diff_blob_malformed() {
+        let valid = DiffBlob {
+            name: "näme with space".to_string(),
+            hash: "1234abcd".to_string(),
+            blob_type: DiffBlobType::File,
+            mode: None,
+        }
+        .to_string();
+        let parsed = DiffBlob::from_str(&valid).unwrap();
+        assert_eq!(parsed.name, "näme with space");
+        let mut inputs = vec![
+            String::new(),
+            "000000".to_string(),
+            " 000000".to_string(),
+            "ab 000000".to_string(),
+            "a b c 010101".to_string(),
+            "a b c  010101".to_string(),
+            "abc 010101".to_string(),
+            "a b c ffffff".to_string(),
+            "a b file zz0104".to_string(),
+            "a b file 01zz04".to_string(),
+            "ä 000000".to_string(),
+            "a b file 01010ä".to_string(),
+            "ä b file 010104".to_string(),
+        ];
+        for i in 0..valid.len() {
+            if let Some(prefix) = valid.get(..i) {
+                inputs.push(prefix.to_string());
+            }
+            if let Some(suffix) = valid.get(i..) {
+                inputs.push(suffix.to_string());
+            }
+        }
+        // deterministic byte mutations of the valid line
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let mut bytes = valid.clone().into_bytes();
+            let i = seed as usize % bytes.len();
+            bytes[i] = (seed >> 32) as u8;
+            inputs.push(String::from_utf8_lossy(&bytes).to_string());
+        }
+        for input in inputs {
+            let _ = DiffBlob::from_str(&input);
+        }
+    }
+
+    #[test]
+    fn test_durability() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("blob");
+        fs::write(&file, b"content").unwrap();
+        let syncs = |durability: Durability| {
+            let mut count = 0;
+            durability
+                .commit_with(&file, &mut |_| {
+                    count += 1;
+                    Ok(())
+                })
+                .unwrap();
+            count
+        };
+        assert_eq!(syncs(Durability::None), 0);
+        assert_eq!(syncs(Durability::Data), 1);
+        assert_eq!(syncs(Durability::Full), if cfg!(unix) { 2 } else { 1 });
+        Durability::Full.commit(&file).unwrap();
+    }
+
     #[test]
     fn test_diff_blob() {
         let blob = DiffBlob {
             name: "name".to_string(),
             hash: "hash".to_string(),
             blob_type: DiffBlobType::Directory,
+            mode: None,
         };
         let s = blob.to_string();
         assert_eq!(s, "name hash directory 040409\n");
@@ -176,30 +1200,73 @@ mod tests {
             name: "name".to_string(),
             hash: "hash".to_string(),
             blob_type: DiffBlobType::File,
+            mode: None,
         };
         let s = blob.to_string();
         assert_eq!(s, "name hash file 040404\n");
+        let blob = DiffBlob {
+            mode: Some(0o755),
+            ..blob
+        };
+        let s = blob.to_string();
+        assert_eq!(s, "name hash file:755 040408\n");
+        let parsed = DiffBlob::from_str(&s).unwrap();
+        assert_eq!(parsed.mode, Some(0o755));
+        assert_eq!(
+            DiffBlob::from_str("name hash file 040404\n").unwrap().mode,
+            None
+        );
+        assert!(DiffBlob::from_str("name hash file:9x 040407\n").is_err());
     }
 
     #[cfg(feature = "binaryBlob")]
     #[test]
     fn test_binary_diff_blob() {
-        let mut blobs = vec![];
-        for i in 0..100 {
-            let blob = DiffBlob {
+        let blobs: Vec<_> = (0..100)
+            .map(|i| DiffBlob {
                 name: format!("name{}", i),
-                hash: format!("hash{}", i),
-                blob_type: DiffBlobType::Directory,
-            };
-            let binary_blob = blob.into_binary();
-            blobs.push(binary_blob);
-        }
-        let blob_store = BinaryDiffBlobStore { blobs };
-        let buf = to_vec(&blob_store).unwrap();
-        let mut blob_store2 = from_bytes::<BinaryDiffBlobStore>(&buf).unwrap();
-        assert_eq!(blob_store2.blobs.len(), 100);
-        let a = blob_store2.blobs.pop().unwrap();
-        assert_eq!(a.name, "name99");
-        println!("{:?}, last hash: {}", buf.len(), a.hash);
+                hash: format!("{:016x}", i),
+                blob_type: match i % 3 {
+                    0 => DiffBlobType::Directory,
+                    1 => DiffBlobType::File,
+                    _ => DiffBlobType::Symlink,
+                },
+                mode: (i % 3 == 1).then_some(0o644),
+            })
+            .collect();
+        let buf = encode_binary_blobs(blobs.clone()).unwrap();
+        let decoded = decode_binary_blobs(&buf).unwrap().unwrap();
+        assert_eq!(decoded.len(), 100);
+        for (a, b) in blobs.iter().zip(&decoded) {
+            assert_eq!(a.to_string(), b.to_string());
+        }
+        assert!(decode_binary_blobs(b"name 0123 file 040404\n").is_none());
+        assert!(decode_binary_blobs(b"\x001name").is_none());
+        let mut newer = buf.clone();
+        newer[BINARY_BLOB_MAGIC.len()] = BINARY_BLOB_VERSION + 1;
+        assert!(matches!(
+            decode_binary_blobs(&newer),
+            Some(Err(DeserializeError::UnsupportedVersion(2)))
+        ));
+
+        // a stored length that does not match its field is corruption
+        let mut binary = blobs[0].clone().into_binary().unwrap();
+        binary.name_len += 1;
+        assert!(matches!(
+            DiffBlob::from_binary(binary),
+            Err(DeserializeError::InvalidNameLengthInfo)
+        ));
+        let mut binary = blobs[0].clone().into_binary().unwrap();
+        binary.hash_len -= 1;
+        assert!(matches!(
+            DiffBlob::from_binary(binary),
+            Err(DeserializeError::InvalidHashLengthInfo)
+        ));
+        let long = DiffBlob {
+            name: "x".repeat(300),
+            ..blobs[0].clone()
+        };
+        let binary = long.clone().into_binary().unwrap();
+        assert_eq!(DiffBlob::from_binary(binary).unwrap().name, long.name);
     }
 }
@@ -0,0 +1,693 @@
+use crate::checkout::{
+    apply_patch_in_place_in, checkout_in, materialize_in, ChangeKind, CheckoutOptions,
+    CheckoutReport,
+};
+use crate::common::{CancellationToken, DeserializeError, Durability, FileParseError, StoreConfig};
+use crate::diff::{
+    compare_blob_files_cross_in, compare_blob_files_in, DiffCollectionType, Traversal,
+};
+use crate::hash::{
+    blob_directory, plan_blob_directory_in, BlobOptions, BlobPlan, BlobReport, HashAlgorithm,
+};
+use crate::manifest::{
+    collect_garbage_in, fsck_in, verify_store_in, GcStats, IntegrityError, ReplicaIssue,
+};
+use crate::merge::merge_subtree_patches_in;
+use crate::patch::{
+    apply_loose_patch_in, apply_patchs_in, create_zip_patch_in, create_zip_patch_with_stats_in,
+    estimate_delta_in, verify_patch_apply_in, write_loose_patch_in, ApplyOptions, DeltaEstimate,
+    IndexEncoding, MissingDeletePolicy, PatchMetadata, PatchOptions, PatchStats, UnpackLimits,
+    VerifyFailure, ZipFileError,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zip::CompressionMethod;
+
+/// Failure of [`Ditiear::build_patch`], tagged with the stage that failed.
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error("Blobbing the new tree failed")]
+    Blob(#[source] io::Error),
+    #[error("Diffing against the old root failed")]
+    Diff(#[source] FileParseError),
+    #[error("Writing the patch failed")]
+    Patch(#[source] ZipFileError),
+}
+
+/// Failure of a [`Ditiear`] operation, tagged with the layer that failed.
+///
+/// The error of the failing module is kept as the source, so the exact cause stays available
+/// without being flattened. More variants may follow in minor releases, matches need a
+/// wildcard arm.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DitiearError {
+    #[error("I/O failed")]
+    Io(#[source] io::Error),
+    #[error("Reading the store failed")]
+    Store(#[source] FileParseError),
+    #[error("Decoding a directory blob failed")]
+    Blob(#[source] DeserializeError),
+    #[error("Reading or writing a patch failed")]
+    Patch(#[source] ZipFileError),
+    #[error("Building a patch failed")]
+    Build(#[source] BuildError),
+}
+
+impl From<io::Error> for DitiearError {
+    fn from(e: io::Error) -> Self {
+        DitiearError::Io(e)
+    }
+}
+
+impl From<FileParseError> for DitiearError {
+    fn from(e: FileParseError) -> Self {
+        DitiearError::Store(e)
+    }
+}
+
+impl From<DeserializeError> for DitiearError {
+    fn from(e: DeserializeError) -> Self {
+        DitiearError::Blob(e)
+    }
+}
+
+impl From<ZipFileError> for DitiearError {
+    fn from(e: ZipFileError) -> Self {
+        DitiearError::Patch(e)
+    }
+}
+
+impl From<BuildError> for DitiearError {
+    fn from(e: BuildError) -> Self {
+        DitiearError::Build(e)
+    }
+}
+
+/// Settings for the whole blob → diff → patch → apply pipeline.
+///
+/// A `Ditiear` carries the same store layout through every operation, so a store written by
+/// [`Ditiear::blob_directory`] is always read back consistently. Use [`Ditiear::builder`] to
+/// configure one.
+#[derive(Clone, Debug, Default)]
+pub struct Ditiear {
+    store: StoreConfig,
+    blob: BlobOptions,
+    patch: PatchOptions,
+    apply: ApplyOptions,
+}
+
+impl Ditiear {
+    pub fn builder() -> DitiearBuilder {
+        DitiearBuilder::default()
+    }
+
+    /// Blob the directory `from_path` into the store at `base`.
+    pub fn blob_directory<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        base: P,
+        from_path: Q,
+    ) -> Result<BlobReport, DitiearError> {
+        Ok(blob_directory(base, from_path, &self.store, &self.blob)?)
+    }
+
+    /// Report which blobs of `from_path` the store at `base` is missing, see
+    /// [`plan_blob_directory`](crate::prelude::plan_blob_directory).
+    pub fn plan_blob_directory<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        base: P,
+        from_path: Q,
+    ) -> Result<BlobPlan, DitiearError> {
+        Ok(plan_blob_directory_in(
+            from_path.as_ref(),
+            base.as_ref(),
+            &self.store,
+            &self.blob,
+        )?)
+    }
+
+    /// Compare two stored roots.
+    pub fn diff<P: AsRef<Path>>(
+        &self,
+        old: &str,
+        new: &str,
+        base: P,
+    ) -> Result<Vec<DiffCollectionType>, DitiearError> {
+        Ok(compare_blob_files_in(
+            old,
+            new,
+            base,
+            &self.store,
+            Traversal::default(),
+            &self.blob.cancellation,
+        )?)
+    }
+
+    /// Compare roots of two separate stores, both laid out with these settings, see
+    /// [`compare_blob_files_cross`](crate::prelude::compare_blob_files_cross).
+    pub fn diff_cross<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        old: &str,
+        old_base: P,
+        new: &str,
+        new_base: Q,
+    ) -> Result<Vec<DiffCollectionType>, DitiearError> {
+        Ok(compare_blob_files_cross_in(
+            old,
+            old_base.as_ref(),
+            new,
+            new_base.as_ref(),
+            &self.store,
+            &self.blob.cancellation,
+        )?)
+    }
+
+    /// Compare shipping the delta of a modified entry with shipping its new blob whole, see
+    /// [`estimate_delta`](crate::prelude::estimate_delta).
+    pub fn estimate_delta<P: AsRef<Path>>(
+        &self,
+        diff: &DiffCollectionType,
+        base: P,
+    ) -> Result<Option<DeltaEstimate>, DitiearError> {
+        Ok(estimate_delta_in(
+            diff,
+            base.as_ref(),
+            &self.store,
+            &self.patch,
+        )?)
+    }
+
+    /// Write a patch turning the stored root `old` into `new`.
+    pub fn create_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        old: &str,
+        new: &str,
+        base: P,
+        to_dest: Q,
+    ) -> Result<(), DitiearError> {
+        let diffs = self.diff(old, new, base.as_ref())?;
+        Ok(create_zip_patch_in(
+            diffs,
+            base,
+            to_dest,
+            &self.store,
+            &self.patch,
+        )?)
+    }
+
+    /// Like [`Ditiear::create_patch`], returning how large the patch is.
+    pub fn create_patch_with_stats<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        old: &str,
+        new: &str,
+        base: P,
+        to_dest: Q,
+    ) -> Result<PatchStats, DitiearError> {
+        let diffs = self.diff(old, new, base.as_ref())?;
+        Ok(create_zip_patch_with_stats_in(
+            diffs,
+            base,
+            to_dest,
+            &self.store,
+            &self.patch,
+        )?)
+    }
+
+    /// Write a patch turning the stored root `old` into `new` as loose objects into `out_dir`,
+    /// see [`write_loose_patch`](crate::prelude::write_loose_patch).
+    pub fn write_loose_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        old: &str,
+        new: &str,
+        base: P,
+        out_dir: Q,
+    ) -> Result<(), DitiearError> {
+        let diffs = self.diff(old, new, base.as_ref())?;
+        Ok(write_loose_patch_in(
+            diffs,
+            base,
+            out_dir,
+            &self.store,
+            &self.patch,
+        )?)
+    }
+
+    /// Merge patches of separate subtrees of `base_root` into one patch, see
+    /// [`merge_subtree_patches`](crate::prelude::merge_subtree_patches).
+    pub fn merge_subtree_patches<P, S, Q, R>(
+        &self,
+        base_root: &str,
+        base: P,
+        subtree_patches: &[(S, Q)],
+        to_dest: R,
+    ) -> Result<String, DitiearError>
+    where
+        P: AsRef<Path>,
+        S: AsRef<str>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        Ok(merge_subtree_patches_in(
+            base_root,
+            base.as_ref(),
+            subtree_patches,
+            to_dest.as_ref(),
+            &self.store,
+            &self.patch,
+        )?)
+    }
+
+    /// Blob `new_tree` into the store at `base` and write a patch from the stored root
+    /// `old_root` to it, returning the new root hash.
+    ///
+    /// Unlike chaining the single steps, a [`DitiearError::Build`] tells which stage failed.
+    pub fn build_patch<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        old_root: &str,
+        new_tree: P,
+        base: Q,
+        to_dest: R,
+    ) -> Result<String, DitiearError> {
+        let new_root = blob_directory(base.as_ref(), new_tree, &self.store, &self.blob)
+            .map_err(BuildError::Blob)?
+            .hash;
+        let diffs = compare_blob_files_in(
+            old_root,
+            &new_root,
+            base.as_ref(),
+            &self.store,
+            Traversal::default(),
+            &self.blob.cancellation,
+        )
+        .map_err(BuildError::Diff)?;
+        create_zip_patch_in(diffs, base, to_dest, &self.store, &self.patch)
+            .map_err(BuildError::Patch)?;
+        Ok(new_root)
+    }
+
+    /// Remove the blobs of the store at `base` that none of `live_roots` reaches, reporting how
+    /// many objects and bytes were freed.
+    pub fn gc<P: AsRef<Path>>(
+        &self,
+        base: P,
+        live_roots: &[&str],
+    ) -> Result<GcStats, DitiearError> {
+        Ok(collect_garbage_in(live_roots, base.as_ref(), &self.store)?)
+    }
+
+    /// Same as [`Ditiear::gc`] with the roots first, returning only the removed hashes.
+    pub fn collect_garbage<P: AsRef<Path>>(
+        &self,
+        roots: &[&str],
+        base: P,
+    ) -> Result<Vec<String>, DitiearError> {
+        Ok(self.gc(base, roots)?.removed)
+    }
+
+    /// Check every blob of the store at `base` and the children of every directory blob.
+    pub fn fsck<P: AsRef<Path>>(&self, base: P) -> Result<Vec<IntegrityError>, DitiearError> {
+        Ok(fsck_in(base.as_ref(), &self.store)?)
+    }
+
+    /// Check that every blob of the store at `base` hashes to its name.
+    pub fn verify_store<P: AsRef<Path>>(&self, base: P) -> Result<Vec<ReplicaIssue>, DitiearError> {
+        Ok(verify_store_in(base.as_ref(), &self.store)?)
+    }
+
+    /// Apply a patch to the store at `base`.
+    pub fn apply<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        patch_path: P,
+        base: Q,
+    ) -> Result<(), DitiearError> {
+        Ok(apply_patchs_in(patch_path, base, &self.store, &self.apply)?)
+    }
+
+    /// Reconstruct every blob of a patch against the store at `base` without changing the
+    /// store, see [`verify_patch_apply`](crate::prelude::verify_patch_apply).
+    pub fn verify_apply<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        patch_path: P,
+        base: Q,
+    ) -> Result<(), Vec<VerifyFailure>> {
+        verify_patch_apply_in(patch_path.as_ref(), base.as_ref(), &self.store, &self.apply)
+    }
+
+    /// Apply a patch written by [`Ditiear::write_loose_patch`] to the store at `base`.
+    pub fn apply_loose<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        patch_dir: P,
+        base: Q,
+    ) -> Result<(), DitiearError> {
+        Ok(apply_loose_patch_in(
+            patch_dir,
+            base,
+            &self.store,
+            &self.apply,
+        )?)
+    }
+
+    /// Apply a patch to the store at `base` and update the working tree checked out from
+    /// `old_root` at `working_dir` to `new_root`, see
+    /// [`apply_patch_in_place_with_progress`](crate::prelude::apply_patch_in_place_with_progress).
+    pub fn apply_in_place<P, Q, R, F>(
+        &self,
+        patch_path: P,
+        base: Q,
+        working_dir: R,
+        old_root: &str,
+        new_root: &str,
+        on_change: F,
+    ) -> Result<(), DitiearError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+        F: Fn(&str, ChangeKind),
+    {
+        Ok(apply_patch_in_place_in(
+            patch_path.as_ref(),
+            base.as_ref(),
+            working_dir.as_ref(),
+            (old_root, new_root),
+            &self.store,
+            &self.apply,
+            on_change,
+        )?)
+    }
+
+    /// Write the files of the stored root `root_hash` with their real names below `dest`, see
+    /// [`checkout_with_options`](crate::prelude::checkout_with_options).
+    pub fn checkout<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        root_hash: &str,
+        base: P,
+        dest: Q,
+        options: &CheckoutOptions,
+    ) -> Result<CheckoutReport, DitiearError> {
+        Ok(checkout_in(
+            root_hash,
+            base.as_ref(),
+            dest.as_ref(),
+            &self.store,
+            options,
+        )?)
+    }
+
+    /// Apply a patch to the store at `store_base` and check the patched root `new_root_hash`
+    /// out below `output_dir`.
+    pub fn materialize<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        patch_path: P,
+        store_base: Q,
+        output_dir: R,
+        new_root_hash: &str,
+    ) -> Result<CheckoutReport, DitiearError> {
+        Ok(materialize_in(
+            patch_path.as_ref(),
+            store_base.as_ref(),
+            output_dir.as_ref(),
+            new_root_hash,
+            &self.store,
+            &self.apply,
+        )?)
+    }
+
+    /// The store at `root`, read and written with these settings.
+    pub fn store_at<P: Into<PathBuf>>(&self, root: P) -> BlobStore {
+        BlobStore {
+            root: root.into(),
+            ditiear: self.clone(),
+        }
+    }
+}
+
+/// A store directory together with the [`Ditiear`] settings it is used with.
+///
+/// The store root is named once, so it cannot be mixed up with the tree being blobbed or the
+/// patch being written as with the free functions taking two paths.
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+    root: PathBuf,
+    ditiear: Ditiear,
+}
+
+impl BlobStore {
+    /// The store at `root` with the default settings, see [`Ditiear::store_at`].
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Ditiear::default().store_at(root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Blob the directory `src` into the store, returning its root hash.
+    pub fn hash_dir<P: AsRef<Path>>(&self, src: P) -> Result<String, DitiearError> {
+        Ok(self.ditiear.blob_directory(&self.root, src)?.hash)
+    }
+
+    /// Compare two stored roots.
+    pub fn compare(&self, old: &str, new: &str) -> Result<Vec<DiffCollectionType>, DitiearError> {
+        self.ditiear.diff(old, new, &self.root)
+    }
+
+    /// Write a patch turning the stored root `old` into `new` to `dest`.
+    pub fn create_patch<P: AsRef<Path>>(
+        &self,
+        old: &str,
+        new: &str,
+        dest: P,
+    ) -> Result<(), DitiearError> {
+        self.ditiear.create_patch(old, new, &self.root, dest)
+    }
+
+    /// Apply a patch to the store.
+    pub fn apply<P: AsRef<Path>>(&self, patch_path: P) -> Result<(), DitiearError> {
+        self.ditiear.apply(patch_path, &self.root)
+    }
+
+    /// Write the files of the stored root `root_hash` below `dest`, see [`Ditiear::checkout`].
+    pub fn checkout<P: AsRef<Path>>(
+        &self,
+        root_hash: &str,
+        dest: P,
+        options: &CheckoutOptions,
+    ) -> Result<CheckoutReport, DitiearError> {
+        self.ditiear.checkout(root_hash, &self.root, dest, options)
+    }
+
+    /// Report which blobs of the directory `src` the store is missing.
+    pub fn plan<P: AsRef<Path>>(&self, src: P) -> Result<BlobPlan, DitiearError> {
+        self.ditiear.plan_blob_directory(&self.root, src)
+    }
+
+    /// Remove the blobs of the store that none of `live_roots` reaches.
+    pub fn gc(&self, live_roots: &[&str]) -> Result<GcStats, DitiearError> {
+        self.ditiear.gc(&self.root, live_roots)
+    }
+
+    /// Check every blob of the store, see [`Ditiear::fsck`].
+    pub fn fsck(&self) -> Result<Vec<IntegrityError>, DitiearError> {
+        self.ditiear.fsck(&self.root)
+    }
+
+    /// Check whether the store holds the blob `hash`.
+    pub fn exists(&self, hash: &str) -> bool {
+        self.ditiear.store.blob_exists(hash, &self.root)
+    }
+}
+
+/// Builder for [`Ditiear`].
+#[derive(Clone, Debug, Default)]
+pub struct DitiearBuilder {
+    inner: Ditiear,
+}
+
+impl DitiearBuilder {
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.inner.store.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Number of leading hash characters used as shard directory name.
+    ///
+    /// A fanout other than the default is recorded in the store on the first write, later
+    /// writes with another fanout fail, see [`read_store_config`](crate::prelude::read_store_config).
+    pub fn fanout(mut self, fanout: usize) -> Self {
+        self.inner.store.fanout = fanout;
+        self
+    }
+
+    /// Extension appended to every blob file name, see [`StoreConfig::extension`].
+    pub fn blob_extension<S: Into<String>>(mut self, extension: S) -> Self {
+        self.inner.store.extension = Some(extension.into());
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionMethod) -> Self {
+        self.inner.patch.compression = compression;
+        self
+    }
+
+    /// Level of the compression of created patches, `None` uses the method's default.
+    pub fn compression_level(mut self, level: Option<i32>) -> Self {
+        self.inner.patch.compression_level = level;
+        self
+    }
+
+    /// Store added blobs uncompressed whose content already is compressed.
+    pub fn store_compressed(mut self, store_compressed: bool) -> Self {
+        self.inner.patch.store_compressed = store_compressed;
+        self
+    }
+
+    /// Metadata written into created patches.
+    pub fn metadata(mut self, metadata: PatchMetadata) -> Self {
+        self.inner.patch.metadata = Some(metadata);
+        self
+    }
+
+    /// Encoding of byte patch positions in created patches.
+    pub fn index_encoding(mut self, index_encoding: IndexEncoding) -> Self {
+        self.inner.patch.index_encoding = index_encoding;
+        self
+    }
+
+    /// Memory map modified blobs while diffing instead of reading them onto the heap.
+    #[cfg(feature = "mmap")]
+    pub fn memory_map(mut self, memory_map: bool) -> Self {
+        self.inner.patch.memory_map = memory_map;
+        self
+    }
+
+    /// Compress the records of created patches as one zstd stream.
+    #[cfg(feature = "zstd")]
+    pub fn zstd_index(mut self, zstd_index: bool) -> Self {
+        self.inner.patch.zstd_index = zstd_index;
+        self
+    }
+
+    /// Leave out add payloads the patch also deletes, see
+    /// [`PatchOptions::reference_deleted_adds`].
+    pub fn reference_deleted_adds(mut self, reference_deleted_adds: bool) -> Self {
+        self.inner.patch.reference_deleted_adds = reference_deleted_adds;
+        self
+    }
+
+    /// Merge byte patches separated by fewer than `gap` unchanged bytes.
+    pub fn coalesce_gap(mut self, gap: usize) -> Self {
+        self.inner.patch.coalesce_gap = gap;
+        self
+    }
+
+    /// Most bytes of a modified file diffed in memory, see
+    /// [`BinaryDiffOptions::max_in_memory`](crate::prelude::BinaryDiffOptions::max_in_memory).
+    pub fn max_in_memory(mut self, max_in_memory: usize) -> Self {
+        self.inner.patch.binary_diff.max_in_memory = max_in_memory;
+        self
+    }
+
+    /// Add ignore patterns used while blobbing.
+    pub fn ignore<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.inner
+            .blob
+            .ignore
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.inner.blob.max_file_size = Some(max_file_size);
+        self
+    }
+
+    pub fn record_paths(mut self, record_paths: bool) -> Self {
+        self.inner.blob.record_paths = record_paths;
+        self
+    }
+
+    /// Hash and copy files in a single read, see [`BlobOptions::single_pass`].
+    pub fn single_pass(mut self, single_pass: bool) -> Self {
+        self.inner.blob.single_pass = single_pass;
+        self
+    }
+
+    /// Store block signatures of file blobs, see [`BlobOptions::store_signatures`].
+    pub fn store_signatures(mut self, store_signatures: bool) -> Self {
+        self.inner.blob.store_signatures = store_signatures;
+        self
+    }
+
+    /// Record file permission bits, see [`BlobOptions::record_mode`].
+    pub fn record_mode(mut self, record_mode: bool) -> Self {
+        self.inner.blob.record_mode = record_mode;
+        self
+    }
+
+    /// Keep empty directories, see [`BlobOptions::keep_empty_dirs`].
+    pub fn keep_empty_dirs(mut self, keep_empty_dirs: bool) -> Self {
+        self.inner.blob.keep_empty_dirs = keep_empty_dirs;
+        self
+    }
+
+    /// Move blobbed files into the store, see [`BlobOptions::consume_source`].
+    pub fn consume_source(mut self, consume_source: bool) -> Self {
+        self.inner.blob.consume_source = consume_source;
+        self
+    }
+
+    /// Syncing of every file written while blobbing, creating and applying patches.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.inner.blob.durability = durability;
+        self.inner.patch.durability = durability;
+        self.inner.apply.durability = durability;
+        self
+    }
+
+    /// Limits on the decompressed size of applied patches.
+    pub fn unpack_limits(mut self, limits: UnpackLimits) -> Self {
+        self.inner.apply.limits = limits;
+        self
+    }
+
+    /// Remove the blobs of deleted entries when applying.
+    pub fn delete_removed(mut self, delete_removed: bool) -> Self {
+        self.inner.apply.delete_removed = delete_removed;
+        self
+    }
+
+    /// Token checked by blobbing, diffing and applying, see [`CancellationToken`].
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.inner.blob.cancellation = token.clone();
+        self.inner.apply.cancellation = token;
+        self
+    }
+
+    /// Handling of deleted entries whose blob the store lacks when applying.
+    pub fn on_missing_delete(mut self, policy: MissingDeletePolicy) -> Self {
+        self.inner.apply.on_missing_delete = policy;
+        self
+    }
+
+    /// Check every blob rebuilt when applying against its hash before publishing it.
+    pub fn verify_hashes(mut self, verify_hashes: bool) -> Self {
+        self.inner.apply.verify_hashes = verify_hashes;
+        self
+    }
+
+    /// Apply patches without checking their old root against the store.
+    pub fn skip_source_check(mut self, skip_source_check: bool) -> Self {
+        self.inner.apply.skip_source_check = skip_source_check;
+        self
+    }
+
+    pub fn build(self) -> Ditiear {
+        self.inner
+    }
+}
@@ -0,0 +1,762 @@
+//! Applying byte patches to in-memory buffers.
+//!
+//! This module only depends on `core` and `alloc`, so it stays available when the crate is
+//! built without the default `std` feature for appliers without a file system.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+fn serialize_bytes<S>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serde_bytes::serialize(bytes.as_ref(), serializer)
+}
+
+fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
+    Ok(Bytes::from(bytes))
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum BytesPatch {
+    Add {
+        old_index: usize,
+        new_index: usize,
+        #[serde(
+            serialize_with = "serialize_bytes",
+            deserialize_with = "deserialize_bytes"
+        )]
+        new_value: Bytes,
+    },
+    Delete {
+        old_index: usize,
+        new_index: usize,
+        #[serde(
+            serialize_with = "serialize_bytes",
+            deserialize_with = "deserialize_bytes"
+        )]
+        old_value: Bytes,
+    },
+    Replace {
+        old_index: usize,
+        new_index: usize,
+        #[serde(
+            serialize_with = "serialize_bytes",
+            deserialize_with = "deserialize_bytes"
+        )]
+        old_value: Bytes,
+        #[serde(
+            serialize_with = "serialize_bytes",
+            deserialize_with = "deserialize_bytes"
+        )]
+        new_value: Bytes,
+    },
+}
+
+impl BytesPatch {
+    /// `(old_index, old_len, new_index, new_len)` of the op.
+    pub(crate) fn ranges(&self) -> (usize, usize, usize, usize) {
+        match self {
+            BytesPatch::Add {
+                old_index,
+                new_index,
+                new_value,
+            } => (*old_index, 0, *new_index, new_value.len()),
+            BytesPatch::Delete {
+                old_index,
+                new_index,
+                old_value,
+            } => (*old_index, old_value.len(), *new_index, 0),
+            BytesPatch::Replace {
+                old_index,
+                new_index,
+                old_value,
+                new_value,
+            } => (*old_index, old_value.len(), *new_index, new_value.len()),
+        }
+    }
+
+    /// The same op with its positions moved by `old` and `new` bytes.
+    #[cfg(feature = "std")]
+    pub(crate) fn offset(self, old: usize, new: usize) -> Self {
+        match self {
+            BytesPatch::Add {
+                old_index,
+                new_index,
+                new_value,
+            } => BytesPatch::Add {
+                old_index: old_index + old,
+                new_index: new_index + new,
+                new_value,
+            },
+            BytesPatch::Delete {
+                old_index,
+                new_index,
+                old_value,
+            } => BytesPatch::Delete {
+                old_index: old_index + old,
+                new_index: new_index + new,
+                old_value,
+            },
+            BytesPatch::Replace {
+                old_index,
+                new_index,
+                old_value,
+                new_value,
+            } => BytesPatch::Replace {
+                old_index: old_index + old,
+                new_index: new_index + new,
+                old_value,
+                new_value,
+            },
+        }
+    }
+
+    /// The op turning the new buffer back into the old one.
+    ///
+    /// Every op records its position in both buffers, so inverting swaps the positions along
+    /// with the removed and inserted bytes.
+    pub fn invert(self) -> Self {
+        match self {
+            BytesPatch::Add {
+                old_index,
+                new_index,
+                new_value,
+            } => BytesPatch::Delete {
+                old_index: new_index,
+                new_index: old_index,
+                old_value: new_value,
+            },
+            BytesPatch::Delete {
+                old_index,
+                new_index,
+                old_value,
+            } => BytesPatch::Add {
+                old_index: new_index,
+                new_index: old_index,
+                new_value: old_value,
+            },
+            BytesPatch::Replace {
+                old_index,
+                new_index,
+                old_value,
+                new_value,
+            } => BytesPatch::Replace {
+                old_index: new_index,
+                new_index: old_index,
+                old_value: new_value,
+                new_value: old_value,
+            },
+        }
+    }
+}
+
+/// Error of [`apply_binary_patch`], the buffer is not the one the patch was made from.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// An op reaches past the end of the old buffer or overlaps the previous op.
+    OutOfBounds { old_index: usize },
+    /// The bytes an op removes differ from the old buffer.
+    Mismatch { old_index: usize },
+    /// Delta encoded ops end early or carry an unknown tag.
+    InvalidEncoding,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::OutOfBounds { old_index } => {
+                write!(f, "Patch op at {} is out of bounds", old_index)
+            }
+            DeltaError::Mismatch { old_index } => {
+                write!(f, "Patch op at {} does not match the old buffer", old_index)
+            }
+            DeltaError::InvalidEncoding => write!(f, "Invalid delta encoded patch ops"),
+        }
+    }
+}
+
+/// Apply the ops of `calculate_binary_diff` to the buffer they were calculated from and return
+/// the new buffer.
+pub fn apply_binary_patch(old: &[u8], patch: &[BytesPatch]) -> Result<Vec<u8>, DeltaError> {
+    let mut ops = patch.iter().collect::<Vec<_>>();
+    ops.sort_by_key(|op| op.ranges().0);
+    let mut result = Vec::with_capacity(old.len());
+    let mut pos = 0;
+    for op in ops {
+        let (old_index, old_len, _, _) = op.ranges();
+        let old_end = old_index
+            .checked_add(old_len)
+            .filter(|end| old_index >= pos && *end <= old.len())
+            .ok_or(DeltaError::OutOfBounds { old_index })?;
+        let (removed, added) = match op {
+            BytesPatch::Add { new_value, .. } => (None, Some(new_value)),
+            BytesPatch::Delete { old_value, .. } => (Some(old_value), None),
+            BytesPatch::Replace {
+                old_value,
+                new_value,
+                ..
+            } => (Some(old_value), Some(new_value)),
+        };
+        if removed.is_some_and(|r| r.as_ref() != &old[old_index..old_end]) {
+            return Err(DeltaError::Mismatch { old_index });
+        }
+        result.extend_from_slice(&old[pos..old_index]);
+        if let Some(added) = added {
+            result.extend_from_slice(added);
+        }
+        pos = old_end;
+    }
+    result.extend_from_slice(&old[pos..]);
+    Ok(result)
+}
+
+/// A run of a buffer described relative to an older buffer.
+enum Piece {
+    /// `len` bytes of the older buffer from `start`.
+    Old { start: usize, len: usize },
+    /// Bytes inserted by an op.
+    New(Bytes),
+}
+
+/// A buffer as the pieces an op list leaves of an old buffer, followed by the rest of the old
+/// buffer from `tail` on.
+struct Pieces {
+    pieces: Vec<Piece>,
+    len: usize,
+    tail: usize,
+}
+
+impl Pieces {
+    /// Old bytes in the old buffer, keyed by their position, are added to `removed`.
+    fn new(ops: &[BytesPatch], removed: &mut BTreeMap<usize, Bytes>) -> Result<Self, DeltaError> {
+        let mut ops = ops.iter().collect::<Vec<_>>();
+        ops.sort_by_key(|op| op.ranges().0);
+        let (mut pieces, mut len, mut pos) = (Vec::new(), 0, 0);
+        for op in ops {
+            let (old_index, old_len, _, _) = op.ranges();
+            let old_end = old_index
+                .checked_add(old_len)
+                .filter(|_| old_index >= pos)
+                .ok_or(DeltaError::OutOfBounds { old_index })?;
+            if old_index > pos {
+                pieces.push(Piece::Old {
+                    start: pos,
+                    len: old_index - pos,
+                });
+                len += old_index - pos;
+            }
+            match op {
+                BytesPatch::Add { new_value, .. } => {
+                    len += new_value.len();
+                    pieces.push(Piece::New(new_value.clone()));
+                }
+                BytesPatch::Delete { old_value, .. } => {
+                    removed.insert(old_index, old_value.clone());
+                }
+                BytesPatch::Replace {
+                    old_value,
+                    new_value,
+                    ..
+                } => {
+                    removed.insert(old_index, old_value.clone());
+                    len += new_value.len();
+                    pieces.push(Piece::New(new_value.clone()));
+                }
+            }
+            pos = old_end;
+        }
+        Ok(Pieces {
+            pieces,
+            len,
+            tail: pos,
+        })
+    }
+
+    /// The pieces of `start..end` of the buffer, in terms of the old buffer.
+    fn slice(&self, start: usize, end: usize) -> Vec<Piece> {
+        let mut out = Vec::new();
+        let mut offset = 0;
+        for piece in &self.pieces {
+            let len = match piece {
+                Piece::Old { len, .. } => *len,
+                Piece::New(bytes) => bytes.len(),
+            };
+            let (from, to) = (start.max(offset), end.min(offset + len));
+            if from < to {
+                out.push(match piece {
+                    Piece::Old { start, .. } => Piece::Old {
+                        start: start + from - offset,
+                        len: to - from,
+                    },
+                    Piece::New(bytes) => Piece::New(bytes.slice(from - offset..to - offset)),
+                });
+            }
+            offset += len;
+        }
+        if end > self.len {
+            let from = start.max(self.len);
+            out.push(Piece::Old {
+                start: self.tail + from - self.len,
+                len: end - from,
+            });
+        }
+        out
+    }
+}
+
+/// Compose `first`, the ops turning a buffer `a` into `b`, with `second`, the ops turning `b`
+/// into `c`, into the ops turning `a` into `c`.
+///
+/// Bytes `second` removes from what `first` inserted are checked against each other, the old
+/// bytes of the composed ops are taken from the old values of both lists, so neither buffer is
+/// needed.
+pub fn compose_ops(
+    first: &[BytesPatch],
+    second: &[BytesPatch],
+) -> Result<Vec<BytesPatch>, DeltaError> {
+    let mut removed = BTreeMap::new();
+    let middle = Pieces::new(first, &mut removed)?;
+    let mut ops = second.iter().collect::<Vec<_>>();
+    ops.sort_by_key(|op| op.ranges().0);
+    // pieces of `c` in terms of `a`
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+    for op in ops {
+        let (old_index, old_len, _, _) = op.ranges();
+        // past the end of `b` the rest of `a` is addressed from `middle.tail` on
+        let old_end = old_index
+            .checked_add(old_len)
+            .filter(|end| {
+                old_index >= pos
+                    && middle
+                        .tail
+                        .checked_add(end.saturating_sub(middle.len))
+                        .is_some()
+            })
+            .ok_or(DeltaError::OutOfBounds { old_index })?;
+        pieces.extend(middle.slice(pos, old_index));
+        let (old_value, new_value) = match op {
+            BytesPatch::Add { new_value, .. } => (None, Some(new_value)),
+            BytesPatch::Delete { old_value, .. } => (Some(old_value), None),
+            BytesPatch::Replace {
+                old_value,
+                new_value,
+                ..
+            } => (Some(old_value), Some(new_value)),
+        };
+        if let Some(old_value) = old_value {
+            let mut offset = 0;
+            for piece in middle.slice(old_index, old_end) {
+                match piece {
+                    Piece::Old { start, len } => {
+                        removed.insert(start, old_value.slice(offset..offset + len));
+                        offset += len;
+                    }
+                    Piece::New(bytes) => {
+                        if bytes != old_value[offset..offset + bytes.len()] {
+                            return Err(DeltaError::Mismatch { old_index });
+                        }
+                        offset += bytes.len();
+                    }
+                }
+            }
+        }
+        if let Some(new_value) = new_value {
+            pieces.push(Piece::New(new_value.clone()));
+        }
+        pos = old_end;
+    }
+    let tail = match pos <= middle.len {
+        true => {
+            pieces.extend(middle.slice(pos, middle.len));
+            middle.tail
+        }
+        false => middle.tail + pos - middle.len,
+    };
+    pieces.push(Piece::Old {
+        start: tail,
+        len: 0,
+    });
+
+    let mut composed = Vec::new();
+    let (mut old_pos, mut new_pos) = (0, 0);
+    let mut literal = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::New(bytes) => literal.extend_from_slice(&bytes),
+            Piece::Old { start, len } => {
+                if start < old_pos {
+                    return Err(DeltaError::OutOfBounds { old_index: start });
+                }
+                let old_value = removed_bytes(&removed, old_pos, start)?;
+                let new_value = Bytes::from(core::mem::take(&mut literal));
+                let new_len = new_value.len();
+                let (old_index, new_index) = (old_pos, new_pos);
+                composed.extend(match (old_value.is_empty(), new_value.is_empty()) {
+                    (true, true) => None,
+                    (true, false) => Some(BytesPatch::Add {
+                        old_index,
+                        new_index,
+                        new_value,
+                    }),
+                    (false, true) => Some(BytesPatch::Delete {
+                        old_index,
+                        new_index,
+                        old_value,
+                    }),
+                    (false, false) => Some(BytesPatch::Replace {
+                        old_index,
+                        new_index,
+                        old_value,
+                        new_value,
+                    }),
+                });
+                old_pos = start + len;
+                new_pos = new_len
+                    .checked_add(len)
+                    .and_then(|len| new_pos.checked_add(len))
+                    .ok_or(DeltaError::OutOfBounds { old_index: start })?;
+            }
+        }
+    }
+    Ok(composed)
+}
+
+/// The removed old bytes of `start..end`, which must be covered by `removed` without a gap.
+fn removed_bytes(
+    removed: &BTreeMap<usize, Bytes>,
+    start: usize,
+    end: usize,
+) -> Result<Bytes, DeltaError> {
+    let mut bytes = Vec::new();
+    for (index, value) in removed.range(start..end) {
+        if *index != start + bytes.len() {
+            return Err(DeltaError::OutOfBounds { old_index: *index });
+        }
+        bytes.extend_from_slice(value);
+    }
+    if start + bytes.len() != end {
+        return Err(DeltaError::OutOfBounds { old_index: start });
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// Encode ops with the position of every op stored relative to the end of the previous one.
+///
+/// Each op is a tag byte followed by zigzag varints of the old and new position deltas and
+/// the length prefixed values, so the usually small gaps between ops take a byte or two
+/// instead of two full integers.
+pub fn encode_ops(patch: &[BytesPatch]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let (mut old_end, mut new_end) = (0i64, 0i64);
+    for op in patch {
+        let (old_index, old_len, new_index, new_len) = op.ranges();
+        let (tag, values): (u8, &[&Bytes]) = match op {
+            BytesPatch::Add { new_value, .. } => (0, &[new_value]),
+            BytesPatch::Delete { old_value, .. } => (1, &[old_value]),
+            BytesPatch::Replace {
+                old_value,
+                new_value,
+                ..
+            } => (2, &[old_value, new_value]),
+        };
+        out.push(tag);
+        write_varint(&mut out, zigzag(old_index as i64 - old_end));
+        write_varint(&mut out, zigzag(new_index as i64 - new_end));
+        for value in values {
+            write_varint(&mut out, value.len() as u64);
+            out.extend_from_slice(value);
+        }
+        old_end = (old_index + old_len) as i64;
+        new_end = (new_index + new_len) as i64;
+    }
+    out
+}
+
+/// Decode ops written by [`encode_ops`].
+pub fn decode_ops(mut data: &[u8]) -> Result<Vec<BytesPatch>, DeltaError> {
+    let mut patch = Vec::new();
+    let (mut old_end, mut new_end) = (0i64, 0i64);
+    while let Some((&tag, rest)) = data.split_first() {
+        data = rest;
+        let position = |data: &mut &[u8], end: i64| {
+            end.checked_add(unzigzag(read_varint(data)?))
+                .and_then(|index| usize::try_from(index).ok())
+                .ok_or(DeltaError::InvalidEncoding)
+        };
+        let old_index = position(&mut data, old_end)?;
+        let new_index = position(&mut data, new_end)?;
+        let op = match tag {
+            0 => BytesPatch::Add {
+                old_index,
+                new_index,
+                new_value: read_value(&mut data)?,
+            },
+            1 => BytesPatch::Delete {
+                old_index,
+                new_index,
+                old_value: read_value(&mut data)?,
+            },
+            2 => BytesPatch::Replace {
+                old_index,
+                new_index,
+                old_value: read_value(&mut data)?,
+                new_value: read_value(&mut data)?,
+            },
+            _ => return Err(DeltaError::InvalidEncoding),
+        };
+        let (_, old_len, _, new_len) = op.ranges();
+        let end = |index: usize, len: usize| {
+            index
+                .checked_add(len)
+                .and_then(|end| i64::try_from(end).ok())
+                .ok_or(DeltaError::InvalidEncoding)
+        };
+        old_end = end(old_index, old_len)?;
+        new_end = end(new_index, new_len)?;
+        patch.push(op);
+    }
+    Ok(patch)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, DeltaError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(DeltaError::InvalidEncoding)?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DeltaError::InvalidEncoding)
+}
+
+fn read_value(data: &mut &[u8]) -> Result<Bytes, DeltaError> {
+    let len = usize::try_from(read_varint(data)?).map_err(|_| DeltaError::InvalidEncoding)?;
+    if len > data.len() {
+        return Err(DeltaError::InvalidEncoding);
+    }
+    let (value, rest) = data.split_at(len);
+    *data = rest;
+    Ok(Bytes::copy_from_slice(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_apply_binary_patch() {
+        let old = b"hello world";
+        let patch = vec![
+            BytesPatch::Replace {
+                old_index: 0,
+                new_index: 0,
+                old_value: Bytes::from_static(b"h"),
+                new_value: Bytes::from_static(b"H"),
+            },
+            BytesPatch::Delete {
+                old_index: 5,
+                new_index: 5,
+                old_value: Bytes::from_static(b" "),
+            },
+            BytesPatch::Add {
+                old_index: 11,
+                new_index: 10,
+                new_value: Bytes::from_static(b"!"),
+            },
+        ];
+        assert_eq!(apply_binary_patch(old, &patch).unwrap(), b"Helloworld!");
+        assert_eq!(
+            apply_binary_patch(b"jello world", &patch),
+            Err(DeltaError::Mismatch { old_index: 0 })
+        );
+        assert_eq!(
+            apply_binary_patch(b"hello", &patch),
+            Err(DeltaError::OutOfBounds { old_index: 5 })
+        );
+        let overflowing = [BytesPatch::Delete {
+            old_index: usize::MAX,
+            new_index: 0,
+            old_value: Bytes::from_static(b"x"),
+        }];
+        assert_eq!(
+            apply_binary_patch(old, &overflowing),
+            Err(DeltaError::OutOfBounds {
+                old_index: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_invert() {
+        let old = b"hello world";
+        let patch = vec![
+            BytesPatch::Replace {
+                old_index: 0,
+                new_index: 0,
+                old_value: Bytes::from_static(b"h"),
+                new_value: Bytes::from_static(b"Hey, h"),
+            },
+            BytesPatch::Delete {
+                old_index: 5,
+                new_index: 10,
+                old_value: Bytes::from_static(b" "),
+            },
+            BytesPatch::Add {
+                old_index: 11,
+                new_index: 15,
+                new_value: Bytes::from_static(b"!"),
+            },
+        ];
+        let new = apply_binary_patch(old, &patch).unwrap();
+        assert_eq!(new, b"Hey, helloworld!");
+        let inverse = patch
+            .into_iter()
+            .map(BytesPatch::invert)
+            .collect::<Vec<_>>();
+        assert_eq!(apply_binary_patch(&new, &inverse).unwrap(), old);
+    }
+
+    #[test]
+    fn test_compose_ops() {
+        let a = b"hello world, hello ditiear";
+        let first = vec![
+            BytesPatch::Replace {
+                old_index: 0,
+                new_index: 0,
+                old_value: Bytes::from_static(b"h"),
+                new_value: Bytes::from_static(b"Hey, h"),
+            },
+            BytesPatch::Delete {
+                old_index: 11,
+                new_index: 16,
+                old_value: Bytes::from_static(b","),
+            },
+            BytesPatch::Add {
+                old_index: 26,
+                new_index: 30,
+                new_value: Bytes::from_static(b"!"),
+            },
+        ];
+        let b = apply_binary_patch(a, &first).unwrap();
+        assert_eq!(b, b"Hey, hello world hello ditiear!");
+        // the second ops reach into bytes the first inserted and bytes both kept
+        let second = vec![
+            BytesPatch::Delete {
+                old_index: 2,
+                new_index: 2,
+                old_value: Bytes::from_static(b"y, he"),
+            },
+            BytesPatch::Replace {
+                old_index: 17,
+                new_index: 12,
+                old_value: Bytes::from_static(b"hello"),
+                new_value: Bytes::from_static(b"bye"),
+            },
+            BytesPatch::Add {
+                old_index: 31,
+                new_index: 24,
+                new_value: Bytes::from_static(b"!!"),
+            },
+        ];
+        let c = apply_binary_patch(&b, &second).unwrap();
+        assert_eq!(c, b"Hello world bye ditiear!!!");
+        let composed = compose_ops(&first, &second).unwrap();
+        assert_eq!(apply_binary_patch(a, &composed).unwrap(), c);
+        assert!(composed.len() <= first.len() + second.len());
+
+        let mismatched = [BytesPatch::Delete {
+            old_index: 0,
+            new_index: 0,
+            old_value: Bytes::from_static(b"Hex"),
+        }];
+        assert_eq!(
+            compose_ops(&first, &mismatched),
+            Err(DeltaError::Mismatch { old_index: 0 })
+        );
+
+        // ops reaching past the range of an index are rejected, not wrapped
+        let overflowing = [BytesPatch::Delete {
+            old_index: usize::MAX,
+            new_index: 0,
+            old_value: Bytes::from_static(b"x"),
+        }];
+        assert_eq!(
+            compose_ops(&overflowing, &second),
+            Err(DeltaError::OutOfBounds {
+                old_index: usize::MAX
+            })
+        );
+        assert_eq!(
+            compose_ops(&first, &overflowing),
+            Err(DeltaError::OutOfBounds {
+                old_index: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_ops_round_trip() {
+        let patch = vec![
+            BytesPatch::Add {
+                old_index: 3,
+                new_index: 3,
+                new_value: Bytes::from_static(b"abc"),
+            },
+            BytesPatch::Delete {
+                old_index: 1000,
+                new_index: 1003,
+                old_value: Bytes::from_static(b"xy"),
+            },
+            BytesPatch::Replace {
+                old_index: 70000,
+                new_index: 70001,
+                old_value: Bytes::from_static(b"q"),
+                new_value: Bytes::new(),
+            },
+        ];
+        let encoded = encode_ops(&patch);
+        assert_eq!(decode_ops(&encoded).unwrap(), patch);
+        for i in 0..encoded.len() {
+            let _ = decode_ops(&encoded[..i]);
+        }
+        assert_eq!(decode_ops(&[7, 0, 0]), Err(DeltaError::InvalidEncoding));
+        // positions running past the range of an offset are rejected, not wrapped
+        let mut overflowing = vec![1];
+        write_varint(&mut overflowing, zigzag(i64::MAX));
+        overflowing.extend([0, 1, b'x']);
+        assert_eq!(decode_ops(&overflowing), Err(DeltaError::InvalidEncoding));
+        let mut overflowing = vec![0];
+        write_varint(&mut overflowing, zigzag(i64::MAX));
+        overflowing.extend([0, 0, 0]);
+        write_varint(&mut overflowing, zigzag(1));
+        overflowing.extend([0, 0]);
+        assert_eq!(decode_ops(&overflowing), Err(DeltaError::InvalidEncoding));
+    }
+}
@@ -1,16 +1,26 @@
-use crate::common::{path_from_hash, DiffBlob, DiffBlobType, FileParseError};
+#[cfg(feature = "binaryBlob")]
+use crate::common::decode_binary_blobs;
+use crate::common::{
+    ensure_store, read_blob, read_store_config, CancellationToken, DiffBlob, DiffBlobType,
+    FileParseError, StoreConfig,
+};
 use crate::diff::DiffCollectionType::Modify;
+use crate::hash::{hash_directory_in, relative_path, BlobOptions, VirtualNode, VirtualTree};
+use crate::timings::{timed, Phase};
 use core::fmt;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs;
-use std::io::read_to_string;
+use std::io;
 use std::path::Path;
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DiffFileType {
     Directory,
     File,
+    /// A symbolic link, its blob holds the link target.
+    Symlink,
 }
 
 impl fmt::Display for DiffFileType {
@@ -21,12 +31,27 @@ impl fmt::Display for DiffFileType {
             match self {
                 DiffFileType::Directory => "Directory",
                 DiffFileType::File => "File",
+                DiffFileType::Symlink => "Symlink",
             }
         )
     }
 }
 
-#[derive(Debug)]
+impl From<&DiffBlobType> for DiffFileType {
+    fn from(blob_type: &DiffBlobType) -> Self {
+        match blob_type {
+            DiffBlobType::Directory => DiffFileType::Directory,
+            DiffBlobType::File => DiffFileType::File,
+            DiffBlobType::Symlink => DiffFileType::Symlink,
+        }
+    }
+}
+
+/// A change between two stored trees.
+///
+/// Serializes with a `change` tag next to the fields of the variant, see [`diff_to_json`].
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
 pub enum DiffCollectionType {
     Add {
         r#type: DiffFileType,
@@ -41,6 +66,50 @@ pub enum DiffCollectionType {
         old: String,
         new: String,
     },
+    /// A directory whose content is unchanged but which lives under a different path.
+    MoveDirectory {
+        old_name: String,
+        new_name: String,
+        hash: String,
+    },
+    /// A file whose content is unchanged but which lives under a different path.
+    Rename {
+        r#type: DiffFileType,
+        old_name: String,
+        new_name: String,
+        hash: String,
+    },
+}
+
+/// A change of a live directory against a stored root, see [`compare_tree_to_root`].
+///
+/// The stored side is named by blob hashes, the working side by paths relative to the working
+/// directory, since its blobs do not exist in the store. Paths are joined with `/`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum WorkingChange {
+    /// An entry only the working directory has.
+    Add { r#type: DiffFileType, path: String },
+    /// An entry only the stored root has, with its blob `hash` and the `path` it had.
+    Delete {
+        r#type: DiffFileType,
+        hash: String,
+        path: String,
+    },
+    /// An entry whose stored blob `old` differs from the working entry at `path`.
+    Modify {
+        r#type: DiffFileType,
+        old: String,
+        path: String,
+    },
+}
+
+/// Render diffs as a JSON array, e.g. to hand a change set to a web frontend.
+///
+/// Every diff is an object with a `change` of `add`, `delete`, `modify`, `move_directory` or
+/// `rename` and the fields of its variant, file types are `directory`, `file` or `symlink`.
+pub fn diff_to_json(diffs: &[DiffCollectionType]) -> String {
+    serde_json::to_string(diffs).expect("diffs always serialize")
 }
 
 impl DiffCollectionType {
@@ -62,134 +131,623 @@ impl DiffBlob {
     }
 }
 
+/// Order in which [`compare_blob_files_with`] visits modified directories.
+///
+/// Both produce the same diffs in a different order. Breadth first keeps every modified
+/// directory of a level queued, depth first only the siblings along the current path, which
+/// bounds the queue on very wide trees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Traversal {
+    #[default]
+    BreadthFirst,
+    DepthFirst,
+}
+
 pub fn compare_blob_files<P: AsRef<Path>>(
     old_hash: &str,
     new_hash: &str,
     base: P,
 ) -> Result<Vec<DiffCollectionType>, FileParseError> {
-    let mut queue = VecDeque::new();
-    queue.push_front((old_hash.to_string(), new_hash.to_string()));
+    compare_blob_files_with(old_hash, new_hash, base, Traversal::default())
+}
+
+/// Like [`compare_blob_files`], visiting modified directories in the given order.
+pub fn compare_blob_files_with<P: AsRef<Path>>(
+    old_hash: &str,
+    new_hash: &str,
+    base: P,
+    traversal: Traversal,
+) -> Result<Vec<DiffCollectionType>, FileParseError> {
+    let store = read_store_config(base.as_ref())?;
+    compare_blob_files_in(
+        old_hash,
+        new_hash,
+        base,
+        &store,
+        traversal,
+        &CancellationToken::default(),
+    )
+}
+
+/// Like [`compare_blob_files`], stopping with [`FileParseError::Cancelled`] before the next
+/// directory entry once `cancel` is set.
+pub fn compare_blob_files_cancellable<P: AsRef<Path>>(
+    old_hash: &str,
+    new_hash: &str,
+    base: P,
+    cancel: &CancellationToken,
+) -> Result<Vec<DiffCollectionType>, FileParseError> {
+    let store = read_store_config(base.as_ref())?;
+    compare_blob_files_in(
+        old_hash,
+        new_hash,
+        base,
+        &store,
+        Traversal::default(),
+        cancel,
+    )
+}
+
+pub(crate) fn compare_blob_files_in<P: AsRef<Path>>(
+    old_hash: &str,
+    new_hash: &str,
+    base: P,
+    store: &StoreConfig,
+    traversal: Traversal,
+    cancel: &CancellationToken,
+) -> Result<Vec<DiffCollectionType>, FileParseError> {
+    compare_walk(
+        old_hash,
+        base.as_ref(),
+        new_hash,
+        base.as_ref(),
+        (store, store),
+        traversal,
+        cancel,
+    )
+    .map(|(result, _)| result)
+}
+
+/// Returns the diffs together with the peak length of the directory queue.
+fn compare_walk(
+    old_hash: &str,
+    old_base: &Path,
+    new_hash: &str,
+    new_base: &Path,
+    stores: (&StoreConfig, &StoreConfig),
+    traversal: Traversal,
+    cancel: &CancellationToken,
+) -> Result<(Vec<DiffCollectionType>, usize), FileParseError> {
     let mut result = vec![];
+    let peak = compare_stream(
+        (old_hash, old_base),
+        (new_hash, new_base),
+        stores,
+        traversal,
+        cancel,
+        &mut |diff| result.push(diff),
+    )?;
+    Ok((result, peak))
+}
+
+/// Like [`compare_blob_files`], handing every diff to `on_diff` instead of collecting them.
+///
+/// Modified entries are delivered as soon as they are found. Added and deleted entries are
+/// held back until the walk ends, because a later counterpart can turn a pair of them into a
+/// move that is left out. When reading the store fails, everything found up to then is still
+/// delivered before the error is returned.
+pub fn compare_blob_files_streaming<P: AsRef<Path>, F: FnMut(DiffCollectionType)>(
+    old_hash: &str,
+    new_hash: &str,
+    base: P,
+    mut on_diff: F,
+) -> Result<(), FileParseError> {
+    let store = read_store_config(base.as_ref())?;
+    compare_stream(
+        (old_hash, base.as_ref()),
+        (new_hash, base.as_ref()),
+        (&store, &store),
+        Traversal::default(),
+        &CancellationToken::default(),
+        &mut on_diff,
+    )
+    .map(|_| ())
+}
+
+/// Diffs of added or deleted entries with their paths below the root.
+type PathedDiffs = Vec<(DiffCollectionType, String)>;
+
+/// Added and deleted entries waiting for the walk to end.
+#[derive(Default)]
+struct PendingMoves {
+    result: PathedDiffs,
     // mark all added files
-    let mut add_set = HashSet::new();
+    add_set: HashSet<String>,
     // mark all deleted files
-    let mut delete_set = HashSet::new();
-    // traverse sub folders using BSF
-    while let Some((old, new)) = queue.pop_back() {
-        // 1. read old and new blob files
-        let old_path = path_from_hash(&old, base.as_ref());
-        let old_file = fs::File::open(old_path)?;
-        let mut old_blobs = HashMap::new();
-        for line in read_to_string(old_file)?.lines() {
-            let blob = DiffBlob::from_str(line)?;
-            old_blobs.insert(blob.unique_name(), blob);
-        }
+    delete_set: HashSet<String>,
+}
 
-        let new_path = path_from_hash(&new, base.as_ref());
-        let new_file = fs::File::open(new_path)?;
-        let mut new_blobs = HashMap::new();
-        for line in read_to_string(new_file)?.lines() {
-            let blob = DiffBlob::from_str(line)?;
-            new_blobs.insert(blob.unique_name(), blob);
+impl PendingMoves {
+    /// Hand over the pending entries, leaving out entries that are both added and deleted.
+    ///
+    /// An added file is paired with a deleted file of the same content into a rename, the
+    /// entries left over after pairing still hold content the other side has and are dropped.
+    fn flush(self, emit: &mut dyn FnMut(DiffCollectionType)) {
+        let invalid_set: HashSet<_> = self.add_set.intersection(&self.delete_set).collect();
+        let mut deleted: HashMap<String, VecDeque<String>> = HashMap::new();
+        for (x, path) in &self.result {
+            if let (DiffCollectionType::Delete { r#type, .. }, Some(hash)) =
+                (x, x.movement_unique_hash())
+            {
+                if *r#type != DiffFileType::Directory && invalid_set.contains(&hash) {
+                    deleted.entry(hash).or_default().push_back(path.clone());
+                }
+            }
+        }
+        for (x, path) in self.result {
+            let Some(hash) = x
+                .movement_unique_hash()
+                .filter(|hash| invalid_set.contains(hash))
+            else {
+                emit(x);
+                continue;
+            };
+            if let DiffCollectionType::Add { r#type, value } = x {
+                if r#type == DiffFileType::Directory {
+                    continue;
+                }
+                if let Some(old_name) = deleted.get_mut(&hash).and_then(VecDeque::pop_front) {
+                    emit(DiffCollectionType::Rename {
+                        r#type,
+                        old_name,
+                        new_name: path,
+                        hash: value,
+                    });
+                }
+            }
         }
+    }
+}
+
+/// Returns the peak length of the directory queue, the old root is read with the first store
+/// config and the new root with the second.
+fn compare_stream(
+    (old_hash, old_base): (&str, &Path),
+    (new_hash, new_base): (&str, &Path),
+    stores: (&StoreConfig, &StoreConfig),
+    traversal: Traversal,
+    cancel: &CancellationToken,
+    emit: &mut dyn FnMut(DiffCollectionType),
+) -> Result<usize, FileParseError> {
+    ensure_store(old_base)?;
+    ensure_store(new_base)?;
+    let mut pending = PendingMoves::default();
+    let outcome = walk_changes(
+        (old_hash, old_base),
+        (new_hash, new_base),
+        stores,
+        traversal,
+        cancel,
+        &mut pending,
+        emit,
+    );
+    pending.flush(emit);
+    outcome
+}
+
+fn walk_changes(
+    (old_hash, old_base): (&str, &Path),
+    (new_hash, new_base): (&str, &Path),
+    (old_store, new_store): (&StoreConfig, &StoreConfig),
+    traversal: Traversal,
+    cancel: &CancellationToken,
+    pending: &mut PendingMoves,
+    emit: &mut dyn FnMut(DiffCollectionType),
+) -> Result<usize, FileParseError> {
+    let mut queue = VecDeque::new();
+    let mut peak = 1;
+    queue.push_front((old_hash.to_string(), new_hash.to_string(), String::new()));
+    // added and deleted directories as (path, hash), walked once moves are paired up
+    let mut added_dirs = vec![];
+    let mut deleted_dirs = vec![];
+    // traverse sub folders using BSF
+    while let Some((old, new, prefix)) = queue.pop_back() {
+        cancel.check()?;
+        // 1. read old and new blob files
+        let old_blobs = read_blobs(&old, old_base, old_store)?;
+        let new_blobs = read_blobs(&new, new_base, new_store)?;
         if old == new {
             continue;
         }
-        result.push(Modify {
+        emit(Modify {
             r#type: DiffFileType::Directory,
             old: old.to_string(),
             new: new.to_string(),
         });
         // 2. compare two blob files and find differences
         for b in old_blobs.values() {
+            cancel.check()?;
             if let Some(new_b) = new_blobs.get(&b.unique_name()) {
                 // if two blobs are the same, skip
-                if b.hash == new_b.hash {
+                if b.hash == new_b.hash && b.mode == new_b.mode {
                     continue;
                 }
                 // if two blobs are different and has the same name and type, mark as modified
-                if let DiffBlobType::File = b.blob_type {
-                    result.push(Modify {
-                        r#type: DiffFileType::File,
+                if b.blob_type != DiffBlobType::Directory {
+                    emit(Modify {
+                        r#type: DiffFileType::from(&b.blob_type),
                         old: b.hash.clone(),
                         new: new_b.hash.clone(),
                     });
                 } else {
-                    queue.push_front((b.hash.clone(), new_b.hash.clone()));
+                    let item = (
+                        b.hash.clone(),
+                        new_b.hash.clone(),
+                        join_name(&prefix, &b.name),
+                    );
+                    match traversal {
+                        Traversal::BreadthFirst => queue.push_front(item),
+                        Traversal::DepthFirst => queue.push_back(item),
+                    }
+                    peak = peak.max(queue.len());
                 }
             } else {
                 // if a blob is in old but not in new, mark as deleted
-                if let DiffBlobType::File = b.blob_type {
+                if b.blob_type != DiffBlobType::Directory {
                     let diff_item = DiffCollectionType::Delete {
-                        r#type: DiffFileType::File,
+                        r#type: DiffFileType::from(&b.blob_type),
                         value: b.hash.clone(),
                     };
                     // it is delete, so unwrap is safe.
-                    delete_set.insert(diff_item.movement_unique_hash().unwrap());
-                    result.push(diff_item);
+                    pending
+                        .delete_set
+                        .insert(diff_item.movement_unique_hash().unwrap());
+                    pending
+                        .result
+                        .push((diff_item, join_name(&prefix, &b.name)));
                 } else {
-                    let (subs, set) = walk_dir(
-                        base.as_ref(),
-                        DiffCollectionType::Delete {
-                            r#type: DiffFileType::Directory,
-                            value: b.hash.clone(),
-                        },
-                    )?;
-                    delete_set.extend(set);
-                    result.extend(subs);
+                    deleted_dirs.push((join_name(&prefix, &b.name), b.hash.clone()));
                 }
             }
         }
         // modified and deleted files are already marked, so we only need to mark added files
         for b in new_blobs.values() {
-            if old_blobs.get(&b.unique_name()).is_some() {
+            cancel.check()?;
+            if old_blobs.contains_key(&b.unique_name()) {
                 continue;
             }
-            if let DiffBlobType::File = b.blob_type {
+            if b.blob_type != DiffBlobType::Directory {
                 let diff_item = DiffCollectionType::Add {
-                    r#type: DiffFileType::File,
+                    r#type: DiffFileType::from(&b.blob_type),
                     value: b.hash.clone(),
                 };
                 // it is add, so unwrap is safe.
-                add_set.insert(diff_item.movement_unique_hash().unwrap());
-                result.push(diff_item);
+                pending
+                    .add_set
+                    .insert(diff_item.movement_unique_hash().unwrap());
+                pending
+                    .result
+                    .push((diff_item, join_name(&prefix, &b.name)));
             } else {
-                let (subs, set) = walk_dir(
-                    base.as_ref(),
-                    DiffCollectionType::Add {
+                added_dirs.push((join_name(&prefix, &b.name), b.hash.clone()));
+            }
+        }
+    }
+    // a deleted directory with the same hash as an added one was moved, its subtree is unchanged
+    for (old_name, hash) in deleted_dirs {
+        if let Some(i) = added_dirs.iter().position(|(_, h)| *h == hash) {
+            let (new_name, _) = added_dirs.swap_remove(i);
+            emit(DiffCollectionType::MoveDirectory {
+                old_name,
+                new_name,
+                hash,
+            });
+            continue;
+        }
+        let (subs, set) = walk_dir(
+            old_base,
+            old_store,
+            DiffCollectionType::Delete {
+                r#type: DiffFileType::Directory,
+                value: hash,
+            },
+            old_name,
+            cancel,
+        )?;
+        pending.delete_set.extend(set);
+        pending.result.extend(subs);
+    }
+    for (new_name, hash) in added_dirs {
+        let (subs, set) = walk_dir(
+            new_base,
+            new_store,
+            DiffCollectionType::Add {
+                r#type: DiffFileType::Directory,
+                value: hash,
+            },
+            new_name,
+            cancel,
+        )?;
+        pending.add_set.extend(set);
+        pending.result.extend(subs);
+    }
+    Ok(peak)
+}
+
+/// Compare roots of two separate stores, each side is read from its own store.
+///
+/// Hashes only depend on content, so independently built stores of the same tree diff as if
+/// they were one store. Each store is read with the layout it records.
+pub fn compare_blob_files_cross<P: AsRef<Path>, Q: AsRef<Path>>(
+    old_hash: &str,
+    old_base: P,
+    new_hash: &str,
+    new_base: Q,
+) -> Result<Vec<DiffCollectionType>, FileParseError> {
+    let (old_base, new_base) = (old_base.as_ref(), new_base.as_ref());
+    let (old_store, new_store) = (read_store_config(old_base)?, read_store_config(new_base)?);
+    compare_walk(
+        old_hash,
+        old_base,
+        new_hash,
+        new_base,
+        (&old_store, &new_store),
+        Traversal::default(),
+        &CancellationToken::default(),
+    )
+    .map(|(result, _)| result)
+}
+
+/// Like [`compare_blob_files_cross`], both stores laid out as `store`.
+pub(crate) fn compare_blob_files_cross_in(
+    old_hash: &str,
+    old_base: &Path,
+    new_hash: &str,
+    new_base: &Path,
+    store: &StoreConfig,
+    cancel: &CancellationToken,
+) -> Result<Vec<DiffCollectionType>, FileParseError> {
+    compare_walk(
+        old_hash,
+        old_base,
+        new_hash,
+        new_base,
+        (store, store),
+        Traversal::default(),
+        cancel,
+    )
+    .map(|(result, _)| result)
+}
+
+/// Compare a live directory against a stored root without writing the directory to the store.
+pub fn compare_tree_to_root<P: AsRef<Path>, Q: AsRef<Path>>(
+    working_dir: P,
+    root_hash: &str,
+    base: Q,
+) -> Result<Vec<WorkingChange>, FileParseError> {
+    ensure_store(base.as_ref())?;
+    let store = read_store_config(base.as_ref())?;
+    let root = working_dir.as_ref();
+    let tree = hash_directory_in(root, root, &store, &BlobOptions::default())?;
+    let mut result = vec![];
+    compare_tree(root, root_hash, &tree, base.as_ref(), &store, &mut result)?;
+    Ok(result)
+}
+
+fn compare_tree(
+    root: &Path,
+    old: &str,
+    tree: &VirtualTree,
+    base: &Path,
+    store: &StoreConfig,
+    result: &mut Vec<WorkingChange>,
+) -> Result<(), FileParseError> {
+    if old == tree.hash {
+        return Ok(());
+    }
+    let old_blobs = read_blobs(old, base, store)?;
+    let prefix = relative_path(root, &tree.path);
+    result.push(WorkingChange::Modify {
+        r#type: DiffFileType::Directory,
+        old: old.to_string(),
+        path: prefix.clone(),
+    });
+    let new_blobs = tree
+        .children
+        .iter()
+        .map(|node| (node.blob().unique_name(), node))
+        .collect::<HashMap<_, _>>();
+    for b in old_blobs.values() {
+        match new_blobs.get(&b.unique_name()) {
+            Some(node) if node.blob().hash == b.hash && node.blob().mode == b.mode => {}
+            Some(node @ (VirtualNode::File { path, .. } | VirtualNode::Symlink { path, .. })) => {
+                result.push(WorkingChange::Modify {
+                    r#type: DiffFileType::from(&node.blob().blob_type),
+                    old: b.hash.clone(),
+                    path: relative_path(root, path),
+                })
+            }
+            Some(VirtualNode::Directory { tree, .. }) => {
+                compare_tree(root, &b.hash, tree, base, store, result)?
+            }
+            None if b.blob_type != DiffBlobType::Directory => result.push(WorkingChange::Delete {
+                r#type: DiffFileType::from(&b.blob_type),
+                hash: b.hash.clone(),
+                path: join_name(&prefix, &b.name),
+            }),
+            None => {
+                let (subs, _) = walk_dir(
+                    base,
+                    store,
+                    DiffCollectionType::Delete {
                         r#type: DiffFileType::Directory,
                         value: b.hash.clone(),
                     },
+                    join_name(&prefix, &b.name),
+                    &CancellationToken::default(),
                 )?;
-                add_set.extend(set);
-                result.extend(subs);
+                result.extend(subs.into_iter().filter_map(|(diff, path)| match diff {
+                    DiffCollectionType::Delete { r#type, value } => Some(WorkingChange::Delete {
+                        r#type,
+                        hash: value,
+                        path,
+                    }),
+                    _ => None,
+                }));
             }
         }
     }
-    // 3. filter out invalid files
-    // invalid files are files that are both added and deleted
-    let invalid_set: HashSet<_> = add_set.intersection(&delete_set).collect();
-    let result = result
-        .into_iter()
-        .filter(|x| {
-            // filter out both added and deleted files.
-            if let Some(hash) = x.movement_unique_hash() {
-                !invalid_set.contains(&hash)
-            } else {
-                true
+    for (name, node) in new_blobs {
+        if !old_blobs.contains_key(&name) {
+            add_virtual_node(root, node, result);
+        }
+    }
+    Ok(())
+}
+
+fn add_virtual_node(root: &Path, node: &VirtualNode, result: &mut Vec<WorkingChange>) {
+    match node {
+        VirtualNode::File { path, .. } | VirtualNode::Symlink { path, .. } => {
+            result.push(WorkingChange::Add {
+                r#type: DiffFileType::from(&node.blob().blob_type),
+                path: relative_path(root, path),
+            })
+        }
+        VirtualNode::Directory { tree, .. } => {
+            result.push(WorkingChange::Add {
+                r#type: DiffFileType::Directory,
+                path: relative_path(root, &tree.path),
+            });
+            for child in &tree.children {
+                add_virtual_node(root, child, result);
             }
+        }
+    }
+}
+
+pub(crate) fn read_blobs(
+    hash: &str,
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<HashMap<String, DiffBlob>, FileParseError> {
+    timed(Phase::Reading, || {
+        parse_blobs(read_blob(store, hash, base)?)
+    })
+}
+
+/// Parse the content of a directory blob, keyed by name and type of each child.
+pub(crate) fn parse_blobs(content: Vec<u8>) -> Result<HashMap<String, DiffBlob>, FileParseError> {
+    let mut blobs = HashMap::new();
+    for blob in parse_lines(&content, Utf8Mode::Strict, &mut vec![])? {
+        blobs.insert(blob.unique_name(), blob);
+    }
+    Ok(blobs)
+}
+
+/// Decoding of directory blob lines that are not valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Mode {
+    /// Fail the whole blob.
+    #[default]
+    Strict,
+    /// Replace invalid sequences with `U+FFFD` and report the line.
+    Lossy,
+}
+
+/// Parse the lines of a directory blob in order, pushing the 1-based numbers of lines that
+/// were not valid UTF-8 to `invalid`.
+fn parse_lines(
+    content: &[u8],
+    mode: Utf8Mode,
+    invalid: &mut Vec<usize>,
+) -> Result<Vec<DiffBlob>, FileParseError> {
+    #[cfg(feature = "binaryBlob")]
+    if let Some(blobs) = decode_binary_blobs(content) {
+        return Ok(blobs?);
+    }
+    let mut blobs = vec![];
+    if content.is_empty() {
+        return Ok(blobs);
+    }
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+        let blob = match (std::str::from_utf8(line), mode) {
+            (Ok(line), _) => DiffBlob::from_str(line)?,
+            (Err(e), Utf8Mode::Strict) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e).into())
+            }
+            (Err(_), Utf8Mode::Lossy) => {
+                invalid.push(i + 1);
+                DiffBlob::from_bytes_lossy(line)?
+            }
+        };
+        blobs.push(blob);
+    }
+    Ok(blobs)
+}
+
+/// A child listed in a directory blob.
+#[derive(Debug)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub hash: String,
+    pub r#type: DiffFileType,
+}
+
+/// A directory blob read with [`read_directory_blob`].
+#[derive(Debug)]
+pub struct DirectoryListing {
+    /// Children in the order of the blob.
+    pub entries: Vec<DirectoryEntry>,
+    /// 1-based numbers of the lines that were not valid UTF-8, only filled in
+    /// [`Utf8Mode::Lossy`].
+    pub invalid_lines: Vec<usize>,
+}
+
+/// Read the directory blob `hash` from the store at `base`.
+///
+/// With [`Utf8Mode::Lossy`] a blob damaged by corruption is still listed, so a repair tool can
+/// see which lines need attention instead of failing on the first bad byte.
+pub fn read_directory_blob<P: AsRef<Path>>(
+    hash: &str,
+    base: P,
+    mode: Utf8Mode,
+) -> Result<DirectoryListing, FileParseError> {
+    let content = read_blob(&read_store_config(base.as_ref())?, hash, base.as_ref())?;
+    let mut invalid_lines = vec![];
+    let entries = parse_lines(&content, mode, &mut invalid_lines)?
+        .into_iter()
+        .map(|blob| DirectoryEntry {
+            r#type: DiffFileType::from(&blob.blob_type),
+            name: blob.name,
+            hash: blob.hash,
         })
         .collect();
-    Ok(result)
+    Ok(DirectoryListing {
+        entries,
+        invalid_lines,
+    })
+}
+
+#[inline]
+fn join_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", prefix, name)
+    }
 }
 
 /**
- * walk directory recursively to mark all sub files and directories with specified change type (add or delete), then return a list of DiffCollectionType and a set of hashes of all files.
+ * walk directory recursively to mark all sub files and directories with specified change type (add or delete), then return a list of DiffCollectionType with the paths below `path` and a set of hashes of all files.
  */
 fn walk_dir<P: AsRef<Path>>(
     base: P,
+    store: &StoreConfig,
     diff_collection_type: DiffCollectionType,
-) -> Result<(Vec<DiffCollectionType>, HashSet<String>), FileParseError> {
+    path: String,
+    cancel: &CancellationToken,
+) -> Result<(PathedDiffs, HashSet<String>), FileParseError> {
     let mut result = vec![];
     let mut set = HashSet::new();
     let mut stack = vec![];
@@ -198,10 +756,10 @@ fn walk_dir<P: AsRef<Path>>(
         DiffCollectionType::Delete { value, .. } => (value, false),
         _ => unreachable!("work_dir"),
     };
-    stack.push(p);
-    while let Some(hash) = stack.pop() {
-        let p = path_from_hash(&hash, base.as_ref());
-        let dir_content = fs::read_to_string(&p)?;
+    stack.push((p, path));
+    while let Some((hash, path)) = stack.pop() {
+        cancel.check()?;
+        let dir_content = read_blob(store, &hash, base.as_ref())?;
         let diff_item = if is_add {
             DiffCollectionType::Add {
                 r#type: DiffFileType::Directory,
@@ -215,28 +773,69 @@ fn walk_dir<P: AsRef<Path>>(
         };
         // only add and delete will marked, so unwrap is safe.
         set.insert(diff_item.movement_unique_hash().unwrap());
-        result.push(diff_item);
-        for line in dir_content.lines() {
-            let blob = DiffBlob::from_str(line)?;
-            if let DiffBlobType::File = blob.blob_type {
+        result.push((diff_item, path.clone()));
+        for blob in parse_lines(&dir_content, Utf8Mode::Strict, &mut vec![])? {
+            if blob.blob_type != DiffBlobType::Directory {
                 let diff_file_item = if is_add {
                     DiffCollectionType::Add {
-                        r#type: DiffFileType::File,
+                        r#type: DiffFileType::from(&blob.blob_type),
                         value: blob.hash.clone(),
                     }
                 } else {
                     DiffCollectionType::Delete {
-                        r#type: DiffFileType::File,
+                        r#type: DiffFileType::from(&blob.blob_type),
                         value: blob.hash.clone(),
                     }
                 };
                 // only add and delete will marked, so unwrap is safe.
                 set.insert(diff_file_item.movement_unique_hash().unwrap());
-                result.push(diff_file_item);
+                result.push((diff_file_item, join_name(&path, &blob.name)));
             } else {
-                stack.push(blob.hash);
+                stack.push((blob.hash, join_name(&path, &blob.name)));
             }
         }
     }
     Ok((result, set))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal_peak_queue() {
+        let old = tempfile::tempdir().unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        // three levels of three modified directories each
+        for path in (0..27).map(|i| format!("{}/{}/{}/leaf.txt", i / 9, i / 3 % 3, i % 3)) {
+            for (root, content) in [(old.path(), "old"), (new.path(), "new")] {
+                let file = root.join(&path);
+                std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+                std::fs::write(file, content).unwrap();
+            }
+        }
+        let old = crate::hash::create_directory_blob_file(store.path(), old.path()).unwrap();
+        let new = crate::hash::create_directory_blob_file(store.path(), new.path()).unwrap();
+        let walk = |traversal| {
+            let (diffs, peak) = compare_walk(
+                &old,
+                store.path(),
+                &new,
+                store.path(),
+                (&StoreConfig::default(), &StoreConfig::default()),
+                traversal,
+                &CancellationToken::default(),
+            )
+            .unwrap();
+            let mut diffs = diffs.iter().map(|d| format!("{:?}", d)).collect::<Vec<_>>();
+            diffs.sort();
+            (diffs, peak)
+        };
+        let (breadth, breadth_peak) = walk(Traversal::BreadthFirst);
+        let (depth, depth_peak) = walk(Traversal::DepthFirst);
+        assert_eq!(breadth, depth);
+        assert_eq!(breadth.len(), 1 + 3 + 9 + 27 + 27);
+        assert!(depth_peak < breadth_peak, "{depth_peak} < {breadth_peak}");
+    }
+}
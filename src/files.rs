@@ -0,0 +1,151 @@
+use crate::common::{PathNormalization, SEPARATORS};
+use crate::delta::{apply_binary_patch, decode_ops, encode_ops, DeltaError};
+use crate::patch::{calculate_binary_diff, ZipFileError};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Zip entry holding the records of a patch keyed by path.
+const FILES_ENTRY: &str = "ditiear.files.patch";
+
+/// Content of a file taking part in a [`FileChange`].
+#[derive(Clone, Debug)]
+pub enum FileSource {
+    Bytes(Bytes),
+    /// Read from this path when the patch is created.
+    Path(PathBuf),
+}
+
+impl FileSource {
+    fn read(&self) -> io::Result<Bytes> {
+        match self {
+            FileSource::Bytes(bytes) => Ok(bytes.clone()),
+            FileSource::Path(path) => fs::read(path).map(Bytes::from),
+        }
+    }
+}
+
+/// A change to a named file, for patches built without a blob store.
+///
+/// Paths are relative to the directory the patch is applied to and use `/` as separator.
+#[derive(Clone, Debug)]
+pub enum FileChange {
+    Add {
+        path: String,
+        new: FileSource,
+    },
+    Delete {
+        path: String,
+    },
+    Modify {
+        path: String,
+        old: FileSource,
+        new: FileSource,
+    },
+}
+
+/// A record of a patch keyed by path, modified files carry ops encoded by [`encode_ops`].
+#[derive(Serialize, Deserialize)]
+enum FilePatch {
+    Add {
+        path: String,
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+    },
+    Delete {
+        path: String,
+    },
+    Modify {
+        path: String,
+        #[serde(with = "serde_bytes")]
+        patch: Vec<u8>,
+    },
+}
+
+/// Write a zip patch of `changes` to `dest`, keyed by path instead of blob hash.
+///
+/// Added files are embedded whole and modified files as byte patches, so the patch can be
+/// applied with [`apply_file_patch`] to a directory holding the old files without any store.
+pub fn create_patch_from_files<P: AsRef<Path>>(
+    changes: Vec<FileChange>,
+    dest: P,
+) -> Result<(), ZipFileError> {
+    let mut records = vec![];
+    for change in changes {
+        records.push(match change {
+            FileChange::Add { path, new } => FilePatch::Add {
+                path,
+                content: new.read()?.to_vec(),
+            },
+            FileChange::Delete { path } => FilePatch::Delete { path },
+            FileChange::Modify { path, old, new } => FilePatch::Modify {
+                path,
+                patch: encode_ops(&calculate_binary_diff(old.read()?, new.read()?)),
+            },
+        });
+    }
+    let mut zip = ZipWriter::new(fs::File::create(dest)?);
+    zip.start_file(
+        FILES_ENTRY,
+        FileOptions::default().compression_method(CompressionMethod::Deflated),
+    )?;
+    zip.write_all(&bincode::serialize(&records)?)?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Apply a patch written by [`create_patch_from_files`] to the files below `dir`.
+///
+/// Records are applied in order. A patch naming a path outside `dir` fails with
+/// [`ZipFileError::PathTraversal`] before any file is touched.
+pub fn apply_file_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_path: P,
+    dir: Q,
+) -> Result<(), ZipFileError> {
+    let mut archive = ZipArchive::new(fs::File::open(patch_path)?)?;
+    let mut buffer = vec![];
+    archive.by_name(FILES_ENTRY)?.read_to_end(&mut buffer)?;
+    let records: Vec<FilePatch> = bincode::deserialize(&buffer)?;
+    let paths = records
+        .iter()
+        .map(|record| match record {
+            FilePatch::Add { path, .. }
+            | FilePatch::Delete { path }
+            | FilePatch::Modify { path, .. } => resolve(dir.as_ref(), path),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (record, path) in records.into_iter().zip(paths) {
+        match record {
+            FilePatch::Add { content, .. } => {
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, content)?;
+            }
+            FilePatch::Delete { .. } => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            FilePatch::Modify { patch, .. } => {
+                let invalid =
+                    |e: DeltaError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+                let ops = decode_ops(&patch).map_err(invalid)?;
+                let new = apply_binary_patch(&fs::read(&path)?, &ops).map_err(invalid)?;
+                fs::write(path, new)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Native path of the record path `path` below `dir`.
+///
+/// A `\` only counts as separator on Windows, a path that is absolute or has an empty, `.` or
+/// `..` component is refused.
+fn resolve(dir: &Path, path: &str) -> Result<PathBuf, ZipFileError> {
+    PathNormalization::denormalize(&path.split(SEPARATORS).collect::<Vec<_>>().join("/"))
+        .map(|native| dir.join(native))
+        .ok_or_else(|| ZipFileError::PathTraversal(path.to_string()))
+}
@@ -1,178 +1,1117 @@
-use crate::common::{split_dir_and_name, DiffBlob, DiffBlobType};
-use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "binaryBlob")]
+use crate::common::encode_binary_blobs;
+use crate::common::{
+    file_mode, link_target, read_store_config, CancellationToken, DiffBlob, DiffBlobType,
+    Durability, PathNormalization, StoreConfig,
+};
+#[cfg(feature = "binaryBlob")]
+use crate::diff::{DiffFileType, DirectoryEntry};
+use crate::index::{PathIndex, META_DIR};
+use crate::signature::store_signature;
+use crate::stats::StoreStats;
+use crate::timings::{timed, Phase};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::hash::Hasher;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "parallel")]
+use std::sync::Mutex;
 use std::{fs, io};
 use twox_hash::XxHash64;
 
+/// Algorithm used to address blobs in a store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    XxHash64,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn hasher(self) -> ContentHasher {
+        match self {
+            HashAlgorithm::XxHash64 => ContentHasher::XxHash64(XxHash64::default()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// Name of the algorithm in the layout file of a store.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::XxHash64 => "xxhash64",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        [
+            HashAlgorithm::XxHash64,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha256,
+        ]
+        .into_iter()
+        .find(|algorithm| algorithm.name() == name)
+    }
+
+    /// Length of a full hex digest, xxHash digests with leading zeros are shorter.
+    pub(crate) fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::XxHash64 => 16,
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 64,
+        }
+    }
+}
+
+pub(crate) enum ContentHasher {
+    XxHash64(XxHash64),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl ContentHasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        timed(Phase::Hashing, || match self {
+            ContentHasher::XxHash64(h) => h.write(bytes),
+            ContentHasher::Blake3(h) => {
+                h.update(bytes);
+            }
+            ContentHasher::Sha256(h) => h.update(bytes),
+        })
+    }
+
+    /// Hex digest, xxhash digests are not zero padded to stay compatible with existing stores.
+    pub(crate) fn finish_hex(self) -> String {
+        match self {
+            ContentHasher::XxHash64(h) => format!("{:x}", h.finish()),
+            ContentHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            ContentHasher::Sha256(h) => h.finalize().iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
 pub fn calculate_file_hash<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    calculate_file_hash_with(path, HashAlgorithm::XxHash64)
+}
+
+/// Check whether two files have the same content.
+///
+/// Files of different size are never hashed, otherwise both are hashed while streaming instead
+/// of being loaded for a byte compare.
+pub fn files_identical<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> io::Result<bool> {
+    if fs::metadata(a.as_ref())?.len() != fs::metadata(b.as_ref())?.len() {
+        return Ok(false);
+    }
+    Ok(calculate_file_hash(a)? == calculate_file_hash(b)?)
+}
+
+/// Like [`calculate_file_hash`], returning the hex digest of the given algorithm.
+pub fn calculate_file_hash_with<P: AsRef<Path>>(
+    path: P,
+    algorithm: HashAlgorithm,
+) -> io::Result<String> {
     let mut file = File::open(path)?;
-    let mut hasher = XxHash64::default();
+    let mut hasher = algorithm.hasher();
     let mut buffer = [0; 1024];
     loop {
         let bytes_read = file.read(&mut buffer)?;
         if bytes_read == 0 {
             break;
         }
-        hasher.write(&buffer[..bytes_read]);
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finish_hex())
+}
+
+/// A fresh path below the store's metadata directory to write a blob to before renaming it
+/// to its content-addressed path.
+pub(crate) fn temp_blob_path(base: &Path) -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp_dir = base.join(META_DIR);
+    fs::create_dir_all(&tmp_dir)?;
+    Ok(tmp_dir.join(format!(
+        "blob-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )))
+}
+
+/// Move the finished file `tmp` to the blob path `p`, returning whether this call published
+/// the blob and `false` when the store held it already.
+///
+/// The file is linked to `p`, which fails atomically once another writer published the same
+/// blob, so threads and processes blobbing identical content need no lock and only one of
+/// them counts the blob. File systems without hard links rename the file instead.
+fn publish(tmp: &Path, p: &Path, durability: Durability) -> io::Result<bool> {
+    fs::create_dir_all(p.parent().unwrap())?;
+    let published = match fs::hard_link(tmp, p) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+        Err(_) if !p.exists() => {
+            fs::rename(tmp, p)?;
+            durability.sync_parent(p)?;
+            return Ok(true);
+        }
+        Err(e) => return Err(e),
+    };
+    fs::remove_file(tmp)?;
+    if published {
+        durability.sync_parent(p)?;
+    }
+    Ok(published)
+}
+
+/// Hash `path` and copy it into the store unless the store holds the blob already.
+///
+/// Files that are stored already are only read, new files are read a second time while they
+/// are copied.
+fn hash_into_store(
+    path: &Path,
+    base: &Path,
+    store: &StoreConfig,
+    durability: Durability,
+    stats: &mut StoreStats,
+) -> io::Result<String> {
+    let hash = calculate_file_hash_with(path, store.hash_algorithm)?;
+    if store.path_from_hash(&hash, base)?.exists() {
+        return Ok(hash);
+    }
+    copy_into_store(path, base, store, durability, stats)
+}
+
+/// Copy `path` into the store while hashing it, so the source is only read once.
+///
+/// The content goes to a temporary file below the store's metadata directory first and is
+/// renamed to its content-addressed path once the hash is known. The copy is written even
+/// when the store holds the blob already, see [`hash_into_store`] for files that likely are
+/// stored.
+pub(crate) fn copy_into_store(
+    path: &Path,
+    base: &Path,
+    store: &StoreConfig,
+    durability: Durability,
+    stats: &mut StoreStats,
+) -> io::Result<String> {
+    let tmp = temp_blob_path(base)?;
+    let result = (|| {
+        let mut source = File::open(path)?;
+        let mut target = File::create_new(&tmp)?;
+        let mut hasher = store.hash_algorithm.hasher();
+        let mut buffer = [0; 8192];
+        let mut size = 0;
+        loop {
+            let bytes_read = source.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+            target.write_all(&buffer[..bytes_read])?;
+            #[cfg(test)]
+            tests::WRITTEN.with(|written| written.set(written.get() + bytes_read as u64));
+            size += bytes_read as u64;
+        }
+        let hash = hasher.finish_hex();
+        let p = store.path_from_hash(&hash, base)?;
+        if !p.exists() {
+            durability.sync_data(&tmp)?;
+            if publish(&tmp, &p, durability)? {
+                stats.added(size);
+            }
+        }
+        Ok(hash)
+    })();
+    // the temporary file is still there when the blob existed or the copy failed
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+/// Write `content` as a blob unless the store holds it already and return its hash.
+fn write_bytes_blob(
+    base: &Path,
+    content: &[u8],
+    store: &StoreConfig,
+    durability: Durability,
+    stats: &mut StoreStats,
+) -> io::Result<String> {
+    let mut hasher = store.hash_algorithm.hasher();
+    hasher.update(content);
+    let hash = hasher.finish_hex();
+    let p = store.path_from_hash(&hash, base)?;
+    if !p.exists() {
+        let tmp = temp_blob_path(base)?;
+        let written = File::create_new(&tmp)
+            .and_then(|mut file| file.write_all(content))
+            .and_then(|_| durability.sync_data(&tmp))
+            .and_then(|_| publish(&tmp, &p, durability));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        if written? {
+            stats.added(content.len() as u64);
+        }
     }
-    Ok(format!("{:x}", hasher.finish()))
+    Ok(hash)
+}
+
+/// Options used while blobbing a directory tree.
+#[derive(Clone, Debug, Default)]
+pub struct BlobOptions {
+    /// Files larger than this many bytes are skipped instead of being blobbed.
+    pub max_file_size: Option<u64>,
+    /// Record the source path of every file blob in the store's path sidecar.
+    ///
+    /// Blobbing a tree again then only hashes the files whose recorded blob is still stored,
+    /// and copies none of the unchanged ones.
+    pub record_paths: bool,
+    /// Hash and copy every file in a single read.
+    ///
+    /// By default a file is hashed first and only copied when the store lacks its blob, which
+    /// reads new files twice but writes nothing for stored ones. A single pass halves the
+    /// reads of trees that are mostly new, at the cost of writing a temporary copy of every
+    /// file, stored or not.
+    pub single_pass: bool,
+    /// Ignore patterns, see [`is_ignored`] for the matching rules.
+    pub ignore: Vec<String>,
+    /// Syncing of written blobs.
+    pub durability: Durability,
+    /// Move files into the store instead of copying them.
+    ///
+    /// Only set this when the source tree is disposable, every blobbed file is gone from it
+    /// afterwards.
+    pub consume_source: bool,
+    /// Store the block signature of every file blob in a sidecar, see [`read_signature`].
+    ///
+    /// Block diffs against a blob can then use the stored signature instead of reading the
+    /// old blob, at the cost of a little more space per blob.
+    ///
+    /// [`read_signature`]: crate::prelude::read_signature
+    pub store_signatures: bool,
+    /// Record the permission bits of every file in its directory blob entry.
+    ///
+    /// A file whose mode alone changed then shows up as modified, and checking out restores
+    /// its mode. Off by default, recorded modes change the hashes of directory blobs.
+    pub record_mode: bool,
+    /// Keep empty directories in the tree as directory blobs without entries.
+    ///
+    /// Every empty directory gets the same blob, each is told apart by its entry in the
+    /// parent's listing, and checking out recreates it. Off by default, kept directories change
+    /// the hashes of their parents.
+    pub keep_empty_dirs: bool,
+    /// Stops blobbing before the next file with an [`io::ErrorKind::Interrupted`] error
+    /// wrapping [`Cancelled`](crate::common::Cancelled) once set.
+    pub cancellation: CancellationToken,
+}
+
+/// Result of blobbing a directory tree with [`BlobOptions`].
+#[derive(Debug)]
+pub struct BlobReport {
+    /// Hash of the root directory blob.
+    pub hash: String,
+    /// Files that were left out of the tree, sorted by path.
+    pub skipped: Vec<PathBuf>,
 }
 
 pub fn create_directory_blob_file<P: AsRef<Path>>(to_path: P, from_path: P) -> io::Result<String> {
+    create_directory_blob_file_with_options(to_path, from_path, &BlobOptions::default())
+        .map(|report| report.hash)
+}
+
+pub fn create_directory_blob_file_with_options<P: AsRef<Path>>(
+    to_path: P,
+    from_path: P,
+    options: &BlobOptions,
+) -> io::Result<BlobReport> {
+    let store = read_store_config(to_path.as_ref())?;
+    blob_directory(to_path, from_path, &store, options)
+}
+
+/// Like [`create_directory_blob_file`], leaving out every entry matching one of `patterns`.
+///
+/// Ignored entries are never hashed, so the root hash does not depend on whether they exist.
+/// See [`is_ignored`] for the matching rules.
+pub fn create_directory_blob_file_with_ignore<P: AsRef<Path>>(
+    to_path: P,
+    from_path: P,
+    patterns: &[&str],
+) -> io::Result<String> {
+    let options = BlobOptions {
+        ignore: patterns.iter().map(|p| p.to_string()).collect(),
+        ..Default::default()
+    };
+    create_directory_blob_file_with_options(to_path, from_path, &options).map(|report| report.hash)
+}
+
+pub(crate) fn blob_directory<P: AsRef<Path>, Q: AsRef<Path>>(
+    to_path: P,
+    from_path: Q,
+    store: &StoreConfig,
+    options: &BlobOptions,
+) -> io::Result<BlobReport> {
+    let root = from_path.as_ref();
     let mut queue: VecDeque<PathBuf> = VecDeque::new();
-    queue.push_back(from_path.as_ref().to_path_buf());
+    queue.push_back(root.to_path_buf());
     let mut directories = Vec::new();
     while let Some(p) = queue.pop_front() {
-        if p.is_dir() {
-            directories.push(p.clone());
-            for entry in fs::read_dir(&p)? {
-                let entry = entry?;
-                let path = entry.path();
-                // TODO: 过滤更多隐藏文件
-                if path.file_name().unwrap().to_str().unwrap() == ".DS_Store" {
-                    continue;
-                }
-                if path.is_dir() {
-                    queue.push_back(path);
-                }
+        options.cancellation.check_io()?;
+        for (path, is_dir) in directory_entries(root, &p, options)? {
+            if is_dir {
+                queue.push_back(path);
             }
         }
+        directories.push(p);
     }
     let mut resolved: HashMap<PathBuf, DiffBlob> = HashMap::new();
+    let mut skipped = Vec::new();
+    let mut index = if options.record_paths {
+        Some(PathIndex::load(&to_path)?)
+    } else {
+        None
+    };
+    store.record_layout(to_path.as_ref())?;
+    let mut stats = StoreStats::load(to_path.as_ref(), store)?;
+    // children come after their parent, so walking backwards resolves them first
     while let Some(current_path) = directories.pop() {
         let mut entries = Vec::new();
-        for entry in fs::read_dir(&current_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.file_name().unwrap().to_str().unwrap() == ".DS_Store" {
-                continue;
-            }
-            if path.is_dir() {
-                if let Some(e) = resolved.get(&path) {
-                    entries.push(e.clone());
-                    resolved.remove(&path);
+        for (path, is_dir) in directory_entries(root, &current_path, options)? {
+            options.cancellation.check_io()?;
+            if is_dir {
+                if let Some(e) = resolved.remove(&path) {
+                    entries.push(e);
                 }
-                // else {
-                //     return Err(io::Error::new(io::ErrorKind::Other, "not found"))
-                // }
             } else {
-                write_file_blob(&to_path, &mut entries, &path)?;
+                let index = index.as_mut().map(|i| (root, i));
+                match write_file_blob(&to_path, &path, store, options, index, &mut stats)? {
+                    Some(blob) => entries.push(blob),
+                    None => skipped.push(path),
+                }
             }
         }
-        if entries.is_empty() {
+        let is_root = current_path == root;
+        let blob = assemble_directory(
+            &to_path,
+            &current_path,
+            is_root,
+            entries,
+            store,
+            options,
+            &mut stats,
+        )?;
+        if let Some(blob) = blob {
+            resolved.insert(current_path, blob);
+        }
+    }
+    skipped.sort();
+    if let Some(index) = index {
+        index.save(&to_path)?;
+    }
+    let hash = resolved
+        .get(root)
+        .ok_or_else(|| io::Error::other("not found"))?
+        .hash
+        .clone();
+    stats.add_version(&hash, to_path.as_ref(), store)?;
+    stats.save(to_path.as_ref(), options.durability)?;
+    Ok(BlobReport { hash, skipped })
+}
+
+/// Entries of `dir` that take part in blobbing, with whether each one is a directory.
+///
+/// This is the one place deciding which entries are left out, so every way of blobbing a tree
+/// agrees on its hash.
+fn directory_entries(
+    root: &Path,
+    dir: &Path,
+    options: &BlobOptions,
+) -> io::Result<Vec<(PathBuf, bool)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // TODO: 过滤更多隐藏文件
+        if path.file_name().unwrap().to_str().unwrap() == ".DS_Store" {
             continue;
         }
-        entries.sort_by(|a, b| a.name.cmp(&b.name));
-        let hash = write_directory_blob(&to_path, &mut entries)?;
-        resolved.insert(
-            current_path.clone(),
-            DiffBlob {
-                name: current_path
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-                hash,
+        // links are never followed, a link to a directory is blobbed as a link
+        let is_dir = fs::symlink_metadata(&path)?.is_dir();
+        if !is_ignored(&options.ignore, root, &path, is_dir) {
+            entries.push((path, is_dir));
+        }
+    }
+    Ok(entries)
+}
+
+/// Write the directory blob of `dir` from the blobs of its children and return its entry in
+/// the parent, `None` for an empty directory below the root unless empty directories are kept.
+fn assemble_directory<P: AsRef<Path>>(
+    to_path: &P,
+    dir: &Path,
+    is_root: bool,
+    mut entries: Vec<DiffBlob>,
+    store: &StoreConfig,
+    options: &BlobOptions,
+    stats: &mut StoreStats,
+) -> io::Result<Option<DiffBlob>> {
+    // the root always gets a blob so that an empty tree still has a well-defined hash
+    if entries.is_empty() && !is_root && !options.keep_empty_dirs {
+        return Ok(None);
+    }
+    timed(Phase::Sorting, || {
+        entries.sort_by(|a, b| a.name.cmp(&b.name))
+    });
+    let hash = write_directory_blob(to_path, &mut entries, store, options.durability, stats)?;
+    Ok(Some(DiffBlob {
+        name: dir.file_name().unwrap().to_str().unwrap().to_string(),
+        hash,
+        blob_type: DiffBlobType::Directory,
+        mode: None,
+    }))
+}
+
+/// A directory tree hashed in memory, without writing anything to a store.
+#[derive(Clone, Debug)]
+pub struct VirtualTree {
+    /// Hash the directory blob of this tree would have in a store.
+    pub hash: String,
+    /// Real path of the directory.
+    pub path: PathBuf,
+    /// Children sorted by name, as they would appear in the directory blob.
+    pub children: Vec<VirtualNode>,
+}
+
+#[derive(Clone, Debug)]
+pub enum VirtualNode {
+    File {
+        name: String,
+        hash: String,
+        path: PathBuf,
+        /// Permission bits, only set when hashing with [`BlobOptions::record_mode`].
+        mode: Option<u32>,
+    },
+    Directory {
+        name: String,
+        tree: VirtualTree,
+    },
+    Symlink {
+        name: String,
+        hash: String,
+        path: PathBuf,
+    },
+}
+
+impl VirtualNode {
+    pub(crate) fn blob(&self) -> DiffBlob {
+        match self {
+            VirtualNode::File {
+                name, hash, mode, ..
+            } => DiffBlob {
+                name: name.clone(),
+                hash: hash.clone(),
+                blob_type: DiffBlobType::File,
+                mode: *mode,
+            },
+            VirtualNode::Directory { name, tree } => DiffBlob {
+                name: name.clone(),
+                hash: tree.hash.clone(),
                 blob_type: DiffBlobType::Directory,
+                mode: None,
+            },
+            VirtualNode::Symlink { name, hash, .. } => DiffBlob {
+                name: name.clone(),
+                hash: hash.clone(),
+                blob_type: DiffBlobType::Symlink,
+                mode: None,
             },
-        );
+        }
     }
-    resolved
-        .get(&from_path.as_ref().to_path_buf())
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "not found"))
-        .map(|e| e.hash.clone())
 }
 
+/// Hash a directory tree the same way [`create_directory_blob_file`] would, but only in memory.
+pub fn hash_directory<P: AsRef<Path>>(from_path: P) -> io::Result<VirtualTree> {
+    hash_directory_in(
+        from_path.as_ref(),
+        from_path.as_ref(),
+        &StoreConfig::default(),
+        &BlobOptions::default(),
+    )
+}
+
+/// What blobbing a directory into a store would write, see [`plan_blob_directory`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlobPlan {
+    /// Hash of the root directory blob.
+    pub hash: String,
+    /// Number of distinct blobs the store does not hold yet.
+    pub new_blobs: usize,
+    /// Total size in bytes of those blobs.
+    pub new_bytes: u64,
+}
+
+/// Hash `from_path` in memory and report which of its blobs the store at `base` is missing,
+/// without writing anything.
+pub fn plan_blob_directory<P: AsRef<Path>, Q: AsRef<Path>>(
+    from_path: P,
+    base: Q,
+) -> io::Result<BlobPlan> {
+    plan_blob_directory_in(
+        from_path.as_ref(),
+        base.as_ref(),
+        &read_store_config(base.as_ref())?,
+        &BlobOptions::default(),
+    )
+}
+
+pub(crate) fn plan_blob_directory_in(
+    from_path: &Path,
+    base: &Path,
+    store: &StoreConfig,
+    options: &BlobOptions,
+) -> io::Result<BlobPlan> {
+    let tree = hash_directory_in(from_path, from_path, store, options)?;
+    let mut plan = BlobPlan {
+        hash: tree.hash.clone(),
+        ..BlobPlan::default()
+    };
+    let mut seen = HashSet::new();
+    let mut stack = vec![&tree];
+    while let Some(tree) = stack.pop() {
+        let mut blob_size = 0;
+        for child in &tree.children {
+            blob_size += child.blob().to_string().len() as u64;
+            match child {
+                VirtualNode::Directory { tree, .. } => stack.push(tree),
+                VirtualNode::File { hash, path, .. } => {
+                    if seen.insert(hash.as_str()) && !store.blob_exists(hash, base) {
+                        plan.new_blobs += 1;
+                        plan.new_bytes += fs::metadata(path)?.len();
+                    }
+                }
+                VirtualNode::Symlink { hash, path, .. } => {
+                    if seen.insert(hash.as_str()) && !store.blob_exists(hash, base) {
+                        plan.new_blobs += 1;
+                        plan.new_bytes += link_target(path)?.len() as u64;
+                    }
+                }
+            }
+        }
+        if seen.insert(tree.hash.as_str()) && !store.blob_exists(&tree.hash, base) {
+            plan.new_blobs += 1;
+            plan.new_bytes += blob_size;
+        }
+    }
+    Ok(plan)
+}
+
+pub(crate) fn hash_directory_in(
+    root: &Path,
+    path: &Path,
+    store: &StoreConfig,
+    options: &BlobOptions,
+) -> io::Result<VirtualTree> {
+    let mut children = vec![];
+    for (path, is_dir) in directory_entries(root, path, options)? {
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if is_dir {
+            let tree = hash_directory_in(root, &path, store, options)?;
+            // empty sub directories are left out, like when blobbing
+            if !tree.children.is_empty() || options.keep_empty_dirs {
+                children.push(VirtualNode::Directory { name, tree });
+            }
+            continue;
+        }
+        if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+            let mut hasher = store.hash_algorithm.hasher();
+            hasher.update(&link_target(&path)?);
+            let hash = hasher.finish_hex();
+            children.push(VirtualNode::Symlink { name, hash, path });
+            continue;
+        }
+        if let Some(max_file_size) = options.max_file_size {
+            if fs::metadata(&path)?.len() > max_file_size {
+                continue;
+            }
+        }
+        let mode = match options.record_mode {
+            true => file_mode(&fs::metadata(&path)?),
+            false => None,
+        };
+        let hash = calculate_file_hash_with(&path, store.hash_algorithm)?;
+        children.push(VirtualNode::File {
+            name,
+            hash,
+            path,
+            mode,
+        });
+    }
+    timed(Phase::Sorting, || {
+        children.sort_by(|a, b| a.blob().name.cmp(&b.blob().name))
+    });
+    let mut hasher = store.hash_algorithm.hasher();
+    for child in &children {
+        hasher.update(child.blob().to_string().as_bytes());
+    }
+    Ok(VirtualTree {
+        hash: hasher.finish_hex(),
+        path: path.to_path_buf(),
+        children,
+    })
+}
+
+/// Like [`create_directory_blob_file`], walking the tree recursively.
 pub fn create_directory_blob_file_rec<P: AsRef<Path>>(
     to_path: P,
     from_path: P,
 ) -> io::Result<String> {
-    // 1. read directory info, if not a directory return error
-    let dir = std::fs::read_dir(from_path)?;
-
-    // 2. walk directory and calculate hash for each file, if is a subdirectory, call create_directory_blob_file recursively
-    let mut blobs = Vec::new();
-    for entry in dir {
-        let entry = entry?;
-        let path = entry.path();
-        // TODO: 过滤更多隐藏文件
-        if path.file_name().unwrap().to_str().unwrap() == ".DS_Store" {
-            continue;
-        }
-        if path.is_dir() {
-            let hash = create_directory_blob_file_rec(to_path.as_ref(), path.as_path())?;
-            let name = path.file_name().unwrap().to_str().unwrap().to_string();
-            let blob = DiffBlob {
-                name,
-                hash,
-                blob_type: DiffBlobType::Directory,
-            };
-            blobs.push(blob);
+    create_directory_blob_file_rec_with_progress(to_path, from_path, |_| {})
+}
+
+/// Progress of blobbing a tree, reported after every file.
+#[derive(Clone, Copy, Debug)]
+pub struct HashProgress<'a> {
+    /// Files hashed so far, including the current one.
+    pub files_done: usize,
+    /// Bytes of all files hashed so far.
+    pub bytes_done: u64,
+    /// The file just hashed.
+    pub current_path: &'a Path,
+}
+
+/// Like [`create_directory_blob_file_rec`], calling `on_progress` after every file is hashed.
+///
+/// The callback only observes the walk, the root hash is the same as without it.
+pub fn create_directory_blob_file_rec_with_progress<P, F>(
+    to_path: P,
+    from_path: P,
+    mut on_progress: F,
+) -> io::Result<String>
+where
+    P: AsRef<Path>,
+    F: FnMut(HashProgress),
+{
+    let root = from_path.as_ref();
+    let (base, options) = (to_path.as_ref(), BlobOptions::default());
+    let store = read_store_config(base)?;
+    let mut stats = StoreStats::load(base, &store)?;
+    let mut progress = ProgressCounter {
+        files_done: 0,
+        bytes_done: 0,
+        on_progress: &mut on_progress,
+    };
+    let blob = blob_directory_rec(
+        &base,
+        root,
+        root,
+        &store,
+        &options,
+        &mut stats,
+        &mut progress,
+    )?;
+    let hash = blob.expect("the root always gets a blob").hash;
+    stats.add_version(&hash, base, &store)?;
+    stats.save(base, options.durability)?;
+    Ok(hash)
+}
+
+/// Files counted by [`create_directory_blob_file_rec_with_progress`].
+struct ProgressCounter<'f> {
+    files_done: usize,
+    bytes_done: u64,
+    on_progress: &'f mut dyn FnMut(HashProgress),
+}
+
+fn blob_directory_rec<P: AsRef<Path>>(
+    to_path: &P,
+    root: &Path,
+    dir: &Path,
+    store: &StoreConfig,
+    options: &BlobOptions,
+    stats: &mut StoreStats,
+    progress: &mut ProgressCounter,
+) -> io::Result<Option<DiffBlob>> {
+    let mut entries = Vec::new();
+    for (path, is_dir) in directory_entries(root, dir, options)? {
+        options.cancellation.check_io()?;
+        if is_dir {
+            entries.extend(blob_directory_rec(
+                to_path, root, &path, store, options, stats, progress,
+            )?);
         } else {
-            write_file_blob(&to_path, &mut blobs, &path)?;
+            let size = fs::symlink_metadata(&path)?.len();
+            if let Some(blob) = write_file_blob(to_path, &path, store, options, None, stats)? {
+                entries.push(blob);
+                progress.files_done += 1;
+                progress.bytes_done += size;
+                (progress.on_progress)(HashProgress {
+                    files_done: progress.files_done,
+                    bytes_done: progress.bytes_done,
+                    current_path: &path,
+                });
+            }
         }
     }
+    assemble_directory(to_path, dir, dir == root, entries, store, options, stats)
+}
 
-    // 3. get all file hashes and sort them by hash
-    blobs.sort_by(|a, b| a.name.cmp(&b.name));
+/// Like [`create_directory_blob_file_rec`], blobbing the entries of every directory on the
+/// rayon thread pool.
+///
+/// Entries are sorted by name once they are joined, so every blob is byte-identical to the
+/// one the serial walk writes.
+#[cfg(feature = "parallel")]
+pub fn create_directory_blob_file_par<P: AsRef<Path>>(
+    to_path: P,
+    from_path: P,
+) -> io::Result<String> {
+    let root = from_path.as_ref();
+    let (base, options) = (to_path.as_ref(), BlobOptions::default());
+    let store = read_store_config(base)?;
+    let stats = Mutex::new(StoreStats::load(base, &store)?);
+    let blob = blob_directory_par(base, root, root, &store, &options, &stats)?;
+    let hash = blob.expect("the root always gets a blob").hash;
+    let mut stats = stats.into_inner().unwrap_or_else(|e| e.into_inner());
+    stats.add_version(&hash, base, &store)?;
+    stats.save(base, options.durability)?;
+    Ok(hash)
+}
 
-    // 4. calculate hash for all file hashes combined
-    write_directory_blob(&to_path, &mut blobs)
+/// Every entry counts its blobs into its own [`StoreStats`], which are merged into `stats`
+/// once the entry is done.
+#[cfg(feature = "parallel")]
+fn blob_directory_par(
+    to_path: &Path,
+    root: &Path,
+    dir: &Path,
+    store: &StoreConfig,
+    options: &BlobOptions,
+    stats: &Mutex<StoreStats>,
+) -> io::Result<Option<DiffBlob>> {
+    use rayon::prelude::*;
+    let merge = |added: StoreStats| {
+        stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .merge(&added)
+    };
+    let entries = directory_entries(root, dir, options)?
+        .into_par_iter()
+        .map(|(path, is_dir)| {
+            options.cancellation.check_io()?;
+            if is_dir {
+                return blob_directory_par(to_path, root, &path, store, options, stats);
+            }
+            let mut added = StoreStats::default();
+            let blob = write_file_blob(&to_path, &path, store, options, None, &mut added)?;
+            merge(added);
+            Ok(blob)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let entries = entries.into_iter().flatten().collect();
+    let mut added = StoreStats::default();
+    let blob = assemble_directory(
+        &to_path,
+        dir,
+        dir == root,
+        entries,
+        store,
+        options,
+        &mut added,
+    );
+    merge(added);
+    blob
+}
+
+/// Write a fresh directory blob listing the given `(hash, name)` file blobs and return its hash.
+///
+/// This repairs a store after a directory blob was lost while its children survived, their
+/// names can be recovered from the path sidecar.
+pub fn recover_directory<P, I, H, N>(children: I, base: P) -> io::Result<String>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (H, N)>,
+    H: Into<String>,
+    N: Into<String>,
+{
+    let store = read_store_config(base.as_ref())?;
+    let mut blobs = vec![];
+    for (hash, name) in children {
+        let hash = hash.into();
+        if !store.path_from_hash(&hash, base.as_ref())?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("blob {} not found", hash),
+            ));
+        }
+        blobs.push(DiffBlob {
+            name: name.into(),
+            hash,
+            blob_type: DiffBlobType::File,
+            mode: None,
+        });
+    }
+    let mut stats = StoreStats::load(base.as_ref(), &store)?;
+    timed(Phase::Sorting, || blobs.sort_by(|a, b| a.name.cmp(&b.name)));
+    let hash = write_directory_blob(&base, &mut blobs, &store, Durability::None, &mut stats)?;
+    stats.save(base.as_ref(), Durability::None)?;
+    Ok(hash)
 }
 
 #[inline]
 fn write_directory_blob<P: AsRef<Path>>(
     to_path: &P,
-    blobs: &mut Vec<DiffBlob>,
+    blobs: &mut [DiffBlob],
+    store: &StoreConfig,
+    durability: Durability,
+    stats: &mut StoreStats,
 ) -> io::Result<String> {
-    let mut hasher = XxHash64::default();
-    for blob in blobs.iter() {
-        hasher.write(blob.to_string().as_bytes());
-    }
-    let hash = format!("{:x}", hasher.finish());
-    let (dir, name) = split_dir_and_name(&hash);
-    let p = &to_path.as_ref().join(dir);
-    if !p.exists() {
-        fs::create_dir_all(p)?;
-    }
-    let p = &p.join(name);
-    if p.exists() {
-        return Ok(hash);
-    } else {
-        let mut file = File::create(p)?;
-        for blob in blobs.iter() {
-            file.write_all(blob.to_string().as_bytes())?;
-        }
-    }
+    let content: String = blobs.iter().map(|blob| blob.to_string()).collect();
+    write_directory_content(to_path, content.as_bytes(), store, durability, stats)
+}
+
+/// Store encoded directory blob `content` under its hash, unless the store holds it already.
+///
+/// Like every other blob it is renamed into place, a crash never leaves a partial directory
+/// blob under its hash.
+fn write_directory_content<P: AsRef<Path>>(
+    to_path: &P,
+    content: &[u8],
+    store: &StoreConfig,
+    durability: Durability,
+    stats: &mut StoreStats,
+) -> io::Result<String> {
+    write_bytes_blob(to_path.as_ref(), content, store, durability, stats)
+}
+
+/// Write a directory blob listing `entries` in the columnar format to the store at `to_path`
+/// and return its hash.
+///
+/// Directory blobs are read in either format once the `binaryBlob` feature is on, so the
+/// returned hash can be compared, diffed and checked out like any other. Entries are sorted by
+/// name, names and hashes must not be longer than 255 bytes.
+#[cfg(feature = "binaryBlob")]
+pub fn write_directory_blob_binary<P: AsRef<Path>>(
+    to_path: P,
+    entries: Vec<DirectoryEntry>,
+) -> io::Result<String> {
+    let store = read_store_config(to_path.as_ref())?;
+    let mut blobs: Vec<DiffBlob> = entries
+        .into_iter()
+        .map(|entry| DiffBlob {
+            name: entry.name,
+            hash: entry.hash,
+            blob_type: match entry.r#type {
+                DiffFileType::Directory => DiffBlobType::Directory,
+                DiffFileType::File => DiffBlobType::File,
+                DiffFileType::Symlink => DiffBlobType::Symlink,
+            },
+            mode: None,
+        })
+        .collect();
+    blobs.sort_by(|a, b| a.name.cmp(&b.name));
+    let content =
+        encode_binary_blobs(blobs).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = StoreStats::load(to_path.as_ref(), &store)?;
+    let hash = write_directory_content(&to_path, &content, &store, Durability::None, &mut stats)?;
+    stats.save(to_path.as_ref(), Durability::None)?;
     Ok(hash)
 }
 
+/// Returns the entry of the file in its directory blob, `None` when the file was skipped
+/// according to `options`.
+///
+/// When `index` is given, the path of the file relative to the blobbed root is recorded in it.
 #[inline]
 fn write_file_blob<P: AsRef<Path>>(
     to_path: &P,
-    entries: &mut Vec<DiffBlob>,
     path: &PathBuf,
-) -> io::Result<()> {
-    let hash = calculate_file_hash(&path)?;
+    store: &StoreConfig,
+    options: &BlobOptions,
+    index: Option<(&Path, &mut PathIndex)>,
+    stats: &mut StoreStats,
+) -> io::Result<Option<DiffBlob>> {
     let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
-    let (dir, name) = split_dir_and_name(&hash);
-    let p = &to_path.as_ref().join(dir);
-    if !p.exists() {
-        fs::create_dir_all(p)?;
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        let target = link_target(path)?;
+        let hash = write_bytes_blob(to_path.as_ref(), &target, store, options.durability, stats)?;
+        if options.consume_source {
+            fs::remove_file(path)?;
+        }
+        return Ok(Some(DiffBlob {
+            name: file_name,
+            hash,
+            blob_type: DiffBlobType::Symlink,
+            mode: None,
+        }));
     }
-    let p = &p.join(name);
-    if !p.exists() {
-        fs::copy(&path, p)?;
+    if let Some(max_file_size) = options.max_file_size {
+        if fs::metadata(path)?.len() > max_file_size {
+            return Ok(None);
+        }
     }
-    let blob = DiffBlob {
+    // read before a consumed source is moved away
+    let mode = match options.record_mode {
+        true => file_mode(&fs::metadata(path)?),
+        false => None,
+    };
+    let hash = if options.consume_source {
+        let hash = calculate_file_hash_with(path, store.hash_algorithm)?;
+        let p = store.path_from_hash(&hash, to_path)?;
+        if !p.exists() {
+            let size = fs::metadata(path)?.len();
+            options.durability.sync_data(path)?;
+            // moving fails across file systems, copy there instead
+            match publish(path, &p, options.durability) {
+                Ok(true) => stats.added(size),
+                Ok(false) => {}
+                Err(_) => {
+                    copy_into_store(path, to_path.as_ref(), store, options.durability, stats)?;
+                }
+            }
+        }
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        hash
+    } else {
+        let indexed = match &index {
+            Some((root, index)) => indexed_blob(path, root, index, to_path.as_ref(), store)?,
+            None => None,
+        };
+        match indexed {
+            Some(hash) => hash,
+            None if options.single_pass => {
+                copy_into_store(path, to_path.as_ref(), store, options.durability, stats)?
+            }
+            None => hash_into_store(path, to_path.as_ref(), store, options.durability, stats)?,
+        }
+    };
+    if options.store_signatures {
+        store_signature(&hash, to_path.as_ref(), store)?;
+    }
+    if let Some((root, index)) = index {
+        index.insert(&hash, &relative_path(root, path));
+    }
+    Ok(Some(DiffBlob {
         name: file_name,
         hash,
         blob_type: DiffBlobType::File,
+        mode,
+    }))
+}
+
+/// Hash of `path` when the path index recorded it as a blob the store still holds, so the
+/// file needs no copy.
+///
+/// Only blobs of the same size as the file are candidates, the file is hashed when there is
+/// one and is not read at all otherwise.
+fn indexed_blob(
+    path: &Path,
+    root: &Path,
+    index: &PathIndex,
+    base: &Path,
+    store: &StoreConfig,
+) -> io::Result<Option<String>> {
+    let len = fs::metadata(path)?.len();
+    let same_size = |hash: &&str| {
+        let blob = store.path_from_hash(hash, base);
+        blob.is_ok_and(|blob| fs::metadata(blob).is_ok_and(|m| m.len() == len))
     };
-    entries.push(blob);
-    Ok(())
+    let candidates = index
+        .hashes(&relative_path(root, path))
+        .filter(same_size)
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let hash = calculate_file_hash_with(path, store.hash_algorithm)?;
+    Ok(candidates.contains(&hash.as_str()).then_some(hash))
+}
+
+pub(crate) fn relative_path(root: &Path, path: &Path) -> String {
+    PathNormalization::relative(root, path)
+}
+
+/// Check `path` against ignore patterns.
+///
+/// A pattern ending in `/` only matches directories. Patterns containing a `/` are matched
+/// against the path relative to `root`, other patterns against the file name alone. `*` matches
+/// any run of characters except `/` and `?` matches a single character.
+pub(crate) fn is_ignored(patterns: &[String], root: &Path, path: &Path, is_dir: bool) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+    let relative = relative_path(root, path);
+    let file_name = relative.rsplit('/').next().unwrap_or(&relative);
+    patterns.iter().any(|pattern| {
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(p) => (p, true),
+            None => (pattern.as_str(), false),
+        };
+        if dir_only && !is_dir {
+            return false;
+        }
+        if pattern.contains('/') {
+            glob_match(
+                pattern.trim_start_matches('/').as_bytes(),
+                relative.as_bytes(),
+            )
+        } else {
+            glob_match(pattern.as_bytes(), file_name.as_bytes())
+        }
+    })
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text)
+                || (!text.is_empty() && text[0] != b'/' && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(c)) if *c != b'/' => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(c)) if p == c => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Bytes the copies into the store wrote on this thread.
+        pub(super) static WRITTEN: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn blob_written(source: &Path, store: &Path, single_pass: bool) -> u64 {
+        let options = BlobOptions {
+            single_pass,
+            ..Default::default()
+        };
+        WRITTEN.with(|written| written.set(0));
+        create_directory_blob_file_with_options(store, source, &options).unwrap();
+        WRITTEN.with(Cell::get)
+    }
+
+    #[test]
+    fn test_stored_files_are_not_copied_again() {
+        let source = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let content = (0..50_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(source.path().join("large.bin"), &content).unwrap();
+        fs::write(source.path().join("small.txt"), b"small").unwrap();
+        let size = content.len() as u64 + 5;
+
+        assert_eq!(blob_written(source.path(), store.path(), false), size);
+        assert_eq!(blob_written(source.path(), store.path(), false), 0);
+        // a single pass copies stored files as well
+        assert_eq!(blob_written(source.path(), store.path(), true), size);
+
+        fs::write(source.path().join("small.txt"), b"changed").unwrap();
+        assert_eq!(blob_written(source.path(), store.path(), false), 7);
+    }
 }
@@ -0,0 +1,126 @@
+use crate::common::{escape_name, needs_escape, unescape_name};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory inside a store holding auxiliary metadata that never takes part in hashing.
+pub(crate) const META_DIR: &str = ".ditiear";
+const PATHS_FILE: &str = "paths";
+
+/// Sidecar index mapping file blob hashes to the source paths they were blobbed from.
+///
+/// Paths are relative to the blobbed root and always use `/` as separator. A path with line
+/// breaks is escaped like a name in a directory blob line, its hash is followed by `:e`.
+#[derive(Debug, Default)]
+pub struct PathIndex {
+    entries: BTreeMap<String, BTreeSet<String>>,
+    /// The same entries keyed by path.
+    hashes: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PathIndex {
+    fn file<P: AsRef<Path>>(base: P) -> PathBuf {
+        base.as_ref().join(META_DIR).join(PATHS_FILE)
+    }
+
+    /// Load the index of a store, an absent index is empty.
+    pub fn load<P: AsRef<Path>>(base: P) -> io::Result<Self> {
+        let mut index = PathIndex::default();
+        let content = match fs::read_to_string(Self::file(base)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            if let Some((hash, path)) = line.split_once(' ') {
+                match hash.strip_suffix(":e") {
+                    Some(hash) => {
+                        let path = unescape_name(path.as_bytes());
+                        index.insert(hash, &String::from_utf8_lossy(&path));
+                    }
+                    None => index.insert(hash, path),
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn save<P: AsRef<Path>>(&self, base: P) -> io::Result<()> {
+        let file = Self::file(base);
+        fs::create_dir_all(file.parent().unwrap())?;
+        let mut writer = io::BufWriter::new(fs::File::create(file)?);
+        for (hash, paths) in &self.entries {
+            for path in paths {
+                let flag = if needs_escape(path) { ":e" } else { "" };
+                writeln!(writer, "{}{} {}", hash, flag, escape_name(path))?;
+            }
+        }
+        writer.flush()
+    }
+
+    pub(crate) fn insert(&mut self, hash: &str, path: &str) {
+        self.entries
+            .entry(hash.to_string())
+            .or_default()
+            .insert(path.to_string());
+        self.hashes
+            .entry(path.to_string())
+            .or_default()
+            .insert(hash.to_string());
+    }
+
+    /// Every file blob hash the source path `path` was blobbed as.
+    pub(crate) fn hashes(&self, path: &str) -> impl Iterator<Item = &str> {
+        self.hashes
+            .get(path)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// Iterate over all file blob hashes with their source paths.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &BTreeSet<String>)> {
+        self.entries
+            .iter()
+            .map(|(hash, paths)| (hash.as_str(), paths))
+    }
+
+    /// All source paths observed for a file blob.
+    pub fn paths(&self, hash: &str) -> Option<&BTreeSet<String>> {
+        self.entries.get(hash)
+    }
+}
+
+/// Read the path sidecar of a store.
+pub fn read_path_index<P: AsRef<Path>>(base: P) -> io::Result<PathIndex> {
+    PathIndex::load(base)
+}
+
+/// File in the meta directory listing auxiliary blobs, like xattr manifests, that no root
+/// reaches but garbage collection must keep.
+const AUXILIARY_FILE: &str = "auxiliary";
+
+/// Record the blob `hash` of the store at `base` as auxiliary, so garbage collection keeps it.
+#[cfg(feature = "xattr")]
+pub(crate) fn record_auxiliary_blob(base: &Path, hash: &str) -> io::Result<()> {
+    if read_auxiliary_blobs(base)?.contains(hash) {
+        return Ok(());
+    }
+    let file = base.join(META_DIR).join(AUXILIARY_FILE);
+    fs::create_dir_all(file.parent().unwrap())?;
+    let mut writer = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    writeln!(writer, "{}", hash)
+}
+
+/// Hashes of every blob recorded with [`record_auxiliary_blob`], empty when there are none.
+pub(crate) fn read_auxiliary_blobs(base: &Path) -> io::Result<BTreeSet<String>> {
+    match fs::read_to_string(base.join(META_DIR).join(AUXILIARY_FILE)) {
+        Ok(content) => Ok(content.lines().map(String::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,221 @@
+use crate::checkout::{resolve_changes_with, ChangeKind, PathChange};
+use crate::common::{ensure_store, read_blob, read_store_config};
+use crate::delta::{apply_binary_patch, BytesPatch};
+use crate::diff::parse_blobs;
+use crate::patch::{unpack_patch, BlobPatch, ZipFileError};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A node of the tree of entries affected by a patch.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PatchTree {
+    pub name: String,
+    /// A directory is `Add` or `Delete` when every file below it is, `Modify` otherwise.
+    pub change: ChangeKind,
+    /// Number of bytes the entry grows by, negative when it shrinks.
+    pub size_delta: i64,
+    /// Changed children sorted by name, always empty for files.
+    pub children: Vec<PatchTree>,
+}
+
+impl PatchTree {
+    /// Find a node by its `/` separated path below this node.
+    pub fn get(&self, path: &str) -> Option<&PatchTree> {
+        path.split('/').try_fold(self, |node, name| {
+            node.children.iter().find(|child| child.name == name)
+        })
+    }
+
+    fn insert(&mut self, components: &[&str], change: ChangeKind, size_delta: i64) {
+        self.size_delta += size_delta;
+        let Some((name, rest)) = components.split_first() else {
+            return;
+        };
+        let i = match self.children.iter().position(|c| c.name == *name) {
+            Some(i) => i,
+            None => {
+                self.children.push(PatchTree {
+                    name: name.to_string(),
+                    change,
+                    size_delta: 0,
+                    children: vec![],
+                });
+                self.children.len() - 1
+            }
+        };
+        let child = &mut self.children[i];
+        if child.change != change {
+            child.change = ChangeKind::Modify;
+        }
+        child.insert(rest, change, size_delta);
+    }
+
+    fn sort(&mut self) {
+        self.children.sort_by(|a, b| a.name.cmp(&b.name));
+        self.children.iter_mut().for_each(PatchTree::sort);
+    }
+}
+
+/// Open a patch and resolve the entries it changes to their paths, without writing anything.
+///
+/// New directory blobs come from the patch itself, either as added payloads or by applying
+/// their replace records to the base blobs in memory. The root is the first replaced blob,
+/// [`compare_blob_files`](crate::prelude::compare_blob_files) always reports the root first.
+pub fn patch_tree<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_path: P,
+    base: Q,
+) -> Result<PatchTree, ZipFileError> {
+    let base = base.as_ref();
+    ensure_store(base)?;
+    let store = read_store_config(base)?;
+    let added = RefCell::new(HashMap::new());
+    let patchs = unpack_patch(patch_path, |buffer, name| {
+        added.borrow_mut().insert(name.to_string(), buffer);
+        Ok(())
+    })?;
+    let mut blobs = added.into_inner();
+    let mut root = None;
+    for patch in patchs {
+        if let BlobPatch::Replace {
+            old_file,
+            new_file,
+            patch,
+        } = patch
+        {
+            let old = read_blob(&store, &old_file, base)?;
+            let new = apply_binary_patch(&old, &patch)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            blobs.insert(new_file.clone(), new);
+            root.get_or_insert((old_file, new_file));
+        }
+    }
+    let mut tree = PatchTree {
+        name: String::new(),
+        change: ChangeKind::Modify,
+        size_delta: 0,
+        children: vec![],
+    };
+    let Some((old_root, new_root)) = root else {
+        return Ok(tree);
+    };
+    let read = |hash: &str| match blobs.get(hash) {
+        Some(content) => parse_blobs(content.clone()),
+        None => parse_blobs(read_blob(&store, hash, base)?),
+    };
+    let size = |hash: &Option<String>| -> io::Result<i64> {
+        Ok(match hash {
+            None => 0,
+            Some(hash) => match blobs.get(hash) {
+                Some(content) => content.len() as i64,
+                None => fs::metadata(store.path_from_hash(hash, base)?)?.len() as i64,
+            },
+        })
+    };
+    for PathChange {
+        path,
+        kind,
+        old,
+        new,
+        ..
+    } in resolve_changes_with(&old_root, &new_root, &read)?
+    {
+        let components = path.split('/').collect::<Vec<_>>();
+        tree.insert(&components, kind, size(&new)? - size(&old)?);
+    }
+    tree.sort();
+    Ok(tree)
+}
+
+/// A base blob that several patches replace with different results.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub old_file: String,
+    /// Every patch replacing the blob with the blob it produces, in the order given.
+    pub results: Vec<(PathBuf, String)>,
+}
+
+/// Report the base blobs that the given patches replace with differing results.
+///
+/// Patches that agree on the result of a replace do not conflict, so combining them is still
+/// consistent.
+pub fn detect_patch_conflicts<P: AsRef<Path>>(
+    patch_paths: &[P],
+) -> Result<Vec<Conflict>, ZipFileError> {
+    let mut replaced: BTreeMap<String, Vec<(PathBuf, String)>> = BTreeMap::new();
+    for patch_path in patch_paths {
+        for patch in unpack_patch(patch_path, |_, _| Ok(()))? {
+            if let BlobPatch::Replace {
+                old_file, new_file, ..
+            } = patch
+            {
+                replaced
+                    .entry(old_file)
+                    .or_default()
+                    .push((patch_path.as_ref().to_path_buf(), new_file));
+            }
+        }
+    }
+    Ok(replaced
+        .into_iter()
+        .filter(|(_, results)| results.iter().any(|(_, new)| *new != results[0].1))
+        .map(|(old_file, results)| Conflict { old_file, results })
+        .collect())
+}
+
+/// What applying a patch would change in a store, read from the patch alone.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PatchSummary {
+    /// Blobs the patch adds, including added blobs that only reference a deleted blob.
+    pub added: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Old and new blob of every replace.
+    pub replaced: Vec<(String, String)>,
+    /// Uncompressed size of the blobs embedded in the patch.
+    pub added_bytes: u64,
+    /// Bytes the store grows by through the added and replaced blobs, negative when it
+    /// shrinks. Deleted blobs are not counted, the patch does not record their size.
+    pub size_delta: i64,
+}
+
+/// Summarize the records of a patch without a base store and without writing anything.
+pub fn inspect_patch<P: AsRef<Path>>(patch_path: P) -> Result<PatchSummary, ZipFileError> {
+    let added_bytes = Cell::new(0);
+    let patchs = unpack_patch(patch_path, |buffer, _| {
+        added_bytes.set(added_bytes.get() + buffer.len() as u64);
+        Ok(())
+    })?;
+    let mut summary = PatchSummary {
+        added_bytes: added_bytes.get(),
+        size_delta: added_bytes.get() as i64,
+        ..PatchSummary::default()
+    };
+    for patch in patchs {
+        match patch {
+            BlobPatch::Add { new_file } => summary.added.push(new_file),
+            BlobPatch::Delete { old_file } => summary.deleted.push(old_file),
+            BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            } => {
+                summary.size_delta += patch
+                    .iter()
+                    .map(|op| match op {
+                        BytesPatch::Add { new_value, .. } => new_value.len() as i64,
+                        BytesPatch::Delete { old_value, .. } => -(old_value.len() as i64),
+                        BytesPatch::Replace {
+                            old_value,
+                            new_value,
+                            ..
+                        } => new_value.len() as i64 - old_value.len() as i64,
+                    })
+                    .sum::<i64>();
+                summary.replaced.push((old_file, new_file));
+            }
+        }
+    }
+    Ok(summary)
+}
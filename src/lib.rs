@@ -1,5 +1,37 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod checkout;
+#[cfg(feature = "std")]
 mod common;
+#[cfg(feature = "std")]
+mod config;
+mod delta;
+#[cfg(feature = "std")]
 mod diff;
+#[cfg(feature = "std")]
+mod files;
+#[cfg(feature = "std")]
 mod hash;
+#[cfg(feature = "std")]
+mod index;
+#[cfg(feature = "std")]
+mod inspect;
+#[cfg(feature = "std")]
+mod manifest;
+#[cfg(feature = "std")]
+mod merge;
+#[cfg(feature = "std")]
 mod patch;
 pub mod prelude;
+#[cfg(feature = "std")]
+mod signature;
+#[cfg(feature = "std")]
+mod staging;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod timings;
+#[cfg(all(feature = "xattr", unix))]
+mod xattrs;
@@ -0,0 +1,407 @@
+use crate::common::{
+    ensure_store, read_store_config, DiffBlob, DiffBlobType, Durability, FileParseError,
+    StoreConfig,
+};
+use crate::diff::read_blobs;
+use crate::hash::calculate_file_hash_with;
+use crate::index::read_auxiliary_blobs;
+use crate::signature::remove_signature;
+use crate::stats::StoreStats;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Result of checking a manifest against a store with [`import_manifest`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ManifestImport {
+    /// Root directory blob the manifest was exported from.
+    pub root: String,
+    /// Listed blobs the store does not hold yet, in manifest order.
+    pub missing: Vec<String>,
+}
+
+/// Export a listing of every blob reachable from `root_hash`.
+///
+/// The first line is the root hash, every following line is the parent directory hash and a
+/// directory blob line of the child. Each directory is listed once, even when it is shared.
+pub fn export_manifest<P: AsRef<Path>>(root_hash: &str, base: P) -> Result<String, FileParseError> {
+    ensure_store(base.as_ref())?;
+    let store = read_store_config(base.as_ref())?;
+    let mut manifest = format!("{}\n", root_hash);
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([root_hash.to_string()]);
+    while let Some(hash) = queue.pop_front() {
+        if !visited.insert(hash.clone()) {
+            continue;
+        }
+        let mut children = read_blobs(&hash, base.as_ref(), &store)?
+            .into_values()
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        for child in children {
+            manifest.push_str(&format!("{} {}", hash, child));
+            if child.blob_type == DiffBlobType::Directory {
+                queue.push_back(child.hash);
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// Summed size of every distinct blob reachable from `root_hash`, directory blobs included.
+///
+/// Blobs shared between entries are stored once and so counted once, unlike the size of a
+/// checkout of the version.
+pub fn version_size<P: AsRef<Path>>(root_hash: &str, base: P) -> Result<u64, FileParseError> {
+    version_size_in(root_hash, base.as_ref(), &read_store_config(base.as_ref())?)
+}
+
+pub(crate) fn version_size_in(
+    root_hash: &str,
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<u64, FileParseError> {
+    let mut size = 0;
+    for (hash, _) in reachable_blobs(root_hash, base, store)? {
+        size += store.blob_size(&hash, base)?;
+    }
+    Ok(size)
+}
+
+/// Content hashes that more than one file of the version `root_hash` refers to, each with the
+/// `/` separated paths of those files.
+///
+/// The store keeps such content once, this reports the redundant copies of the tree itself.
+/// Sorted by hash, the paths of each hash sorted too.
+pub fn find_duplicate_files<P: AsRef<Path>>(
+    root_hash: &str,
+    base: P,
+) -> Result<Vec<(String, Vec<String>)>, FileParseError> {
+    let base = base.as_ref();
+    ensure_store(base)?;
+    let store = read_store_config(base)?;
+    let mut files: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    // directories are not deduplicated, a repeated directory repeats its files
+    let mut stack = vec![(root_hash.to_string(), String::new())];
+    while let Some((hash, prefix)) = stack.pop() {
+        for child in read_blobs(&hash, base, &store)?.into_values() {
+            let path = if prefix.is_empty() {
+                child.name
+            } else {
+                format!("{}/{}", prefix, child.name)
+            };
+            match child.blob_type {
+                DiffBlobType::Directory => stack.push((child.hash, path)),
+                DiffBlobType::File => files.entry(child.hash).or_default().push(path),
+                // links to the same target are no duplicated content
+                DiffBlobType::Symlink => {}
+            }
+        }
+    }
+    Ok(files
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(hash, mut paths)| {
+            paths.sort();
+            (hash, paths)
+        })
+        .collect())
+}
+
+/// Every distinct blob reachable from `root_hash` with its type, the root first.
+pub(crate) fn reachable_blobs(
+    root_hash: &str,
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<Vec<(String, DiffBlobType)>, FileParseError> {
+    reachable_blobs_except(root_hash, base, store, &HashSet::new())
+}
+
+/// Like [`reachable_blobs`], leaving out the blobs in `have` together with everything only
+/// reachable through them.
+fn reachable_blobs_except(
+    root_hash: &str,
+    base: &Path,
+    store: &StoreConfig,
+    have: &HashSet<String>,
+) -> Result<Vec<(String, DiffBlobType)>, FileParseError> {
+    ensure_store(base)?;
+    if have.contains(root_hash) {
+        return Ok(vec![]);
+    }
+    let mut visited = HashSet::from([root_hash.to_string()]);
+    let mut stack = vec![root_hash.to_string()];
+    let mut blobs = vec![(root_hash.to_string(), DiffBlobType::Directory)];
+    while let Some(hash) = stack.pop() {
+        for child in read_blobs(&hash, base, store)?.into_values() {
+            if have.contains(&child.hash) || !visited.insert(child.hash.clone()) {
+                continue;
+            }
+            if child.blob_type == DiffBlobType::Directory {
+                stack.push(child.hash.clone());
+            }
+            blobs.push((child.hash, child.blob_type));
+        }
+    }
+    Ok(blobs)
+}
+
+/// Blobs reachable from `root_hash` in the store at `base` that a client holding `have`
+/// still needs, the root first.
+///
+/// A directory in `have` is taken to come with its whole subtree, so nothing below it is
+/// listed.
+pub fn missing_blobs_for_root<P: AsRef<Path>>(
+    root_hash: &str,
+    base: P,
+    have: &HashSet<String>,
+) -> Result<Vec<String>, FileParseError> {
+    let store = read_store_config(base.as_ref())?;
+    let blobs = reachable_blobs_except(root_hash, base.as_ref(), &store, have)?;
+    Ok(blobs.into_iter().map(|(hash, _)| hash).collect())
+}
+
+/// A blob of a source store that a replica lacks or holds with different content.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplicaIssue {
+    Missing(String),
+    Mismatched { hash: String, actual: String },
+}
+
+/// Check that a replica holds every blob reachable from `root_hash` in `source_base`.
+///
+/// Blobs are content addressed, so a replica blob matches when its content hashes to its
+/// name, the source is only read to walk the tree.
+pub fn verify_replica<P: AsRef<Path>, Q: AsRef<Path>>(
+    root_hash: &str,
+    source_base: P,
+    replica_base: Q,
+) -> Result<Vec<ReplicaIssue>, FileParseError> {
+    ensure_store(replica_base.as_ref())?;
+    let source = read_store_config(source_base.as_ref())?;
+    let replica = read_store_config(replica_base.as_ref())?;
+    let mut issues = vec![];
+    for (hash, _) in reachable_blobs(root_hash, source_base.as_ref(), &source)? {
+        let path = replica.path_from_hash(&hash, replica_base.as_ref())?;
+        if !path.is_file() {
+            issues.push(ReplicaIssue::Missing(hash));
+            continue;
+        }
+        let actual = calculate_file_hash_with(&path, source.hash_algorithm)?;
+        if actual != hash {
+            issues.push(ReplicaIssue::Mismatched { hash, actual });
+        }
+    }
+    Ok(issues)
+}
+
+/// What a garbage collection with [`gc`] removed from a store.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Hashes of the removed blobs, sorted.
+    pub removed: Vec<String>,
+    pub objects_freed: u64,
+    pub bytes_freed: u64,
+}
+
+/// Remove every blob of the store at `base` that is not reachable from one of `live_roots`,
+/// reporting how many objects and bytes were freed.
+///
+/// Only blobs the store wrote are considered, files that are not named like blobs or whose
+/// content does not hash to their name are left alone. Auxiliary blobs no root reaches, like
+/// the manifests written by `capture_xattrs`, are kept as well.
+///
+/// Every directory blob reachable from `live_roots` is read before anything is removed, so a
+/// root or subtree missing from the store fails the collection without touching the store.
+pub fn gc<P: AsRef<Path>>(base: P, live_roots: &[&str]) -> Result<GcStats, FileParseError> {
+    collect_garbage_in(
+        live_roots,
+        base.as_ref(),
+        &read_store_config(base.as_ref())?,
+    )
+}
+
+/// Same as [`gc`], returning only the removed hashes, sorted.
+///
+/// Kept for callers from before [`gc`], note that the roots come first here.
+pub fn collect_garbage<P: AsRef<Path>>(
+    roots: &[&str],
+    base: P,
+) -> Result<Vec<String>, FileParseError> {
+    Ok(gc(base, roots)?.removed)
+}
+
+pub(crate) fn collect_garbage_in(
+    roots: &[&str],
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<GcStats, FileParseError> {
+    ensure_store(base)?;
+    let mut live = read_auxiliary_blobs(base)?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    for root in roots {
+        live.extend(
+            reachable_blobs(root, base, store)?
+                .into_iter()
+                .map(|(hash, _)| hash),
+        );
+    }
+    let mut stats = StoreStats::load(base, store)?;
+    let mut freed = GcStats::default();
+    for (hash, path) in store.list_blobs(base)? {
+        // a file named like a blob whose content does not hash to the name was not written
+        // by the store, fsck reports it instead
+        if !live.contains(&hash) && calculate_file_hash_with(&path, store.hash_algorithm)? == hash {
+            let size = fs::metadata(&path)?.len();
+            fs::remove_file(path)?;
+            remove_signature(&hash, base)?;
+            stats.removed(size);
+            freed.objects_freed += 1;
+            freed.bytes_freed += size;
+            freed.removed.push(hash);
+        }
+    }
+    stats.retain_stored_versions(base, store);
+    stats.save(base, Durability::None)?;
+    Ok(freed)
+}
+
+/// Check that every blob of the store at `base` hashes to its name, files that do not look like
+/// blobs of the store are not checked.
+pub fn verify_store<P: AsRef<Path>>(base: P) -> Result<Vec<ReplicaIssue>, FileParseError> {
+    verify_store_in(base.as_ref(), &read_store_config(base.as_ref())?)
+}
+
+pub(crate) fn verify_store_in(
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<Vec<ReplicaIssue>, FileParseError> {
+    ensure_store(base)?;
+    let mut issues = vec![];
+    for (hash, path) in store.list_blobs(base)? {
+        let actual = calculate_file_hash_with(&path, store.hash_algorithm)?;
+        if actual != hash {
+            issues.push(ReplicaIssue::Mismatched { hash, actual });
+        }
+    }
+    Ok(issues)
+}
+
+/// Damage found in a store by [`fsck`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The content of the blob `hash` hashes to `actual`.
+    Mismatched { hash: String, actual: String },
+    /// The directory blob `parent` lists the child `name` as `hash`, which the store lacks.
+    Dangling {
+        parent: String,
+        name: String,
+        hash: String,
+    },
+}
+
+/// Check every blob of the store at `base`, reporting all damage found instead of stopping at
+/// the first.
+///
+/// Each blob must hash to its name, as [`verify_store`] checks, and every child of a directory
+/// blob must be in the store. Blobs are not typed on disk, so the directory blobs checked are
+/// the ones reached from the versions recorded in the store, walking down from their roots.
+/// A directory blob that is itself damaged is not walked.
+pub fn fsck<P: AsRef<Path>>(base: P) -> Result<Vec<IntegrityError>, FileParseError> {
+    fsck_in(base.as_ref(), &read_store_config(base.as_ref())?)
+}
+
+pub(crate) fn fsck_in(
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<Vec<IntegrityError>, FileParseError> {
+    let mut errors = vec![];
+    let mut damaged = HashSet::new();
+    for issue in verify_store_in(base, store)? {
+        if let ReplicaIssue::Mismatched { hash, actual } = issue {
+            damaged.insert(hash.clone());
+            errors.push(IntegrityError::Mismatched { hash, actual });
+        }
+    }
+    let stats = StoreStats::load(base, store)?;
+    let mut visited = HashSet::new();
+    let mut stack: Vec<String> = stats
+        .roots()
+        .filter(|root| store.blob_exists(root, base))
+        .map(String::from)
+        .collect();
+    stack.reverse();
+    while let Some(hash) = stack.pop() {
+        if damaged.contains(&hash) || !visited.insert(hash.clone()) {
+            continue;
+        }
+        let mut children: Vec<_> = read_blobs(&hash, base, store)?.into_values().collect();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut directories = vec![];
+        for child in children {
+            if !store.blob_exists(&child.hash, base) {
+                errors.push(IntegrityError::Dangling {
+                    parent: hash.clone(),
+                    name: child.name,
+                    hash: child.hash,
+                });
+            } else if child.blob_type == DiffBlobType::Directory {
+                directories.push(child.hash);
+            }
+        }
+        stack.extend(directories.into_iter().rev());
+    }
+    Ok(errors)
+}
+
+/// Check a manifest written by [`export_manifest`] against the store at `base`.
+///
+/// Blobs the store lacks are reported as missing so a target can be prepared, a directory
+/// blob the store does hold must list exactly the children of the manifest.
+pub fn import_manifest<P: AsRef<Path>>(
+    manifest: &str,
+    base: P,
+) -> Result<ManifestImport, FileParseError> {
+    ensure_store(base.as_ref())?;
+    let store = read_store_config(base.as_ref())?;
+    let mut lines = manifest.lines();
+    let root = lines
+        .next()
+        .ok_or_else(|| FileParseError::ManifestMismatch("empty manifest".to_string()))?
+        .to_string();
+    let mut children: BTreeMap<String, Vec<DiffBlob>> = BTreeMap::new();
+    let mut order = vec![root.clone()];
+    for line in lines {
+        let (parent, blob) = line.split_once(' ').ok_or_else(|| {
+            FileParseError::ManifestMismatch(format!("invalid manifest line {:?}", line))
+        })?;
+        let blob = DiffBlob::from_str(blob)?;
+        order.push(blob.hash.clone());
+        children.entry(parent.to_string()).or_default().push(blob);
+    }
+    let mut missing = vec![];
+    let mut seen = HashSet::new();
+    for hash in order {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        if !store.blob_exists(&hash, base.as_ref()) {
+            missing.push(hash);
+            continue;
+        }
+        let Some(listed) = children.get(&hash) else {
+            continue;
+        };
+        let stored = read_blobs(&hash, base.as_ref(), &store)?;
+        let matches = stored.len() == listed.len()
+            && listed
+                .iter()
+                .all(|b| stored.values().any(|s| s.to_string() == b.to_string()));
+        if !matches {
+            return Err(FileParseError::ManifestMismatch(hash));
+        }
+    }
+    Ok(ManifestImport { root, missing })
+}
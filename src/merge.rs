@@ -0,0 +1,332 @@
+#[cfg(feature = "binaryBlob")]
+use crate::common::{decode_binary_blobs, encode_binary_blobs};
+use crate::common::{
+    ensure_store, read_blob, read_store_config, DiffBlob, DiffBlobType, StoreConfig,
+};
+use crate::delta::{apply_binary_patch, compose_ops, BytesPatch, DeltaError};
+use crate::diff::read_blobs;
+use crate::hash::HashAlgorithm;
+use crate::patch::{
+    calculate_binary_diff, unpack_patch, write_patch_with_payloads, BlobPatch, PatchOptions,
+    ZipFileError,
+};
+use bytes::Bytes;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Combine patches of disjoint subtrees of the stored root `base_root` into one patch of the
+/// whole tree written to `to_dest`, returning the new root hash.
+///
+/// Every subtree patch comes with the `/` separated path of its directory below `base_root`
+/// and must have been made between two versions of that directory. The directory blobs above
+/// the subtrees are rebuilt and patched too, in the format each was stored in, so the merged
+/// patch turns `base_root` into a consistent new root.
+pub fn merge_subtree_patches<P, S, Q, R>(
+    base_root: &str,
+    base: P,
+    subtree_patches: &[(S, Q)],
+    to_dest: R,
+) -> Result<String, ZipFileError>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    merge_subtree_patches_in(
+        base_root,
+        base.as_ref(),
+        subtree_patches,
+        to_dest.as_ref(),
+        &read_store_config(base.as_ref())?,
+        &PatchOptions::default(),
+    )
+}
+
+pub(crate) fn merge_subtree_patches_in<S: AsRef<str>, Q: AsRef<Path>>(
+    base_root: &str,
+    base: &Path,
+    subtree_patches: &[(S, Q)],
+    to_dest: &Path,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<String, ZipFileError> {
+    ensure_store(base)?;
+    let payloads = RefCell::new(HashMap::new());
+    let mut records = vec![];
+    let mut replaced = BTreeMap::new();
+    for (subtree, patch) in subtree_patches {
+        let subtree = subtree.as_ref();
+        let invalid = || ZipFileError::InvalidSubtree(subtree.to_string());
+        let path = subtree
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let old = find_directory(base_root, &path, base, store)?.ok_or_else(invalid)?;
+        let patchs = unpack_patch(patch, |buffer, name| {
+            payloads.borrow_mut().insert(name.to_string(), buffer);
+            Ok(())
+        })?;
+        if patchs.is_empty() {
+            continue;
+        }
+        let new = patchs
+            .iter()
+            .find_map(|p| match p {
+                BlobPatch::Replace {
+                    old_file, new_file, ..
+                } if *old_file == old => Some(new_file.clone()),
+                _ => None,
+            })
+            .ok_or_else(invalid)?;
+        if replaced
+            .keys()
+            .any(|p: &Vec<String>| p.starts_with(&path) || path.starts_with(p))
+        {
+            return Err(invalid());
+        }
+        replaced.insert(path, new);
+        records.extend(patchs);
+    }
+    let mut ancestors = vec![];
+    let new_root = rebuild(
+        base_root,
+        &mut vec![],
+        &replaced,
+        base,
+        store,
+        &mut ancestors,
+    )?;
+    // the root goes first, like in a patch of the whole tree
+    ancestors.reverse();
+    ancestors.extend(records);
+    let mut added = HashSet::new();
+    ancestors.retain(|p| match p {
+        BlobPatch::Add { new_file } => added.insert(new_file.clone()),
+        _ => true,
+    });
+    let payloads = payloads.into_inner();
+    let payload = |hash: &str| {
+        payloads
+            .get(hash)
+            .map(|content| -> Box<dyn Read> { Box::new(io::Cursor::new(content.clone())) })
+            .ok_or_else(|| ZipFileError::MissingBaseBlob(hash.to_string()))
+    };
+    let file = fs::File::create(to_dest)?;
+    write_patch_with_payloads(ancestors, file, options, store.hash_algorithm, &payload)?;
+    Ok(new_root)
+}
+
+/// A blob produced by a patch of a chain, relative to the store the first patch applies to.
+enum Composed {
+    Added(Bytes),
+    /// The blob `base` of that store with `ops` applied.
+    Derived {
+        base: String,
+        ops: Vec<BytesPatch>,
+    },
+}
+
+/// Fold the patches `patches`, each made against the new root of the one before, into one
+/// patch from the old root of the first to the new root of the last written to `to_dest`,
+/// returning that new root.
+///
+/// A blob added and later replaced is added in its final form, a blob added and later deleted
+/// is left out, and the ops of a blob replaced several times are composed into ops against
+/// the blob the first patch started from. Blobs produced along the chain are otherwise kept,
+/// they may still be shared by unchanged entries of the final version.
+///
+/// A patch whose root record does not continue from the root the previous patch ended at
+/// fails with [`ZipFileError::SourceMismatch`].
+pub fn compose_patches<P: AsRef<Path>, Q: AsRef<Path>>(
+    patches: &[P],
+    to_dest: Q,
+) -> Result<Option<String>, ZipFileError> {
+    let invalid = |e: DeltaError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let mut produced: BTreeMap<String, Composed> = BTreeMap::new();
+    let mut deleted = BTreeSet::new();
+    let mut root: Option<String> = None;
+    for patch in patches {
+        let payloads = RefCell::new(HashMap::new());
+        let records = unpack_patch(patch, |buffer, name| {
+            payloads.borrow_mut().insert(name.to_string(), buffer);
+            Ok(())
+        })?;
+        let mut payloads = payloads.into_inner();
+        // the root record comes first
+        if let Some(BlobPatch::Replace {
+            old_file, new_file, ..
+        }) = records.first()
+        {
+            if root.as_ref().is_some_and(|root| root != old_file) {
+                return Err(ZipFileError::SourceMismatch(old_file.clone()));
+            }
+            root = Some(new_file.clone());
+        }
+        // deletes of a patch apply after its other records, which may still read the blob
+        let (deletes, records): (Vec<_>, Vec<_>) = records
+            .into_iter()
+            .partition(|record| matches!(record, BlobPatch::Delete { .. }));
+        for record in records {
+            match record {
+                BlobPatch::Add { new_file } => {
+                    // an add without payload refers to a blob of the previous version
+                    if let Some(content) = payloads.remove(&new_file) {
+                        produced.insert(new_file.clone(), Composed::Added(content.into()));
+                    }
+                    deleted.remove(&new_file);
+                }
+                BlobPatch::Replace {
+                    old_file,
+                    new_file,
+                    patch,
+                } => {
+                    let composed = match produced.get(&old_file) {
+                        Some(Composed::Added(content)) => Composed::Added(
+                            apply_binary_patch(content, &patch).map_err(invalid)?.into(),
+                        ),
+                        Some(Composed::Derived { base, ops }) => Composed::Derived {
+                            base: base.clone(),
+                            ops: compose_ops(ops, &patch).map_err(invalid)?,
+                        },
+                        None => Composed::Derived {
+                            base: old_file,
+                            ops: patch,
+                        },
+                    };
+                    deleted.remove(&new_file);
+                    produced.insert(new_file, composed);
+                }
+                BlobPatch::Delete { .. } => {}
+            }
+        }
+        for record in deletes {
+            if let BlobPatch::Delete { old_file } = record {
+                if produced.remove(&old_file).is_none() {
+                    deleted.insert(old_file);
+                }
+            }
+        }
+    }
+
+    let mut records = vec![];
+    let mut payloads = HashMap::new();
+    let mut push = |hash: String, composed: Composed| match composed {
+        Composed::Added(content) => {
+            payloads.insert(hash.clone(), content);
+            records.push(BlobPatch::Add { new_file: hash });
+        }
+        Composed::Derived { base, ops } => records.push(BlobPatch::Replace {
+            old_file: base,
+            new_file: hash,
+            patch: ops,
+        }),
+    };
+    // the root goes first, like in a patch of the whole tree
+    if let Some(root) = &root {
+        if let Some(composed) = produced.remove(root) {
+            push(root.clone(), composed);
+        }
+    }
+    for (hash, composed) in produced {
+        push(hash, composed);
+    }
+    records.extend(
+        deleted
+            .into_iter()
+            .map(|old_file| BlobPatch::Delete { old_file }),
+    );
+    let payload = |hash: &str| {
+        payloads
+            .get(hash)
+            .map(|content| -> Box<dyn Read> { Box::new(io::Cursor::new(content.clone())) })
+            .ok_or_else(|| ZipFileError::MissingBaseBlob(hash.to_string()))
+    };
+    let file = fs::File::create(to_dest)?;
+    write_patch_with_payloads(
+        records,
+        file,
+        &PatchOptions::default(),
+        HashAlgorithm::default(),
+        &payload,
+    )?;
+    Ok(root)
+}
+
+/// Hash of the directory at `path` below the directory `root`, `None` when there is none.
+fn find_directory(
+    root: &str,
+    path: &[String],
+    base: &Path,
+    store: &StoreConfig,
+) -> Result<Option<String>, ZipFileError> {
+    let mut dir = root.to_string();
+    for name in path {
+        let child = read_blobs(&dir, base, store)?
+            .into_values()
+            .find(|child| child.blob_type == DiffBlobType::Directory && child.name == *name);
+        match child {
+            Some(child) => dir = child.hash,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(dir))
+}
+
+/// Hash of the directory `hash` at `path` once the subtrees in `replaced` are swapped in.
+///
+/// Replace records of every rebuilt directory are pushed to `records`, children before their
+/// parent.
+fn rebuild(
+    hash: &str,
+    path: &mut Vec<String>,
+    replaced: &BTreeMap<Vec<String>, String>,
+    base: &Path,
+    store: &StoreConfig,
+    records: &mut Vec<BlobPatch>,
+) -> Result<String, ZipFileError> {
+    if let Some(new) = replaced.get(path.as_slice()) {
+        return Ok(new.clone());
+    }
+    if !replaced.keys().any(|p| p.starts_with(path)) {
+        return Ok(hash.to_string());
+    }
+    let mut children = read_blobs(hash, base, store)?
+        .into_values()
+        .collect::<Vec<_>>();
+    for child in &mut children {
+        if child.blob_type == DiffBlobType::Directory {
+            path.push(child.name.clone());
+            child.hash = rebuild(&child.hash, path, replaced, base, store, records)?;
+            path.pop();
+        }
+    }
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    let old = Bytes::from(read_blob(store, hash, base)?);
+    // rebuilt in the format it was stored in
+    #[cfg(feature = "binaryBlob")]
+    let content = match decode_binary_blobs(&old) {
+        Some(_) => Bytes::from(
+            encode_binary_blobs(children)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        ),
+        None => Bytes::from(children.iter().map(DiffBlob::to_string).collect::<String>()),
+    };
+    #[cfg(not(feature = "binaryBlob"))]
+    let content = Bytes::from(children.iter().map(DiffBlob::to_string).collect::<String>());
+    let mut hasher = store.hash_algorithm.hasher();
+    hasher.update(&content);
+    let new = hasher.finish_hex();
+    if new != hash {
+        records.push(BlobPatch::Replace {
+            old_file: hash.to_string(),
+            new_file: new.clone(),
+            patch: calculate_binary_diff(old, content),
+        });
+    }
+    Ok(new)
+}
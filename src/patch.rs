@@ -1,111 +1,545 @@
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use similar::{capture_diff_slices, Algorithm, DiffOp};
+use similar::{capture_diff_slices, Algorithm, DiffOp, TextDiff};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
 use std::fs::OpenOptions;
+use std::hash::Hasher;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::{fs, path::Path};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use twox_hash::XxHash64;
 use zip::write::{FileOptions, ZipWriter};
 use zip::{CompressionMethod, ZipArchive};
 
 use crate::common::DeserializeError;
+use crate::delta::{decode_ops, encode_ops, BytesPatch};
+use crate::hash::{calculate_file_hash_with, HashAlgorithm};
+use crate::index::META_DIR;
+use crate::signature::{calculate_block_diff, calculate_signature, SIGNATURE_BLOCK_SIZE};
+use crate::staging::Staging;
+use crate::stats::StoreStats;
+use crate::timings::{timed, Phase};
 use crate::{
-    common::{path_from_hash, FileParseError},
-    diff::DiffCollectionType,
+    common::{
+        canonical_store, check_entry_name, is_valid_hash, read_blob, read_store_config,
+        CancellationToken, Durability, FileParseError, StoreConfig,
+    },
+    diff::{read_blobs, DiffCollectionType, DiffFileType},
 };
 
-fn serialize_bytes<S>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serde_bytes::serialize(bytes.as_ref(), serializer)
+pub fn calculate_binary_diff(old: Bytes, new: Bytes) -> Vec<BytesPatch> {
+    calculate_binary_diff_with_algorithm(old, new, DiffAlgorithm::default())
 }
 
-fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let bytes: Vec<u8> = serde_bytes::deserialize(deserializer)?;
-    Ok(Bytes::from(bytes))
+/// Algorithm finding the ops of a binary diff.
+///
+/// Every algorithm produces an exact patch, they differ in speed and in how small the patch
+/// is for a given kind of content, see [`benchmark_diff`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    Patience,
+    Lcs,
+    /// Match the blocks of the old input by a rolling checksum like rsync, see
+    /// [`calculate_binary_diff_rolling`].
+    Rolling,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub enum BytesPatch {
+/// Like [`calculate_binary_diff`], matching fixed size blocks of `old` wherever they show up in
+/// `new` instead of aligning the inputs byte by byte.
+///
+/// The time taken grows with the input length only, so bytes inserted near the front of a
+/// large file cost a patch of about one block instead of a diff through the whole tail.
+pub fn calculate_binary_diff_rolling(old: Bytes, new: Bytes) -> Vec<BytesPatch> {
+    calculate_binary_diff_with_algorithm(old, new, DiffAlgorithm::Rolling)
+}
+
+/// Like [`calculate_binary_diff`], finding the ops with the given algorithm.
+pub fn calculate_binary_diff_with_algorithm(
+    old: Bytes,
+    new: Bytes,
+    algorithm: DiffAlgorithm,
+) -> Vec<BytesPatch> {
+    timed(Phase::Diffing, || diff_ops(old, new, algorithm))
+}
+
+/// Diff `old` and `new` with each of `algorithms`, returning the time every run took and the
+/// size of its encoded patch.
+pub fn benchmark_diff(
+    old: Bytes,
+    new: Bytes,
+    algorithms: &[DiffAlgorithm],
+) -> Vec<(DiffAlgorithm, Duration, usize)> {
+    algorithms
+        .iter()
+        .map(|&algorithm| {
+            let start = Instant::now();
+            let patch = calculate_binary_diff_with_algorithm(old.clone(), new.clone(), algorithm);
+            let elapsed = start.elapsed();
+            (algorithm, elapsed, encode_ops(&patch).len())
+        })
+        .collect()
+}
+
+/// Memory limits of a binary diff, see [`calculate_binary_diff_bounded`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BinaryDiffOptions {
+    /// Most bytes of both inputs together that are diffed in one piece, larger inputs are
+    /// diffed in windows of half this size. Files over it are not read at all when creating a
+    /// patch, their new blob is added whole instead.
+    pub max_in_memory: usize,
+}
+
+impl Default for BinaryDiffOptions {
+    fn default() -> Self {
+        BinaryDiffOptions {
+            max_in_memory: usize::MAX,
+        }
+    }
+}
+
+/// Like [`calculate_binary_diff`], never diffing more than `options.max_in_memory` bytes of
+/// both inputs in one piece.
+///
+/// Larger inputs are diffed window by window like [`calculate_binary_diff_with_progress`], so
+/// the memory the diff itself takes stays bounded however large the inputs are.
+pub fn calculate_binary_diff_bounded(
+    old: Bytes,
+    new: Bytes,
+    options: &BinaryDiffOptions,
+) -> Vec<BytesPatch> {
+    if old.len().saturating_add(new.len()) <= options.max_in_memory {
+        return calculate_binary_diff(old, new);
+    }
+    let window = (options.max_in_memory / 2).max(1);
+    calculate_binary_diff_with_progress(old, new, window, |_, _| {})
+}
+
+/// Size of the windows [`calculate_binary_diff_with_progress`] diffs one at a time.
+pub(crate) const PROGRESS_WINDOW: usize = 1 << 20;
+
+/// Like [`calculate_binary_diff`], diffing the inputs window by window and calling `progress`
+/// with the bytes of both inputs processed so far and their total after every window.
+///
+/// Each window of the old input is diffed against a window of the new input starting where
+/// the diff of the window before last matched both, the ops past that point are diffed again
+/// with the next window. Content inserted or removed early only shifts the later windows, the
+/// patch is still exact but may be larger than a one-shot diff when a change is longer than a
+/// window.
+pub fn calculate_binary_diff_with_progress<F: FnMut(usize, usize)>(
+    old: Bytes,
+    new: Bytes,
+    window: usize,
+    mut progress: F,
+) -> Vec<BytesPatch> {
+    let window = window.max(1);
+    let total = old.len() + new.len();
+    let mut result = vec![];
+    let (mut old_start, mut new_start) = (0, 0);
+    loop {
+        let old_end = (old_start + window).min(old.len());
+        let new_end = (new_start + window).min(new.len());
+        let ops =
+            calculate_binary_diff(old.slice(old_start..old_end), new.slice(new_start..new_end));
+        let last = old_end == old.len() && new_end == new.len();
+        // the end of the last run both windows share, the ops after it may only be there
+        // because a window cut the inputs short
+        let (mut synced, mut ends) = (None, (0, 0));
+        for (i, op) in ops.iter().enumerate() {
+            let (old_index, old_len, new_index, new_len) = op.ranges();
+            if old_index > ends.0 {
+                synced = Some((i, (old_index, new_index)));
+            }
+            ends = (old_index + old_len, new_index + new_len);
+        }
+        if ends.0 < old_end - old_start {
+            synced = Some((ops.len(), (old_end - old_start, new_end - new_start)));
+        }
+        let (kept, (old_len, new_len)) = match synced {
+            Some(synced) if !last => synced,
+            _ => (ops.len(), (old_end - old_start, new_end - new_start)),
+        };
+        result.extend(
+            ops.into_iter()
+                .take(kept)
+                .map(|op| op.offset(old_start, new_start)),
+        );
+        old_start += old_len;
+        new_start += new_len;
+        progress(old_start + new_start, total);
+        if last {
+            return result;
+        }
+    }
+}
+
+fn diff_ops(old: Bytes, new: Bytes, algorithm: DiffAlgorithm) -> Vec<BytesPatch> {
+    let algorithm = match algorithm {
+        DiffAlgorithm::Myers => Algorithm::Myers,
+        DiffAlgorithm::Patience => Algorithm::Patience,
+        DiffAlgorithm::Lcs => Algorithm::Lcs,
+        DiffAlgorithm::Rolling => {
+            let signature = calculate_signature(&old, SIGNATURE_BLOCK_SIZE);
+            return calculate_block_diff(old, new, &signature);
+        }
+    };
+    let ops = capture_diff_slices(algorithm, old.as_ref(), new.as_ref());
+    // similar does not always report where an insert sits in the old sequence, so positions
+    // are tracked from the lengths of the preceding ops instead
+    let (mut old_pos, mut new_pos) = (0, 0);
+    let mut result = vec![];
+    for op in ops {
+        let (old_len, new_len) = (op.old_range().len(), op.new_range().len());
+        match op {
+            DiffOp::Equal { .. } => {}
+            DiffOp::Delete { .. } => result.push(BytesPatch::Delete {
+                old_index: old_pos,
+                new_index: new_pos,
+                old_value: old.slice(old_pos..old_pos + old_len),
+            }),
+            DiffOp::Insert { .. } => result.push(BytesPatch::Add {
+                old_index: old_pos,
+                new_index: new_pos,
+                new_value: new.slice(new_pos..new_pos + new_len),
+            }),
+            DiffOp::Replace { .. } => result.push(BytesPatch::Replace {
+                old_index: old_pos,
+                new_index: new_pos,
+                old_value: old.slice(old_pos..old_pos + old_len),
+                new_value: new.slice(new_pos..new_pos + new_len),
+            }),
+        }
+        old_pos += old_len;
+        new_pos += new_len;
+    }
+    result
+}
+
+/// How [`content_changed`] compares its inputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiffMode {
+    /// Compare the bytes as they are.
+    #[default]
+    Exact,
+    /// When both inputs are UTF-8 text, unify line endings and trim the whitespace around
+    /// every line before comparing, so reformatting is no change.
+    IgnoreWhitespace,
+}
+
+/// Whether `new` differs from `old` when compared according to `mode`.
+///
+/// Use this to skip the diff of a file whose content did not meaningfully change, the ops of
+/// [`calculate_binary_diff`] always reproduce every byte of `new`.
+pub fn content_changed(old: &[u8], new: &[u8], mode: DiffMode) -> bool {
+    match (mode, std::str::from_utf8(old), std::str::from_utf8(new)) {
+        // `lines` already strips `\r\n`, trimming takes care of lone `\r` and indentation
+        (DiffMode::IgnoreWhitespace, Ok(old), Ok(new)) => {
+            !old.lines().map(str::trim).eq(new.lines().map(str::trim))
+        }
+        _ => old != new,
+    }
+}
+
+/// Render the changes between two texts as a unified diff with three lines of context, for
+/// reviewing modified text files.
+pub fn render_text_diff(old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header("old", "new")
+        .to_string()
+}
+
+/// Cheaper way of shipping a modified file, see [`estimate_delta`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    Delta,
+    Full,
+}
+
+/// Bytes a modified blob takes in a patch as a delta next to the bytes of its new version
+/// added whole, both encoded and compressed the way the patch would be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeltaEstimate {
+    pub delta_size: u64,
+    pub full_size: u64,
+    /// The smaller of both, the full file when they tie.
+    pub recommended: Transfer,
+}
+
+impl DeltaEstimate {
+    /// Delta size relative to the full size, below `1.0` when the delta saves bytes.
+    pub fn ratio(&self) -> f64 {
+        match self.full_size {
+            0 => 1.0,
+            full_size => self.delta_size as f64 / full_size as f64,
+        }
+    }
+}
+
+/// Compare shipping the delta of a [`DiffCollectionType::Modify`] with shipping its new blob
+/// whole, `None` for any other diff.
+///
+/// Both are measured as the records and entries a patch written with the default
+/// [`PatchOptions`] would hold.
+pub fn estimate_delta<P: AsRef<Path>>(
+    diff: &DiffCollectionType,
+    base: P,
+) -> Result<Option<DeltaEstimate>, FileParseError> {
+    estimate_delta_in(
+        diff,
+        base.as_ref(),
+        &read_store_config(base.as_ref())?,
+        &PatchOptions::default(),
+    )
+}
+
+pub(crate) fn estimate_delta_in(
+    diff: &DiffCollectionType,
+    base: &Path,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<Option<DeltaEstimate>, FileParseError> {
+    let DiffCollectionType::Modify { old, new, .. } = diff else {
+        return Ok(None);
+    };
+    let (old_file, new_file) = (old.clone(), new.clone());
+    let old = bytes_from(old, base, store)?;
+    let new = bytes_from(new, base, store)?;
+    let mut patch = calculate_binary_diff_bounded(old.clone(), new.clone(), &options.binary_diff);
+    if options.coalesce_gap > 0 {
+        patch = coalesce_binary_diff(patch, &old, &new, options.coalesce_gap);
+    }
+    let replace = BlobPatch::Replace {
+        old_file,
+        new_file: new_file.clone(),
+        patch,
+    };
+    let delta_size = index_size(&replace, options)?;
+    let payload_options = match options.store_compressed && is_compressed(&new) {
+        true => FileOptions::default().compression_method(CompressionMethod::Stored),
+        false => options.entry_options(),
+    };
+    let full_size =
+        index_size(&BlobPatch::Add { new_file }, options)? + entry_size(&new, payload_options)?;
+    let recommended = match delta_size < full_size {
+        true => Transfer::Delta,
+        false => Transfer::Full,
+    };
+    Ok(Some(DeltaEstimate {
+        delta_size,
+        full_size,
+        recommended,
+    }))
+}
+
+/// Bytes `record` takes in the index of a patch written with `options`.
+fn index_size(record: &BlobPatch, options: &PatchOptions) -> Result<u64, FileParseError> {
+    let serialize = |e: bincode::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+    let serialized = match options.index_encoding {
+        IndexEncoding::Absolute => bincode::serialize(record).map_err(serialize)?,
+        IndexEncoding::Delta => {
+            bincode::serialize(&CompactBlobPatch::from(record)).map_err(serialize)?
+        }
+    };
+    #[cfg(feature = "zstd")]
+    if options.zstd_index {
+        return Ok(zstd::stream::encode_all(serialized.as_slice(), 19)?.len() as u64);
+    }
+    Ok(entry_size(&serialized, options.entry_options())?)
+}
+
+/// Compressed size of `content` as a zip entry written with `entry_options`.
+fn entry_size(content: &[u8], entry_options: FileOptions) -> io::Result<u64> {
+    let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+    zip.start_file("entry", entry_options)?;
+    zip.write_all(content)?;
+    let mut archive = ZipArchive::new(zip.finish()?)?;
+    let size = archive.by_index(0)?.compressed_size();
+    Ok(size)
+}
+
+/// Merge ops of a diff of `old` and `new` that are separated by fewer than `gap` unchanged
+/// bytes into a single replace, a few repeated bytes are cheaper than the overhead of an op.
+///
+/// Only an op starting after the end of the one before on both sides is merged, overlapping or
+/// unordered ops are kept as they are.
+pub fn coalesce_binary_diff(
+    patch: Vec<BytesPatch>,
+    old: &Bytes,
+    new: &Bytes,
+    gap: usize,
+) -> Vec<BytesPatch> {
+    let mut result: Vec<BytesPatch> = vec![];
+    for op in patch {
+        let (old_index, old_len, new_index, new_len) = op.ranges();
+        let merged = match result.last() {
+            Some(last) => {
+                let (last_old, last_old_len, last_new, last_new_len) = last.ranges();
+                let after = new_index >= last_new + last_new_len;
+                if after
+                    && old_index
+                        .checked_sub(last_old + last_old_len)
+                        .is_some_and(|unchanged| unchanged < gap)
+                {
+                    Some(BytesPatch::Replace {
+                        old_index: last_old,
+                        new_index: last_new,
+                        old_value: old.slice(last_old..old_index + old_len),
+                        new_value: new.slice(last_new..new_index + new_len),
+                    })
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        match merged {
+            Some(merged) => *result.last_mut().unwrap() = merged,
+            None => result.push(op),
+        }
+    }
+    result
+}
+
+/// Zip entry holding the records of a patch with absolute op positions.
+const PATCH_ENTRY: &str = "ditiear.patch";
+/// Zip entry holding the records of a patch with delta encoded op positions.
+const PATCH_ENTRY_V2: &str = "ditiear.v2.patch";
+
+/// Suffix of a patch entry whose records are compressed as one zstd stream.
+const ZSTD_SUFFIX: &str = ".zst";
+
+/// Zip entry holding the JSON encoded [`PatchMetadata`] of a patch.
+const META_ENTRY: &str = "ditiear.meta";
+
+/// Zip entry holding the JSON encoded [`PatchManifest`] of a patch.
+const MANIFEST_ENTRY: &str = "ditiear.manifest";
+
+/// Header every patch is written with, naming the roots it turns into each other.
+///
+/// Applying a patch with a manifest fails with [`ZipFileError::SourceMismatch`] unless
+/// `old_root` is one of the versions recorded in the store, so a patch is never applied to a
+/// base it was not made for. Holding the blob is not enough, it may only be a subtree of
+/// another version. A store that records no versions, for example one copied without its
+/// `.ditiear` directory, only needs to hold `old_root` as a directory blob.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchManifest {
+    /// `1` for records with absolute op positions, `2` for delta encoded positions.
+    pub format_version: u32,
+    /// Root the patch was made against, `None` when its first record replaces no root.
+    pub old_root: Option<String>,
+    pub new_root: Option<String>,
+    pub hash_algorithm: HashAlgorithm,
+    /// Number of records in the patch.
+    pub entries: usize,
+}
+
+impl PatchManifest {
+    fn new(patchs: &[BlobPatch], options: &PatchOptions, hash_algorithm: HashAlgorithm) -> Self {
+        // the root record comes first
+        let (old_root, new_root) = match patchs.first() {
+            Some(BlobPatch::Replace {
+                old_file, new_file, ..
+            }) => (Some(old_file.clone()), Some(new_file.clone())),
+            _ => (None, None),
+        };
+        PatchManifest {
+            format_version: match options.index_encoding {
+                IndexEncoding::Absolute => 1,
+                IndexEncoding::Delta => 2,
+            },
+            old_root,
+            new_root,
+            hash_algorithm,
+            entries: patchs.len(),
+        }
+    }
+}
+
+/// Human readable description of a patch, it plays no part in applying it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchMetadata {
+    pub source_version: Option<String>,
+    pub target_version: Option<String>,
+    /// Creation time in seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    pub notes: Option<String>,
+}
+
+/// How the positions of byte patch ops are stored in a patch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexEncoding {
+    /// Absolute positions, readable by every version.
+    #[default]
+    Absolute,
+    /// Varint positions relative to the previous op, smaller for files with many ops.
+    ///
+    /// These patches use a second format version that older appliers do not read.
+    Delta,
+}
+
+/// A `BlobPatch` record of the second format version, ops are encoded by [`encode_ops`].
+#[derive(Serialize, Deserialize)]
+enum CompactBlobPatch {
     Add {
-        old_index: usize,
-        new_index: usize,
-        #[serde(
-            serialize_with = "serialize_bytes",
-            deserialize_with = "deserialize_bytes"
-        )]
-        new_value: Bytes,
+        new_file: String,
     },
     Delete {
-        old_index: usize,
-        new_index: usize,
-        #[serde(
-            serialize_with = "serialize_bytes",
-            deserialize_with = "deserialize_bytes"
-        )]
-        old_value: Bytes,
+        old_file: String,
     },
     Replace {
-        old_index: usize,
-        new_index: usize,
-        #[serde(
-            serialize_with = "serialize_bytes",
-            deserialize_with = "deserialize_bytes"
-        )]
-        old_value: Bytes,
-        #[serde(
-            serialize_with = "serialize_bytes",
-            deserialize_with = "deserialize_bytes"
-        )]
-        new_value: Bytes,
+        old_file: String,
+        new_file: String,
+        #[serde(with = "serde_bytes")]
+        patch: Vec<u8>,
     },
 }
 
-#[allow(unreachable_code)]
-pub fn calculate_binary_diff(old: Bytes, new: Bytes) -> Vec<BytesPatch> {
-    let ops = capture_diff_slices(Algorithm::Myers, old.as_ref(), new.as_ref());
-    ops.iter()
-        .filter(|op| match op {
-            DiffOp::Equal { .. } => false,
-            _ => true,
-        })
-        .map(|op| match op {
-            DiffOp::Delete {
-                old_index,
-                old_len,
-                new_index,
-            } => BytesPatch::Delete {
-                old_index: *old_index,
-                new_index: *new_index,
-                old_value: old.slice(*old_index..*old_index + *old_len),
+impl From<&BlobPatch> for CompactBlobPatch {
+    fn from(patch: &BlobPatch) -> Self {
+        match patch {
+            BlobPatch::Add { new_file } => CompactBlobPatch::Add {
+                new_file: new_file.clone(),
             },
-            DiffOp::Insert {
-                old_index,
-                new_index,
-                new_len,
-            } => BytesPatch::Add {
-                old_index: *old_index,
-                new_index: *new_index,
-                new_value: new.slice(*new_index..*new_index + *new_len),
+            BlobPatch::Delete { old_file } => CompactBlobPatch::Delete {
+                old_file: old_file.clone(),
             },
-            DiffOp::Replace {
-                old_index,
-                old_len,
-                new_index,
-                new_len,
-            } => BytesPatch::Replace {
-                old_index: *old_index,
-                new_index: *new_index,
-                old_value: old.slice(*old_index..*old_index + *old_len),
-                new_value: new.slice(*new_index..*new_index + *new_len),
+            BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            } => CompactBlobPatch::Replace {
+                old_file: old_file.clone(),
+                new_file: new_file.clone(),
+                patch: encode_ops(patch),
+            },
+        }
+    }
+}
+
+impl TryFrom<CompactBlobPatch> for BlobPatch {
+    type Error = io::Error;
+
+    fn try_from(patch: CompactBlobPatch) -> Result<Self, Self::Error> {
+        Ok(match patch {
+            CompactBlobPatch::Add { new_file } => BlobPatch::Add { new_file },
+            CompactBlobPatch::Delete { old_file } => BlobPatch::Delete { old_file },
+            CompactBlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            } => BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch: decode_ops(&patch)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
             },
-            _ => !unreachable!(),
         })
-        .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -124,10 +558,70 @@ pub enum BlobPatch {
 }
 
 impl BlobPatch {
-    fn from<T, P>(diffs: T, base_path: P) -> Result<Vec<BlobPatch>, FileParseError>
+    /// Every blob hash the record names.
+    fn hashes(&self) -> Vec<&str> {
+        match self {
+            BlobPatch::Add { new_file } => vec![new_file],
+            BlobPatch::Delete { old_file } => vec![old_file],
+            BlobPatch::Replace {
+                old_file, new_file, ..
+            } => vec![old_file, new_file],
+        }
+    }
+
+    fn from<T, P>(
+        diffs: T,
+        base_path: P,
+        store: &StoreConfig,
+        options: &PatchOptions,
+    ) -> Result<Vec<BlobPatch>, FileParseError>
+    where
+        T: IntoIterator<Item = DiffCollectionType>,
+        P: AsRef<Path>,
+    {
+        Self::from_with_progress(diffs, base_path, store, options, None, &mut vec![])
+    }
+
+    /// Like [`BlobPatch::from`], diffing modified files window by window and reporting the
+    /// progress of each to `progress` with the hash of the new blob.
+    fn from_with_progress<T, P>(
+        diffs: T,
+        base_path: P,
+        store: &StoreConfig,
+        options: &PatchOptions,
+        progress: Option<&DiffProgress>,
+        warnings: &mut Vec<PatchWarning>,
+    ) -> Result<Vec<BlobPatch>, FileParseError>
     where
         T: IntoIterator<Item = DiffCollectionType>,
         P: AsRef<Path>,
+    {
+        let read = |hash: &str| {
+            #[cfg(feature = "mmap")]
+            if options.memory_map {
+                return mapped_bytes_from(hash, base_path.as_ref(), store);
+            }
+            bytes_from(hash, base_path.as_ref(), store)
+        };
+        let size = |hash: &str| store.blob_size(hash, base_path.as_ref());
+        Self::from_source(diffs, &read, Some(&size), options, progress, warnings)
+    }
+
+    /// Like [`BlobPatch::from_with_progress`], taking the content of modified blobs from
+    /// `read`.
+    ///
+    /// A modified file whose blobs `size` reports to be larger together than
+    /// [`BinaryDiffOptions::max_in_memory`] is not read, its new blob is added whole.
+    fn from_source<T>(
+        diffs: T,
+        read: &BlobSource,
+        size: Option<&BlobSize>,
+        options: &PatchOptions,
+        progress: Option<&DiffProgress>,
+        warnings: &mut Vec<PatchWarning>,
+    ) -> Result<Vec<BlobPatch>, FileParseError>
+    where
+        T: IntoIterator<Item = DiffCollectionType>,
     {
         let mut result = vec![];
         for diff in diffs {
@@ -138,10 +632,64 @@ impl BlobPatch {
                 DiffCollectionType::Delete { value, .. } => {
                     result.push(BlobPatch::Delete { old_file: value })
                 }
-                DiffCollectionType::Modify { old, new, .. } => {
-                    let old_buffer = bytes_from(&old, base_path.as_ref())?;
-                    let new_buffer = bytes_from(&new, base_path.as_ref())?;
-                    let patch = calculate_binary_diff(old_buffer, new_buffer);
+                // moved directories and renamed files keep their hash, so the store already
+                // has every blob
+                DiffCollectionType::MoveDirectory { .. } | DiffCollectionType::Rename { .. } => {}
+                DiffCollectionType::Modify { r#type, old, new } => {
+                    // the root directory blob must stay a replace, it comes first
+                    if let (DiffFileType::File | DiffFileType::Symlink, Some(size)) = (r#type, size)
+                    {
+                        let total = size(&old)?.saturating_add(size(&new)?);
+                        if total > options.binary_diff.max_in_memory as u64 {
+                            result.push(BlobPatch::Add { new_file: new });
+                            continue;
+                        }
+                    }
+                    let (old_buffer, new_buffer) =
+                        match read(&old).and_then(|o| Ok((o, read(&new)?))) {
+                            Ok(buffers) => buffers,
+                            Err(error)
+                                if options.on_read_error == ReadErrorPolicy::Fail
+                                    || r#type == DiffFileType::Directory =>
+                            {
+                                return Err(error)
+                            }
+                            Err(error) => {
+                                let add = options.on_read_error == ReadErrorPolicy::AddNew
+                                    && read(&new).is_ok();
+                                warnings.push(PatchWarning {
+                                    old_file: old,
+                                    new_file: new.clone(),
+                                    error: error.to_string(),
+                                    added: add,
+                                });
+                                if add {
+                                    result.push(BlobPatch::Add { new_file: new });
+                                }
+                                continue;
+                            }
+                        };
+                    let mut patch = match progress {
+                        Some(progress) => calculate_binary_diff_with_progress(
+                            old_buffer.clone(),
+                            new_buffer.clone(),
+                            PROGRESS_WINDOW,
+                            |done, total| progress(&new, done, total),
+                        ),
+                        None => calculate_binary_diff_bounded(
+                            old_buffer.clone(),
+                            new_buffer.clone(),
+                            &options.binary_diff,
+                        ),
+                    };
+                    if options.coalesce_gap > 0 {
+                        patch = coalesce_binary_diff(
+                            patch,
+                            &old_buffer,
+                            &new_buffer,
+                            options.coalesce_gap,
+                        );
+                    }
                     result.push(BlobPatch::Replace {
                         old_file: old,
                         new_file: new,
@@ -154,12 +702,58 @@ impl BlobPatch {
     }
 }
 
-fn bytes_from<P: AsRef<Path>>(hash: &str, base_path: P) -> Result<Bytes, FileParseError> {
-    let old_path = path_from_hash(hash, base_path.as_ref());
-    let mut old_file = fs::File::open(old_path)?;
-    let mut old_buffer = Vec::new();
-    old_file.read_to_end(&mut old_buffer)?;
-    Ok(Bytes::from(old_buffer))
+/// Invert records read with [`unpack_patch`], so applying them undoes the patch.
+///
+/// Adds and deletes swap and every replace turns its new blob back into its old one. The
+/// inverse carries no payloads, the blobs it adds are the ones the patch deleted and must
+/// still be in the store. The order is kept, so the root replace stays first.
+pub fn invert_patch(patchs: Vec<BlobPatch>) -> Vec<BlobPatch> {
+    patchs
+        .into_iter()
+        .map(|patch| match patch {
+            BlobPatch::Add { new_file } => BlobPatch::Delete { old_file: new_file },
+            BlobPatch::Delete { old_file } => BlobPatch::Add { new_file: old_file },
+            BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            } => BlobPatch::Replace {
+                old_file: new_file,
+                new_file: old_file,
+                patch: patch.into_iter().map(BytesPatch::invert).collect(),
+            },
+        })
+        .collect()
+}
+
+fn bytes_from<P: AsRef<Path>>(
+    hash: &str,
+    base_path: P,
+    store: &StoreConfig,
+) -> Result<Bytes, FileParseError> {
+    Ok(Bytes::from(read_blob(store, hash, base_path.as_ref())?))
+}
+
+/// Map a blob into memory, the pages are only loaded as the diff reaches them.
+#[cfg(feature = "mmap")]
+fn mapped_bytes_from(
+    hash: &str,
+    base_path: &Path,
+    store: &StoreConfig,
+) -> Result<Bytes, FileParseError> {
+    let file =
+        fs::File::open(store.path_from_hash(hash, base_path)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => FileParseError::MissingBaseBlob(hash.to_string()),
+            _ => FileParseError::Io(e),
+        })?;
+    // an empty file cannot be mapped on every platform
+    if file.metadata()?.len() == 0 {
+        return Ok(Bytes::new());
+    }
+    // SAFETY: blobs are content addressed and never written in place, apply only ever creates
+    // new blobs, so the mapped file does not change while it is mapped
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(Bytes::from_owner(map))
 }
 
 #[derive(Error, Debug)]
@@ -172,6 +766,32 @@ pub enum ZipFileError {
     Serialize(#[from] bincode::Error),
     #[error("Zip error")]
     Zip(#[from] zip::result::ZipError),
+    #[error("Store not found: {0}")]
+    StoreNotFound(PathBuf),
+    #[error("Blob {0} is missing from the store")]
+    MissingBaseBlob(String),
+    #[error("Manifest does not match the store: {0}")]
+    ManifestMismatch(String),
+    #[error("Patch entry {0} exceeds the decompression limit")]
+    DecompressionLimitExceeded(String),
+    #[error("Invalid patch metadata")]
+    Metadata(#[from] serde_json::Error),
+    #[error("Patch names malformed blob hash {0:?}")]
+    InvalidHash(String),
+    #[error("Patch entry {0} needs a feature this build lacks")]
+    UnsupportedEntry(String),
+    #[error("Subtree {0} is not a directory of the base root or overlaps another subtree")]
+    InvalidSubtree(String),
+    #[error("Operation was cancelled")]
+    Cancelled,
+    #[error("Patch entry {0:?} points outside the store")]
+    PathTraversal(String),
+    #[error("Patch entry {0:?} is named neither as a patch entry nor as a blob hash")]
+    InvalidEntryName(String),
+    #[error("Patch was made against root {0} which is no version of the store")]
+    SourceMismatch(String),
+    #[error("Rebuilt blob hashes to {actual} instead of {expected}")]
+    HashMismatch { expected: String, actual: String },
 }
 
 impl From<FileParseError> for ZipFileError {
@@ -179,176 +799,1210 @@ impl From<FileParseError> for ZipFileError {
         match e {
             FileParseError::Io(e) => ZipFileError::Io(e),
             FileParseError::Parse(e) => ZipFileError::Parse(e),
+            FileParseError::StoreNotFound(p) => ZipFileError::StoreNotFound(p),
+            FileParseError::MissingBaseBlob(h) => ZipFileError::MissingBaseBlob(h),
+            FileParseError::ManifestMismatch(m) => ZipFileError::ManifestMismatch(m),
+            FileParseError::Cancelled => ZipFileError::Cancelled,
+            FileParseError::PathTraversal(n) => ZipFileError::PathTraversal(n),
+        }
+    }
+}
+
+/// Options used while writing a patch.
+#[derive(Clone, Debug)]
+pub struct PatchOptions {
+    /// Compression of every entry in the patch zip.
+    pub compression: CompressionMethod,
+    /// Level of `compression`, `None` uses the default level of the method.
+    pub compression_level: Option<i32>,
+    /// Store added blobs uncompressed whose content already is compressed, like JPEG, PNG or
+    /// zip files, instead of spending time on compressing them again.
+    pub store_compressed: bool,
+    /// Merge byte patches separated by fewer unchanged bytes than this into one replace,
+    /// `0` keeps every op as it is.
+    pub coalesce_gap: usize,
+    /// Syncing of the written patch file.
+    pub durability: Durability,
+    /// Encoding of byte patch positions.
+    pub index_encoding: IndexEncoding,
+    /// Memory map both sides of a modified file instead of reading them onto the heap.
+    #[cfg(feature = "mmap")]
+    pub memory_map: bool,
+    /// Metadata written into the patch.
+    pub metadata: Option<PatchMetadata>,
+    /// Leave out the payload of an added blob that the patch also deletes.
+    ///
+    /// Content moved to a path rename detection does not pair up is encoded as a delete and
+    /// an add of the same hash. The base already holds that blob, so the add only needs to
+    /// reference it.
+    pub reference_deleted_adds: bool,
+    /// What to do when a blob of a modified file or symbolic link cannot be read.
+    pub on_read_error: ReadErrorPolicy,
+    /// Memory limits of diffing modified files.
+    pub binary_diff: BinaryDiffOptions,
+    /// Compress the records as one zstd stream instead of with `compression`.
+    ///
+    /// Records repeat a lot of structure between each other, which a whole stream compresses
+    /// better than the zip entry compression does. Pays off for patches with many records.
+    #[cfg(feature = "zstd")]
+    pub zstd_index: bool,
+}
+
+impl Default for PatchOptions {
+    fn default() -> Self {
+        PatchOptions {
+            compression: CompressionMethod::Deflated,
+            compression_level: None,
+            store_compressed: false,
+            coalesce_gap: 0,
+            durability: Durability::None,
+            index_encoding: IndexEncoding::Absolute,
+            #[cfg(feature = "mmap")]
+            memory_map: false,
+            metadata: None,
+            reference_deleted_adds: false,
+            on_read_error: ReadErrorPolicy::Fail,
+            binary_diff: BinaryDiffOptions::default(),
+            #[cfg(feature = "zstd")]
+            zstd_index: false,
         }
     }
 }
 
+impl PatchOptions {
+    fn entry_options(&self) -> FileOptions {
+        FileOptions::default()
+            .compression_method(self.compression)
+            .compression_level(self.compression_level)
+    }
+}
+
+/// Leading bytes of formats whose content is already compressed.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\xff\xd8\xff",
+    b"\x89PNG",
+    b"GIF8",
+    b"PK\x03\x04",
+    b"\x1f\x8b",
+    b"\x28\xb5\x2f\xfd",
+    b"\xfd7zXZ\x00",
+    b"BZh",
+    b"7z\xbc\xaf\x27\x1c",
+    b"OggS",
+    b"fLaC",
+    b"ID3",
+];
+
+/// Bytes at the start of a blob that [`is_compressed`] looks at.
+const MAGIC_PROBE: u64 = 12;
+
+/// Whether `content` starts like a compressed image, archive or media file.
+fn is_compressed(content: &[u8]) -> bool {
+    let riff = content.starts_with(b"RIFF") && content.get(8..12) == Some(b"WEBP");
+    let iso_media = content.get(4..8) == Some(b"ftyp");
+    riff || iso_media
+        || COMPRESSED_MAGIC
+            .iter()
+            .any(|magic| content.starts_with(magic))
+}
+
+/// Caps on the decompressed size of a patch, so a hostile patch cannot exhaust memory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnpackLimits {
+    /// Largest decompressed size of a single entry.
+    pub max_entry_size: Option<u64>,
+    /// Largest decompressed size of all entries together.
+    pub max_total_size: Option<u64>,
+}
+
+/// Handling of a modified file or symbolic link whose old or new blob cannot be read, see
+/// [`PatchOptions::on_read_error`].
+///
+/// A modified directory blob that cannot be read always fails the patch, leaving it out would
+/// leave the directory blobs above it pointing at a tree the patch does not build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadErrorPolicy {
+    /// Fail the whole patch.
+    #[default]
+    Fail,
+    /// Leave the entry out of the patch.
+    Skip,
+    /// Add the new blob whole when it is readable, leave the entry out otherwise.
+    AddNew,
+}
+
+/// Handling of a deleted entry whose blob is already gone from the store, see
+/// [`ApplyOptions::on_missing_delete`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingDeletePolicy {
+    /// Treat the blob as deleted, so applying a patch again succeeds.
+    #[default]
+    Ignore,
+    /// Fail with [`ZipFileError::MissingBaseBlob`] before anything is deleted.
+    Error,
+}
+
+/// A modified entry that did not get a replace record because a blob could not be read.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PatchWarning {
+    pub old_file: String,
+    pub new_file: String,
+    /// Why reading failed.
+    pub error: String,
+    /// Whether the new blob was added whole instead.
+    pub added: bool,
+}
+
+/// Options used while applying a patch.
+#[derive(Clone, Debug, Default)]
+pub struct ApplyOptions {
+    /// Remove the blobs of deleted entries from the store.
+    ///
+    /// Only enable this for stores that hold a single version, blobs may be shared between
+    /// versions.
+    pub delete_removed: bool,
+    /// Syncing of the blobs written to the store.
+    pub durability: Durability,
+    /// Limits enforced while extracting the patch.
+    pub limits: UnpackLimits,
+    /// Stops applying before the next record once set, the store is left as it was.
+    pub cancellation: CancellationToken,
+    /// Handling of deleted entries whose blob the store lacks, only consulted together with
+    /// `delete_removed`.
+    pub on_missing_delete: MissingDeletePolicy,
+    /// Hash every blob rebuilt from a replace before it is published and fail with
+    /// [`ZipFileError::HashMismatch`] when it differs from the hash the patch names.
+    pub verify_hashes: bool,
+    /// Apply without checking the old root of the patch manifest against the store, see
+    /// [`PatchManifest`].
+    pub skip_source_check: bool,
+}
+
 pub fn create_zip_patch<T, P>(diffs: T, from_dir: P, to_dest: P) -> Result<(), ZipFileError>
 where
     T: IntoIterator<Item = DiffCollectionType>,
     P: AsRef<Path>,
 {
-    let patchs = BlobPatch::from(diffs, from_dir.as_ref())?;
-    if patchs.is_empty() {
-        return Ok(());
-    }
-    let zip_file = fs::File::create(to_dest)?;
-    let mut zip = ZipWriter::new(zip_file);
-    zip.start_file(
-        "ditiear.patch",
-        FileOptions::default().compression_method(CompressionMethod::Deflated),
-    )?;
-    let mut add_patchs = vec![];
-    for p in patchs {
-        let serialized = bincode::serialize(&p)?;
-        zip.write_all(&serialized)?;
-        match p {
-            BlobPatch::Add { .. } => {
-                add_patchs.push(p);
-            }
-            _ => {}
-        }
-    }
-    for p in add_patchs {
-        match p {
-            BlobPatch::Add { new_file } => {
-                let bytes = bytes_from(&new_file, from_dir.as_ref())?;
-                zip.start_file(
-                    new_file,
-                    FileOptions::default().compression_method(CompressionMethod::Deflated),
-                )?;
-                zip.write_all(&bytes)?;
-            }
-            _ => {}
-        }
-    }
-    zip.finish()?;
-    Ok(())
+    let store = read_store_config(from_dir.as_ref())?;
+    create_zip_patch_in(diffs, from_dir, to_dest, &store, &PatchOptions::default())
 }
 
-pub fn unpack_patch<P: AsRef<Path>, F>(
-    patch_path: P,
-    process_file: F,
-) -> Result<Vec<BlobPatch>, ZipFileError>
+/// Size of a patch written by [`create_zip_patch_with_stats`].
+///
+/// Files count the entries that are no directories, literal bytes are the content the patch
+/// carries rather than references into the old blobs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PatchStats {
+    pub added_files: usize,
+    pub deleted_files: usize,
+    pub modified_files: usize,
+    /// Bytes of every added blob, directory blobs included, and the new bytes of every byte
+    /// patch.
+    pub literal_bytes: u64,
+    /// Size of the written patch file, `0` when there were no changes and nothing was written.
+    pub patch_file_size: u64,
+}
+
+/// Like [`create_zip_patch`], returning how large the patch is.
+pub fn create_zip_patch_with_stats<T, P>(
+    diffs: T,
+    from_dir: P,
+    to_dest: P,
+) -> Result<PatchStats, ZipFileError>
 where
-    F: Fn(Vec<u8>, &str) -> Result<(), io::Error>,
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
 {
-    let zip_file = fs::File::open(patch_path)?;
-    let mut archive = ZipArchive::new(zip_file)?;
-
-    let mut patchs = vec![];
-    for i in 0..archive.len() {
-        let mut file: zip::read::ZipFile<'_> = archive.by_index(i)?;
-        if file.name() != "ditiear.patch" {
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
-            process_file(buf, file.name())?;
-            continue;
-        }
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let patch: BlobPatch = bincode::deserialize(&buffer)?;
-        patchs.push(patch);
-    }
-    Ok(patchs)
+    let store = read_store_config(from_dir.as_ref())?;
+    create_zip_patch_with_stats_in(diffs, from_dir, to_dest, &store, &PatchOptions::default())
 }
 
-pub fn apply_patchs<P: AsRef<Path>>(patch_path: P, base_path: P) -> Result<(), ZipFileError> {
-    let patchs = unpack_patch(patch_path, |buffer, name| {
-        let path = path_from_hash(name, base_path.as_ref());
-        let mut file = fs::File::create(path)?;
-        file.write_all(&buffer)
-    })?;
-    for patch in patchs {
-        match patch {
-            BlobPatch::Add { new_file } => {
-                // TODO: Check if file exists
+pub(crate) fn create_zip_patch_with_stats_in<T, P, Q>(
+    diffs: T,
+    from_dir: P,
+    to_dest: Q,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<PatchStats, ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let diffs: Vec<_> = diffs.into_iter().collect();
+    let mut stats = PatchStats::default();
+    for diff in &diffs {
+        match diff {
+            DiffCollectionType::Add { r#type, .. } if *r#type != DiffFileType::Directory => {
+                stats.added_files += 1
             }
-            BlobPatch::Delete { old_file } => {
-                // TODO: Check whether file need to be deleted according to settings
-                // let path = path_from_hash(&old_file, base_path.as_ref());
-                // fs::remove_file(path)?;
+            DiffCollectionType::Delete { r#type, .. } if *r#type != DiffFileType::Directory => {
+                stats.deleted_files += 1
             }
-            BlobPatch::Replace {
-                old_file,
-                new_file,
-                patch,
-            } => {
-                let mut replacements = patch
-                    .into_iter()
-                    .map(|item| match item {
-                        BytesPatch::Add {
-                            old_index,
-                            new_value,
-                            ..
-                        } => Replacement {
-                            start: old_index,
-                            length: 0,
-                            content: new_value,
-                        },
-                        BytesPatch::Delete {
-                            old_index,
-                            old_value,
-                            ..
-                        } => Replacement {
-                            start: old_index,
-                            length: old_value.len(),
-                            content: Bytes::new(),
-                        },
-                        BytesPatch::Replace {
-                            old_index,
-                            old_value,
-                            new_value,
-                            ..
-                        } => Replacement {
-                            start: old_index,
-                            length: old_value.len(),
-                            content: new_value,
-                        },
-                    })
-                    .collect::<Vec<_>>();
-                replacements.sort_by(|a, b| a.start.cmp(&b.start));
-                let old_path = path_from_hash(&old_file, base_path.as_ref());
-                let old_bak_path =
-                    path_from_hash(&format!("{}.bak", &old_file), base_path.as_ref());
-                replace_parts_file(old_path.as_path(), old_bak_path.as_path(), &replacements)?;
-                let new_path = path_from_hash(&new_file, base_path.as_ref());
-                fs::rename(old_bak_path.as_path(), new_path.as_path())?;
+            DiffCollectionType::Modify { r#type, .. } if *r#type != DiffFileType::Directory => {
+                stats.modified_files += 1
             }
+            _ => {}
         }
     }
-    Ok(())
+    let patchs = BlobPatch::from(diffs, from_dir.as_ref(), store, options)?;
+    for patch in &patchs {
+        stats.literal_bytes += match patch {
+            BlobPatch::Add { new_file } => store.blob_size(new_file, from_dir.as_ref())?,
+            BlobPatch::Delete { .. } => 0,
+            BlobPatch::Replace { patch, .. } => patch
+                .iter()
+                .map(|op| match op {
+                    BytesPatch::Add { new_value, .. } | BytesPatch::Replace { new_value, .. } => {
+                        new_value.len() as u64
+                    }
+                    BytesPatch::Delete { .. } => 0,
+                })
+                .sum(),
+        };
+    }
+    if patchs.is_empty() {
+        return Ok(stats);
+    }
+    let zip_file = fs::File::create(to_dest.as_ref())?;
+    write_patchs(patchs, from_dir, zip_file, store, options)?;
+    stats.patch_file_size = fs::metadata(to_dest.as_ref())?.len();
+    Ok(stats)
 }
 
-struct Replacement {
-    start: usize,
-    length: usize,
-    content: Bytes,
+/// Like [`create_zip_patch`], calling `progress` while diffing every modified file with the
+/// hash of its new blob, the bytes of both versions processed so far and their total.
+pub fn create_zip_patch_with_progress<T, P, F>(
+    diffs: T,
+    from_dir: P,
+    to_dest: P,
+    progress: F,
+) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    F: Fn(&str, usize, usize),
+{
+    let store = read_store_config(from_dir.as_ref())?;
+    create_zip_patch_reporting(
+        diffs,
+        from_dir,
+        to_dest,
+        &store,
+        &PatchOptions::default(),
+        Some(&progress),
+    )
+    .map(|_| ())
 }
 
-fn copy_with_buffer<R: Read, W: Write>(
-    reader: &mut R,
-    writer: &mut W,
-    mut bytes: usize,
-) -> io::Result<()> {
-    let mut buffer = vec![0; 1024.min(bytes)];
-    while bytes > 0 {
-        let len = reader.read(&mut buffer)?;
-        writer.write_all(&buffer[..len])?;
-        bytes = bytes.saturating_sub(len);
-    }
-    Ok(())
+/// Like [`create_zip_patch`], configured by `options`.
+///
+/// Returns the modified entries left out or added whole according to
+/// [`PatchOptions::on_read_error`].
+pub fn create_zip_patch_with_options<T, P>(
+    diffs: T,
+    from_dir: P,
+    to_dest: P,
+    options: &PatchOptions,
+) -> Result<Vec<PatchWarning>, ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+{
+    let store = read_store_config(from_dir.as_ref())?;
+    create_zip_patch_reporting(diffs, from_dir, to_dest, &store, options, None)
 }
 
-fn replace_parts_file<P: AsRef<Path>, Q: AsRef<Path>>(
-    original_file: P,
-    dest_file: Q,
-    replacements: &[Replacement],
-) -> io::Result<()> {
-    let original = OpenOptions::new().read(true).open(original_file.as_ref())?;
+pub(crate) fn create_zip_patch_in<T, P, Q>(
+    diffs: T,
+    from_dir: P,
+    to_dest: Q,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    create_zip_patch_reporting(diffs, from_dir, to_dest, store, options, None).map(|_| ())
+}
+
+/// Callback of [`create_zip_patch_with_progress`].
+type DiffProgress<'a> = dyn Fn(&str, usize, usize) + 'a;
+
+/// Reader of blob content by hash, see [`create_zip_patch_with_provider`].
+type BlobSource<'a> = dyn Fn(&str) -> Result<Bytes, FileParseError> + 'a;
+
+/// Size of a blob by hash, without reading it.
+type BlobSize<'a> = dyn Fn(&str) -> Result<u64, FileParseError> + 'a;
+
+/// Stream of the payload of an added blob, see [`write_patch_with_payloads`].
+pub(crate) type PayloadSource<'a> = dyn Fn(&str) -> Result<Box<dyn Read + 'a>, ZipFileError> + 'a;
+
+/// Like [`create_zip_patch`], taking the content of every modified and added blob from
+/// `provider` instead of a local store.
+///
+/// This builds a patch on a machine that can fetch blobs, e.g. from a server, without holding
+/// them. A provider failing with [`io::ErrorKind::NotFound`] reports the blob as missing.
+pub fn create_zip_patch_with_provider<T, P, F>(
+    diffs: T,
+    to_dest: P,
+    provider: F,
+) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    F: Fn(&str) -> io::Result<Bytes>,
+{
+    let read = |hash: &str| {
+        provider(hash).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => FileParseError::MissingBaseBlob(hash.to_string()),
+            _ => FileParseError::Io(e),
+        })
+    };
+    let options = PatchOptions::default();
+    let patchs = BlobPatch::from_source(diffs, &read, None, &options, None, &mut vec![])?;
+    if patchs.is_empty() {
+        return Ok(());
+    }
+    let zip_file = fs::File::create(to_dest.as_ref())?;
+    write_patch_with_payloads(
+        patchs,
+        zip_file,
+        &options,
+        HashAlgorithm::default(),
+        &|hash| Ok(Box::new(io::Cursor::new(read(hash)?))),
+    )
+}
+
+fn create_zip_patch_reporting<T, P, Q>(
+    diffs: T,
+    from_dir: P,
+    to_dest: Q,
+    store: &StoreConfig,
+    options: &PatchOptions,
+    progress: Option<&DiffProgress>,
+) -> Result<Vec<PatchWarning>, ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut warnings = vec![];
+    let patchs = BlobPatch::from_with_progress(
+        diffs,
+        from_dir.as_ref(),
+        store,
+        options,
+        progress,
+        &mut warnings,
+    )?;
+    if patchs.is_empty() {
+        return Ok(warnings);
+    }
+    let zip_file = fs::File::create(to_dest.as_ref())?;
+    write_patchs(patchs, from_dir, zip_file, store, options)?;
+    options.durability.commit(to_dest.as_ref())?;
+    Ok(warnings)
+}
+
+/// Write a patch into any seekable writer, e.g. a `Cursor<Vec<u8>>` or a response body.
+///
+/// Unlike [`create_zip_patch`] an empty diff still writes an empty archive.
+pub fn create_zip_patch_to_writer<T, P, W>(
+    diffs: T,
+    from_dir: P,
+    writer: W,
+) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    W: Write + Seek,
+{
+    let store = read_store_config(from_dir.as_ref())?;
+    let options = PatchOptions::default();
+    let patchs = BlobPatch::from(diffs, from_dir.as_ref(), &store, &options)?;
+    write_patchs(patchs, from_dir, writer, &store, &options)
+}
+
+/// Same as [`create_zip_patch_to_writer`].
+#[deprecated(note = "use `create_zip_patch_to_writer`, which takes the same arguments")]
+pub fn write_zip_patch_to<T, P, W>(diffs: T, from_dir: P, sink: W) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    W: Write + Seek,
+{
+    create_zip_patch_to_writer(diffs, from_dir, sink)
+}
+
+fn write_patchs<P: AsRef<Path>, W: Write + Seek>(
+    patchs: Vec<BlobPatch>,
+    from_dir: P,
+    sink: W,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<(), ZipFileError> {
+    // streamed, an added blob is never read onto the heap as a whole
+    let payload = |hash: &str| -> Result<Box<dyn Read>, ZipFileError> {
+        match fs::File::open(store.path_from_hash(hash, from_dir.as_ref())?) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(ZipFileError::MissingBaseBlob(hash.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    };
+    write_patch_with_payloads(patchs, sink, options, store.hash_algorithm, &payload)
+}
+
+/// Write a patch, taking the content of every added blob from `payload`.
+pub(crate) fn write_patch_with_payloads<W: Write + Seek>(
+    patchs: Vec<BlobPatch>,
+    sink: W,
+    options: &PatchOptions,
+    hash_algorithm: HashAlgorithm,
+    payload: &PayloadSource,
+) -> Result<(), ZipFileError> {
+    timed(Phase::Writing, || {
+        write_entries(patchs, sink, options, hash_algorithm, payload)
+    })
+}
+
+fn write_entries<W: Write + Seek>(
+    patchs: Vec<BlobPatch>,
+    sink: W,
+    options: &PatchOptions,
+    hash_algorithm: HashAlgorithm,
+    payload: &PayloadSource,
+) -> Result<(), ZipFileError> {
+    let mut zip = ZipWriter::new(sink);
+    let manifest = PatchManifest::new(&patchs, options, hash_algorithm);
+    let entry = match options.index_encoding {
+        IndexEncoding::Absolute => PATCH_ENTRY,
+        IndexEncoding::Delta => PATCH_ENTRY_V2,
+    };
+    let deleted: HashSet<String> = match options.reference_deleted_adds {
+        true => patchs
+            .iter()
+            .filter_map(|p| match p {
+                BlobPatch::Delete { old_file } => Some(old_file.clone()),
+                _ => None,
+            })
+            .collect(),
+        false => HashSet::new(),
+    };
+    let mut index = vec![];
+    let mut add_patchs = vec![];
+    for p in patchs {
+        let serialized = match options.index_encoding {
+            IndexEncoding::Absolute => bincode::serialize(&p)?,
+            IndexEncoding::Delta => bincode::serialize(&CompactBlobPatch::from(&p))?,
+        };
+        index.extend(serialized);
+        if let BlobPatch::Add { new_file } = &p {
+            if !deleted.contains(new_file) {
+                add_patchs.push(p);
+            }
+        }
+    }
+    #[cfg(feature = "zstd")]
+    if options.zstd_index {
+        zip.start_file(
+            format!("{}{}", entry, ZSTD_SUFFIX),
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        index = zstd::stream::encode_all(index.as_slice(), 19)?;
+    } else {
+        zip.start_file(entry, options.entry_options())?;
+    }
+    #[cfg(not(feature = "zstd"))]
+    zip.start_file(entry, options.entry_options())?;
+    zip.write_all(&index)?;
+    zip.start_file(MANIFEST_ENTRY, options.entry_options())?;
+    serde_json::to_writer(&mut zip, &manifest)?;
+    if let Some(metadata) = &options.metadata {
+        zip.start_file(META_ENTRY, options.entry_options())?;
+        serde_json::to_writer(&mut zip, metadata)?;
+    }
+    for p in add_patchs {
+        if let BlobPatch::Add { new_file } = p {
+            let mut reader = payload(&new_file)?;
+            // enough of the start to recognize compressed content
+            let mut head = vec![];
+            (&mut reader).take(MAGIC_PROBE).read_to_end(&mut head)?;
+            let entry_options = match options.store_compressed && is_compressed(&head) {
+                true => FileOptions::default().compression_method(CompressionMethod::Stored),
+                false => options.entry_options(),
+            };
+            zip.start_file(new_file, entry_options)?;
+            zip.write_all(&head)?;
+            io::copy(&mut reader, &mut zip)?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Read the records of a patch, handing the content of every embedded blob to `process_file`
+/// together with its hash.
+///
+/// Entries with a separator, `..`, a NUL or an absolute prefix in their name fail the unpack with
+/// [`ZipFileError::PathTraversal`], other entries not named as a blob hash this crate writes
+/// with [`ZipFileError::InvalidEntryName`]. Either happens before `process_file` sees them, so
+/// the names it gets can be joined onto a store path safely.
+pub fn unpack_patch<P: AsRef<Path>, F>(
+    patch_path: P,
+    process_file: F,
+) -> Result<Vec<BlobPatch>, ZipFileError>
+where
+    F: Fn(Vec<u8>, &str) -> Result<(), io::Error>,
+{
+    unpack_patch_from_reader(fs::File::open(patch_path)?, process_file)
+}
+
+/// Like [`unpack_patch`], reading the patch from `reader`, e.g. a `Cursor` over a patch held
+/// in memory.
+pub fn unpack_patch_from_reader<R: Read + Seek, F>(
+    reader: R,
+    process_file: F,
+) -> Result<Vec<BlobPatch>, ZipFileError>
+where
+    F: Fn(Vec<u8>, &str) -> Result<(), io::Error>,
+{
+    unpack_patch_from(reader, UnpackLimits::default(), process_file)
+}
+
+/// Like [`unpack_patch`], failing with `DecompressionLimitExceeded` as soon as an entry
+/// decompresses to more than `limits` allow.
+///
+/// Entries are never decompressed past the limit, whatever size the archive claims.
+pub fn unpack_patch_with_limits<P: AsRef<Path>, F>(
+    patch_path: P,
+    limits: UnpackLimits,
+    process_file: F,
+) -> Result<Vec<BlobPatch>, ZipFileError>
+where
+    F: Fn(Vec<u8>, &str) -> Result<(), io::Error>,
+{
+    unpack_patch_from(fs::File::open(patch_path)?, limits, process_file)
+}
+
+/// Like [`unpack_patch_with_limits`], reading the patch from `reader`.
+pub(crate) fn unpack_patch_from<R: Read + Seek, F>(
+    reader: R,
+    limits: UnpackLimits,
+    process_file: F,
+) -> Result<Vec<BlobPatch>, ZipFileError>
+where
+    F: Fn(Vec<u8>, &str) -> Result<(), io::Error>,
+{
+    let mut archive = ZipArchive::new(reader)?;
+
+    let mut patchs = vec![];
+    let mut total: u64 = 0;
+    for i in 0..archive.len() {
+        let mut file: zip::read::ZipFile<'_> = archive.by_index(i)?;
+        let name = file.name().to_string();
+        let limit = match (limits.max_entry_size, limits.max_total_size) {
+            (Some(entry), Some(all)) => Some(entry.min(all.saturating_sub(total))),
+            (entry, all) => entry.or(all.map(|all| all.saturating_sub(total))),
+        };
+        let buffer = read_limited(&mut file, limit, &name)?;
+        if let Some(entry) = name
+            .strip_suffix(ZSTD_SUFFIX)
+            .filter(|entry| *entry == PATCH_ENTRY || *entry == PATCH_ENTRY_V2)
+        {
+            let records = decompress_index(&buffer, limit, &name)?;
+            total += records.len() as u64;
+            patchs.extend(decode_records(entry, &records)?);
+            continue;
+        }
+        total += buffer.len() as u64;
+        if name == META_ENTRY || name == MANIFEST_ENTRY {
+            continue;
+        }
+        if name != PATCH_ENTRY && name != PATCH_ENTRY_V2 {
+            // zip tools take a `\` for a separator on every platform
+            if check_entry_name(&name).is_err() || name.contains('\\') {
+                return Err(ZipFileError::PathTraversal(name));
+            }
+            // only lowercase hex passes, so nothing but a blob name can reach a store path
+            if !is_valid_hash(&name) {
+                return Err(ZipFileError::InvalidEntryName(name));
+            }
+            process_file(buffer, &name)?;
+            continue;
+        }
+        patchs.extend(decode_records(&name, &buffer)?);
+    }
+    Ok(patchs)
+}
+
+/// Decompress a zstd compressed patch entry.
+#[cfg(feature = "zstd")]
+fn decompress_index(
+    buffer: &[u8],
+    limit: Option<u64>,
+    name: &str,
+) -> Result<Vec<u8>, ZipFileError> {
+    read_limited(zstd::stream::read::Decoder::new(buffer)?, limit, name)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_index(_: &[u8], _: Option<u64>, name: &str) -> Result<Vec<u8>, ZipFileError> {
+    Err(ZipFileError::UnsupportedEntry(name.to_string()))
+}
+
+/// Read `reader` to the end, failing when it yields more than `limit` bytes.
+fn read_limited<R: Read>(
+    mut reader: R,
+    limit: Option<u64>,
+    name: &str,
+) -> Result<Vec<u8>, ZipFileError> {
+    let mut buffer = Vec::new();
+    match limit {
+        Some(limit) => {
+            reader.by_ref().take(limit + 1).read_to_end(&mut buffer)?;
+            if buffer.len() as u64 > limit {
+                return Err(ZipFileError::DecompressionLimitExceeded(name.to_string()));
+            }
+        }
+        None => {
+            reader.read_to_end(&mut buffer)?;
+        }
+    }
+    Ok(buffer)
+}
+
+/// Decode the records of the patch entry `name`.
+fn decode_records(name: &str, buffer: &[u8]) -> Result<Vec<BlobPatch>, ZipFileError> {
+    let mut patchs = vec![];
+    // records are written back to back, so keep reading until the entry is exhausted
+    let mut cursor = io::Cursor::new(buffer);
+    while (cursor.position() as usize) < buffer.len() {
+        let patch: BlobPatch = if name == PATCH_ENTRY {
+            bincode::deserialize_from(&mut cursor)?
+        } else {
+            bincode::deserialize_from::<_, CompactBlobPatch>(&mut cursor)?.try_into()?
+        };
+        // hashes end up in store paths, reject them before anything is written
+        for hash in patch.hashes() {
+            if !is_valid_hash(hash) {
+                return Err(ZipFileError::InvalidHash(hash.to_string()));
+            }
+        }
+        patchs.push(patch);
+    }
+    Ok(patchs)
+}
+
+/// Read the metadata of a patch, `None` when it was written without any.
+pub fn read_patch_metadata<P: AsRef<Path>>(
+    patch_path: P,
+) -> Result<Option<PatchMetadata>, ZipFileError> {
+    let mut archive = ZipArchive::new(fs::File::open(patch_path)?)?;
+    let entry = match archive.by_name(META_ENTRY) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_reader(entry)?))
+}
+
+/// Read the manifest of a patch, `None` for patches written before manifests were.
+pub fn read_patch_manifest<P: AsRef<Path>>(
+    patch_path: P,
+) -> Result<Option<PatchManifest>, ZipFileError> {
+    let mut archive = ZipArchive::new(fs::File::open(patch_path)?)?;
+    let entry = match archive.by_name(MANIFEST_ENTRY) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_reader(entry)?))
+}
+
+/// Calculate a stable fingerprint of what a patch does.
+///
+/// The fingerprint only depends on the set of `BlobPatch` records in the patch,
+/// so it is independent of compression and of the order of entries in the zip.
+pub fn patch_fingerprint<P: AsRef<Path>>(patch_path: P) -> Result<String, ZipFileError> {
+    let patchs = unpack_patch(patch_path, |_, _| Ok(()))?;
+    let mut records = patchs
+        .iter()
+        .map(bincode::serialize)
+        .collect::<Result<Vec<_>, _>>()?;
+    records.sort();
+    let mut hasher = XxHash64::default();
+    for record in records {
+        hasher.write(&(record.len() as u64).to_le_bytes());
+        hasher.write(&record);
+    }
+    Ok(format!("{:x}", hasher.finish()))
+}
+
+/// List the blobs added by a patch that the store at `base` does not hold yet.
+///
+/// Content may be shared between versions, a client only has to fetch the blobs reported here.
+pub fn missing_add_blobs<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_path: P,
+    base_path: Q,
+) -> Result<Vec<String>, ZipFileError> {
+    let store = read_store_config(base_path.as_ref())?;
+    let patchs = unpack_patch(patch_path, |_, _| Ok(()))?;
+    Ok(patchs
+        .into_iter()
+        .filter_map(|p| match p {
+            BlobPatch::Add { new_file } if !store.blob_exists(&new_file, base_path.as_ref()) => {
+                Some(new_file)
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Apply a patch to the store at `base_path`.
+///
+/// Blobs the store already holds are never rewritten. New blobs are written aside and renamed
+/// into place, and deletions only run after every new blob exists, so a concurrent reader of
+/// an existing blob never sees it change.
+pub fn apply_patchs<P: AsRef<Path>>(patch_path: P, base_path: P) -> Result<(), ZipFileError> {
+    let store = read_store_config(base_path.as_ref())?;
+    apply_patchs_in(patch_path, base_path, &store, &ApplyOptions::default())
+}
+
+/// Like [`apply_patchs`], checking every rebuilt blob against its hash before publishing it.
+///
+/// A rebuilt blob that hashes differently, for example because the base blob was corrupted,
+/// fails with [`ZipFileError::HashMismatch`] and never enters the store.
+pub fn apply_patchs_verified<P: AsRef<Path>>(
+    patch_path: P,
+    base_path: P,
+) -> Result<(), ZipFileError> {
+    let options = ApplyOptions {
+        verify_hashes: true,
+        ..ApplyOptions::default()
+    };
+    let store = read_store_config(base_path.as_ref())?;
+    apply_patchs_in(patch_path, base_path, &store, &options)
+}
+
+pub(crate) fn apply_patchs_in<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_path: P,
+    base_path: Q,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+) -> Result<(), ZipFileError> {
+    let base = &canonical_store(base_path.as_ref())?;
+    let manifest = read_patch_manifest(patch_path.as_ref())?;
+    let stats = StoreStats::load(base, store)?;
+    let old_root = manifest.and_then(|manifest| manifest.old_root);
+    if let Some(old_root) = old_root.filter(|_| !options.skip_source_check) {
+        let current = store.blob_exists(&old_root, base)
+            && if stats.versions() == 0 {
+                read_blobs(&old_root, base, store).is_ok()
+            } else {
+                stats.roots().any(|r| r == old_root)
+            };
+        if !current {
+            return Err(ZipFileError::SourceMismatch(old_root));
+        }
+    }
+    store.record_layout(base)?;
+    let stats = RefCell::new(stats);
+    let staging = RefCell::new(Staging::new(&base.join(META_DIR), options.durability)?);
+    let patchs = unpack_patch_with_limits(patch_path, options.limits, |buffer, name| {
+        let path = store.path_from_hash(name, base)?;
+        let mut staging = staging.borrow_mut();
+        if path.exists() || staging.staged(&path).is_some() {
+            return Ok(());
+        }
+        let size = staging.write(&path, |tmp| fs::write(tmp, &buffer))?;
+        stats.borrow_mut().added(size);
+        Ok(())
+    })?;
+    let (staging, stats) = (staging.into_inner(), stats.into_inner());
+    apply_records(patchs, base, store, options, staging, stats)
+}
+
+/// Apply unpacked records to the store at `base`, the add payloads must already be staged
+/// or stored.
+///
+/// Rebuilt blobs are staged next to the add payloads and nothing enters the store until every
+/// record applied, a failing patch leaves the store as it was.
+fn apply_records(
+    patchs: Vec<BlobPatch>,
+    base: &Path,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+    mut staging: Staging,
+    mut stats: StoreStats,
+) -> Result<(), ZipFileError> {
+    // the root record comes first
+    let root = match patchs.first() {
+        Some(BlobPatch::Replace { new_file, .. }) => Some(new_file.clone()),
+        _ => None,
+    };
+    let deleted = apply_counted(patchs, base, store, options, &mut staging, &mut stats)?;
+    staging.commit()?;
+    // deletions run last, a deleted blob may still be the source of a replace
+    for old_file in deleted {
+        let path = store.path_from_hash(&old_file, base)?;
+        if path.exists() {
+            let size = fs::metadata(&path)?.len();
+            fs::remove_file(path)?;
+            stats.removed(size);
+        }
+    }
+    if let Some(root) = root {
+        stats.add_version(&root, base, store)?;
+    }
+    stats.save(base, options.durability)?;
+    Ok(())
+}
+
+/// Stage the blobs of `patchs`, returning the blobs to delete once they are in place.
+fn apply_counted(
+    patchs: Vec<BlobPatch>,
+    base: &Path,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+    staging: &mut Staging,
+    stats: &mut StoreStats,
+) -> Result<Vec<String>, ZipFileError> {
+    let mut deleted = vec![];
+    let mut produced = HashSet::new();
+    for patch in patchs {
+        if options.cancellation.is_cancelled() {
+            return Err(ZipFileError::Cancelled);
+        }
+        match patch {
+            BlobPatch::Add { new_file } => {
+                // a referenced add carries no payload, the base must already hold it
+                let path = store.path_from_hash(&new_file, base)?;
+                if !path.is_file() && staging.staged(&path).is_none() {
+                    return Err(ZipFileError::MissingBaseBlob(new_file));
+                }
+                produced.insert(new_file);
+            }
+            BlobPatch::Delete { old_file } => {
+                if !options.delete_removed {
+                    continue;
+                }
+                if options.on_missing_delete == MissingDeletePolicy::Error
+                    && !store.blob_exists(&old_file, base)
+                {
+                    return Err(ZipFileError::MissingBaseBlob(old_file));
+                }
+                deleted.push(old_file);
+            }
+            BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            } => {
+                let old_path = staging.resolve(&store.path_from_hash(&old_file, base)?);
+                if !old_path.is_file() {
+                    return Err(ZipFileError::MissingBaseBlob(old_file));
+                }
+                let new_path = store.path_from_hash(&new_file, base)?;
+                if !new_path.exists() && staging.staged(&new_path).is_none() {
+                    let replacements = replacements_from(patch);
+                    let size = staging.write(&new_path, |tmp| {
+                        replace_parts_file(&old_path, tmp, &replacements)?;
+                        if options.verify_hashes {
+                            let actual = calculate_file_hash_with(tmp, store.hash_algorithm)?;
+                            if actual != new_file {
+                                return Err(ZipFileError::HashMismatch {
+                                    expected: new_file.clone(),
+                                    actual,
+                                });
+                            }
+                        }
+                        Ok(())
+                    })?;
+                    stats.added(size);
+                }
+                produced.insert(new_file);
+            }
+        }
+    }
+    // the blob may have moved, the new version still needs it
+    deleted.retain(|old_file| !produced.contains(old_file));
+    Ok(deleted)
+}
+
+/// Write a patch as loose objects into the directory `out_dir`: the records as one
+/// `ditiear.patch` file next to every added blob as its own file named by its hash.
+///
+/// Unlike a zip patch, a client can fetch just the index and the added blobs it lacks, see
+/// [`missing_add_blobs`]. Nothing is written when there are no changes.
+pub fn write_loose_patch<T, P, Q>(diffs: T, from_dir: P, out_dir: Q) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let store = read_store_config(from_dir.as_ref())?;
+    write_loose_patch_in(diffs, from_dir, out_dir, &store, &PatchOptions::default())
+}
+
+pub(crate) fn write_loose_patch_in<T, P, Q>(
+    diffs: T,
+    from_dir: P,
+    out_dir: Q,
+    store: &StoreConfig,
+    options: &PatchOptions,
+) -> Result<(), ZipFileError>
+where
+    T: IntoIterator<Item = DiffCollectionType>,
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let patchs = BlobPatch::from(diffs, from_dir.as_ref(), store, options)?;
+    if patchs.is_empty() {
+        return Ok(());
+    }
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    let mut index = vec![];
+    for p in &patchs {
+        index.extend(bincode::serialize(p)?);
+        if let BlobPatch::Add { new_file } = p {
+            fs::write(
+                out_dir.join(new_file),
+                bytes_from(new_file, from_dir.as_ref(), store)?,
+            )?;
+        }
+    }
+    // the index goes last, a directory holding it is complete
+    Ok(fs::write(out_dir.join(PATCH_ENTRY), index)?)
+}
+
+/// Apply a patch written by [`write_loose_patch`] from the directory `patch_dir` to the store
+/// at `base_path`.
+///
+/// Only the added blobs the store lacks are read, the others may be absent from `patch_dir`.
+pub fn apply_loose_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_dir: P,
+    base_path: Q,
+) -> Result<(), ZipFileError> {
+    let store = read_store_config(base_path.as_ref())?;
+    apply_loose_patch_in(patch_dir, base_path, &store, &ApplyOptions::default())
+}
+
+pub(crate) fn apply_loose_patch_in<P: AsRef<Path>, Q: AsRef<Path>>(
+    patch_dir: P,
+    base_path: Q,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+) -> Result<(), ZipFileError> {
+    let (patch_dir, base) = (patch_dir.as_ref(), &canonical_store(base_path.as_ref())?);
+    let patchs = decode_records(PATCH_ENTRY, &fs::read(patch_dir.join(PATCH_ENTRY))?)?;
+    store.record_layout(base)?;
+    let mut stats = StoreStats::load(base, store)?;
+    let mut staging = Staging::new(&base.join(META_DIR), options.durability)?;
+    for patch in &patchs {
+        if let BlobPatch::Add { new_file } = patch {
+            let path = store.path_from_hash(new_file, base)?;
+            if path.exists() || staging.staged(&path).is_some() {
+                continue;
+            }
+            let source = patch_dir.join(new_file);
+            if !source.is_file() {
+                return Err(ZipFileError::MissingBaseBlob(new_file.clone()));
+            }
+            let size = staging.write(&path, |tmp| fs::copy(&source, tmp).map(|_| ()))?;
+            stats.added(size);
+        }
+    }
+    apply_records(patchs, base, store, options, staging, stats)
+}
+
+#[derive(Debug)]
+pub enum VerifyFailure {
+    /// The patch itself could not be read.
+    Unreadable(ZipFileError),
+    /// Reconstructing `new_file` failed.
+    Io { new_file: String, error: io::Error },
+    /// The reconstructed content does not hash to the blob it is meant to produce.
+    HashMismatch { expected: String, actual: String },
+}
+
+/// Reconstruct every blob of a patch and check it against its expected hash, without touching
+/// the store.
+///
+/// Blobs are hashed with the algorithm the patch manifest names, the one of the store for a
+/// patch without a manifest. Reconstructed blobs are staged below the meta directory of the
+/// store and removed again, a replace may start from a blob added or rebuilt earlier in the
+/// same patch.
+pub fn verify_patch_apply<P: AsRef<Path>>(
+    patch_path: P,
+    base_path: P,
+) -> Result<(), Vec<VerifyFailure>> {
+    let store = read_store_config(base_path.as_ref())
+        .map_err(|e| vec![VerifyFailure::Unreadable(e.into())])?;
+    verify_patch_apply_in(
+        patch_path.as_ref(),
+        base_path.as_ref(),
+        &store,
+        &ApplyOptions::default(),
+    )
+}
+
+pub(crate) fn verify_patch_apply_in(
+    patch_path: &Path,
+    base: &Path,
+    store: &StoreConfig,
+    options: &ApplyOptions,
+) -> Result<(), Vec<VerifyFailure>> {
+    let unreadable = |e: ZipFileError| vec![VerifyFailure::Unreadable(e)];
+    let base = &canonical_store(base).map_err(|e| unreadable(e.into()))?;
+    let algorithm = read_patch_manifest(patch_path)
+        .map_err(unreadable)?
+        .map_or(store.hash_algorithm, |manifest| manifest.hash_algorithm);
+    // never committed, dropping the staging removes everything reconstructed
+    let staging =
+        Staging::new(&base.join(META_DIR), Durability::None).map_err(|e| unreadable(e.into()))?;
+    let staging = RefCell::new(staging);
+    let failures = RefCell::new(vec![]);
+    let patchs = unpack_patch_with_limits(patch_path, options.limits, |buffer, name| {
+        let mut hasher = algorithm.hasher();
+        hasher.update(&buffer);
+        let actual = hasher.finish_hex();
+        if actual != name {
+            failures.borrow_mut().push(VerifyFailure::HashMismatch {
+                expected: name.to_string(),
+                actual,
+            });
+            return Ok(());
+        }
+        let path = store.path_from_hash(name, base)?;
+        let mut staging = staging.borrow_mut();
+        if !path.exists() && staging.staged(&path).is_none() {
+            staging.write(&path, |tmp| fs::write(tmp, &buffer))?;
+        }
+        Ok(())
+    })
+    .map_err(unreadable)?;
+    let (mut staging, mut failures) = (staging.into_inner(), failures.into_inner());
+    for patch in patchs {
+        let BlobPatch::Replace {
+            old_file,
+            new_file,
+            patch,
+        } = patch
+        else {
+            continue;
+        };
+        let replacements = replacements_from(patch);
+        let mut rebuild = || -> io::Result<String> {
+            let old_path = staging.resolve(&store.path_from_hash(&old_file, base)?);
+            let new_path = store.path_from_hash(&new_file, base)?;
+            let mut actual = String::new();
+            staging.write(&new_path, |tmp| {
+                replace_parts_file(&old_path, tmp, &replacements)?;
+                actual = calculate_file_hash_with(tmp, algorithm)?;
+                Ok::<_, io::Error>(())
+            })?;
+            Ok(actual)
+        };
+        match rebuild() {
+            Ok(actual) if actual != new_file => failures.push(VerifyFailure::HashMismatch {
+                expected: new_file,
+                actual,
+            }),
+            Ok(_) => {}
+            Err(error) => failures.push(VerifyFailure::Io { new_file, error }),
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+struct Replacement {
+    start: usize,
+    length: usize,
+    content: Bytes,
+}
+
+fn replacements_from(patch: Vec<BytesPatch>) -> Vec<Replacement> {
+    let mut replacements = patch
+        .into_iter()
+        .map(|item| match item {
+            BytesPatch::Add {
+                old_index,
+                new_value,
+                ..
+            } => Replacement {
+                start: old_index,
+                length: 0,
+                content: new_value,
+            },
+            BytesPatch::Delete {
+                old_index,
+                old_value,
+                ..
+            } => Replacement {
+                start: old_index,
+                length: old_value.len(),
+                content: Bytes::new(),
+            },
+            BytesPatch::Replace {
+                old_index,
+                old_value,
+                new_value,
+                ..
+            } => Replacement {
+                start: old_index,
+                length: old_value.len(),
+                content: new_value,
+            },
+        })
+        .collect::<Vec<_>>();
+    replacements.sort_by_key(|r| r.start);
+    replacements
+}
+
+/// Copy exactly `bytes` bytes from `reader` to `writer`, a reader ending before that fails
+/// with `UnexpectedEof`.
+fn copy_with_buffer<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut bytes: usize,
+) -> io::Result<()> {
+    let mut buffer = vec![0; 1024.min(bytes)];
+    while bytes > 0 {
+        let chunk = buffer.len().min(bytes);
+        let len = reader.read(&mut buffer[..chunk])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        writer.write_all(&buffer[..len])?;
+        bytes -= len;
+    }
+    Ok(())
+}
+
+/// Write `original_file` with `replacements` applied to `dest_file`.
+///
+/// Replacements must be sorted by start and must not overlap, the old bytes of every one have
+/// to start at or after the end of the one before. Others fail with `InvalidData`.
+fn replace_parts_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    original_file: P,
+    dest_file: Q,
+    replacements: &[Replacement],
+) -> io::Result<()> {
+    let original = OpenOptions::new().read(true).open(original_file.as_ref())?;
     let dest = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(dest_file.as_ref())?;
 
     let mut reader = io::BufReader::new(original);
@@ -356,7 +2010,15 @@ fn replace_parts_file<P: AsRef<Path>, Q: AsRef<Path>>(
 
     let mut current_pos = 0;
     for replacement in replacements {
-        let bytes_to_copy = replacement.start - current_pos;
+        let bytes_to_copy = replacement.start.checked_sub(current_pos).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "replacement at {} overlaps or precedes the one ending at {}",
+                    replacement.start, current_pos
+                ),
+            )
+        })?;
         // avoid high memory usage
         copy_with_buffer(&mut reader, &mut writer, bytes_to_copy)?;
         // avoid copying 0 bytes
@@ -374,6 +2036,77 @@ fn replace_parts_file<P: AsRef<Path>, Q: AsRef<Path>>(
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_replace_parts_file_rejects_overlap() {
+        use super::Replacement;
+        use bytes::Bytes;
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        std::fs::write(&old, b"0123456789").unwrap();
+        let replacement = |start, length, content: &'static str| Replacement {
+            start,
+            length,
+            content: Bytes::from(content),
+        };
+
+        let valid = [
+            replacement(2, 2, "ab"),
+            replacement(4, 0, "+"),
+            replacement(8, 1, ""),
+        ];
+        super::replace_parts_file(&old, &new, &valid).unwrap();
+        assert_eq!(std::fs::read(&new).unwrap(), b"01ab+45679");
+
+        let overlapping = [replacement(2, 4, "ab"), replacement(5, 1, "c")];
+        let out_of_order = [replacement(6, 1, "ab"), replacement(2, 1, "c")];
+        for replacements in [&overlapping, &out_of_order] {
+            let error = super::replace_parts_file(&old, &new, replacements).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_replace_parts_file_close_replacements() {
+        use super::Replacement;
+        use bytes::Bytes;
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let content = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&old, &content).unwrap();
+        // the gaps before both replacements are no multiple of the copy buffer
+        let replacements = [
+            Replacement {
+                start: 1500,
+                length: 4,
+                content: Bytes::from("first"),
+            },
+            Replacement {
+                start: 1510,
+                length: 2,
+                content: Bytes::from("second"),
+            },
+        ];
+        super::replace_parts_file(&old, &new, &replacements).unwrap();
+        let expected = [
+            &content[..1500],
+            b"first",
+            &content[1504..1510],
+            b"second",
+            &content[1512..],
+        ]
+        .concat();
+        assert_eq!(std::fs::read(&new).unwrap(), expected);
+
+        // a replacement past the end of the file fails instead of waiting for more bytes
+        let past_end = [Replacement {
+            start: 20_000,
+            length: 0,
+            content: Bytes::new(),
+        }];
+        let error = super::replace_parts_file(&old, &new, &past_end).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
     #[test]
     fn test_blob_patch() {
         use crate::patch::BlobPatch;
@@ -415,4 +2148,850 @@ mod tests {
         }
         // assert_eq!(patch, deserialized);
     }
+
+    fn write_test_patch(
+        path: &std::path::Path,
+        patchs: &[super::BlobPatch],
+        method: zip::CompressionMethod,
+    ) {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        zip.start_file(
+            "ditiear.patch",
+            zip::write::FileOptions::default().compression_method(method),
+        )
+        .unwrap();
+        for p in patchs {
+            zip.write_all(&bincode::serialize(p).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_patch_fingerprint() {
+        use crate::patch::BlobPatch;
+        let dir = tempfile::tempdir().unwrap();
+        let add = || BlobPatch::Add {
+            new_file: "1234abcd".to_string(),
+        };
+        let delete = || BlobPatch::Delete {
+            old_file: "5678ef01".to_string(),
+        };
+        let stored = dir.path().join("stored.zip");
+        let deflated = dir.path().join("deflated.zip");
+        write_test_patch(&stored, &[add(), delete()], zip::CompressionMethod::Stored);
+        write_test_patch(
+            &deflated,
+            &[delete(), add()],
+            zip::CompressionMethod::Deflated,
+        );
+        assert_ne!(
+            std::fs::read(&stored).unwrap(),
+            std::fs::read(&deflated).unwrap()
+        );
+        let a = super::patch_fingerprint(&stored).unwrap();
+        let b = super::patch_fingerprint(&deflated).unwrap();
+        assert_eq!(a, b);
+
+        let other = dir.path().join("other.zip");
+        write_test_patch(&other, &[add()], zip::CompressionMethod::Deflated);
+        assert_ne!(a, super::patch_fingerprint(&other).unwrap());
+    }
+
+    #[test]
+    fn test_verify_patch_apply() {
+        use crate::common::path_from_hash;
+        use crate::patch::{BlobPatch, BytesPatch, VerifyFailure};
+        use bytes::Bytes;
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        let old = Bytes::from("hello world");
+        let new = Bytes::from("hello world!");
+        let old_hash = xxhash(&old);
+        let new_hash = xxhash(&new);
+        let old_path = path_from_hash(&old_hash, &base).unwrap();
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::write(&old_path, &old).unwrap();
+
+        let good = dir.path().join("good.zip");
+        let patch = BlobPatch::Replace {
+            old_file: old_hash.clone(),
+            new_file: new_hash.clone(),
+            patch: super::calculate_binary_diff(old.clone(), new.clone()),
+        };
+        write_test_patch(&good, &[patch], zip::CompressionMethod::Deflated);
+        super::verify_patch_apply(&good, &base).unwrap();
+
+        // a delta that inserts the wrong byte
+        let corrupt = dir.path().join("corrupt.zip");
+        let patch = BlobPatch::Replace {
+            old_file: old_hash.clone(),
+            new_file: new_hash.clone(),
+            patch: vec![BytesPatch::Add {
+                old_index: 11,
+                new_index: 11,
+                new_value: Bytes::from("?"),
+            }],
+        };
+        write_test_patch(&corrupt, &[patch], zip::CompressionMethod::Deflated);
+        let failures = super::verify_patch_apply(&corrupt, &base).unwrap_err();
+        assert_eq!(failures.len(), 1);
+        match &failures[0] {
+            VerifyFailure::HashMismatch { expected, .. } => assert_eq!(expected, &new_hash),
+            other => panic!("{:?}", other),
+        }
+        // nothing was committed to the store
+        assert!(!path_from_hash(&new_hash, &base).unwrap().exists());
+
+        // a replace may start from a blob rebuilt earlier in the same patch
+        let newer = Bytes::from("hello world!!");
+        let newer_hash = xxhash(&newer);
+        let chained = dir.path().join("chained.zip");
+        let patchs = [
+            BlobPatch::Replace {
+                old_file: old_hash.clone(),
+                new_file: new_hash.clone(),
+                patch: super::calculate_binary_diff(old.clone(), new.clone()),
+            },
+            BlobPatch::Replace {
+                old_file: new_hash.clone(),
+                new_file: newer_hash.clone(),
+                patch: super::calculate_binary_diff(new.clone(), newer.clone()),
+            },
+        ];
+        write_test_patch(&chained, &patchs, zip::CompressionMethod::Deflated);
+        super::verify_patch_apply(&chained, &base).unwrap();
+        assert!(!path_from_hash(&newer_hash, &base).unwrap().exists());
+        // the reconstructions are staged in the store and removed again
+        let left = std::fs::read_dir(base.join(crate::index::META_DIR))
+            .map(|dir| dir.count())
+            .unwrap_or(0);
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_apply_patchs_verified() {
+        use crate::common::path_from_hash;
+        use crate::patch::{BlobPatch, BytesPatch, ZipFileError};
+        use bytes::Bytes;
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        let old = Bytes::from("hello world");
+        let old_hash = xxhash(&old);
+        let new_hash = xxhash(b"hello world!");
+        let old_path = path_from_hash(&old_hash, &base).unwrap();
+        std::fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+        std::fs::write(&old_path, &old).unwrap();
+
+        // a delta that inserts the wrong byte
+        let corrupt = dir.path().join("corrupt.zip");
+        let patch = BlobPatch::Replace {
+            old_file: old_hash.clone(),
+            new_file: new_hash.clone(),
+            patch: vec![BytesPatch::Add {
+                old_index: 11,
+                new_index: 11,
+                new_value: Bytes::from("?"),
+            }],
+        };
+        write_test_patch(&corrupt, &[patch], zip::CompressionMethod::Deflated);
+        match super::apply_patchs_verified(&corrupt, &base) {
+            Err(ZipFileError::HashMismatch { expected, actual }) => {
+                assert_eq!(expected, new_hash);
+                assert_eq!(actual, xxhash(b"hello world?"));
+            }
+            other => panic!("{:?}", other),
+        }
+        // nothing was committed to the store
+        assert!(!path_from_hash(&new_hash, &base).unwrap().exists());
+    }
+
+    #[test]
+    fn test_failed_apply_leaves_store_unchanged() {
+        use crate::common::path_from_hash;
+        use crate::patch::{BlobPatch, ZipFileError};
+        use std::io::Write;
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        let added = xxhash(b"added");
+        let missing = xxhash(b"missing");
+
+        let patch = dir.path().join("patch.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&patch).unwrap());
+        zip.start_file(&added, zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"added").unwrap();
+        zip.start_file("ditiear.patch", zip::write::FileOptions::default())
+            .unwrap();
+        let records = [
+            BlobPatch::Add {
+                new_file: added.clone(),
+            },
+            BlobPatch::Replace {
+                old_file: missing.clone(),
+                new_file: xxhash(b"new"),
+                patch: vec![],
+            },
+        ];
+        for record in &records {
+            zip.write_all(&bincode::serialize(record).unwrap()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        match super::apply_patchs(&patch, &base) {
+            Err(ZipFileError::MissingBaseBlob(hash)) => assert_eq!(hash, missing),
+            other => panic!("{:?}", other),
+        }
+        // the add payload was staged but never published, and the staging is gone
+        assert!(!path_from_hash(&added, &base).unwrap().exists());
+        let meta = base.join(crate::index::META_DIR);
+        let leftovers = std::fs::read_dir(&meta)
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn test_invert_patch() {
+        use crate::delta::apply_binary_patch;
+        use crate::patch::BlobPatch;
+        use bytes::Bytes;
+        let old = Bytes::from(
+            (0..2000u32)
+                .map(|i| (i * 7 % 251) as u8)
+                .collect::<Vec<_>>(),
+        );
+        let mut new = old.to_vec();
+        new.splice(100..140, b"inserted in the middle".iter().copied());
+        new.drain(900..1000);
+        new.extend_from_slice(b"appended");
+        let new = Bytes::from(new);
+        let patchs = vec![
+            BlobPatch::Replace {
+                old_file: "1".to_string(),
+                new_file: "2".to_string(),
+                patch: super::calculate_binary_diff(old.clone(), new.clone()),
+            },
+            BlobPatch::Add {
+                new_file: "3".to_string(),
+            },
+            BlobPatch::Delete {
+                old_file: "4".to_string(),
+            },
+        ];
+        let inverse = super::invert_patch(patchs);
+        match &inverse[..] {
+            [BlobPatch::Replace {
+                old_file,
+                new_file,
+                patch,
+            }, BlobPatch::Delete { old_file: deleted }, BlobPatch::Add { new_file: added }] => {
+                assert_eq!((old_file.as_str(), new_file.as_str()), ("2", "1"));
+                assert_eq!((deleted.as_str(), added.as_str()), ("3", "4"));
+                assert_eq!(apply_binary_patch(&new, patch).unwrap(), old);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn xxhash(bytes: &[u8]) -> String {
+        use std::hash::Hasher;
+        let mut hasher = twox_hash::XxHash64::default();
+        hasher.write(bytes);
+        format!("{:x}", hasher.finish())
+    }
+
+    #[test]
+    fn test_coalesce_binary_diff() {
+        use bytes::Bytes;
+        let old = (0..300u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut new = old.clone();
+        for i in (0..new.len()).step_by(3) {
+            new[i] = new[i].wrapping_add(1);
+        }
+        let (old, new) = (Bytes::from(old), Bytes::from(new));
+        let ops = super::calculate_binary_diff(old.clone(), new.clone());
+        let coalesced = super::coalesce_binary_diff(ops, &old, &new, 4);
+        assert!(coalesced.len() < 5, "{} ops", coalesced.len());
+        // ops that do not follow each other are never merged
+        let unordered = vec![
+            super::BytesPatch::Delete {
+                old_index: 10,
+                new_index: 10,
+                old_value: old.slice(10..12),
+            },
+            super::BytesPatch::Delete {
+                old_index: 4,
+                new_index: 4,
+                old_value: old.slice(4..6),
+            },
+        ];
+        assert_eq!(
+            super::coalesce_binary_diff(unordered, &old, &new, 4).len(),
+            2
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let (old_path, new_path) = (dir.path().join("old"), dir.path().join("new"));
+        std::fs::write(&old_path, &old).unwrap();
+        let replacements = super::replacements_from(coalesced);
+        super::replace_parts_file(&old_path, &new_path, &replacements).unwrap();
+        assert_eq!(std::fs::read(&new_path).unwrap(), new.as_ref());
+    }
+
+    #[test]
+    fn test_missing_add_blobs() {
+        let base = tempfile::tempdir().unwrap();
+        let patch = base.path().join("add.zip");
+        let (held, fresh) = (b"held".as_slice(), b"fresh".as_slice());
+        let held_path = crate::common::StoreConfig::default()
+            .path_from_hash(&xxhash(held), base.path())
+            .unwrap();
+        std::fs::create_dir_all(held_path.parent().unwrap()).unwrap();
+        std::fs::write(held_path, held).unwrap();
+        write_test_patch(
+            &patch,
+            &[
+                super::BlobPatch::Add {
+                    new_file: xxhash(held),
+                },
+                super::BlobPatch::Add {
+                    new_file: xxhash(fresh),
+                },
+            ],
+            zip::CompressionMethod::Stored,
+        );
+        assert_eq!(
+            super::missing_add_blobs(&patch, base.path()).unwrap(),
+            vec![xxhash(fresh)]
+        );
+    }
+
+    #[test]
+    fn test_store_compressed() {
+        use crate::diff::{DiffCollectionType, DiffFileType};
+        use zip::CompressionMethod;
+        let base = tempfile::tempdir().unwrap();
+        let jpeg = [b"\xff\xd8\xff\xe0".as_slice(), &[7; 64]].concat();
+        let text = b"plain text ".repeat(16);
+        let store = crate::common::StoreConfig::default();
+        for content in [&jpeg, &text] {
+            let path = store.path_from_hash(&xxhash(content), base.path()).unwrap();
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content).unwrap();
+        }
+        let diffs = [&jpeg, &text].map(|content| DiffCollectionType::Add {
+            r#type: DiffFileType::File,
+            value: xxhash(content),
+        });
+        let patch = base.path().join("patch.zip");
+        let options = super::PatchOptions {
+            compression: CompressionMethod::Bzip2,
+            compression_level: Some(9),
+            store_compressed: true,
+            ..Default::default()
+        };
+        super::create_zip_patch_with_options(diffs, base.path(), &patch, &options).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&patch).unwrap()).unwrap();
+        let method = |archive: &mut zip::ZipArchive<_>, name: &str| {
+            archive.by_name(name).unwrap().compression()
+        };
+        assert_eq!(
+            method(&mut archive, &xxhash(&jpeg)),
+            CompressionMethod::Stored
+        );
+        assert_eq!(
+            method(&mut archive, &xxhash(&text)),
+            CompressionMethod::Bzip2
+        );
+        assert_eq!(
+            method(&mut archive, "ditiear.patch"),
+            CompressionMethod::Bzip2
+        );
+
+        let extracted = std::cell::RefCell::new(vec![]);
+        super::unpack_patch(&patch, |buffer, _| {
+            extracted.borrow_mut().push(buffer);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(extracted.into_inner(), [jpeg, text]);
+    }
+
+    #[test]
+    fn test_reference_deleted_adds() {
+        use crate::diff::{DiffCollectionType, DiffFileType};
+        let base = tempfile::tempdir().unwrap();
+        let content = b"moved content".as_slice();
+        let path = crate::common::StoreConfig::default()
+            .path_from_hash(&xxhash(content), base.path())
+            .unwrap();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        let diffs = || {
+            vec![
+                DiffCollectionType::Delete {
+                    r#type: DiffFileType::File,
+                    value: xxhash(content),
+                },
+                DiffCollectionType::Add {
+                    r#type: DiffFileType::File,
+                    value: xxhash(content),
+                },
+            ]
+        };
+        let entries = |patch: &std::path::Path| {
+            let archive = zip::ZipArchive::new(std::fs::File::open(patch).unwrap()).unwrap();
+            archive.file_names().map(String::from).collect::<Vec<_>>()
+        };
+        let embedded = base.path().join("embedded.zip");
+        let options = super::PatchOptions::default();
+        super::create_zip_patch_with_options(diffs(), base.path(), &embedded, &options).unwrap();
+        assert!(entries(&embedded).contains(&xxhash(content)));
+
+        let referenced = base.path().join("referenced.zip");
+        let options = super::PatchOptions {
+            reference_deleted_adds: true,
+            ..Default::default()
+        };
+        super::create_zip_patch_with_options(diffs(), base.path(), &referenced, &options).unwrap();
+        assert!(!entries(&referenced).contains(&xxhash(content)));
+        assert!(
+            std::fs::metadata(&referenced).unwrap().len()
+                < std::fs::metadata(&embedded).unwrap().len()
+        );
+
+        let options = super::ApplyOptions {
+            delete_removed: true,
+            ..Default::default()
+        };
+        super::apply_patchs_in(
+            &referenced,
+            base.path(),
+            &crate::common::StoreConfig::default(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_content_changed_ignore_whitespace() {
+        use super::{content_changed, DiffMode};
+        let unix = b"fn main() {\n    run();\n}\n";
+        let windows = b"fn main() {\r\n\trun();  \r\n}\r\n";
+        assert!(!content_changed(unix, unix, DiffMode::Exact));
+        assert!(content_changed(unix, windows, DiffMode::Exact));
+        assert!(!content_changed(unix, windows, DiffMode::IgnoreWhitespace));
+        let changed = b"fn main() {\r\n  stop();\r\n}\r\n";
+        assert!(content_changed(unix, changed, DiffMode::IgnoreWhitespace));
+        // binary input is compared as it is
+        let binary = [0xff, b' ', 0xfe];
+        let spaced = [0xff, b' ', b' ', 0xfe];
+        assert!(content_changed(
+            &binary,
+            &spaced,
+            DiffMode::IgnoreWhitespace
+        ));
+    }
+
+    #[test]
+    fn test_benchmark_diff() {
+        use super::{benchmark_diff, DiffAlgorithm};
+        use bytes::Bytes;
+        let old = Bytes::from("a shared prefix, then the old middle, and a shared suffix\n");
+        let new = Bytes::from("a shared prefix, then a different middle, and a shared suffix\n");
+        let algorithms = [
+            DiffAlgorithm::Myers,
+            DiffAlgorithm::Patience,
+            DiffAlgorithm::Lcs,
+            DiffAlgorithm::Rolling,
+        ];
+        let results = benchmark_diff(old, new, &algorithms);
+        assert_eq!(results.len(), algorithms.len());
+        for ((algorithm, _, size), expected) in results.iter().zip(algorithms) {
+            assert_eq!(*algorithm, expected);
+            assert!(*size > 0);
+        }
+    }
+
+    #[test]
+    fn test_calculate_binary_diff_bounded() {
+        use super::{calculate_binary_diff_bounded, BinaryDiffOptions, BytesPatch};
+        use crate::delta::apply_binary_patch;
+        use bytes::Bytes;
+        let old = Bytes::from("0123456789".repeat(10));
+        let new = Bytes::from("0123x56789".repeat(10));
+        let unbounded =
+            calculate_binary_diff_bounded(old.clone(), new.clone(), &BinaryDiffOptions::default());
+        assert_eq!(
+            unbounded,
+            super::calculate_binary_diff(old.clone(), new.clone())
+        );
+        let options = BinaryDiffOptions { max_in_memory: 32 };
+        let windowed = calculate_binary_diff_bounded(old.clone(), new.clone(), &options);
+        assert_eq!(apply_binary_patch(&old, &windowed).unwrap(), new);
+        // no op spans more than a window
+        let longest = windowed
+            .iter()
+            .map(|op| match op {
+                BytesPatch::Add { new_value, .. } => new_value.len(),
+                BytesPatch::Delete { old_value, .. } => old_value.len(),
+                BytesPatch::Replace { old_value, .. } => old_value.len(),
+            })
+            .max()
+            .unwrap();
+        assert!(longest <= 16);
+        let shifted = Bytes::from(["x".as_bytes(), &old].concat());
+        let windowed = calculate_binary_diff_bounded(old.clone(), shifted.clone(), &options);
+        assert_eq!(apply_binary_patch(&old, &windowed).unwrap(), shifted);
+        assert_eq!(windowed.len(), 1);
+    }
+
+    #[test]
+    fn test_benchmark_rolling_prepend() {
+        use super::{benchmark_diff, calculate_binary_diff_rolling, DiffAlgorithm};
+        use crate::delta::apply_binary_patch;
+        use bytes::Bytes;
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let old: Vec<u8> = (0..1 << 20)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let new = [&[0xff][..], &old].concat();
+        let (old, new) = (Bytes::from(old), Bytes::from(new));
+
+        let ops = calculate_binary_diff_rolling(old.clone(), new.clone());
+        assert_eq!(apply_binary_patch(&old, &ops).unwrap(), new);
+        let results = benchmark_diff(old, new, &[DiffAlgorithm::Myers, DiffAlgorithm::Rolling]);
+        assert_eq!(results[0].0, DiffAlgorithm::Myers);
+        assert_eq!(results[1].0, DiffAlgorithm::Rolling);
+        // the prepended byte and the tail shorter than a block
+        assert!(results[1].2 < 2 * super::SIGNATURE_BLOCK_SIZE);
+
+        // every block of a run of one byte has the same checksums
+        let old = Bytes::from(vec![0; 1 << 20]);
+        let new = Bytes::from([&[1][..], &old].concat());
+        let ops = calculate_binary_diff_rolling(old.clone(), new.clone());
+        assert_eq!(apply_binary_patch(&old, &ops).unwrap(), new);
+        assert!(ops.len() <= 2);
+    }
+
+    #[test]
+    fn test_unpack_patch_rejects_malformed_hash() {
+        use crate::patch::BlobPatch;
+        let dir = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        for hash in ["../../escape", "ABCD", "a", ""] {
+            let patch = dir.path().join("malformed.zip");
+            write_test_patch(
+                &patch,
+                &[BlobPatch::Replace {
+                    old_file: "5678ef01".to_string(),
+                    new_file: hash.to_string(),
+                    patch: vec![],
+                }],
+                zip::CompressionMethod::Stored,
+            );
+            let error = super::apply_patchs(&patch, &store.path().to_path_buf()).unwrap_err();
+            assert!(matches!(error, super::ZipFileError::InvalidHash(h) if h == hash));
+        }
+        assert_eq!(std::fs::read_dir(store.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_apply_patch_rejects_short_blob_name() {
+        use std::io::Write;
+        let dir = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("short.zip");
+        let write = |name: &str| {
+            let mut zip = zip::ZipWriter::new(std::fs::File::create(&patch).unwrap());
+            zip.start_file("ditiear.patch", zip::write::FileOptions::default())
+                .unwrap();
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(b"payload").unwrap();
+            zip.finish().unwrap();
+        };
+        write("a");
+        let error = super::apply_patchs(&patch, &store.path().to_path_buf()).unwrap_err();
+        assert!(matches!(error, super::ZipFileError::InvalidEntryName(h) if h == "a"));
+
+        // a hash no longer than the fanout has no blob file name
+        write("abc");
+        let config = crate::common::StoreConfig {
+            fanout: 3,
+            ..Default::default()
+        };
+        assert!(config.split_dir_and_name("abc").is_err());
+        let error =
+            super::apply_patchs_in(&patch, store.path(), &config, &Default::default()).unwrap_err();
+        assert!(
+            matches!(error, super::ZipFileError::Io(e) if e.kind() == std::io::ErrorKind::InvalidData)
+        );
+        assert!(config.list_blobs(store.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unpack_patch_rejects_path_traversal() {
+        use std::io::Write;
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        std::fs::create_dir(&store).unwrap();
+        let patch = dir.path().join("traversal.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&patch).unwrap());
+        zip.start_file("ditiear.patch", zip::write::FileOptions::default())
+            .unwrap();
+        zip.start_file("../escape", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"payload").unwrap();
+        zip.finish().unwrap();
+
+        // a relative base with `..` resolves to the same store
+        let base = store.join("..").join("store");
+        let error = super::apply_patchs(&patch, &base).unwrap_err();
+        assert!(matches!(error, super::ZipFileError::PathTraversal(name) if name == "../escape"));
+        assert!(!dir.path().join("escape").exists());
+        assert_eq!(std::fs::read_dir(&store).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_unpack_patch_rejects_malicious_entry_names() {
+        use std::io::Write;
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("a").join("b").join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        let patch = dir.path().join("malicious.zip");
+        for name in [
+            "../../etc/cron.d/x",
+            "ab/../../x",
+            "/tmp/x",
+            "..\\..\\x",
+            "ab\0cd",
+            "ABCD",
+        ] {
+            let mut zip = zip::ZipWriter::new(std::fs::File::create(&patch).unwrap());
+            zip.start_file("ditiear.patch", zip::write::FileOptions::default())
+                .unwrap();
+            // a valid blob first, it must not be kept once the bad entry is found
+            zip.start_file("1234abcd", zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(b"fine").unwrap();
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(b"* * * * * root sh").unwrap();
+            zip.finish().unwrap();
+
+            let unpacked = super::unpack_patch(&patch, |_, name| {
+                assert_eq!(name, "1234abcd");
+                Ok(())
+            });
+            let error = super::apply_patchs(&patch, &store).unwrap_err();
+            match name {
+                "ABCD" => {
+                    assert!(
+                        matches!(unpacked, Err(super::ZipFileError::InvalidEntryName(n)) if n == name)
+                    );
+                    assert!(matches!(error, super::ZipFileError::InvalidEntryName(n) if n == name));
+                }
+                _ => {
+                    assert!(
+                        matches!(unpacked, Err(super::ZipFileError::PathTraversal(n)) if n == name)
+                    );
+                    assert!(matches!(error, super::ZipFileError::PathTraversal(n) if n == name));
+                }
+            }
+        }
+        assert!(!dir.path().join("etc").exists() && !dir.path().join("a").join("x").exists());
+        let store_config = crate::common::StoreConfig::default();
+        assert!(store_config.list_blobs(&store).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_index() {
+        use crate::common::StoreConfig;
+        use crate::patch::{BlobPatch, BytesPatch};
+        use bytes::Bytes;
+        let dir = tempfile::tempdir().unwrap();
+        let patchs = || {
+            (0..2000u64)
+                .map(|i| BlobPatch::Replace {
+                    old_file: format!("{:x}", i * 7919 + 0x1000),
+                    new_file: format!("{:x}", i * 7907 + 0x2000),
+                    patch: vec![BytesPatch::Add {
+                        old_index: i as usize,
+                        new_index: i as usize,
+                        new_value: Bytes::from("x"),
+                    }],
+                })
+                .collect::<Vec<_>>()
+        };
+        let write = |name: &str, zstd_index: bool| {
+            let path = dir.path().join(name);
+            let options = super::PatchOptions {
+                zstd_index,
+                ..Default::default()
+            };
+            let file = std::fs::File::create(&path).unwrap();
+            super::write_patchs(
+                patchs(),
+                dir.path(),
+                file,
+                &StoreConfig::default(),
+                &options,
+            )
+            .unwrap();
+            path
+        };
+        let deflated = write("deflated.zip", false);
+        let compressed = write("zstd.zip", true);
+        let index_size = |path: &std::path::Path| {
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+            let size = archive.by_index(0).unwrap().compressed_size();
+            size
+        };
+        assert!(index_size(&compressed) < index_size(&deflated));
+        let unpacked = super::unpack_patch(&compressed, |_, _| Ok(())).unwrap();
+        assert_eq!(unpacked, patchs());
+        assert_eq!(
+            super::patch_fingerprint(&compressed).unwrap(),
+            super::patch_fingerprint(&deflated).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_calculate_binary_diff_with_progress() {
+        use bytes::Bytes;
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[1_000..1_010].fill(0);
+        new.splice(50_000..50_000, b"inserted".iter().copied());
+        new.truncate(99_000);
+        let (old, new) = (Bytes::from(old), Bytes::from(new));
+        let mut reports = vec![];
+        let ops = super::calculate_binary_diff_with_progress(
+            old.clone(),
+            new.clone(),
+            16 * 1024,
+            |done, total| reports.push((done, total)),
+        );
+        assert!(reports.len() > 1);
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(
+            reports.last().unwrap(),
+            &(old.len() + new.len(), old.len() + new.len())
+        );
+        assert_eq!(crate::delta::apply_binary_patch(&old, &ops).unwrap(), new);
+
+        // an insertion ahead of every window does not misalign the ones after it
+        let shifted = Bytes::from([b"shifted".as_slice(), &old].concat());
+        let ops = super::calculate_binary_diff_with_progress(
+            old.clone(),
+            shifted.clone(),
+            4096,
+            |_, _| {},
+        );
+        assert_eq!(
+            crate::delta::apply_binary_patch(&old, &ops).unwrap(),
+            shifted
+        );
+        assert_eq!(ops.len(), 1, "{:?}", ops);
+    }
+
+    #[test]
+    fn test_render_text_diff() {
+        let old = "title\nspeed = 1\nlives = 3\n";
+        let new = "title\nspeed = 2\nlives = 3\nmusic = on\n";
+        let rendered = super::render_text_diff(old, new);
+        assert!(rendered.starts_with("--- old\n+++ new\n"));
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"-speed = 1"));
+        assert!(lines.contains(&"+speed = 2"));
+        assert!(lines.contains(&"+music = on"));
+        assert!(lines.contains(&" lives = 3"));
+        assert!(super::render_text_diff(old, old).is_empty());
+    }
+
+    #[test]
+    fn test_unpack_patch_limits() {
+        use std::io::Write;
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("bomb.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&patch).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("ditiear.patch", options).unwrap();
+        zip.start_file("b0b0", options).unwrap();
+        let chunk = vec![0u8; 1 << 20];
+        for _ in 0..16 {
+            zip.write_all(&chunk).unwrap();
+        }
+        zip.finish().unwrap();
+        assert!(std::fs::metadata(&patch).unwrap().len() < 1 << 16);
+
+        let limited = |limits| super::unpack_patch_with_limits(&patch, limits, |_, _| Ok(()));
+        let error = limited(super::UnpackLimits {
+            max_entry_size: Some(1 << 20),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(
+            matches!(error, super::ZipFileError::DecompressionLimitExceeded(name) if name == "b0b0")
+        );
+        let error = limited(super::UnpackLimits {
+            max_total_size: Some(1 << 20),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            super::ZipFileError::DecompressionLimitExceeded(_)
+        ));
+        assert!(limited(super::UnpackLimits {
+            max_entry_size: Some(16 << 20),
+            max_total_size: Some(16 << 20),
+        })
+        .is_ok());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_memory_mapped_diff() {
+        use crate::common::StoreConfig;
+        use crate::diff::{DiffCollectionType, DiffFileType};
+        let base = tempfile::tempdir().unwrap();
+        let old = (0..300_000u32).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+        let mut new = old.clone();
+        new.splice(150_000..150_010, *b"inserted!!inserted!!");
+        new.truncate(290_000);
+        let store = StoreConfig::default();
+        for (hash, content) in [("aold", &old), ("bnew", &new)] {
+            let path = store.path_from_hash(hash, base.path()).unwrap();
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let diff = || {
+            vec![DiffCollectionType::Modify {
+                r#type: DiffFileType::File,
+                old: "aold".to_string(),
+                new: "bnew".to_string(),
+            }]
+        };
+        let read =
+            super::BlobPatch::from(diff(), base.path(), &store, &Default::default()).unwrap();
+        let options = super::PatchOptions {
+            memory_map: true,
+            ..Default::default()
+        };
+        let mapped = super::BlobPatch::from(diff(), base.path(), &store, &options).unwrap();
+        assert_eq!(read, mapped);
+    }
 }
@@ -1,15 +1,92 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "std")]
 use std::path::Path;
 
-pub use crate::diff::compare_blob_files;
+#[cfg(feature = "std")]
+pub use crate::checkout::{
+    apply_patch_in_memory, apply_patch_in_place, apply_patch_in_place_with_progress, checkout,
+    checkout_with_options, materialize, ChangeKind, CheckoutOptions, CheckoutReport,
+};
+#[cfg(feature = "std")]
+pub use crate::common::{
+    blob_exists, blob_size, read_store_config, CancellationToken, Cancelled, DeserializeError,
+    Durability, FileParseError, StoreConfig,
+};
+#[cfg(feature = "std")]
+pub use crate::config::{BlobStore, BuildError, Ditiear, DitiearBuilder, DitiearError};
+pub use crate::delta::{
+    apply_binary_patch, compose_ops, decode_ops, encode_ops, BytesPatch, DeltaError,
+};
+#[cfg(feature = "std")]
+pub use crate::diff::{
+    compare_blob_files, compare_blob_files_cancellable, compare_blob_files_cross,
+    compare_blob_files_streaming, compare_blob_files_with, compare_tree_to_root, diff_to_json,
+    read_directory_blob, DiffCollectionType, DiffFileType, DirectoryEntry, DirectoryListing,
+    Traversal, Utf8Mode, WorkingChange,
+};
+#[cfg(feature = "std")]
+pub use crate::files::{apply_file_patch, create_patch_from_files, FileChange, FileSource};
+#[cfg(feature = "parallel")]
+pub use crate::hash::create_directory_blob_file_par;
+#[cfg(feature = "binaryBlob")]
+pub use crate::hash::write_directory_blob_binary;
+#[cfg(feature = "std")]
 pub use crate::hash::{
-    calculate_file_hash, create_directory_blob_file, create_directory_blob_file_rec,
+    calculate_file_hash, calculate_file_hash_with, create_directory_blob_file,
+    create_directory_blob_file_rec, create_directory_blob_file_rec_with_progress,
+    create_directory_blob_file_with_ignore, create_directory_blob_file_with_options,
+    files_identical, hash_directory, plan_blob_directory, recover_directory, BlobOptions, BlobPlan,
+    BlobReport, HashAlgorithm, HashProgress, VirtualNode, VirtualTree,
+};
+#[cfg(feature = "std")]
+pub use crate::index::{read_path_index, PathIndex};
+#[cfg(feature = "std")]
+pub use crate::inspect::{
+    detect_patch_conflicts, inspect_patch, patch_tree, Conflict, PatchSummary, PatchTree,
+};
+#[cfg(feature = "std")]
+pub use crate::manifest::{
+    collect_garbage, export_manifest, find_duplicate_files, fsck, gc, import_manifest,
+    missing_blobs_for_root, verify_replica, verify_store, version_size, GcStats, IntegrityError,
+    ManifestImport, ReplicaIssue,
 };
-use crate::patch::ZipFileError;
+#[cfg(feature = "std")]
+pub use crate::merge::{compose_patches, merge_subtree_patches};
+#[cfg(feature = "std")]
+#[allow(deprecated)]
+pub use crate::patch::write_zip_patch_to;
+#[cfg(feature = "std")]
 pub use crate::patch::{
-    apply_patchs, calculate_binary_diff, create_zip_patch, unpack_patch, BytesPatch,
+    apply_loose_patch, apply_patchs, apply_patchs_verified, benchmark_diff, calculate_binary_diff,
+    calculate_binary_diff_bounded, calculate_binary_diff_rolling,
+    calculate_binary_diff_with_algorithm, calculate_binary_diff_with_progress,
+    coalesce_binary_diff, content_changed, create_zip_patch, create_zip_patch_to_writer,
+    create_zip_patch_with_options, create_zip_patch_with_progress, create_zip_patch_with_provider,
+    create_zip_patch_with_stats, estimate_delta, invert_patch, missing_add_blobs,
+    patch_fingerprint, read_patch_manifest, read_patch_metadata, render_text_diff, unpack_patch,
+    unpack_patch_from_reader, unpack_patch_with_limits, verify_patch_apply, write_loose_patch,
+    ApplyOptions, BinaryDiffOptions, DeltaEstimate, DiffAlgorithm, DiffMode, IndexEncoding,
+    MissingDeletePolicy, PatchManifest, PatchMetadata, PatchOptions, PatchStats, PatchWarning,
+    ReadErrorPolicy, Transfer, UnpackLimits, VerifyFailure, ZipFileError,
 };
+#[cfg(feature = "std")]
+pub use crate::signature::{
+    calculate_block_diff, calculate_block_diff_from, calculate_signature, read_signature, Signature,
+};
+#[cfg(feature = "std")]
+pub use crate::stats::{recompute_store_stats, store_stats, StoreStats};
+#[cfg(feature = "timings")]
+pub use crate::timings::{collect_timings, Timings};
+#[cfg(all(feature = "xattr", unix))]
+pub use crate::xattrs::{
+    capture_xattrs, diff_xattrs, read_xattrs, restore_xattrs, XattrChange, XattrManifest,
+};
+#[cfg(feature = "std")]
+pub use zip::CompressionMethod;
 
 /// Create a patch file from two blobs
+#[cfg(feature = "std")]
 pub fn create_diff_patch<P: AsRef<Path>>(
     old: &str,
     new: &str,
@@ -19,3 +96,46 @@ pub fn create_diff_patch<P: AsRef<Path>>(
     let diffs = compare_blob_files(old, new, from_dir.as_ref())?;
     create_zip_patch(diffs, from_dir, to_dest)
 }
+
+/// Like [`create_diff_patch`], diffing and writing the patch on a blocking thread of the
+/// current tokio runtime.
+///
+/// The work starts once the returned future is first polled, which must happen inside a tokio
+/// runtime, and awaiting it does not block the runtime. The patch is written straight to
+/// `to_dest` while the blobs are diffed, a panic of the work is resumed by the future.
+#[cfg(feature = "async")]
+pub fn create_diff_patch_async<P: AsRef<Path>>(
+    old: &str,
+    new: &str,
+    from_dir: P,
+    to_dest: P,
+) -> impl Future<Output = Result<(), ZipFileError>> {
+    let (old, new) = (old.to_string(), new.to_string());
+    let (from_dir, to_dest) = (
+        from_dir.as_ref().to_path_buf(),
+        to_dest.as_ref().to_path_buf(),
+    );
+    async move {
+        let task =
+            tokio::task::spawn_blocking(move || create_diff_patch(&old, &new, &from_dir, &to_dest));
+        match task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // the runtime shut down before the work ran
+            Err(e) => Err(ZipFileError::Io(std::io::Error::other(e))),
+        }
+    }
+}
+
+/// Create a patch of two blobs as loose objects in the directory `out_dir`, see
+/// [`write_loose_patch`].
+#[cfg(feature = "std")]
+pub fn create_loose_patch<P: AsRef<Path>, Q: AsRef<Path>>(
+    old: &str,
+    new: &str,
+    from_dir: P,
+    out_dir: Q,
+) -> Result<(), ZipFileError> {
+    let diffs = compare_blob_files(old, new, from_dir.as_ref())?;
+    write_loose_patch(diffs, from_dir, out_dir)
+}
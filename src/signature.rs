@@ -0,0 +1,259 @@
+use crate::common::StoreConfig;
+use crate::delta::BytesPatch;
+use crate::hash::temp_blob_path;
+use crate::index::META_DIR;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use twox_hash::XxHash64;
+
+const SIGNATURES_DIR: &str = "signatures";
+
+/// Block size of the signatures stored while blobbing.
+pub(crate) const SIGNATURE_BLOCK_SIZE: usize = 1024;
+
+/// Checksums of the fixed size blocks of a buffer, like an rsync signature file.
+///
+/// A block diff against a signature finds the blocks of the new buffer that the old one
+/// already holds without scanning the old buffer, see [`calculate_block_diff`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    block_size: usize,
+    /// Rolling and strong checksum of every full block, in order.
+    blocks: Vec<(u32, u64)>,
+}
+
+impl Signature {
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Number of full blocks, a shorter tail is not part of the signature.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// Adler style checksum that can be moved along a buffer one byte at a time.
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut rolling = Rolling {
+            a: 0,
+            b: 0,
+            len: block.len() as u32,
+        };
+        for (i, byte) in block.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(*byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((block.len() - i) as u32 * *byte as u32);
+        }
+        rolling
+    }
+
+    /// Drop `out` from the front of the window and append `next` to it.
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> u64 {
+    let mut hasher = XxHash64::default();
+    hasher.write(block);
+    hasher.finish()
+}
+
+/// Compute the signature of `data` with blocks of `block_size` bytes.
+pub fn calculate_signature(data: &[u8], block_size: usize) -> Signature {
+    assert!(block_size > 0, "block size must not be zero");
+    Signature {
+        block_size,
+        blocks: data
+            .chunks_exact(block_size)
+            .map(|block| (Rolling::new(block).digest(), strong(block)))
+            .collect(),
+    }
+}
+
+/// Diff `old` against `new` by matching the blocks of `signature`, the signature of `old`.
+///
+/// Only the blocks of the new buffer are checksummed, `old` is sliced for the bytes the ops
+/// remove but never scanned. Matches keep the order of the old buffer, so the ops apply with
+/// [`apply_binary_patch`](crate::prelude::apply_binary_patch) like the ops of
+/// [`calculate_binary_diff`](crate::prelude::calculate_binary_diff), although usually less
+/// minimal.
+pub fn calculate_block_diff(old: Bytes, new: Bytes, signature: &Signature) -> Vec<BytesPatch> {
+    let read_old = |start: usize, end: usize| Ok(old.slice(start..end));
+    block_diff(old.len(), new, signature, read_old).expect("slicing a buffer never fails")
+}
+
+/// Like [`calculate_block_diff`], reading from `old` only the bytes the ops remove.
+///
+/// With a stored signature, see [`read_signature`], the old blob is opened but only the parts
+/// of it that changed are read.
+pub fn calculate_block_diff_from<R: Read + Seek>(
+    mut old: R,
+    new: Bytes,
+    signature: &Signature,
+) -> io::Result<Vec<BytesPatch>> {
+    let old_len = old.seek(SeekFrom::End(0))?;
+    let old_len = usize::try_from(old_len).map_err(io::Error::other)?;
+    block_diff(old_len, new, signature, |start, end| {
+        let mut buffer = vec![0; end - start];
+        old.seek(SeekFrom::Start(start as u64))?;
+        old.read_exact(&mut buffer)?;
+        Ok(Bytes::from(buffer))
+    })
+}
+
+/// Block diff of an old buffer of `old_len` bytes, whose bytes `start..end` `read_old` returns.
+fn block_diff<F>(
+    old_len: usize,
+    new: Bytes,
+    signature: &Signature,
+    mut read_old: F,
+) -> io::Result<Vec<BytesPatch>>
+where
+    F: FnMut(usize, usize) -> io::Result<Bytes>,
+{
+    let size = signature.block_size;
+    let weak = signature
+        .blocks
+        .iter()
+        .map(|(weak, _)| *weak)
+        .collect::<HashSet<_>>();
+    // blocks are pushed in order, so every list is sorted
+    let mut candidates: HashMap<(u32, u64), Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks.iter().enumerate() {
+        candidates.entry(*block).or_default().push(i);
+    }
+    let mut ops = vec![];
+    let (mut old_pos, mut literal, mut i) = (0, 0, 0);
+    let mut rolling = new.get(..size).map(Rolling::new);
+    while let Some(mut window) = rolling {
+        let digest = window.digest();
+        // the strong checksum is only taken once the weak one matched some block
+        let matched = weak
+            .contains(&digest)
+            .then(|| candidates.get(&(digest, strong(&new[i..i + size]))))
+            .flatten()
+            .and_then(|blocks| {
+                // the first block at or after the old position, matches must not cross
+                let first = blocks.partition_point(|j| j * size < old_pos);
+                blocks.get(first).copied()
+            });
+        match matched {
+            Some(j) => {
+                let removed = read_old(old_pos, j * size)?;
+                push_gap(
+                    &mut ops,
+                    (old_pos, removed),
+                    (literal, new.slice(literal..i)),
+                );
+                old_pos = (j + 1) * size;
+                i += size;
+                literal = i;
+                rolling = new.get(i..i + size).map(Rolling::new);
+            }
+            None if i + size < new.len() => {
+                window.roll(new[i], new[i + size]);
+                rolling = Some(window);
+                i += 1;
+            }
+            None => break,
+        }
+    }
+    let removed = read_old(old_pos, old_len)?;
+    push_gap(
+        &mut ops,
+        (old_pos, removed),
+        (literal, new.slice(literal..)),
+    );
+    Ok(ops)
+}
+
+/// Push the op replacing `old_value` at `old_index` with `new_value` at `new_index`, nothing
+/// when both are empty.
+fn push_gap(
+    ops: &mut Vec<BytesPatch>,
+    (old_index, old_value): (usize, Bytes),
+    (new_index, new_value): (usize, Bytes),
+) {
+    ops.extend(match (old_value.is_empty(), new_value.is_empty()) {
+        (true, true) => None,
+        (true, false) => Some(BytesPatch::Add {
+            old_index,
+            new_index,
+            new_value,
+        }),
+        (false, true) => Some(BytesPatch::Delete {
+            old_index,
+            new_index,
+            old_value,
+        }),
+        (false, false) => Some(BytesPatch::Replace {
+            old_index,
+            new_index,
+            old_value,
+            new_value,
+        }),
+    });
+}
+
+fn signature_file(hash: &str, base: &Path) -> PathBuf {
+    base.join(META_DIR).join(SIGNATURES_DIR).join(hash)
+}
+
+/// Store the signature of the blob `hash` in the signature sidecar, unless it is there already.
+pub(crate) fn store_signature(hash: &str, base: &Path, store: &StoreConfig) -> io::Result<()> {
+    let file = signature_file(hash, base);
+    if file.exists() {
+        return Ok(());
+    }
+    let content = fs::read(store.path_from_hash(hash, base)?)?;
+    let signature = calculate_signature(&content, SIGNATURE_BLOCK_SIZE);
+    fs::create_dir_all(file.parent().unwrap())?;
+    let encoded = bincode::serialize(&signature).map_err(io::Error::other)?;
+    // renamed into place, so a concurrent reader never sees a partial signature
+    let tmp = temp_blob_path(base)?;
+    fs::write(&tmp, encoded)?;
+    fs::rename(tmp, file)
+}
+
+/// Remove the stored signature of a blob that left the store.
+pub(crate) fn remove_signature(hash: &str, base: &Path) -> io::Result<()> {
+    match fs::remove_file(signature_file(hash, base)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Read the signature stored for the blob `hash` while blobbing with
+/// [`BlobOptions::store_signatures`](crate::prelude::BlobOptions::store_signatures).
+pub fn read_signature<P: AsRef<Path>>(hash: &str, base: P) -> io::Result<Signature> {
+    let content = fs::read(signature_file(hash, base.as_ref()))?;
+    bincode::deserialize(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,316 @@
+use crate::common::Durability;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Prefix of the name of every staging directory.
+const STAGING_PREFIX: &str = ".ditiear-apply-";
+/// File in a staging directory its owner holds locked for as long as it lives.
+const LOCK_FILE: &str = "lock";
+/// File in a staging directory listing the changes of a commit in progress.
+const JOURNAL_FILE: &str = "journal";
+
+/// A change held back until [`Staging::commit`].
+enum Staged {
+    /// Rename the staged file to the destination.
+    Write(PathBuf),
+    /// Remove the destination.
+    Remove,
+}
+
+/// One change of a commit as recorded in the journal.
+///
+/// The destination is absolute, the staged file is named relative to the staging directory.
+/// The previous destination of change `i` is moved to [`backup_name`]`(i)`.
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    dest: PathBuf,
+    staged: Option<String>,
+}
+
+fn backup_name(i: usize) -> String {
+    format!("backup-{}", i)
+}
+
+/// Files written while applying a patch, collected in a directory of their own and only moved
+/// into place once the whole patch applied.
+///
+/// Dropping a staging without committing it removes everything staged and leaves the
+/// destinations untouched. A commit first writes a journal of its changes, a process dying
+/// halfway through leaves the journal behind and the next staging in the same parent rolls
+/// the destinations back to how they were.
+pub(crate) struct Staging {
+    dir: PathBuf,
+    durability: Durability,
+    next: usize,
+    changes: Vec<(PathBuf, Staged)>,
+    /// Staged file of every destination written, the last write wins.
+    written: HashMap<PathBuf, PathBuf>,
+    /// Lock file held while the staging directory exists, so no other staging recovers it.
+    lock: Option<fs::File>,
+}
+
+impl Staging {
+    /// Start a staging in a fresh directory below `parent`, which must be on the same file
+    /// system as the destinations. The directory is only created once something is staged.
+    ///
+    /// Stagings left below `parent` by a process that died are recovered first.
+    pub(crate) fn new(parent: &Path, durability: Durability) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        recover(parent)?;
+        let dir = parent.join(format!(
+            "{}{}-{}",
+            STAGING_PREFIX,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(Staging {
+            dir,
+            durability,
+            next: 0,
+            changes: vec![],
+            written: HashMap::new(),
+            lock: None,
+        })
+    }
+
+    fn next_path(&mut self) -> io::Result<PathBuf> {
+        if self.lock.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let lock = fs::File::create(self.dir.join(LOCK_FILE))?;
+            lock.lock()?;
+            self.lock = Some(lock);
+        }
+        self.next += 1;
+        Ok(self.dir.join(self.next.to_string()))
+    }
+
+    /// Write the future content of `dest` with `write` to a staged file, returning its size.
+    pub(crate) fn write<F, E>(&mut self, dest: &Path, write: F) -> Result<u64, E>
+    where
+        F: FnOnce(&Path) -> Result<(), E>,
+        E: From<io::Error>,
+    {
+        let tmp = self.next_path()?;
+        write(&tmp)?;
+        self.durability.sync_data(&tmp)?;
+        let size = fs::metadata(&tmp)?.len();
+        self.written.insert(dest.to_path_buf(), tmp.clone());
+        self.changes.push((dest.to_path_buf(), Staged::Write(tmp)));
+        Ok(size)
+    }
+
+    /// Remove `dest` once committed.
+    pub(crate) fn remove(&mut self, dest: &Path) {
+        self.written.remove(dest);
+        self.changes.push((dest.to_path_buf(), Staged::Remove));
+    }
+
+    /// The staged file that will become `dest`, if any.
+    pub(crate) fn staged(&self, dest: &Path) -> Option<&Path> {
+        self.written.get(dest).map(PathBuf::as_path)
+    }
+
+    /// Path to read the current content of `dest` from, the staged file when there is one.
+    pub(crate) fn resolve(&self, dest: &Path) -> PathBuf {
+        self.staged(dest).unwrap_or(dest).to_path_buf()
+    }
+
+    /// Move every staged change into place, in the order it was staged.
+    ///
+    /// Replaced and removed destinations are kept in the staging directory until all changes
+    /// are in place, so a failing rename puts back every destination changed before it. The
+    /// journal is removed once the last change is in place, that is the point where the
+    /// commit counts as done.
+    pub(crate) fn commit(mut self) -> io::Result<()> {
+        let changes = std::mem::take(&mut self.changes);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let journal = changes
+            .iter()
+            .map(|(dest, change)| {
+                let staged = match change {
+                    Staged::Write(tmp) => Some(tmp.file_name().unwrap().to_string_lossy().into()),
+                    Staged::Remove => None,
+                };
+                Ok(JournalEntry {
+                    dest: std::path::absolute(dest)?,
+                    staged,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.write_journal(&journal)?;
+        for (i, (dest, change)) in changes.into_iter().enumerate() {
+            if let Err(e) = self.commit_one(i, &dest, change) {
+                let _ = roll_back(&self.dir, &journal);
+                let _ = fs::remove_file(self.dir.join(JOURNAL_FILE));
+                return Err(e);
+            }
+        }
+        fs::remove_file(self.dir.join(JOURNAL_FILE))?;
+        self.durability.sync_parent(&self.dir.join(JOURNAL_FILE))
+    }
+
+    fn write_journal(&mut self, journal: &[JournalEntry]) -> io::Result<()> {
+        let encoded = bincode::serialize(journal).map_err(io::Error::other)?;
+        // renamed into place, a journal is never read half written
+        let tmp = self.next_path()?;
+        fs::write(&tmp, encoded)?;
+        self.durability.sync_data(&tmp)?;
+        let file = self.dir.join(JOURNAL_FILE);
+        fs::rename(tmp, &file)?;
+        self.durability.sync_parent(&file)
+    }
+
+    /// Apply change `i`, moving the previous destination to its backup.
+    fn commit_one(&mut self, i: usize, dest: &Path, change: Staged) -> io::Result<()> {
+        match fs::symlink_metadata(dest) {
+            Ok(_) => fs::rename(dest, self.dir.join(backup_name(i)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Staged::Write(tmp) = change {
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::rename(&tmp, dest)?;
+            self.durability.sync_parent(dest)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        // some platforms cannot remove a file that is open
+        self.lock.take();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Undo the changes of a commit that got as far as the files in `dir` show, last one first.
+///
+/// A write whose staged file is gone has been renamed to its destination, a backup that still
+/// exists has not been put back.
+fn roll_back(dir: &Path, journal: &[JournalEntry]) -> io::Result<()> {
+    for (i, entry) in journal.iter().enumerate().rev() {
+        if let Some(staged) = &entry.staged {
+            if !dir.join(staged).exists() {
+                remove_path(&entry.dest)?;
+            }
+        }
+        let backup = dir.join(backup_name(i));
+        if fs::symlink_metadata(&backup).is_ok() {
+            fs::create_dir_all(entry.dest.parent().unwrap())?;
+            fs::rename(backup, &entry.dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recover the stagings below `parent` whose owner is gone, rolling back a commit that did not
+/// finish and removing what was staged.
+///
+/// A staging still locked by a live owner is left alone.
+fn recover(parent: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(parent) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        let is_staging = dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(STAGING_PREFIX));
+        // a staging takes its lock right after creating its directory
+        let Ok(lock) = fs::File::open(dir.join(LOCK_FILE)) else {
+            continue;
+        };
+        if !is_staging || lock.try_lock().is_err() {
+            continue;
+        }
+        drop(lock);
+        match fs::read(dir.join(JOURNAL_FILE)) {
+            Ok(journal) => {
+                let journal: Vec<JournalEntry> = bincode::deserialize(&journal)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                roll_back(&dir, &journal)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupted_commit_is_rolled_back() {
+        let parent = tempfile::tempdir().unwrap();
+        let tree = parent.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join("a.txt"), b"old a").unwrap();
+        fs::write(tree.join("b.txt"), b"old b").unwrap();
+
+        let mut staging = Staging::new(parent.path(), Durability::None).unwrap();
+        staging
+            .write(&tree.join("a.txt"), |tmp| fs::write(tmp, b"new a"))
+            .unwrap();
+        staging.remove(&tree.join("b.txt"));
+        staging
+            .write(&tree.join("c.txt"), |tmp| fs::write(tmp, b"new c"))
+            .unwrap();
+        // commit the first two changes and die before the third
+        let changes = std::mem::take(&mut staging.changes);
+        let journal = changes
+            .iter()
+            .map(|(dest, change)| JournalEntry {
+                dest: dest.clone(),
+                staged: match change {
+                    Staged::Write(tmp) => Some(tmp.file_name().unwrap().to_str().unwrap().into()),
+                    Staged::Remove => None,
+                },
+            })
+            .collect::<Vec<_>>();
+        staging.write_journal(&journal).unwrap();
+        for (i, (dest, change)) in changes.into_iter().enumerate().take(2) {
+            staging.commit_one(i, &dest, change).unwrap();
+        }
+        assert_eq!(fs::read(tree.join("a.txt")).unwrap(), b"new a");
+        assert!(!tree.join("b.txt").exists());
+        staging.lock.take();
+        std::mem::forget(staging);
+
+        // a staging of another process still holding its lock is left alone
+        let live = parent.path().join(format!("{}live", STAGING_PREFIX));
+        fs::create_dir(&live).unwrap();
+        let lock = fs::File::create(live.join(LOCK_FILE)).unwrap();
+        lock.lock().unwrap();
+
+        let _next = Staging::new(parent.path(), Durability::None).unwrap();
+        assert_eq!(fs::read(tree.join("a.txt")).unwrap(), b"old a");
+        assert_eq!(fs::read(tree.join("b.txt")).unwrap(), b"old b");
+        assert!(!tree.join("c.txt").exists());
+        let left = fs::read_dir(parent.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&live) && left.contains(&tree));
+    }
+}
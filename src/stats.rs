@@ -0,0 +1,236 @@
+use crate::common::{
+    ensure_store, is_valid_hash, read_store_config, Durability, FileParseError, StoreConfig,
+};
+use crate::index::META_DIR;
+use crate::manifest::version_size_in;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const STATS_FILE: &str = "stats";
+
+/// Running totals of a store, kept in a sidecar that blobbing, applying and garbage collection
+/// update as they go.
+///
+/// Versions are the roots blobbed into or applied to the store, the logical size of a version
+/// is its [`version_size`](crate::prelude::version_size).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of blobs in the store.
+    pub blobs: u64,
+    /// Summed size of every blob in the store.
+    pub bytes: u64,
+    versions: BTreeMap<String, u64>,
+}
+
+impl StoreStats {
+    fn file(base: &Path) -> PathBuf {
+        base.join(META_DIR).join(STATS_FILE)
+    }
+
+    /// Load the sidecar of a store, an absent sidecar is rebuilt from a full scan.
+    pub(crate) fn load(base: &Path, store: &StoreConfig) -> io::Result<Self> {
+        let content = match fs::read_to_string(Self::file(base)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Self::recompute(base, store, [])
+            }
+            Err(e) => return Err(e),
+        };
+        let mut stats = StoreStats::default();
+        for line in content.lines() {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid stats line");
+            let mut parts = line.split(' ');
+            let number = |part: Option<&str>| -> io::Result<u64> {
+                part.and_then(|p| p.parse().ok()).ok_or_else(invalid)
+            };
+            match parts.next() {
+                Some("blobs") => stats.blobs = number(parts.next())?,
+                Some("bytes") => stats.bytes = number(parts.next())?,
+                Some("version") => {
+                    let root = parts.next().ok_or_else(invalid)?.to_string();
+                    stats.versions.insert(root, number(parts.next())?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Write the sidecar aside and rename it into place, so a crash never leaves it half
+    /// written.
+    pub(crate) fn save(&self, base: &Path, durability: Durability) -> io::Result<()> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let file = Self::file(base);
+        fs::create_dir_all(file.parent().unwrap())?;
+        let tmp = file.with_file_name(format!(
+            "{}.{}-{}",
+            STATS_FILE,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = (|| {
+            let mut writer = io::BufWriter::new(fs::File::create(&tmp)?);
+            writeln!(writer, "blobs {}", self.blobs)?;
+            writeln!(writer, "bytes {}", self.bytes)?;
+            for (root, size) in &self.versions {
+                writeln!(writer, "version {} {}", root, size)?;
+            }
+            writer.flush()?;
+            drop(writer);
+            durability.sync_data(&tmp)?;
+            fs::rename(&tmp, &file)
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written?;
+        durability.sync_parent(&file)
+    }
+
+    /// Roots of the version lines of the sidecar that still parse, for a sidecar that does
+    /// not load as a whole.
+    fn salvage_versions(base: &Path) -> Vec<String> {
+        let Ok(content) = fs::read(Self::file(base)) else {
+            return vec![];
+        };
+        String::from_utf8_lossy(&content)
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split(' ');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some("version"), Some(root), Some(size), None)
+                        if is_valid_hash(root) && size.parse::<u64>().is_ok() =>
+                    {
+                        Some(root.to_string())
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Scan the whole store, keeping the given versions that are still stored.
+    fn recompute<I: IntoIterator<Item = String>>(
+        base: &Path,
+        store: &StoreConfig,
+        versions: I,
+    ) -> io::Result<Self> {
+        let mut stats = StoreStats::default();
+        // A store blobbed into for the first time is created by its first write.
+        if !base.exists() {
+            return Ok(stats);
+        }
+        for (_, path) in store.list_blobs(base)? {
+            stats.added(fs::metadata(path)?.len());
+        }
+        for root in versions {
+            if store.blob_exists(&root, base) {
+                stats.add_version(&root, base, store)?;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Count a blob of `size` bytes that was written to the store.
+    pub(crate) fn added(&mut self, size: u64) {
+        self.blobs += 1;
+        self.bytes += size;
+    }
+
+    /// Count a blob of `size` bytes that was removed from the store.
+    pub(crate) fn removed(&mut self, size: u64) {
+        self.blobs = self.blobs.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(size);
+    }
+
+    /// Add the counts of `other`, taken on a separate thread, to these.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn merge(&mut self, other: &StoreStats) {
+        self.blobs += other.blobs;
+        self.bytes += other.bytes;
+        for (root, size) in &other.versions {
+            self.versions.entry(root.clone()).or_insert(*size);
+        }
+    }
+
+    /// Record the root `root` as a version, its size is only computed the first time.
+    pub(crate) fn add_version(
+        &mut self,
+        root: &str,
+        base: &Path,
+        store: &StoreConfig,
+    ) -> io::Result<()> {
+        if !self.versions.contains_key(root) {
+            let size = version_size_in(root, base, store).map_err(into_io)?;
+            self.versions.insert(root.to_string(), size);
+        }
+        Ok(())
+    }
+
+    /// Forget the versions whose root blob is gone.
+    pub(crate) fn retain_stored_versions(&mut self, base: &Path, store: &StoreConfig) {
+        self.versions
+            .retain(|root, _| store.blob_exists(root, base));
+    }
+
+    /// Root blob of every version recorded in the store, sorted.
+    pub(crate) fn roots(&self) -> impl Iterator<Item = &str> {
+        self.versions.keys().map(String::as_str)
+    }
+
+    /// Number of versions recorded in the store.
+    pub fn versions(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// Summed size of every version, what the store would take without sharing blobs between
+    /// versions.
+    pub fn logical_bytes(&self) -> u64 {
+        self.versions.values().sum()
+    }
+
+    /// Ratio of the logical size of all versions to the bytes actually stored, `1.0` for an
+    /// empty store.
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 1.0,
+            bytes => self.logical_bytes() as f64 / bytes as f64,
+        }
+    }
+}
+
+fn into_io(e: FileParseError) -> io::Error {
+    match e {
+        FileParseError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Read the statistics of the store at `base` from its sidecar.
+pub fn store_stats<P: AsRef<Path>>(base: P) -> io::Result<StoreStats> {
+    StoreStats::load(base.as_ref(), &read_store_config(base.as_ref())?)
+}
+
+/// Rebuild the statistics sidecar of the store at `base` from a full scan of the store.
+///
+/// This repairs a sidecar that went out of sync, for example after blobs were removed by hand.
+/// Only versions known to the old sidecar can be kept, the store itself does not tell roots
+/// apart from other directories. The version lines of a damaged sidecar that still parse are
+/// kept as well.
+pub fn recompute_store_stats<P: AsRef<Path>>(base: P) -> io::Result<StoreStats> {
+    let base = base.as_ref();
+    ensure_store(base).map_err(into_io)?;
+    let store = read_store_config(base)?;
+    let versions = match fs::metadata(StoreStats::file(base)) {
+        Ok(_) => StoreStats::load(base, &store)
+            .map(|stats| stats.versions.into_keys().collect())
+            .unwrap_or_else(|_| StoreStats::salvage_versions(base)),
+        Err(_) => vec![],
+    };
+    let stats = StoreStats::recompute(base, &store, versions)?;
+    stats.save(base, Durability::None)?;
+    Ok(stats)
+}
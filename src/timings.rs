@@ -0,0 +1,77 @@
+#[cfg(feature = "timings")]
+use std::cell::RefCell;
+#[cfg(feature = "timings")]
+use std::time::{Duration, Instant};
+
+/// Phase of the pipeline an operation is accounted to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Phase {
+    Hashing,
+    Sorting,
+    Reading,
+    Diffing,
+    Writing,
+}
+
+/// Time spent per phase while running the closure of [`collect_timings`].
+#[cfg(feature = "timings")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Hashing file contents and directory blobs.
+    pub hashing: Duration,
+    /// Sorting directory entries.
+    pub sorting: Duration,
+    /// Reading and parsing directory blobs while diffing.
+    pub reading: Duration,
+    /// Computing binary diffs of modified files.
+    pub diffing: Duration,
+    /// Writing patch archives.
+    pub writing: Duration,
+}
+
+#[cfg(feature = "timings")]
+thread_local! {
+    static ACTIVE: RefCell<Option<Timings>> = const { RefCell::new(None) };
+}
+
+/// Run `f` and return its result with the time the pipeline functions it called on this
+/// thread spent in each phase.
+#[cfg(feature = "timings")]
+pub fn collect_timings<R>(f: impl FnOnce() -> R) -> (R, Timings) {
+    let outer = ACTIVE.with(|active| active.replace(Some(Timings::default())));
+    let result = f();
+    let timings = ACTIVE
+        .with(|active| active.replace(outer))
+        .unwrap_or_default();
+    (result, timings)
+}
+
+/// Run `f`, accounting its duration to `phase` when timings are being collected.
+#[cfg(feature = "timings")]
+pub(crate) fn timed<R>(phase: Phase, f: impl FnOnce() -> R) -> R {
+    if ACTIVE.with(|active| active.borrow().is_none()) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    ACTIVE.with(|active| {
+        if let Some(timings) = active.borrow_mut().as_mut() {
+            let slot = match phase {
+                Phase::Hashing => &mut timings.hashing,
+                Phase::Sorting => &mut timings.sorting,
+                Phase::Reading => &mut timings.reading,
+                Phase::Diffing => &mut timings.diffing,
+                Phase::Writing => &mut timings.writing,
+            };
+            *slot += elapsed;
+        }
+    });
+    result
+}
+
+#[cfg(not(feature = "timings"))]
+#[inline(always)]
+pub(crate) fn timed<R>(_phase: Phase, f: impl FnOnce() -> R) -> R {
+    f()
+}
@@ -0,0 +1,268 @@
+use crate::common::{check_entry_name, read_store_config, Durability};
+use crate::hash::relative_path;
+use crate::index::record_auxiliary_blob;
+use crate::stats::StoreStats;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Extended attributes of a tree, keyed by path relative to the tree root and attribute name.
+///
+/// Attributes are kept out of the directory blobs so they never change a content hash, a
+/// manifest is stored as its own blob next to the tree it was captured from.
+pub type XattrManifest = BTreeMap<String, BTreeMap<String, Vec<u8>>>;
+
+/// An attribute that differs between two manifests.
+#[derive(Debug, PartialEq, Eq)]
+pub struct XattrChange {
+    pub path: String,
+    pub name: String,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+/// Capture the extended attributes of every file below `from_path` into a manifest blob and
+/// return the manifest hash.
+pub fn capture_xattrs<P: AsRef<Path>, Q: AsRef<Path>>(from_path: P, base: Q) -> io::Result<String> {
+    let mut manifest = XattrManifest::new();
+    collect(from_path.as_ref(), from_path.as_ref(), &mut manifest)?;
+    let content = encode(&manifest);
+    let store = read_store_config(base.as_ref())?;
+    let mut hasher = store.hash_algorithm.hasher();
+    hasher.update(content.as_bytes());
+    let hash = hasher.finish_hex();
+    let path = store.path_from_hash(&hash, base.as_ref())?;
+    if !path.exists() {
+        let mut stats = StoreStats::load(base.as_ref(), &store)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, &content)?;
+        stats.added(content.len() as u64);
+        stats.save(base.as_ref(), Durability::None)?;
+    }
+    // no root reaches the manifest, garbage collection would remove it otherwise
+    record_auxiliary_blob(base.as_ref(), &hash)?;
+    Ok(hash)
+}
+
+fn collect(root: &Path, dir: &Path, manifest: &mut XattrManifest) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, manifest)?;
+            continue;
+        }
+        let mut attrs = BTreeMap::new();
+        for name in xattr::list(&path)? {
+            if let Some(value) = xattr::get(&path, &name)? {
+                attrs.insert(name.to_string_lossy().to_string(), value);
+            }
+        }
+        if !attrs.is_empty() {
+            manifest.insert(relative_path(root, &path), attrs);
+        }
+    }
+    Ok(())
+}
+
+/// Read a manifest blob written by [`capture_xattrs`].
+pub fn read_xattrs<P: AsRef<Path>>(hash: &str, base: P) -> io::Result<XattrManifest> {
+    let path = read_store_config(base.as_ref())?.path_from_hash(hash, base)?;
+    let mut manifest = XattrManifest::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let mut parts = line.splitn(3, '\t');
+        let (Some(path), Some(name), Some(value)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid xattr manifest line",
+            ));
+        };
+        manifest
+            .entry(unescape(path))
+            .or_default()
+            .insert(unescape(name), decode_hex(value)?);
+    }
+    Ok(manifest)
+}
+
+/// List the attributes that were added, removed or changed between two manifests.
+pub fn diff_xattrs<P: AsRef<Path>>(old: &str, new: &str, base: P) -> io::Result<Vec<XattrChange>> {
+    let old = read_xattrs(old, base.as_ref())?;
+    let new = read_xattrs(new, base.as_ref())?;
+    let empty = BTreeMap::new();
+    let mut changes = vec![];
+    for path in old
+        .keys()
+        .chain(new.keys().filter(|p| !old.contains_key(*p)))
+    {
+        let old_attrs = old.get(path).unwrap_or(&empty);
+        let new_attrs = new.get(path).unwrap_or(&empty);
+        for name in old_attrs
+            .keys()
+            .chain(new_attrs.keys().filter(|n| !old_attrs.contains_key(*n)))
+        {
+            let (o, n) = (old_attrs.get(name), new_attrs.get(name));
+            if o != n {
+                changes.push(XattrChange {
+                    path: path.clone(),
+                    name: name.clone(),
+                    old: o.cloned(),
+                    new: n.cloned(),
+                });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Set the attributes of a manifest on the files of a checked out tree at `dest`.
+///
+/// A manifest path that is not a plain relative path, or that leads through a symbolic link,
+/// fails with [`io::ErrorKind::InvalidData`] before its attributes are set.
+pub fn restore_xattrs<P: AsRef<Path>, Q: AsRef<Path>>(
+    hash: &str,
+    base: P,
+    dest: Q,
+) -> io::Result<()> {
+    for (path, attrs) in read_xattrs(hash, base)? {
+        let mut file = dest.as_ref().to_path_buf();
+        for name in path.split('/') {
+            let through_link = fs::symlink_metadata(&file).is_ok_and(|m| m.is_symlink());
+            if file != dest.as_ref() && through_link {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("xattr path {:?} leads through a link", path),
+                ));
+            }
+            let name = check_entry_name(name)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            file.push(name);
+        }
+        for (name, value) in attrs {
+            xattr::set(&file, name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode(manifest: &XattrManifest) -> String {
+    let mut content = String::new();
+    for (path, attrs) in manifest {
+        for (name, value) in attrs {
+            let value = value
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            content.push_str(&format!("{}\t{}\t{}\n", escape(path), escape(name), value));
+        }
+    }
+    content
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn decode_hex(s: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid xattr value");
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(s.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::StoreConfig;
+
+    #[test]
+    fn test_xattr_round_trip() {
+        let source = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let file = source.path().join("tagged.txt");
+        fs::write(&file, b"content").unwrap();
+        let before = crate::hash::calculate_file_hash(&file).unwrap();
+        let plain = crate::hash::create_directory_blob_file(store.path(), source.path()).unwrap();
+        let empty = capture_xattrs(source.path(), store.path()).unwrap();
+        if xattr::set(&file, "user.ditiear", b"quarantine").is_err() {
+            // the file system does not support user attributes
+            return;
+        }
+        let root = crate::hash::create_directory_blob_file(store.path(), source.path()).unwrap();
+        assert_eq!(root, plain);
+        assert_eq!(crate::hash::calculate_file_hash(&file).unwrap(), before);
+
+        let manifest = capture_xattrs(source.path(), store.path()).unwrap();
+        let changes = diff_xattrs(&empty, &manifest, store.path()).unwrap();
+        assert_eq!(
+            changes,
+            vec![XattrChange {
+                path: "tagged.txt".to_string(),
+                name: "user.ditiear".to_string(),
+                old: None,
+                new: Some(b"quarantine".to_vec()),
+            }]
+        );
+
+        fs::write(dest.path().join("tagged.txt"), b"content").unwrap();
+        restore_xattrs(&manifest, store.path(), dest.path()).unwrap();
+        assert_eq!(
+            xattr::get(dest.path().join("tagged.txt"), "user.ditiear").unwrap(),
+            Some(b"quarantine".to_vec())
+        );
+
+        // the manifest is reachable from no root but survives garbage collection
+        crate::manifest::gc(store.path(), &[&root]).unwrap();
+        assert_eq!(read_xattrs(&manifest, store.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_restore_xattrs_rejects_escaping_paths() {
+        let store = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let config = StoreConfig::default();
+        for path in ["../outside.txt", "/etc/passwd", "a//b"] {
+            let manifest = XattrManifest::from([(
+                path.to_string(),
+                BTreeMap::from([("user.ditiear".to_string(), b"x".to_vec())]),
+            )]);
+            let content = encode(&manifest);
+            let mut hasher = config.hash_algorithm.hasher();
+            hasher.update(content.as_bytes());
+            let hash = hasher.finish_hex();
+            let blob = config.path_from_hash(&hash, store.path()).unwrap();
+            fs::create_dir_all(blob.parent().unwrap()).unwrap();
+            fs::write(blob, content).unwrap();
+            let error = restore_xattrs(&hash, store.path(), dest.path()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,5 +1,10 @@
+#![cfg(feature = "std")]
 use ditiear::prelude::*;
-use std::{io::Write, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 #[test]
 fn test_calculate_binary_diff() {
@@ -26,6 +31,25 @@ fn test_calculate_file_hash() {
     }
 }
 
+#[test]
+fn test_calculate_file_hash_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("abc.txt");
+    std::fs::write(&file, "abc").unwrap();
+    assert_eq!(
+        calculate_file_hash_with(&file, HashAlgorithm::Sha256).unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        calculate_file_hash_with(&file, HashAlgorithm::Blake3).unwrap(),
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+    );
+    assert_eq!(
+        calculate_file_hash_with(&file, HashAlgorithm::XxHash64).unwrap(),
+        calculate_file_hash(&file).unwrap()
+    );
+}
+
 #[test]
 fn test_create_directory_blob_file_rec() {
     match create_directory_blob_file_rec("./tests/assets_blobs_rec", "./tests/assets") {
@@ -88,3 +112,3336 @@ fn test_unpack_patch() {
         println!("{:?}", p);
     }
 }
+
+#[test]
+fn test_empty_root_directory() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let iterative = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let recursive = create_directory_blob_file_rec(store.path(), source.path()).unwrap();
+    assert_eq!(iterative, "ef46db3751d8e999");
+    assert_eq!(iterative, recursive);
+}
+
+#[test]
+fn test_hash_progress() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("a.txt", b"aa"),
+            ("dir/b.txt", b"bbb"),
+            ("dir/sub/c.txt", b"c"),
+        ],
+    );
+    let mut reports = vec![];
+    let hash = create_directory_blob_file_rec_with_progress(store.path(), source.path(), |p| {
+        reports.push((p.files_done, p.bytes_done, p.current_path.to_path_buf()))
+    })
+    .unwrap();
+    assert_eq!(
+        hash,
+        create_directory_blob_file_rec(store.path(), source.path()).unwrap()
+    );
+    assert_eq!(reports.len(), 3);
+    assert_eq!(reports.iter().map(|r| r.0).collect::<Vec<_>>(), [1, 2, 3]);
+    assert_eq!(reports[2].1, 6);
+    let mut paths = reports.into_iter().map(|r| r.2).collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+        paths,
+        ["a.txt", "dir/b.txt", "dir/sub/c.txt"].map(|p| source.path().join(p))
+    );
+}
+
+#[test]
+fn test_skip_oversize_files() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    std::fs::write(source.path().join("small.txt"), b"small").unwrap();
+    std::fs::write(source.path().join("large.bin"), vec![7u8; 4096]).unwrap();
+    let options = BlobOptions {
+        max_file_size: Some(1024),
+        ..Default::default()
+    };
+    let report =
+        create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap();
+    assert_eq!(report.skipped, vec![source.path().join("large.bin")]);
+
+    // the tree must hash as if the oversize file was never there
+    let expected = tempfile::tempdir().unwrap();
+    std::fs::write(expected.path().join("small.txt"), b"small").unwrap();
+    let expected_hash = create_directory_blob_file(store.path(), expected.path()).unwrap();
+    assert_eq!(report.hash, expected_hash);
+    let large_hash = calculate_file_hash(source.path().join("large.bin")).unwrap();
+    assert!(!store
+        .path()
+        .join(&large_hash[..1])
+        .join(&large_hash[1..])
+        .exists());
+}
+
+fn write_tree(root: &Path, files: &[(&str, &[u8])]) {
+    for (name, content) in files {
+        let p = root.join(name);
+        std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+        std::fs::write(p, content).unwrap();
+    }
+}
+
+#[test]
+fn test_compare_moved_directory() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("a/keep.txt", b"a"),
+            ("a/sub/x.txt", b"x"),
+            ("a/sub/deep/y.txt", b"y"),
+            ("b/z.txt", b"z"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("a/keep.txt", b"a"),
+            ("b/z.txt", b"z"),
+            ("b/sub/x.txt", b"x"),
+            ("b/sub/deep/y.txt", b"y"),
+        ],
+    );
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    let moves = diffs
+        .iter()
+        .filter_map(|d| match d {
+            DiffCollectionType::MoveDirectory {
+                old_name, new_name, ..
+            } => Some((old_name.as_str(), new_name.as_str())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(moves, vec![("a/sub", "b/sub")]);
+    assert!(diffs.iter().all(|d| !matches!(
+        d,
+        DiffCollectionType::Add { .. } | DiffCollectionType::Delete { .. }
+    )));
+}
+
+#[test]
+fn test_compare_renamed_files() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let asset = vec![7u8; 64 * 1024];
+    write_tree(
+        v1.path(),
+        &[
+            ("assets/big.bin", &asset),
+            ("assets/readme.txt", b"readme"),
+            ("old/inner.txt", b"inner"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("assets/readme.txt", b"readme"),
+            ("media/big.bin", &asset),
+            ("new/place/inner.txt", b"inner"),
+        ],
+    );
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    let mut renames = diffs
+        .iter()
+        .filter_map(|d| match d {
+            DiffCollectionType::Rename {
+                r#type: DiffFileType::File,
+                old_name,
+                new_name,
+                ..
+            } => Some((old_name.as_str(), new_name.as_str())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    renames.sort();
+    assert_eq!(
+        renames,
+        [
+            ("assets/big.bin", "media/big.bin"),
+            ("old/inner.txt", "new/place/inner.txt")
+        ]
+    );
+    assert!(diffs.iter().all(|d| !matches!(
+        d,
+        DiffCollectionType::Add {
+            r#type: DiffFileType::File,
+            ..
+        } | DiffCollectionType::Delete {
+            r#type: DiffFileType::File,
+            ..
+        }
+    )));
+
+    let patch = out.path().join("patch.zip");
+    create_zip_patch(diffs, store.path(), patch.as_path()).unwrap();
+    assert!(std::fs::metadata(&patch).unwrap().len() < asset.len() as u64 / 8);
+}
+
+#[test]
+fn test_record_source_paths() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("top.txt", b"top"), ("nested/dir/leaf.txt", b"leaf")],
+    );
+    let options = BlobOptions {
+        record_paths: true,
+        ..Default::default()
+    };
+    let report =
+        create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap();
+    let leaf_hash = calculate_file_hash(source.path().join("nested/dir/leaf.txt")).unwrap();
+    let index = read_path_index(store.path()).unwrap();
+    let paths = index.paths(&leaf_hash).unwrap();
+    assert_eq!(
+        paths.iter().collect::<Vec<_>>(),
+        vec!["nested/dir/leaf.txt"]
+    );
+
+    // the sidecar is auxiliary, blobbing without it yields the same root
+    let plain = create_directory_blob_file(store.path(), source.path()).unwrap();
+    assert_eq!(report.hash, plain);
+}
+
+#[test]
+fn test_path_index_escapes_line_breaks() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a\nb.txt", b"broken"), ("back\\slash\r", b"slash")],
+    );
+    let options = BlobOptions {
+        record_paths: true,
+        ..Default::default()
+    };
+    create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap();
+    let index = read_path_index(store.path()).unwrap();
+    let mut paths = index
+        .iter()
+        .flat_map(|(_, paths)| paths.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, ["a\nb.txt", "back\\slash\r"]);
+    let broken = calculate_file_hash(source.path().join("a\nb.txt")).unwrap();
+    assert_eq!(
+        index.paths(&broken).unwrap().iter().collect::<Vec<_>>(),
+        ["a\nb.txt"]
+    );
+}
+
+#[test]
+fn test_configured_pipeline() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("readme.txt", b"version one"),
+            ("gone.txt", b"removed in v2"),
+            ("data/values.bin", &[1, 2, 3, 4, 5]),
+            ("build.tmp", b"ignored"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("readme.txt", b"version two"),
+            ("data/values.bin", &[1, 2, 3, 4, 5, 6]),
+            ("data/new.txt", b"added in v2"),
+            ("build.tmp", b"ignored, but different"),
+        ],
+    );
+    let ditiear = Ditiear::builder()
+        .hash_algorithm(HashAlgorithm::Sha256)
+        .fanout(2)
+        .compression(CompressionMethod::Stored)
+        .ignore(["*.tmp"])
+        .delete_removed(true)
+        .durability(Durability::Full)
+        .build();
+    let old = ditiear
+        .blob_directory(server.path(), v1.path())
+        .unwrap()
+        .hash;
+    let new = ditiear
+        .blob_directory(server.path(), v2.path())
+        .unwrap()
+        .hash;
+    assert_eq!(old.len(), 64);
+    assert_eq!(
+        ditiear
+            .blob_directory(client.path(), v1.path())
+            .unwrap()
+            .hash,
+        old
+    );
+
+    let patch = out.path().join("patch.zip");
+    ditiear
+        .create_patch(&old, &new, server.path(), &patch)
+        .unwrap();
+    ditiear.apply(&patch, client.path()).unwrap();
+
+    let shard = |hash: &str| client.path().join(&hash[..2]).join(&hash[2..]);
+    assert!(shard(&new).exists());
+    // blobs of removed entries are gone from the client
+    let diffs = ditiear.diff(&old, &new, client.path()).unwrap();
+    assert!(!diffs.is_empty());
+    for diff in &diffs {
+        if let DiffCollectionType::Delete { value, .. } = diff {
+            assert!(!shard(value).exists());
+        }
+    }
+}
+
+#[test]
+fn test_store_records_its_fanout() {
+    let store = tempfile::tempdir().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+    write_tree(tree.path(), &[("a.txt", b"alpha"), ("sub/b.txt", b"beta")]);
+    let wide = Ditiear::builder().fanout(2).build();
+    let root = wide.blob_directory(store.path(), tree.path()).unwrap().hash;
+    assert!(store.path().join(&root[..2]).join(&root[2..]).is_file());
+    assert_eq!(read_store_config(store.path()).unwrap().fanout, 2);
+    // blobbing again with the recorded fanout is fine
+    wide.blob_directory(store.path(), tree.path()).unwrap();
+
+    // a default store records nothing and reads back the default fanout
+    let narrow = tempfile::tempdir().unwrap();
+    create_directory_blob_file_rec(narrow.path(), tree.path()).unwrap();
+    assert!(!narrow.path().join(".ditiear/layout").exists());
+    assert_eq!(read_store_config(narrow.path()).unwrap().fanout, 1);
+
+    for ditiear in [
+        Ditiear::default(),
+        Ditiear::builder().fanout(3).build(),
+        Ditiear::builder().fanout(16).build(),
+    ] {
+        match ditiear.blob_directory(store.path(), tree.path()) {
+            Err(DitiearError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    // a fanout too long for xxHash digests is fine for longer ones
+    let sha = tempfile::tempdir().unwrap();
+    Ditiear::builder()
+        .hash_algorithm(HashAlgorithm::Sha256)
+        .fanout(16)
+        .build()
+        .blob_directory(sha.path(), tree.path())
+        .unwrap();
+    assert_eq!(read_store_config(sha.path()).unwrap().fanout, 16);
+
+    // the hash algorithm and extension are recorded next to the fanout
+    let blake = tempfile::tempdir().unwrap();
+    Ditiear::builder()
+        .hash_algorithm(HashAlgorithm::Blake3)
+        .blob_extension("blob")
+        .build()
+        .blob_directory(blake.path(), tree.path())
+        .unwrap();
+    let config = read_store_config(blake.path()).unwrap();
+    assert_eq!(config.fanout, 1);
+    assert_eq!(config.hash_algorithm, HashAlgorithm::Blake3);
+    assert_eq!(config.extension.as_deref(), Some("blob"));
+    match Ditiear::default().blob_directory(blake.path(), tree.path()) {
+        Err(DitiearError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+fn count_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| {
+            let p = e.unwrap().path();
+            if p.is_dir() {
+                count_files(&p)
+            } else {
+                1
+            }
+        })
+        .sum()
+}
+
+#[test]
+fn test_compare_tree_to_root() {
+    let working = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        working.path(),
+        &[
+            ("same.txt", b"same"),
+            ("docs/edit.txt", b"before"),
+            ("docs/remove.txt", b"remove me"),
+            ("gone/deep/file.txt", b"gone"),
+        ],
+    );
+    let root = create_directory_blob_file(store.path(), working.path()).unwrap();
+    assert_eq!(hash_directory(working.path()).unwrap().hash, root);
+    let old_edit = calculate_file_hash(working.path().join("docs/edit.txt")).unwrap();
+    let removed = calculate_file_hash(working.path().join("docs/remove.txt")).unwrap();
+
+    write_tree(
+        working.path(),
+        &[("docs/edit.txt", b"after"), ("new/file.txt", b"new")],
+    );
+    std::fs::remove_file(working.path().join("docs/remove.txt")).unwrap();
+    std::fs::remove_dir_all(working.path().join("gone")).unwrap();
+    let stored = count_files(store.path());
+    let diffs = compare_tree_to_root(working.path(), &root, store.path()).unwrap();
+    assert_eq!(count_files(store.path()), stored);
+
+    let mut found = (false, false, false);
+    let mut deleted = vec![];
+    for diff in &diffs {
+        match diff {
+            WorkingChange::Modify {
+                r#type: DiffFileType::File,
+                old,
+                path,
+            } => found.0 = *old == old_edit && path == "docs/edit.txt",
+            WorkingChange::Add {
+                r#type: DiffFileType::File,
+                path,
+            } => found.1 = path == "new/file.txt",
+            WorkingChange::Delete {
+                r#type: DiffFileType::File,
+                hash,
+                path,
+            } if path == "docs/remove.txt" => found.2 = *hash == removed,
+            WorkingChange::Delete { path, .. } => deleted.push(path.as_str()),
+            _ => {}
+        }
+    }
+    assert_eq!(found, (true, true, true));
+    deleted.sort();
+    assert_eq!(deleted, ["gone", "gone/deep", "gone/deep/file.txt"]);
+}
+
+#[test]
+fn test_recover_directory() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"a"), ("b.txt", b"b"), ("c.bin", &[0, 1, 2])],
+    );
+    let options = BlobOptions {
+        record_paths: true,
+        ..Default::default()
+    };
+    let root = create_directory_blob_file_with_options(store.path(), source.path(), &options)
+        .unwrap()
+        .hash;
+    let root_path = store.path().join(&root[..1]).join(&root[1..]);
+    std::fs::remove_file(&root_path).unwrap();
+
+    let index = read_path_index(store.path()).unwrap();
+    let children = index
+        .iter()
+        .map(|(hash, paths)| (hash.to_string(), paths.iter().next().unwrap().clone()))
+        .collect::<Vec<_>>();
+    let recovered = recover_directory(children, store.path()).unwrap();
+    assert_eq!(recovered, root);
+    assert!(root_path.exists());
+}
+
+#[test]
+fn test_checkout_rejects_escaping_entry_names() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let parent = tempfile::tempdir().unwrap();
+    let dest = parent.path().join("dest");
+    write_tree(source.path(), &[("a.txt", b"a")]);
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let line = std::fs::read_to_string(store.path().join(&root[..1]).join(&root[1..])).unwrap();
+    let file = line.split(' ').nth(1).unwrap();
+    let mut names = vec!["../ev", "/abs", "a/b", ".."];
+    if cfg!(windows) {
+        names.push("a\\b");
+    }
+    for name in names {
+        let evil = recover_directory([(file, name)], store.path()).unwrap();
+        let error = checkout(&evil, store.path(), &dest).unwrap_err();
+        assert!(matches!(error, FileParseError::PathTraversal(n) if n == name));
+    }
+    assert!(!parent.path().join("ev").exists());
+    // elsewhere a backslash is part of the name
+    if !cfg!(windows) {
+        let named = recover_directory([(file, "a\\b.txt")], store.path()).unwrap();
+        checkout(&named, store.path(), &dest).unwrap();
+        assert_eq!(std::fs::read(dest.join("a\\b.txt")).unwrap(), b"a");
+    }
+}
+
+#[test]
+fn test_store_not_found_and_missing_blob() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let empty = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("file.txt", b"one")]);
+    write_tree(v2.path(), &[("file.txt", b"two")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let patch = empty.path().join("patch.zip");
+    create_diff_patch(&old, &new, store.path(), &patch).unwrap();
+
+    let missing = store.path().join("does-not-exist");
+    assert!(matches!(
+        compare_blob_files(&old, &new, &missing),
+        Err(FileParseError::StoreNotFound(p)) if p == missing
+    ));
+    assert!(matches!(
+        apply_patchs(&patch, &missing),
+        Err(ZipFileError::StoreNotFound(_))
+    ));
+
+    // an existing store where the old root blob is gone
+    let client = tempfile::tempdir().unwrap();
+    assert!(matches!(
+        compare_blob_files(&old, &new, client.path()),
+        Err(FileParseError::MissingBaseBlob(h)) if h == old
+    ));
+    assert!(matches!(
+        apply_patchs(patch.as_path(), client.path()),
+        Err(ZipFileError::SourceMismatch(h)) if h == old
+    ));
+}
+
+#[test]
+fn test_patch_manifest_guards_the_base() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"one"), ("b.txt", b"kept")]);
+    write_tree(v2.path(), &[("a.txt", b"two"), ("b.txt", b"kept")]);
+    write_tree(other.path(), &[("a.txt", b"unrelated")]);
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    let patch = server.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    create_zip_patch(diffs, server.path(), patch.as_path()).unwrap();
+
+    let manifest = read_patch_manifest(&patch).unwrap().unwrap();
+    assert_eq!(manifest.format_version, 1);
+    assert_eq!(manifest.old_root.as_deref(), Some(old.as_str()));
+    assert_eq!(manifest.new_root.as_deref(), Some(new.as_str()));
+    assert_eq!(manifest.hash_algorithm, HashAlgorithm::XxHash64);
+    assert_eq!(
+        manifest.entries,
+        unpack_patch(&patch, |_, _| Ok(())).unwrap().len()
+    );
+
+    // a store holding another tree is refused before anything is written
+    create_directory_blob_file(client.path(), other.path()).unwrap();
+    assert!(matches!(
+        apply_patchs(patch.as_path(), client.path()),
+        Err(ZipFileError::SourceMismatch(h)) if h == old
+    ));
+    assert!(!blob_exists(&new, client.path()));
+
+    // holding the old root only as a subtree of another version is no match either
+    let nested = tempfile::tempdir().unwrap();
+    let outer = tempfile::tempdir().unwrap();
+    write_tree(outer.path(), &[("v1/a.txt", b"one"), ("v1/b.txt", b"kept")]);
+    create_directory_blob_file_rec(nested.path(), outer.path()).unwrap();
+    assert!(blob_exists(&old, nested.path()));
+    assert!(matches!(
+        apply_patchs(patch.as_path(), nested.path()),
+        Err(ZipFileError::SourceMismatch(h)) if h == old
+    ));
+
+    // a store copied without its sidecar records no versions, holding the root is enough
+    let replica = tempfile::tempdir().unwrap();
+    create_directory_blob_file(replica.path(), v1.path()).unwrap();
+    std::fs::remove_dir_all(replica.path().join(".ditiear")).unwrap();
+    apply_patchs(patch.as_path(), replica.path()).unwrap();
+    assert!(blob_exists(&new, replica.path()));
+
+    // unless the check is skipped
+    let unchecked = Ditiear::builder().skip_source_check(true).build();
+    unchecked.apply(&patch, nested.path()).unwrap();
+    assert!(blob_exists(&new, nested.path()));
+
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    apply_patchs(patch.as_path(), client.path()).unwrap();
+    assert!(blob_exists(&new, client.path()));
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_write_zip_patch_to_buffer() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"first"), ("b.txt", b"kept")]);
+    write_tree(v2.path(), &[("a.txt", b"second"), ("b.txt", b"kept")]);
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    write_zip_patch_to(diffs, server.path(), &mut buffer).unwrap();
+    assert!(!buffer.get_ref().is_empty());
+
+    let patch = client.path().join("patch.zip");
+    std::fs::write(&patch, buffer.into_inner()).unwrap();
+    apply_patchs(patch.as_path(), client.path()).unwrap();
+    let second = calculate_file_hash(v2.path().join("a.txt")).unwrap();
+    assert!(blob_exists(&second, client.path()));
+    assert!(blob_exists(&new, client.path()));
+}
+
+#[test]
+fn test_patch_round_trips_through_memory() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"first")]);
+    write_tree(v2.path(), &[("a.txt", b"second"), ("b.txt", b"new")]);
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+
+    let mut writer = std::io::Cursor::new(Vec::new());
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    create_zip_patch_to_writer(diffs, server.path(), &mut writer).unwrap();
+    let on_disk = server.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    create_zip_patch(diffs, server.path(), on_disk.as_path()).unwrap();
+
+    let added = std::cell::RefCell::new(vec![]);
+    let from_memory = unpack_patch_from_reader(
+        std::io::Cursor::new(writer.into_inner()),
+        |content, hash| {
+            added.borrow_mut().push((hash.to_string(), content));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(from_memory, unpack_patch(&on_disk, |_, _| Ok(())).unwrap());
+    let b = calculate_file_hash(v2.path().join("b.txt")).unwrap();
+    assert!(added.into_inner().contains(&(b, b"new".to_vec())));
+}
+
+#[test]
+fn test_files_identical() {
+    let dir = tempfile::tempdir().unwrap();
+    write_tree(
+        dir.path(),
+        &[
+            ("a.txt", b"same content"),
+            ("b.txt", b"same content"),
+            ("c.txt", b"same length!"),
+            ("d.txt", b"longer content"),
+        ],
+    );
+    let file = |name: &str| dir.path().join(name);
+    assert!(files_identical(file("a.txt"), file("b.txt")).unwrap());
+    assert!(!files_identical(file("a.txt"), file("c.txt")).unwrap());
+    assert!(!files_identical(file("a.txt"), file("d.txt")).unwrap());
+}
+
+#[test]
+fn test_apply_patch_in_place() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let working = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let v1_files: &[(&str, &[u8])] = &[
+        ("keep.txt", b"keep"),
+        ("gone.txt", b"removed in v2"),
+        ("edit.txt", b"version one"),
+        ("sub/old.txt", b"whole directory removed"),
+        ("user/deleted.txt", b"already removed by the user"),
+        ("user/stays.txt", b"stays"),
+    ];
+    write_tree(v1.path(), v1_files);
+    write_tree(
+        v2.path(),
+        &[
+            ("keep.txt", b"keep"),
+            ("edit.txt", b"version two"),
+            ("new/added.txt", b"added in v2"),
+            ("user/stays.txt", b"stays"),
+        ],
+    );
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    write_tree(working.path(), v1_files);
+    std::fs::remove_file(working.path().join("user/deleted.txt")).unwrap();
+
+    let patch = out.path().join("patch.zip");
+    create_diff_patch(&old, &new, server.path(), patch.as_path()).unwrap();
+    apply_patch_in_place(&patch, client.path(), working.path(), &old, &new).unwrap();
+
+    assert!(!working.path().join("gone.txt").exists());
+    assert!(!working.path().join("sub").exists());
+    assert!(working.path().join("user/stays.txt").exists());
+    assert_eq!(
+        std::fs::read(working.path().join("edit.txt")).unwrap(),
+        b"version two"
+    );
+    assert!(compare_tree_to_root(working.path(), &new, client.path())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_patch_tree() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("keep.txt", b"keep"),
+            ("edit.txt", b"short"),
+            ("dir/gone.txt", b"gone"),
+            ("dir/stays.txt", b"stays"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("keep.txt", b"keep"),
+            ("edit.txt", b"a bit longer"),
+            ("dir/stays.txt", b"stays"),
+            ("dir/sub/new.txt", b"new file"),
+        ],
+    );
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    let patch = out.path().join("patch.zip");
+    create_diff_patch(&old, &new, server.path(), patch.as_path()).unwrap();
+
+    let tree = patch_tree(&patch, client.path()).unwrap();
+    let names = |node: &PatchTree| {
+        node.children
+            .iter()
+            .map(|c| c.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&tree), ["dir", "edit.txt"]);
+    assert_eq!(tree.get("edit.txt").unwrap().change, ChangeKind::Modify);
+    assert_eq!(tree.get("edit.txt").unwrap().size_delta, 7);
+    assert_eq!(tree.get("dir").unwrap().change, ChangeKind::Modify);
+    assert_eq!(names(tree.get("dir").unwrap()), ["gone.txt", "sub"]);
+    assert_eq!(tree.get("dir/gone.txt").unwrap().change, ChangeKind::Delete);
+    assert_eq!(tree.get("dir/sub").unwrap().change, ChangeKind::Add);
+    assert_eq!(tree.get("dir/sub/new.txt").unwrap().change, ChangeKind::Add);
+    assert_eq!(tree.get("dir").unwrap().size_delta, 4);
+    assert_eq!(tree.size_delta, 11);
+}
+
+#[test]
+fn test_names_with_line_breaks_round_trip() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("a\nb.txt", b"old"),
+            ("back\\slash\r", b"same"),
+            ("plain.txt", b"plain"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("a\nb.txt", b"new"),
+            ("back\\slash\r", b"same"),
+            ("plain.txt", b"plain"),
+        ],
+    );
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+
+    let listing = read_directory_blob(&old, store.path(), Utf8Mode::Strict).unwrap();
+    let mut names = listing
+        .entries
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a\nb.txt", "back\\slash\r", "plain.txt"]);
+
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    let modified = diffs
+        .iter()
+        .filter(|d| {
+            matches!(
+                d,
+                DiffCollectionType::Modify {
+                    r#type: DiffFileType::File,
+                    ..
+                }
+            )
+        })
+        .count();
+    assert_eq!(modified, 1);
+    assert!(diffs.iter().all(|d| !matches!(
+        d,
+        DiffCollectionType::Add { .. } | DiffCollectionType::Delete { .. }
+    )));
+}
+
+#[test]
+fn test_diff_to_json() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[("edit.txt", b"old"), ("dir/gone.txt", b"gone")],
+    );
+    write_tree(v2.path(), &[("edit.txt", b"new")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+
+    let json = diff_to_json(&diffs);
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value[0]["change"], "modify");
+    assert_eq!(value[0]["type"], "directory");
+    assert_eq!(value[0]["old"], old.as_str());
+    assert_eq!(value[0]["new"], new.as_str());
+    assert!(value
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|diff| diff["change"] == "delete" && diff["type"] == "directory"));
+    let parsed: Vec<DiffCollectionType> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, diffs);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_create_diff_patch_async() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"old")]);
+    write_tree(v2.path(), &[("a.txt", b"new"), ("b.txt", b"added")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let (sync, async_) = (out.path().join("sync.zip"), out.path().join("async.zip"));
+    create_diff_patch(&old, &new, store.path(), sync.as_path()).unwrap();
+    runtime
+        .block_on(create_diff_patch_async(
+            &old,
+            &new,
+            store.path(),
+            async_.as_path(),
+        ))
+        .unwrap();
+    assert_eq!(
+        inspect_patch(&sync).unwrap(),
+        inspect_patch(&async_).unwrap()
+    );
+    assert!(runtime
+        .block_on(create_diff_patch_async(
+            &old,
+            "0",
+            store.path(),
+            async_.as_path()
+        ))
+        .is_err());
+}
+
+#[test]
+fn test_inspect_patch() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("edit.txt", b"short"), ("gone.txt", b"gone")]);
+    write_tree(
+        v2.path(),
+        &[("edit.txt", b"a bit longer"), ("new.txt", b"new file")],
+    );
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    let patch = out.path().join("patch.zip");
+    create_diff_patch(&old, &new, server.path(), patch.as_path()).unwrap();
+
+    let summary = inspect_patch(&patch).unwrap();
+    assert_eq!(
+        summary.added,
+        [calculate_file_hash(v2.path().join("new.txt")).unwrap()]
+    );
+    assert_eq!(
+        summary.deleted,
+        [calculate_file_hash(v1.path().join("gone.txt")).unwrap()]
+    );
+    // the root and edit.txt
+    assert_eq!(summary.replaced.len(), 2);
+    assert_eq!(summary.replaced[0], (old, new));
+    assert_eq!(summary.added_bytes, 8);
+    // nothing was extracted next to the patch
+    assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 1);
+}
+
+#[cfg(feature = "binaryBlob")]
+#[test]
+fn test_binary_directory_blob_round_trip() {
+    let store = tempfile::tempdir().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+    for i in 0..1000 {
+        let name = format!("file{:04}.txt", i);
+        std::fs::write(tree.path().join(name), format!("content {}", i)).unwrap();
+    }
+    let text = create_directory_blob_file(store.path(), tree.path()).unwrap();
+    let listing = read_directory_blob(&text, store.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries;
+    assert_eq!(listing.len(), 1000);
+    let entries = || {
+        listing
+            .iter()
+            .map(|e| DirectoryEntry {
+                name: e.name.clone(),
+                hash: e.hash.clone(),
+                r#type: e.r#type,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let binary = write_directory_blob_binary(store.path(), entries()).unwrap();
+    assert_ne!(binary, text);
+    let read = read_directory_blob(&binary, store.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries;
+    assert_eq!(read.len(), 1000);
+    for (a, b) in listing.iter().zip(&read) {
+        assert_eq!((&a.name, &a.hash, &a.r#type), (&b.name, &b.hash, &b.r#type));
+    }
+    // both formats list the same children, only the roots differ
+    let diffs = compare_blob_files(&text, &binary, store.path()).unwrap();
+    assert_eq!(diffs.len(), 1);
+
+    let mut fewer = entries();
+    let removed = fewer.remove(500);
+    let smaller = write_directory_blob_binary(store.path(), fewer).unwrap();
+    let diffs = compare_blob_files(&binary, &smaller, store.path()).unwrap();
+    assert!(diffs.contains(&DiffCollectionType::Delete {
+        r#type: DiffFileType::File,
+        value: removed.hash,
+    }));
+    assert!(fsck(store.path()).unwrap().is_empty());
+}
+
+#[test]
+fn test_create_zip_patch_with_stats() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[("edit.txt", b"0123456789"), ("gone.txt", b"gone")],
+    );
+    write_tree(
+        v2.path(),
+        &[("edit.txt", b"01234ab56789"), ("sub/new.txt", b"new file")],
+    );
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    let patch = out.path().join("patch.zip");
+    let stats = create_zip_patch_with_stats(diffs, server.path(), patch.as_path()).unwrap();
+    assert_eq!(stats.added_files, 1);
+    assert_eq!(stats.deleted_files, 1);
+    assert_eq!(stats.modified_files, 1);
+    assert_eq!(
+        stats.patch_file_size,
+        std::fs::metadata(&patch).unwrap().len()
+    );
+    // the added file and `sub` directory blob, the two inserted bytes and the new root lines
+    let summary = inspect_patch(&patch).unwrap();
+    assert!(stats.literal_bytes > summary.added_bytes + 2);
+
+    let unchanged = out.path().join("unchanged.zip");
+    let stats = create_zip_patch_with_stats(vec![], server.path(), unchanged.as_path()).unwrap();
+    assert_eq!(stats, PatchStats::default());
+    assert!(!unchanged.exists());
+}
+
+#[test]
+fn test_oversized_modified_files_are_added_whole() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let large = vec![7u8; 4096];
+    let mut changed = large.clone();
+    changed[100] = 8;
+    write_tree(v1.path(), &[("large.bin", &large), ("small.txt", b"old")]);
+    write_tree(v2.path(), &[("large.bin", &changed), ("small.txt", b"new")]);
+    let ditiear = Ditiear::builder().max_in_memory(1024).build();
+    let old = ditiear
+        .blob_directory(server.path(), v1.path())
+        .unwrap()
+        .hash;
+    let new = ditiear
+        .blob_directory(server.path(), v2.path())
+        .unwrap()
+        .hash;
+    let patch = out.path().join("patch.zip");
+    ditiear
+        .create_patch(&old, &new, server.path(), &patch)
+        .unwrap();
+
+    let summary = inspect_patch(&patch).unwrap();
+    let large_hash = calculate_file_hash(v2.path().join("large.bin")).unwrap();
+    assert_eq!(summary.added, [large_hash]);
+    assert_eq!(summary.added_bytes, 4096);
+    // the root and small.txt are still diffed
+    assert_eq!(summary.replaced.len(), 2);
+    assert_eq!(summary.replaced[0], (old.clone(), new.clone()));
+
+    ditiear.blob_directory(client.path(), v1.path()).unwrap();
+    ditiear.apply(&patch, client.path()).unwrap();
+    let dest = out.path().join("checkout");
+    checkout(&new, client.path(), &dest).unwrap();
+    assert_eq!(std::fs::read(dest.join("large.bin")).unwrap(), changed);
+    assert_eq!(std::fs::read(dest.join("small.txt")).unwrap(), b"new");
+}
+
+#[test]
+fn test_detect_patch_conflicts() {
+    let v1 = tempfile::tempdir().unwrap();
+    let left = tempfile::tempdir().unwrap();
+    let right = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[("shared.txt", b"base"), ("other.txt", b"other")],
+    );
+    write_tree(
+        left.path(),
+        &[("shared.txt", b"left"), ("other.txt", b"other")],
+    );
+    write_tree(
+        right.path(),
+        &[("shared.txt", b"right"), ("other.txt", b"other")],
+    );
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let left_root = create_directory_blob_file(store.path(), left.path()).unwrap();
+    let right_root = create_directory_blob_file(store.path(), right.path()).unwrap();
+    let (left_patch, right_patch) = (out.path().join("left.zip"), out.path().join("right.zip"));
+    create_diff_patch(&old, &left_root, store.path(), left_patch.as_path()).unwrap();
+    create_diff_patch(&old, &right_root, store.path(), right_patch.as_path()).unwrap();
+
+    assert!(detect_patch_conflicts(&[&left_patch, &left_patch])
+        .unwrap()
+        .is_empty());
+    let conflicts = detect_patch_conflicts(&[&left_patch, &right_patch]).unwrap();
+    let shared = calculate_file_hash(v1.path().join("shared.txt")).unwrap();
+    let conflict = conflicts.iter().find(|c| c.old_file == shared).unwrap();
+    assert_eq!(
+        conflict.results,
+        vec![
+            (
+                left_patch.clone(),
+                calculate_file_hash(left.path().join("shared.txt")).unwrap()
+            ),
+            (
+                right_patch.clone(),
+                calculate_file_hash(right.path().join("shared.txt")).unwrap()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_consume_source() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("build/out.bin", &[1, 2, 3]),
+            ("build/copy.bin", &[1, 2, 3]),
+        ],
+    );
+    let hash = calculate_file_hash(source.path().join("build/out.bin")).unwrap();
+    let options = BlobOptions {
+        consume_source: true,
+        ..Default::default()
+    };
+    let report =
+        create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap();
+    assert!(!source.path().join("build/out.bin").exists());
+    assert!(!source.path().join("build/copy.bin").exists());
+    assert!(blob_exists(&hash, store.path()));
+    assert!(blob_exists(&report.hash, store.path()));
+}
+
+#[test]
+fn test_manifest_round_trip() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("top.txt", b"top"),
+            ("a/file.txt", b"same"),
+            ("b/file.txt", b"same"),
+            ("a/deep/more.txt", b"more"),
+        ],
+    );
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let manifest = export_manifest(&root, store.path()).unwrap();
+    assert_eq!(manifest.lines().next().unwrap(), root);
+    assert!(manifest.contains(" top.txt "));
+    assert!(manifest.contains(" more.txt "));
+
+    create_directory_blob_file(target.path(), source.path()).unwrap();
+    let imported = import_manifest(&manifest, target.path()).unwrap();
+    assert_eq!(imported.root, root);
+    assert!(imported.missing.is_empty());
+
+    let more = calculate_file_hash(source.path().join("a/deep/more.txt")).unwrap();
+    std::fs::remove_file(target.path().join(&more[..1]).join(&more[1..])).unwrap();
+    let empty = tempfile::tempdir().unwrap();
+    assert_eq!(
+        import_manifest(&manifest, target.path()).unwrap().missing,
+        vec![more]
+    );
+    // root, top.txt, a, b, the shared file.txt, deep and more.txt
+    assert_eq!(
+        import_manifest(&manifest, empty.path())
+            .unwrap()
+            .missing
+            .len(),
+        7
+    );
+}
+
+#[test]
+fn test_single_pass_blob_copy() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let content = (0..50_000u32).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+    write_tree(
+        source.path(),
+        &[("large.bin", &content), ("dup.bin", &content)],
+    );
+    let single = tempfile::tempdir().unwrap();
+    let options = BlobOptions {
+        single_pass: true,
+        ..Default::default()
+    };
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let report =
+        create_directory_blob_file_with_options(single.path(), source.path(), &options).unwrap();
+    assert_eq!(report.hash, root);
+    let hash = calculate_file_hash(source.path().join("large.bin")).unwrap();
+    for store in [store.path(), single.path()] {
+        let stored = store.join(&hash[..1]).join(&hash[1..]);
+        assert_eq!(std::fs::read(stored).unwrap(), content);
+    }
+    // temporary copies never stay behind, only the sidecars are left
+    assert_eq!(
+        std::fs::read_dir(store.path().join(".ditiear"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name() != "stats")
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn test_indexed_files_are_not_copied_again() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("same.txt", b"same"), ("edit.txt", b"old!")],
+    );
+    let options = BlobOptions {
+        record_paths: true,
+        ..Default::default()
+    };
+    let blob = |options| {
+        create_directory_blob_file_with_options(store.path(), source.path(), options)
+            .unwrap()
+            .hash
+    };
+    let old = blob(&options);
+    assert_eq!(blob(&options), old);
+
+    // a file of the same size with other content is still copied
+    std::fs::write(source.path().join("edit.txt"), b"new!").unwrap();
+    let new = blob(&options);
+    assert_ne!(new, old);
+    assert_eq!(new, blob(&BlobOptions::default()));
+    let edited = calculate_file_hash(source.path().join("edit.txt")).unwrap();
+    let stored = store.path().join(&edited[..1]).join(&edited[1..]);
+    assert_eq!(std::fs::read(stored).unwrap(), b"new!");
+}
+
+#[test]
+fn test_version_size() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("a.txt", &[1; 100]),
+            ("copy/a.txt", &[1; 100]),
+            ("b.txt", &[2; 10]),
+        ],
+    );
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let root_size = blob_size(&root, store.path()).unwrap();
+    let dir_line = std::fs::read_to_string(store.path().join(&root[..1]).join(&root[1..]))
+        .unwrap()
+        .lines()
+        .find(|l| l.starts_with("copy "))
+        .unwrap()
+        .to_string();
+    let copy_hash = dir_line.split(' ').nth(1).unwrap();
+    let copy_size = blob_size(copy_hash, store.path()).unwrap();
+    assert_eq!(
+        version_size(&root, store.path()).unwrap(),
+        100 + 10 + root_size + copy_size
+    );
+}
+
+#[test]
+fn test_apply_patch_in_place_progress() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let working = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let v1_files: &[(&str, &[u8])] = &[
+        ("textures/hero.png", b"old hero"),
+        ("textures/unused.png", b"unused"),
+        ("readme.txt", b"readme"),
+    ];
+    write_tree(v1.path(), v1_files);
+    write_tree(
+        v2.path(),
+        &[
+            ("textures/hero.png", b"new hero"),
+            ("sounds/jump.wav", b"jump"),
+            ("readme.txt", b"readme"),
+        ],
+    );
+    let old = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    write_tree(working.path(), v1_files);
+    let patch = out.path().join("patch.zip");
+    create_diff_patch(&old, &new, server.path(), patch.as_path()).unwrap();
+
+    let calls = std::cell::RefCell::new(vec![]);
+    apply_patch_in_place_with_progress(
+        &patch,
+        client.path(),
+        working.path(),
+        &old,
+        &new,
+        |path, kind| calls.borrow_mut().push((path.to_string(), kind)),
+    )
+    .unwrap();
+    let mut calls = calls.into_inner();
+    calls.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        calls,
+        vec![
+            ("sounds/jump.wav".to_string(), ChangeKind::Add),
+            ("textures/hero.png".to_string(), ChangeKind::Modify),
+            ("textures/unused.png".to_string(), ChangeKind::Delete),
+        ]
+    );
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    for entry in std::fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            std::fs::create_dir_all(&target).unwrap();
+            copy_dir(&path, &target);
+        } else {
+            std::fs::copy(&path, &target).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_verify_replica() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let replica = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"a"), ("dir/b.txt", b"b"), ("dir/c.txt", b"c")],
+    );
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    copy_dir(store.path(), replica.path());
+    assert!(verify_replica(&root, store.path(), replica.path())
+        .unwrap()
+        .is_empty());
+
+    let b = calculate_file_hash(source.path().join("dir/b.txt")).unwrap();
+    let c = calculate_file_hash(source.path().join("dir/c.txt")).unwrap();
+    std::fs::remove_file(replica.path().join(&b[..1]).join(&b[1..])).unwrap();
+    let c_path = replica.path().join(&c[..1]).join(&c[1..]);
+    std::fs::write(&c_path, b"corrupt").unwrap();
+    let corrupt = calculate_file_hash(&c_path).unwrap();
+    let mut issues = verify_replica(&root, store.path(), replica.path()).unwrap();
+    issues.sort_by_key(|i| matches!(i, ReplicaIssue::Mismatched { .. }));
+    assert_eq!(
+        issues,
+        vec![
+            ReplicaIssue::Missing(b),
+            ReplicaIssue::Mismatched {
+                hash: c,
+                actual: corrupt,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_delta_index_encoding() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let old = (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut new = old.clone();
+    for i in (0..new.len()).step_by(97) {
+        new[i] = new[i].wrapping_add(1);
+    }
+    write_tree(v1.path(), &[("data.bin", &old)]);
+    write_tree(v2.path(), &[("data.bin", &new)]);
+    let patch_with = |encoding, name: &str| {
+        let ditiear = Ditiear::builder()
+            .compression(CompressionMethod::Stored)
+            .index_encoding(encoding)
+            .build();
+        let old_root = ditiear
+            .blob_directory(server.path(), v1.path())
+            .unwrap()
+            .hash;
+        let new_root = ditiear
+            .blob_directory(server.path(), v2.path())
+            .unwrap()
+            .hash;
+        let patch = out.path().join(name);
+        ditiear
+            .create_patch(&old_root, &new_root, server.path(), &patch)
+            .unwrap();
+        patch
+    };
+    let absolute = patch_with(IndexEncoding::Absolute, "absolute.zip");
+    let delta = patch_with(IndexEncoding::Delta, "delta.zip");
+    assert!(
+        std::fs::metadata(&delta).unwrap().len() * 2 < std::fs::metadata(&absolute).unwrap().len()
+    );
+    assert_eq!(
+        unpack_patch(&absolute, |_, _| Ok(())).unwrap(),
+        unpack_patch(&delta, |_, _| Ok(())).unwrap()
+    );
+
+    let client = tempfile::tempdir().unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    apply_patchs(delta.as_path(), client.path()).unwrap();
+    let hash = calculate_file_hash(v2.path().join("data.bin")).unwrap();
+    assert_eq!(
+        std::fs::read(client.path().join(&hash[..1]).join(&hash[1..])).unwrap(),
+        new
+    );
+}
+
+#[test]
+fn test_build_patch_stages() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"one")]);
+    write_tree(v2.path(), &[("a.txt", b"two")]);
+    let ditiear = Ditiear::default();
+    let old = ditiear
+        .blob_directory(store.path(), v1.path())
+        .unwrap()
+        .hash;
+    let patch = out.path().join("patch.zip");
+    let new = ditiear
+        .build_patch(&old, v2.path(), store.path(), &patch)
+        .unwrap();
+    assert!(patch.exists());
+    assert!(blob_exists(&new, store.path()));
+
+    let missing_tree = v2.path().join("missing");
+    assert!(matches!(
+        ditiear.build_patch(&old, &missing_tree, store.path(), &patch),
+        Err(DitiearError::Build(BuildError::Blob(_)))
+    ));
+    assert!(matches!(
+        ditiear.build_patch("0123456789abcdef", v2.path(), store.path(), &patch),
+        Err(DitiearError::Build(BuildError::Diff(
+            FileParseError::MissingBaseBlob(_)
+        )))
+    ));
+    let unwritable = out.path().join("missing").join("patch.zip");
+    assert!(matches!(
+        ditiear.build_patch(&old, v2.path(), store.path(), &unwritable),
+        Err(DitiearError::Build(BuildError::Patch(ZipFileError::Io(_))))
+    ));
+}
+
+#[test]
+fn test_patch_metadata() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"one")]);
+    write_tree(v2.path(), &[("a.txt", b"two"), ("b.txt", b"new")]);
+    let metadata = PatchMetadata {
+        source_version: Some("1.0.0".to_string()),
+        target_version: Some("1.1.0".to_string()),
+        created_at: Some(1_700_000_000),
+        notes: Some("Fixes \"everything\"\nand more".to_string()),
+    };
+    let ditiear = Ditiear::builder().metadata(metadata.clone()).build();
+    let old = ditiear
+        .blob_directory(server.path(), v1.path())
+        .unwrap()
+        .hash;
+    let new = ditiear
+        .blob_directory(server.path(), v2.path())
+        .unwrap()
+        .hash;
+    let patch = out.path().join("patch.zip");
+    ditiear
+        .create_patch(&old, &new, server.path(), &patch)
+        .unwrap();
+    assert_eq!(read_patch_metadata(&patch).unwrap(), Some(metadata));
+
+    // metadata is never mistaken for an added blob
+    ditiear.blob_directory(client.path(), v1.path()).unwrap();
+    ditiear.apply(&patch, client.path()).unwrap();
+    assert!(!client.path().join("d").join("itiear.meta").exists());
+
+    let plain = out.path().join("plain.zip");
+    create_diff_patch(&old, &new, server.path(), plain.as_path()).unwrap();
+    assert_eq!(read_patch_metadata(&plain).unwrap(), None);
+}
+
+#[test]
+fn test_compare_blob_files_cross() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let runner1 = tempfile::tempdir().unwrap();
+    let runner2 = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("same.txt", b"same"),
+            ("edit.txt", b"one"),
+            ("old/x.txt", b"x"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("same.txt", b"same"),
+            ("edit.txt", b"two"),
+            ("new/y.txt", b"y"),
+        ],
+    );
+    let old = create_directory_blob_file(runner1.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(runner2.path(), v2.path()).unwrap();
+    assert!(compare_blob_files(&old, &new, runner1.path()).is_err());
+
+    let hash = |root: &Path, name: &str| calculate_file_hash(root.join(name)).unwrap();
+    let mut changes = compare_blob_files_cross(&old, runner1.path(), &new, runner2.path())
+        .unwrap()
+        .into_iter()
+        .filter_map(|d| match d {
+            DiffCollectionType::Add {
+                r#type: DiffFileType::File,
+                value,
+            } => Some(format!("add {}", value)),
+            DiffCollectionType::Delete {
+                r#type: DiffFileType::File,
+                value,
+            } => Some(format!("delete {}", value)),
+            DiffCollectionType::Modify {
+                r#type: DiffFileType::File,
+                old,
+                new,
+            } => Some(format!("modify {} {}", old, new)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    changes.sort();
+    let mut expected = vec![
+        format!("add {}", hash(v2.path(), "new/y.txt")),
+        format!("delete {}", hash(v1.path(), "old/x.txt")),
+        format!(
+            "modify {} {}",
+            hash(v1.path(), "edit.txt"),
+            hash(v2.path(), "edit.txt")
+        ),
+    ];
+    expected.sort();
+    assert_eq!(changes, expected);
+}
+
+#[test]
+fn test_materialize_writes_the_patched_tree() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"one"), ("dir/b.txt", b"two")]);
+    write_tree(v2.path(), &[("a.txt", b"one!"), ("dir/c/d.txt", b"three")]);
+    let old_root = create_directory_blob_file(server.path(), v1.path()).unwrap();
+    let new_root = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old_root, &new_root, server.path()).unwrap();
+    let patch = out.path().join("update.zip");
+    create_zip_patch(diffs, server.path(), patch.as_path()).unwrap();
+
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    let tree = out.path().join("tree");
+    let report = materialize(&patch, client.path(), &tree, &new_root).unwrap();
+    assert_eq!(report.written, 2);
+    assert_eq!(std::fs::read(tree.join("a.txt")).unwrap(), b"one!");
+    assert_eq!(std::fs::read(tree.join("dir/c/d.txt")).unwrap(), b"three");
+    assert!(!tree.join("dir/b.txt").exists());
+    assert_eq!(hash_directory(&tree).unwrap().hash, new_root);
+}
+
+#[test]
+fn test_checkout_skip_identical() {
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let dest = tempfile::tempdir().unwrap();
+    let files: &[(&str, &[u8])] = &[
+        ("a.txt", b"a"),
+        ("dir/b.txt", b"b"),
+        ("dir/deep/c.txt", b"c"),
+        ("d.txt", b"d"),
+    ];
+    write_tree(v2.path(), files);
+    let root = create_directory_blob_file(store.path(), v2.path()).unwrap();
+
+    let fresh = tempfile::tempdir().unwrap();
+    let report = checkout(&root, store.path(), fresh.path()).unwrap();
+    assert_eq!(report.written, 4);
+    assert!(compare_tree_to_root(fresh.path(), &root, store.path())
+        .unwrap()
+        .is_empty());
+
+    write_tree(dest.path(), files);
+    std::fs::remove_file(dest.path().join("dir/deep/c.txt")).unwrap();
+    let options = CheckoutOptions {
+        skip_identical: true,
+        ..CheckoutOptions::default()
+    };
+    let report = checkout_with_options(&root, store.path(), dest.path(), &options).unwrap();
+    assert_eq!(
+        report,
+        CheckoutReport {
+            written: 1,
+            skipped: 3
+        }
+    );
+    assert_eq!(
+        std::fs::read(dest.path().join("dir/deep/c.txt")).unwrap(),
+        b"c"
+    );
+}
+
+#[cfg(feature = "timings")]
+#[test]
+fn test_collect_timings() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let patch = tempfile::tempdir().unwrap();
+    let big: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+    let mut changed = big.clone();
+    changed[1000..1100].fill(0);
+    write_tree(v1.path(), &[("big.bin", &big), ("dir/a.txt", b"a")]);
+    write_tree(v2.path(), &[("big.bin", &changed), ("dir/a.txt", b"b")]);
+
+    let ((), timings) = collect_timings(|| {
+        let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+        let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+        let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+        create_zip_patch(diffs, store.path(), &patch.path().join("v1-v2.zip")).unwrap();
+    });
+    assert!(timings.hashing > std::time::Duration::ZERO);
+    assert!(timings.reading > std::time::Duration::ZERO);
+    assert!(timings.diffing > std::time::Duration::ZERO);
+    assert!(timings.writing > std::time::Duration::ZERO);
+
+    let (_, outside) = collect_timings(|| ());
+    assert_eq!(outside, Timings::default());
+}
+
+#[test]
+fn test_plan_blob_directory() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"a"), ("dir/b.txt", b"b")]);
+    write_tree(
+        v2.path(),
+        &[
+            ("a.txt", b"a"),
+            ("dir/b.txt", b"b"),
+            ("dir/copy.txt", b"b"),
+            ("new/c.txt", b"ccc"),
+        ],
+    );
+    create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let before = count_files(store.path());
+
+    let plan = plan_blob_directory(v2.path(), store.path()).unwrap();
+    assert_eq!(count_files(store.path()), before);
+    let root = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    assert_eq!(plan.hash, root);
+    assert_eq!(plan.new_blobs, count_files(store.path()) - before);
+
+    let again = plan_blob_directory(v2.path(), store.path()).unwrap();
+    assert_eq!((again.new_blobs, again.new_bytes), (0, 0));
+}
+
+#[test]
+fn test_apply_never_mutates_existing_blobs() {
+    use std::io::Read;
+
+    fn snapshot(dir: &Path, out: &mut Vec<(std::path::PathBuf, Vec<u8>)>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                snapshot(&path, out);
+            } else {
+                out.push((path.clone(), std::fs::read(&path).unwrap()));
+            }
+        }
+        out.sort();
+    }
+
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let patch_dir = tempfile::tempdir().unwrap();
+    let old_content: Vec<u8> = (0..32 * 1024u32).map(|i| (i % 253) as u8).collect();
+    let mut new_content = old_content.clone();
+    new_content[100..200].fill(7);
+    write_tree(
+        v1.path(),
+        &[("data.bin", &old_content), ("moved.txt", b"moved")],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("data.bin", &new_content),
+            ("dir/moved.txt", b"moved"),
+            ("new.txt", b"new"),
+        ],
+    );
+    let old = create_directory_blob_file(client.path(), v1.path()).unwrap();
+    create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let patch = patch_dir.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    create_zip_patch(diffs, store.path(), &patch).unwrap();
+
+    // a reader in the middle of the blob that the patch replaces
+    let old_hash = calculate_file_hash(v1.path().join("data.bin")).unwrap();
+    let old_path = client.path().join(&old_hash[..1]).join(&old_hash[1..]);
+    let mut reader = std::fs::File::open(&old_path).unwrap();
+    let mut head = vec![0; 1000];
+    reader.read_exact(&mut head).unwrap();
+
+    let ditiear = Ditiear::builder().delete_removed(true).build();
+    ditiear.apply(&patch, client.path()).unwrap();
+    let mut rest = vec![];
+    reader.read_to_end(&mut rest).unwrap();
+    head.extend(rest);
+    assert_eq!(head, old_content);
+    assert!(blob_exists(
+        &calculate_file_hash(v2.path().join("new.txt")).unwrap(),
+        client.path()
+    ));
+    // the moved blob is deleted and added by the same patch
+    assert!(blob_exists(
+        &calculate_file_hash(v2.path().join("dir/moved.txt")).unwrap(),
+        client.path()
+    ));
+
+    // applying onto a store that already holds every new blob changes nothing
+    let mut before = vec![];
+    snapshot(store.path(), &mut before);
+    apply_patchs(&patch, &store.path().to_path_buf()).unwrap();
+    let mut after = vec![];
+    snapshot(store.path(), &mut after);
+    assert_eq!(before, after);
+}
+
+#[test]
+fn test_missing_blobs_for_root() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[("a.txt", b"a"), ("dir/b.txt", b"b"), ("dir/c.txt", b"c")],
+    );
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let hash = |path: &str| calculate_file_hash(source.path().join(path)).unwrap();
+    let dir = hash_directory(source.path().join("dir")).unwrap().hash;
+
+    let all = missing_blobs_for_root(&root, store.path(), &HashSet::new()).unwrap();
+    assert_eq!(all.len(), 5);
+    assert_eq!(all[0], root);
+
+    let have = HashSet::from([dir.clone()]);
+    let mut missing = missing_blobs_for_root(&root, store.path(), &have).unwrap();
+    missing.sort();
+    let mut expected = vec![root.clone(), hash("a.txt")];
+    expected.sort();
+    assert_eq!(missing, expected);
+
+    let have = HashSet::from([root.clone()]);
+    assert!(missing_blobs_for_root(&root, store.path(), &have)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_apply_patch_in_memory() {
+    fn read_store(dir: &Path, out: &mut HashMap<String, bytes::Bytes>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                read_store(&path, out);
+            } else {
+                let shard = path
+                    .parent()
+                    .unwrap()
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap();
+                let name = path.file_name().unwrap().to_str().unwrap();
+                out.insert(
+                    format!("{}{}", shard, name),
+                    std::fs::read(&path).unwrap().into(),
+                );
+            }
+        }
+    }
+
+    fn expected(tree: &VirtualTree, prefix: &Path, out: &mut HashMap<PathBuf, bytes::Bytes>) {
+        for child in &tree.children {
+            match child {
+                VirtualNode::File { name, path, .. } => {
+                    out.insert(prefix.join(name), std::fs::read(path).unwrap().into());
+                }
+                VirtualNode::Directory { name, tree } => expected(tree, &prefix.join(name), out),
+                VirtualNode::Symlink { .. } => unreachable!("the trees hold no links"),
+            }
+        }
+    }
+
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let patch_dir = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("a.txt", b"hello world"),
+            ("dir/b.txt", b"b"),
+            ("gone.txt", b"x"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("a.txt", b"hello there world"),
+            ("dir/b.txt", b"b"),
+            ("dir/deep/c.txt", b"c"),
+        ],
+    );
+    let old = create_directory_blob_file(client.path(), v1.path()).unwrap();
+    create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let patch = patch_dir.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    create_zip_patch(diffs, store.path(), &patch).unwrap();
+
+    let mut base = HashMap::new();
+    read_store(client.path(), &mut base);
+    let files = apply_patch_in_memory(&std::fs::read(&patch).unwrap(), &base, &old).unwrap();
+    let mut want = HashMap::new();
+    expected(
+        &hash_directory(v2.path()).unwrap(),
+        Path::new(""),
+        &mut want,
+    );
+    assert_eq!(files, want);
+
+    base.clear();
+    assert!(matches!(
+        apply_patch_in_memory(&std::fs::read(&patch).unwrap(), &base, &old),
+        Err(ZipFileError::MissingBaseBlob(_))
+    ));
+}
+
+#[test]
+fn test_compare_blob_files_streaming_keeps_partial_results() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[("a.txt", b"a"), ("gone.txt", b"gone"), ("dir/b.txt", b"b")],
+    );
+    write_tree(v2.path(), &[("a.txt", b"A"), ("dir/b.txt", b"B")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+
+    let mut complete = vec![];
+    compare_blob_files_streaming(&old, &new, store.path(), |d| {
+        complete.push(format!("{:?}", d))
+    })
+    .unwrap();
+    let mut collected = compare_blob_files(&old, &new, store.path())
+        .unwrap()
+        .iter()
+        .map(|d| format!("{:?}", d))
+        .collect::<Vec<_>>();
+    complete.sort();
+    collected.sort();
+    assert_eq!(complete, collected);
+
+    // reading the new sub directory fails with an I/O error
+    let dir = hash_directory(v2.path().join("dir")).unwrap().hash;
+    let dir_path = store.path().join(&dir[..1]).join(&dir[1..]);
+    std::fs::remove_file(&dir_path).unwrap();
+    std::fs::create_dir(&dir_path).unwrap();
+    let mut partial = vec![];
+    let error = compare_blob_files_streaming(&old, &new, store.path(), |d| {
+        partial.push(format!("{:?}", d))
+    })
+    .unwrap_err();
+    assert!(matches!(error, FileParseError::Io(_)));
+    let a_old = calculate_file_hash(v1.path().join("a.txt")).unwrap();
+    let gone = calculate_file_hash(v1.path().join("gone.txt")).unwrap();
+    assert_eq!(partial.len(), 3);
+    assert!(partial[0].contains(&old));
+    assert!(partial.iter().any(|d| d.contains(&a_old)));
+    assert!(partial.iter().any(|d| d.contains(&gone)));
+}
+
+#[test]
+fn test_loose_patch() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("a.txt", b"hello world"),
+            ("dir/b.txt", b"b"),
+            ("gone.txt", b"x"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("a.txt", b"hello there world"),
+            ("dir/b.txt", b"b"),
+            ("new/c.txt", b"c"),
+        ],
+    );
+    let old = create_directory_blob_file(client.path(), v1.path()).unwrap();
+    create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let loose = out.path().join("v1-v2");
+    create_loose_patch(&old, &new, store.path(), &loose).unwrap();
+    let c = calculate_file_hash(v2.path().join("new/c.txt")).unwrap();
+    assert!(loose.join("ditiear.patch").is_file());
+    assert_eq!(std::fs::read(loose.join(&c)).unwrap(), b"c");
+
+    apply_loose_patch(&loose, client.path()).unwrap();
+    assert!(compare_blob_files(&new, &new, client.path())
+        .unwrap()
+        .is_empty());
+    assert!(compare_tree_to_root(v2.path(), &new, client.path())
+        .unwrap()
+        .is_empty());
+
+    // blobs the store already holds are not fetched
+    std::fs::remove_file(loose.join(&c)).unwrap();
+    apply_loose_patch(&loose, client.path()).unwrap();
+    let fresh = tempfile::tempdir().unwrap();
+    create_directory_blob_file(fresh.path(), v1.path()).unwrap();
+    assert!(matches!(
+        apply_loose_patch(&loose, fresh.path()),
+        Err(ZipFileError::MissingBaseBlob(h)) if h == c
+    ));
+}
+
+#[test]
+fn test_blob_implementations_agree() {
+    let trees: &[&[(&str, &[u8])]] = &[
+        &[("a.txt", b"a")],
+        &[
+            ("a.txt", b"a"),
+            ("dir/b.txt", b"b"),
+            ("dir/deep/c.txt", b"c"),
+        ],
+        &[
+            ("same/x.txt", b"x"),
+            ("other/x.txt", b"x"),
+            (".DS_Store", b"junk"),
+        ],
+        &[],
+    ];
+    for (i, files) in trees.iter().enumerate() {
+        let source = tempfile::tempdir().unwrap();
+        write_tree(source.path(), files);
+        if i % 2 == 1 {
+            std::fs::create_dir_all(source.path().join("empty/nested")).unwrap();
+            std::fs::create_dir_all(source.path().join("dir/also_empty")).unwrap();
+        }
+        let iterative = tempfile::tempdir().unwrap();
+        let recursive = tempfile::tempdir().unwrap();
+        let a = create_directory_blob_file(iterative.path(), source.path()).unwrap();
+        let b = create_directory_blob_file_rec(recursive.path(), source.path()).unwrap();
+        assert_eq!(a, b, "tree {}", i);
+        assert_eq!(hash_directory(source.path()).unwrap().hash, a);
+        assert_eq!(count_files(iterative.path()), count_files(recursive.path()));
+    }
+}
+
+#[test]
+fn test_recorded_paths_use_forward_slashes() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("dir/sub/a.txt", b"a")]);
+    let options = BlobOptions {
+        record_paths: true,
+        ..Default::default()
+    };
+    create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap();
+    let hash = calculate_file_hash(source.path().join("dir/sub/a.txt")).unwrap();
+    let index = read_path_index(store.path()).unwrap();
+    assert_eq!(
+        index.paths(&hash).unwrap().iter().collect::<Vec<_>>(),
+        ["dir/sub/a.txt"]
+    );
+}
+
+#[test]
+fn test_find_duplicate_files() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(
+        source.path(),
+        &[
+            ("a.txt", b"same"),
+            ("dir/copy.txt", b"same"),
+            ("dir/unique.txt", b"unique"),
+            ("other/x.txt", b"x"),
+        ],
+    );
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let duplicates = find_duplicate_files(&root, store.path()).unwrap();
+    let same = calculate_file_hash(source.path().join("a.txt")).unwrap();
+    assert_eq!(
+        duplicates,
+        vec![(same, vec!["a.txt".to_string(), "dir/copy.txt".to_string()])]
+    );
+}
+
+#[test]
+fn test_merge_subtree_patches() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("assets/ui/button.png", b"button"),
+            ("assets/audio/jump.wav", b"jump"),
+            ("readme.txt", b"readme"),
+        ],
+    );
+    write_tree(
+        v2.path(),
+        &[
+            ("assets/ui/button.png", b"shiny button"),
+            ("assets/ui/icon.png", b"icon"),
+            ("assets/audio/jump.wav", b"jump"),
+            ("assets/audio/land.wav", b"land"),
+            ("readme.txt", b"readme"),
+        ],
+    );
+    let base_root = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    let mut subtree_patches = vec![];
+    for subtree in ["assets/ui", "assets/audio"] {
+        let old = hash_directory(v1.path().join(subtree)).unwrap().hash;
+        let new = create_directory_blob_file(store.path(), &v2.path().join(subtree)).unwrap();
+        let patch = patches.path().join(subtree.replace('/', "-"));
+        create_diff_patch(&old, &new, store.path(), &patch).unwrap();
+        subtree_patches.push((subtree, patch));
+    }
+
+    let merged = patches.path().join("merged.zip");
+    let new_root =
+        merge_subtree_patches(&base_root, store.path(), &subtree_patches, &merged).unwrap();
+    assert_eq!(new_root, hash_directory(v2.path()).unwrap().hash);
+    apply_patchs(&merged, &client.path().to_path_buf()).unwrap();
+    assert!(compare_tree_to_root(v2.path(), &new_root, client.path())
+        .unwrap()
+        .is_empty());
+
+    let overlapping = [subtree_patches[0].clone(), subtree_patches[0].clone()];
+    assert!(matches!(
+        merge_subtree_patches(&base_root, store.path(), &overlapping, &merged),
+        Err(ZipFileError::InvalidSubtree(_))
+    ));
+    // a patch of another directory than the one it is merged at
+    let misplaced = [("assets/audio", subtree_patches[0].1.clone())];
+    assert!(matches!(
+        merge_subtree_patches(&base_root, store.path(), &misplaced, &merged),
+        Err(ZipFileError::InvalidSubtree(path)) if path == "assets/audio"
+    ));
+    let missing = [("assets/video", subtree_patches[0].1.clone())];
+    assert!(matches!(
+        merge_subtree_patches(&base_root, store.path(), &missing, &merged),
+        Err(ZipFileError::InvalidSubtree(_))
+    ));
+}
+
+#[cfg(feature = "binaryBlob")]
+#[test]
+fn test_merge_subtree_patches_keeps_columnar_directories() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"old")]);
+    write_tree(v2.path(), &[("a.txt", b"new")]);
+    let old_sub = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new_sub = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let root = |sub: &str| {
+        write_directory_blob_binary(
+            store.path(),
+            vec![DirectoryEntry {
+                name: "sub".to_string(),
+                hash: sub.to_string(),
+                r#type: DiffFileType::Directory,
+            }],
+        )
+        .unwrap()
+    };
+    let base_root = root(&old_sub);
+    let patch = patches.path().join("sub.zip");
+    create_diff_patch(&old_sub, &new_sub, store.path(), &patch).unwrap();
+
+    let merged = patches.path().join("merged.zip");
+    let new_root =
+        merge_subtree_patches(&base_root, store.path(), &[("sub", &patch)], &merged).unwrap();
+    assert_eq!(new_root, root(&new_sub));
+}
+
+#[test]
+fn test_compose_patches_chains_three_patches() {
+    let data = (0..8_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    let edit = |data: &[u8], at: usize, insert: &[u8], remove: usize| {
+        [&data[..at], insert, &data[at + remove..]].concat()
+    };
+    let data2 = edit(&data, 100, b"first edit", 3);
+    let data3 = edit(&edit(&data2, 4_000, b"second", 0), 50, b"", 40);
+    let data4 = edit(&data3, 7_000, b"third edit near the end", 10);
+    let versions: Vec<Vec<(&str, &[u8])>> = vec![
+        vec![("data.bin", &data), ("kept.txt", b"kept")],
+        vec![
+            ("data.bin", &data2),
+            ("kept.txt", b"kept"),
+            ("notes.txt", b"draft"),
+            ("tmp/scratch.txt", b"temporary"),
+        ],
+        vec![
+            ("data.bin", &data3),
+            ("kept.txt", b"kept"),
+            ("notes.txt", b"final notes"),
+            ("tmp/scratch.txt", b"temporary"),
+        ],
+        vec![
+            ("data.bin", &data4),
+            ("kept.txt", b"kept"),
+            ("notes.txt", b"final notes"),
+        ],
+    ];
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let trees = versions
+        .iter()
+        .map(|files| {
+            let tree = tempfile::tempdir().unwrap();
+            write_tree(tree.path(), files);
+            tree
+        })
+        .collect::<Vec<_>>();
+    let roots = trees
+        .iter()
+        .map(|tree| create_directory_blob_file(server.path(), tree.path()).unwrap())
+        .collect::<Vec<_>>();
+    let patches = roots
+        .windows(2)
+        .enumerate()
+        .map(|(i, pair)| {
+            let patch = out.path().join(format!("{}.zip", i));
+            let diffs = compare_blob_files(&pair[0], &pair[1], server.path()).unwrap();
+            create_zip_patch(diffs, server.path(), patch.as_path()).unwrap();
+            patch
+        })
+        .collect::<Vec<_>>();
+
+    let composed = out.path().join("composed.zip");
+    let root = compose_patches(&patches, &composed).unwrap();
+    assert_eq!(root.as_deref(), Some(roots[3].as_str()));
+    let manifest = read_patch_manifest(&composed).unwrap().unwrap();
+    assert_eq!(manifest.old_root.as_deref(), Some(roots[0].as_str()));
+
+    let added = std::cell::RefCell::new(HashSet::new());
+    unpack_patch(&composed, |_, hash| {
+        added.borrow_mut().insert(hash.to_string());
+        Ok(())
+    })
+    .unwrap();
+    let added = added.into_inner();
+    let hash_of =
+        |tree: usize, name: &str| calculate_file_hash(trees[tree].path().join(name)).unwrap();
+    // added then replaced is added in its final form, added then deleted is left out
+    assert!(added.contains(&hash_of(2, "notes.txt")));
+    assert!(!added.contains(&hash_of(1, "tmp/scratch.txt")));
+    // the three byte patches are composed against the first version
+    assert!(!added.contains(&hash_of(3, "data.bin")));
+
+    create_directory_blob_file(client.path(), trees[0].path()).unwrap();
+    apply_patchs(composed.as_path(), client.path()).unwrap();
+    assert!(
+        compare_tree_to_root(trees[3].path(), &roots[3], client.path())
+            .unwrap()
+            .is_empty()
+    );
+
+    let skipping = [patches[0].clone(), patches[2].clone()];
+    assert!(matches!(
+        compose_patches(&skipping, &composed),
+        Err(ZipFileError::SourceMismatch(root)) if root == roots[2]
+    ));
+}
+
+#[test]
+fn test_patch_read_error_policy() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"old a"), ("b.txt", b"old b")]);
+    write_tree(v2.path(), &[("a.txt", b"new a"), ("b.txt", b"new b")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    // the old blob of a.txt becomes unreadable
+    let old_a = calculate_file_hash(v1.path().join("a.txt")).unwrap();
+    let new_a = calculate_file_hash(v2.path().join("a.txt")).unwrap();
+    let old_a_path = store.path().join(&old_a[..1]).join(&old_a[1..]);
+    std::fs::remove_file(&old_a_path).unwrap();
+    std::fs::create_dir(&old_a_path).unwrap();
+    let diffs = || compare_blob_files(&old, &new, store.path()).unwrap();
+
+    let failing = patches.path().join("fail.zip");
+    assert!(create_zip_patch(diffs(), store.path(), &failing).is_err());
+
+    let skipped = patches.path().join("skip.zip");
+    let options = PatchOptions {
+        on_read_error: ReadErrorPolicy::Skip,
+        ..Default::default()
+    };
+    let warnings =
+        create_zip_patch_with_options(diffs(), store.path(), &skipped, &options).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(
+        (warnings[0].old_file.as_str(), warnings[0].added),
+        (old_a.as_str(), false)
+    );
+    let records = unpack_patch(&skipped, |_, _| Ok(())).unwrap();
+    let format = format!("{:?}", records);
+    assert!(!format.contains(&old_a) && !format.contains(&new_a));
+    assert!(format.contains(&calculate_file_hash(v2.path().join("b.txt")).unwrap()));
+
+    let added = patches.path().join("add.zip");
+    let options = PatchOptions {
+        on_read_error: ReadErrorPolicy::AddNew,
+        ..Default::default()
+    };
+    let warnings = create_zip_patch_with_options(diffs(), store.path(), &added, &options).unwrap();
+    assert!(warnings[0].added);
+    let payloads = std::cell::RefCell::new(vec![]);
+    unpack_patch(&added, |_, name| {
+        payloads.borrow_mut().push(name.to_string());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(payloads.into_inner(), vec![new_a]);
+
+    // an unreadable directory blob is never skipped
+    let diffs = diffs();
+    let old_root_path = store.path().join(&old[..1]).join(&old[1..]);
+    std::fs::remove_file(&old_root_path).unwrap();
+    std::fs::create_dir(&old_root_path).unwrap();
+    let options = PatchOptions {
+        on_read_error: ReadErrorPolicy::Skip,
+        ..Default::default()
+    };
+    let directory = patches.path().join("directory.zip");
+    assert!(create_zip_patch_with_options(diffs, store.path(), &directory, &options).is_err());
+}
+
+#[test]
+fn test_cancel_blobbing() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let count = 3000;
+    for i in 0..count {
+        std::fs::write(source.path().join(format!("{}.txt", i)), i.to_string()).unwrap();
+    }
+    let token = CancellationToken::new();
+    let options = BlobOptions {
+        cancellation: token.clone(),
+        ..Default::default()
+    };
+    let marker = store.path().join(".ditiear");
+    let canceller = std::thread::spawn(move || {
+        // the first blob creates the temporary directory
+        while !marker.exists() {
+            std::thread::yield_now();
+        }
+        token.cancel();
+    });
+    let error =
+        create_directory_blob_file_with_options(store.path(), source.path(), &options).unwrap_err();
+    canceller.join().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+    assert!(error.get_ref().is_some_and(|inner| inner.is::<Cancelled>()));
+    let blobs = walkdir_count(store.path());
+    assert!(blobs < count, "{} of {} files were blobbed", blobs, count);
+}
+
+#[test]
+fn test_cancel_diffing_with_shared_flag() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"a"), ("gone/b.txt", b"b")]);
+    write_tree(v2.path(), &[("a.txt", b"changed")]);
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let token = CancellationToken::from(flag.clone());
+    assert!(compare_blob_files_cancellable(&old, &new, store.path(), &token).is_ok());
+    flag.store(true, std::sync::atomic::Ordering::Relaxed);
+    assert!(token.is_cancelled());
+    assert!(matches!(
+        compare_blob_files_cancellable(&old, &new, store.path(), &token),
+        Err(FileParseError::Cancelled)
+    ));
+}
+
+fn walkdir_count(dir: &Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !path.ends_with(".ditiear"))
+        .map(|path| match path.is_dir() {
+            true => walkdir_count(&path),
+            false => 1,
+        })
+        .sum()
+}
+
+#[test]
+fn test_blob_extension_keeps_foreign_files() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    std::fs::write(v1.path().join("a.txt"), "old").unwrap();
+    std::fs::write(v2.path().join("a.txt"), "new").unwrap();
+    let ditiear = Ditiear::builder().blob_extension("blob").build();
+    let old = ditiear
+        .blob_directory(store.path(), v1.path())
+        .unwrap()
+        .hash;
+    let new = ditiear
+        .blob_directory(store.path(), v2.path())
+        .unwrap()
+        .hash;
+    let root_path = store
+        .path()
+        .join(&new[..1])
+        .join(format!("{}.blob", &new[1..]));
+    assert!(root_path.is_file());
+    assert_eq!(ditiear.diff(&old, &new, store.path()).unwrap().len(), 2);
+
+    // a file of another tool that happens to look like a bare blob name
+    let foreign = store.path().join(&new[..1]).join("cafe");
+    std::fs::write(&foreign, "not a blob").unwrap();
+    assert!(ditiear.verify_store(store.path()).unwrap().is_empty());
+    let removed = ditiear.collect_garbage(&[&new], store.path()).unwrap();
+    assert_eq!(removed.len(), 2);
+    assert!(removed.contains(&old));
+    assert!(foreign.is_file());
+    assert!(ditiear.verify_store(store.path()).unwrap().is_empty());
+    assert!(ditiear.diff(&new, &new, store.path()).unwrap().is_empty());
+}
+
+#[test]
+fn test_free_functions_read_the_recorded_layout() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"old"), ("sub/b.txt", b"beta")]);
+    write_tree(v2.path(), &[("a.txt", b"new"), ("sub/b.txt", b"beta")]);
+    let ditiear = Ditiear::builder()
+        .fanout(2)
+        .hash_algorithm(HashAlgorithm::Blake3)
+        .blob_extension("blob")
+        .build();
+    let old = ditiear
+        .blob_directory(store.path(), v1.path())
+        .unwrap()
+        .hash;
+    ditiear.blob_directory(client.path(), v1.path()).unwrap();
+    // blobbing with the free functions follows the recorded layout instead of failing
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    assert_eq!(new.len(), 64);
+    assert!(blob_exists(&old, store.path()));
+    assert!(blob_exists(&new, store.path()));
+    assert_eq!(blob_size(&new, store.path()).unwrap(), {
+        let path = store
+            .path()
+            .join(&new[..2])
+            .join(format!("{}.blob", &new[2..]));
+        std::fs::metadata(path).unwrap().len()
+    });
+    assert!(compare_tree_to_root(v2.path(), &new, store.path())
+        .unwrap()
+        .is_empty());
+
+    let patch = scratch.path().join("patch.zip");
+    create_diff_patch(&old, &new, store.path(), &patch).unwrap();
+    verify_patch_apply(patch.as_path(), client.path()).unwrap();
+    ditiear.verify_apply(&patch, client.path()).unwrap();
+    apply_patchs(patch.as_path(), client.path()).unwrap();
+    let checkout = scratch.path().join("checkout");
+    checkout_with_options(&new, client.path(), &checkout, &CheckoutOptions::default()).unwrap();
+    assert_eq!(std::fs::read(checkout.join("a.txt")).unwrap(), b"new");
+
+    assert!(verify_store(store.path()).unwrap().is_empty());
+    assert!(fsck(store.path()).unwrap().is_empty());
+    let a = read_directory_blob(&old, store.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries
+        .into_iter()
+        .find(|entry| entry.name == "a.txt")
+        .unwrap()
+        .hash;
+    let a_path = store.path().join(&a[..2]).join(format!("{}.blob", &a[2..]));
+    std::fs::write(a_path, "bit rot").unwrap();
+    assert_eq!(verify_store(store.path()).unwrap().len(), 1);
+    assert_eq!(fsck(store.path()).unwrap().len(), 1);
+    assert_eq!(gc(store.path(), &[&new]).unwrap().removed, vec![old]);
+}
+
+#[test]
+fn test_configured_store_is_used_by_every_entry_point() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"old"), ("sub/b.txt", b"beta")]);
+    write_tree(v2.path(), &[("a.txt", b"new"), ("sub/b.txt", b"beta")]);
+    let ditiear = Ditiear::builder().fanout(2).blob_extension("blob").build();
+    let plan = ditiear
+        .plan_blob_directory(store.path(), v1.path())
+        .unwrap();
+    let old = ditiear
+        .blob_directory(store.path(), v1.path())
+        .unwrap()
+        .hash;
+    assert_eq!(plan.hash, old);
+    let new = ditiear
+        .blob_directory(store.path(), v2.path())
+        .unwrap()
+        .hash;
+    assert_eq!(
+        ditiear
+            .plan_blob_directory(store.path(), v2.path())
+            .unwrap()
+            .new_blobs,
+        0
+    );
+    ditiear.blob_directory(client.path(), v1.path()).unwrap();
+    assert_eq!(
+        ditiear
+            .diff_cross(&old, client.path(), &new, store.path())
+            .unwrap()
+            .len(),
+        2
+    );
+    let modify = ditiear
+        .diff(&old, &new, store.path())
+        .unwrap()
+        .into_iter()
+        .find(|d| matches!(d, DiffCollectionType::Modify { r#type, .. } if *r#type == DiffFileType::File))
+        .unwrap();
+    assert!(ditiear
+        .estimate_delta(&modify, store.path())
+        .unwrap()
+        .is_some());
+
+    let patch = scratch.path().join("patch.zip");
+    let stats = ditiear
+        .create_patch_with_stats(&old, &new, store.path(), &patch)
+        .unwrap();
+    assert_eq!(stats.modified_files, 1);
+    let checkout = scratch.path().join("checkout");
+    let report = ditiear
+        .materialize(&patch, client.path(), &checkout, &new)
+        .unwrap();
+    assert_eq!(report.written, 2);
+    assert_eq!(std::fs::read(checkout.join("a.txt")).unwrap(), b"new");
+    assert!(client
+        .path()
+        .join(&new[..2])
+        .join(format!("{}.blob", &new[2..]))
+        .is_file());
+
+    let loose = scratch.path().join("loose");
+    ditiear
+        .write_loose_patch(&old, &new, store.path(), &loose)
+        .unwrap();
+    let other = tempfile::tempdir().unwrap();
+    ditiear.blob_directory(other.path(), v1.path()).unwrap();
+    ditiear.apply_loose(&loose, other.path()).unwrap();
+    let work = scratch.path().join("work");
+    ditiear
+        .store_at(other.path())
+        .checkout(&new, &work, &CheckoutOptions::default())
+        .unwrap();
+    assert_eq!(std::fs::read(work.join("a.txt")).unwrap(), b"new");
+}
+
+#[test]
+fn test_fsck_reports_every_damaged_blob() {
+    let store = tempfile::tempdir().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+    write_tree(
+        tree.path(),
+        &[
+            ("a.txt", b"alpha"),
+            ("b.txt", b"beta"),
+            ("sub/c.txt", b"gamma"),
+        ],
+    );
+    // a file that reads like a directory blob listing a child the store lacks
+    let listing = format!("gone.txt {} file 084004\n", "0".repeat(64));
+    std::fs::write(tree.path().join("listing.txt"), listing).unwrap();
+    let root = create_directory_blob_file_rec(store.path(), tree.path()).unwrap();
+    assert!(fsck(store.path()).unwrap().is_empty());
+
+    let blob = |hash: &str| store.path().join(&hash[..1]).join(&hash[1..]);
+    let a = calculate_file_hash(tree.path().join("a.txt")).unwrap();
+    let c = calculate_file_hash(tree.path().join("sub/c.txt")).unwrap();
+    let sub = read_directory_blob(&root, store.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries
+        .into_iter()
+        .find(|entry| entry.name == "sub")
+        .unwrap()
+        .hash;
+    std::fs::write(blob(&a), "bit rot").unwrap();
+    std::fs::remove_file(blob(&c)).unwrap();
+
+    let errors = fsck(store.path()).unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.contains(&IntegrityError::Mismatched {
+        hash: a.clone(),
+        actual: calculate_file_hash(blob(&a)).unwrap(),
+    }));
+    assert!(errors.contains(&IntegrityError::Dangling {
+        parent: sub,
+        name: "c.txt".to_string(),
+        hash: c,
+    }));
+    assert!(matches!(
+        fsck(store.path().join("missing")),
+        Err(FileParseError::StoreNotFound(_))
+    ));
+}
+
+#[test]
+fn test_gc_reports_freed_objects() {
+    let store = tempfile::tempdir().unwrap();
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[("kept.txt", b"kept"), ("sub/old.txt", b"only old")],
+    );
+    write_tree(v2.path(), &[("kept.txt", b"kept"), ("new.txt", b"new")]);
+    let old = create_directory_blob_file_rec(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file_rec(store.path(), v2.path()).unwrap();
+
+    // a file of another tool named like a blob, which no version reaches either
+    let stray = store.path().join("a").join("bcdef0123456789");
+    std::fs::create_dir_all(stray.parent().unwrap()).unwrap();
+    std::fs::write(&stray, "not a blob").unwrap();
+
+    // a missing root fails before anything is removed
+    assert!(gc(store.path(), &[&new, "0123456789abcdef"]).is_err());
+    assert!(blob_exists(&old, store.path()));
+
+    let before = store_stats(store.path()).unwrap();
+    let stats = gc(store.path(), &[&new]).unwrap();
+    let after = store_stats(store.path()).unwrap();
+    // the old root, the `sub` directory and `sub/old.txt`
+    assert_eq!(stats.objects_freed, 3);
+    assert_eq!(stats.removed.len(), 3);
+    assert!(stats.removed.contains(&old));
+    assert_eq!(stats.bytes_freed, before.bytes - after.bytes);
+    assert!(stray.is_file());
+    assert_eq!(after.blobs, 3);
+    assert!(!blob_exists(&old, store.path()));
+    assert!(compare_blob_files(&new, &new, store.path())
+        .unwrap()
+        .is_empty());
+    assert_eq!(gc(store.path(), &[&new]).unwrap(), GcStats::default());
+}
+
+#[test]
+fn test_store_stats_follow_apply() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let device = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    std::fs::write(v1.path().join("a.txt"), "shared").unwrap();
+    std::fs::write(v1.path().join("b.txt"), "old content").unwrap();
+    std::fs::write(v2.path().join("a.txt"), "shared").unwrap();
+    std::fs::write(v2.path().join("b.txt"), "new content").unwrap();
+    std::fs::write(v2.path().join("c.txt"), "added").unwrap();
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    create_directory_blob_file(device.path(), v1.path()).unwrap();
+    let patch = patches.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    create_zip_patch(diffs, store.path(), &patch).unwrap();
+
+    let before = store_stats(device.path()).unwrap();
+    assert_eq!((before.blobs, before.versions()), (3, 1));
+    apply_patchs(patch.as_path(), device.path()).unwrap();
+    let incremental = store_stats(device.path()).unwrap();
+    assert_eq!(incremental.blobs, 6);
+    assert_eq!(incremental.versions(), 2);
+    assert_eq!(incremental, recompute_store_stats(device.path()).unwrap());
+    assert_eq!(incremental, store_stats(store.path()).unwrap());
+    assert!(incremental.dedup_ratio() > 1.0);
+}
+
+#[test]
+fn test_blobbing_creates_the_store() {
+    let source = tempfile::tempdir().unwrap();
+    let parent = tempfile::tempdir().unwrap();
+    let store = parent.path().join("store");
+    write_tree(source.path(), &[("a.txt", b"a"), ("sub/b.txt", b"b")]);
+    create_directory_blob_file_rec(store.as_path(), source.path()).unwrap();
+    let stats = store_stats(&store).unwrap();
+    assert_eq!((stats.blobs, stats.versions()), (4, 1));
+}
+
+#[test]
+fn test_apply_delete_of_missing_blob() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    std::fs::write(v1.path().join("a.txt"), "kept").unwrap();
+    std::fs::write(v1.path().join("gone.txt"), "removed").unwrap();
+    std::fs::write(v2.path().join("a.txt"), "kept").unwrap();
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    let patch = patches.path().join("patch.zip");
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    create_zip_patch(diffs, store.path(), &patch).unwrap();
+    // another version sharing the blob already removed it
+    let gone = calculate_file_hash(v1.path().join("gone.txt")).unwrap();
+    std::fs::remove_file(client.path().join(&gone[..1]).join(&gone[1..])).unwrap();
+
+    let ignore = Ditiear::builder().delete_removed(true).build();
+    ignore.apply(&patch, client.path()).unwrap();
+    assert!(blob_exists(&new, client.path()));
+
+    let strict = Ditiear::builder()
+        .delete_removed(true)
+        .on_missing_delete(MissingDeletePolicy::Error)
+        .build();
+    let error = strict.apply(&patch, client.path()).unwrap_err();
+    assert!(
+        matches!(error, DitiearError::Patch(ZipFileError::MissingBaseBlob(hash)) if hash == gone)
+    );
+}
+
+#[test]
+fn test_estimate_delta_recommendation() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let noise = |seed: u64| {
+        let mut state = seed;
+        (0..2_000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut edited = noise(1);
+    edited[1_000..1_010].copy_from_slice(b"0123456789");
+    std::fs::write(v1.path().join("edited.bin"), noise(1)).unwrap();
+    std::fs::write(v2.path().join("edited.bin"), edited).unwrap();
+    std::fs::write(v1.path().join("rewritten.bin"), noise(2)).unwrap();
+    std::fs::write(v2.path().join("rewritten.bin"), noise(3)).unwrap();
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    let estimate = |name: &str| {
+        let hash = calculate_file_hash(v2.path().join(name)).unwrap();
+        let diff = diffs
+            .iter()
+            .find(|d| matches!(d, DiffCollectionType::Modify { new, .. } if *new == hash))
+            .unwrap();
+        estimate_delta(diff, store.path()).unwrap().unwrap()
+    };
+
+    let edited = estimate("edited.bin");
+    assert_eq!(edited.recommended, Transfer::Delta);
+    assert!(edited.ratio() < 0.1);
+    let rewritten = estimate("rewritten.bin");
+    assert_eq!(rewritten.recommended, Transfer::Full);
+    // noise does not compress, the whole blob goes into the patch
+    assert!(rewritten.full_size >= 2_000);
+    assert!(rewritten.ratio() >= 1.0);
+
+    // text is measured compressed like the patch would store it
+    std::fs::write(v1.path().join("text.txt"), "old line\n".repeat(500)).unwrap();
+    std::fs::write(v2.path().join("text.txt"), "new line\n".repeat(500)).unwrap();
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let hash = calculate_file_hash(v2.path().join("text.txt")).unwrap();
+    let diff = compare_blob_files(&old, &new, store.path())
+        .unwrap()
+        .into_iter()
+        .find(|d| matches!(d, DiffCollectionType::Modify { new, .. } if *new == hash))
+        .unwrap();
+    let deflated = estimate_delta(&diff, store.path()).unwrap().unwrap();
+    let stored = Ditiear::builder()
+        .compression(CompressionMethod::Stored)
+        .build()
+        .estimate_delta(&diff, store.path())
+        .unwrap()
+        .unwrap();
+    assert!(deflated.full_size < 500);
+    assert!(stored.full_size >= 4_500);
+}
+
+#[test]
+fn test_create_zip_patch_with_provider_map() {
+    fn read_store(dir: &Path, out: &mut HashMap<String, bytes::Bytes>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.ends_with(".ditiear") {
+                continue;
+            }
+            for blob in std::fs::read_dir(&path).unwrap() {
+                let blob = blob.unwrap().path();
+                let hash = format!(
+                    "{}{}",
+                    path.file_name().unwrap().to_str().unwrap(),
+                    blob.file_name().unwrap().to_str().unwrap()
+                );
+                out.insert(hash, std::fs::read(blob).unwrap().into());
+            }
+        }
+    }
+
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let patches = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("a.txt", b"hello world"), ("b.txt", b"b")]);
+    write_tree(
+        v2.path(),
+        &[("a.txt", b"hello there world"), ("c.txt", b"c")],
+    );
+    let store = tempfile::tempdir().unwrap();
+    let old = create_directory_blob_file(store.path(), v1.path()).unwrap();
+    let new = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, store.path()).unwrap();
+    let local = patches.path().join("local.zip");
+    let again = compare_blob_files(&old, &new, store.path()).unwrap();
+    create_zip_patch(again, store.path(), &local).unwrap();
+    let mut served = HashMap::new();
+    read_store(store.path(), &mut served);
+    // blobs are only reachable through the provider from here on
+    drop(store);
+
+    let provided = patches.path().join("provided.zip");
+    create_zip_patch_with_provider(diffs, &provided, |hash| {
+        served
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, hash.to_string()))
+    })
+    .unwrap();
+    let records = |patch: &Path| format!("{:?}", unpack_patch(patch, |_, _| Ok(())).unwrap());
+    assert_eq!(records(&provided), records(&local));
+
+    create_directory_blob_file(client.path(), v1.path()).unwrap();
+    apply_patchs(provided.as_path(), client.path()).unwrap();
+    assert!(compare_blob_files(&new, &new, client.path())
+        .unwrap()
+        .is_empty());
+    assert!(blob_exists(
+        &calculate_file_hash(v2.path().join("c.txt")).unwrap(),
+        client.path()
+    ));
+}
+
+#[test]
+fn test_read_directory_blob_lossy() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(source.path(), &[("a.txt", b"a"), ("b.txt", b"b")]);
+    let root = create_directory_blob_file(store.path(), source.path()).unwrap();
+    let root_path = store.path().join(&root[..1]).join(&root[1..]);
+    let content = std::fs::read(&root_path).unwrap();
+    let position = content.windows(5).position(|w| w == b"b.txt").unwrap();
+    let mut damaged = content.clone();
+    damaged[position] = 0xff;
+    std::fs::write(&root_path, damaged).unwrap();
+
+    assert!(read_directory_blob(&root, store.path(), Utf8Mode::Strict).is_err());
+    let listing = read_directory_blob(&root, store.path(), Utf8Mode::Lossy).unwrap();
+    assert_eq!(listing.invalid_lines, vec![2]);
+    let names = listing
+        .entries
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["a.txt", "\u{fffd}.txt"]);
+    assert_eq!(
+        listing.entries[1].hash,
+        calculate_file_hash(source.path().join("b.txt")).unwrap()
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_blobbing_matches_serial() {
+    let tree = tempfile::tempdir().unwrap();
+    for dir in ["a", "a/nested", "b", "c"] {
+        std::fs::create_dir_all(tree.path().join(dir)).unwrap();
+        for i in 0..20 {
+            let path = tree.path().join(dir).join(format!("file-{}.txt", i));
+            std::fs::write(&path, format!("content {}", i)).unwrap();
+            // identical content hashed by several threads at once
+            std::fs::write(
+                tree.path().join(dir).join(format!("copy-{}.txt", i)),
+                "shared",
+            )
+            .unwrap();
+        }
+    }
+    let serial = tempfile::tempdir().unwrap();
+    let parallel = tempfile::tempdir().unwrap();
+    let expected = create_directory_blob_file_rec(serial.path(), tree.path()).unwrap();
+    let hash = create_directory_blob_file_par(parallel.path(), tree.path()).unwrap();
+    assert_eq!(hash, expected);
+    assert_eq!(
+        store_stats(parallel.path()).unwrap(),
+        store_stats(serial.path()).unwrap()
+    );
+    assert!(verify_store(parallel.path()).unwrap().is_empty());
+}
+
+#[test]
+fn test_ditiear_error_taxonomy() {
+    use std::error::Error;
+    let store = tempfile::tempdir().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+    let missing = store.path().join("missing");
+    let ditiear = Ditiear::default();
+    let root = ditiear
+        .blob_directory(store.path(), tree.path())
+        .unwrap()
+        .hash;
+
+    let errors = [
+        ditiear.blob_directory(store.path(), &missing).unwrap_err(),
+        ditiear
+            .diff(&root, "0123456789abcdef", store.path())
+            .unwrap_err(),
+        DitiearError::from(DeserializeError::InvalidSeparator),
+        ditiear.apply(&missing, store.path()).unwrap_err(),
+        ditiear
+            .build_patch(&root, &missing, store.path(), missing.join("patch.zip"))
+            .unwrap_err(),
+    ];
+    for (i, error) in errors.iter().enumerate() {
+        let kind = match error {
+            DitiearError::Io(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+                0
+            }
+            DitiearError::Store(FileParseError::MissingBaseBlob(hash)) => {
+                assert_eq!(hash, "0123456789abcdef");
+                1
+            }
+            DitiearError::Blob(DeserializeError::InvalidSeparator) => 2,
+            DitiearError::Patch(ZipFileError::Io(_)) => 3,
+            DitiearError::Build(BuildError::Blob(_)) => 4,
+            other => panic!("unexpected error {:?}", other),
+        };
+        assert_eq!(kind, i);
+        // the module error is the source of every variant
+        assert!(error.source().is_some());
+    }
+    let build = errors[4].source().unwrap();
+    assert!(build.source().unwrap().is::<std::io::Error>());
+}
+
+#[test]
+fn test_blob_store_names_the_root_once() {
+    let store_dir = tempfile::tempdir().unwrap();
+    let old_tree = tempfile::tempdir().unwrap();
+    let new_tree = tempfile::tempdir().unwrap();
+    write_tree(old_tree.path(), &[("a.txt", b"old"), ("b.txt", b"kept")]);
+    write_tree(new_tree.path(), &[("a.txt", b"new"), ("b.txt", b"kept")]);
+
+    let store = BlobStore::new(store_dir.path());
+    assert_eq!(store.root(), store_dir.path());
+    let old = store.hash_dir(old_tree.path()).unwrap();
+    let new = store.hash_dir(new_tree.path()).unwrap();
+    assert!(store.exists(&old));
+    assert!(store.exists(&new));
+    assert!(!store.exists("0123456789abcdef"));
+
+    let diffs = store.compare(&old, &new).unwrap();
+    assert_eq!(
+        diffs,
+        compare_blob_files(&old, &new, store_dir.path()).unwrap()
+    );
+
+    let target_dir = tempfile::tempdir().unwrap();
+    let target = Ditiear::default().store_at(target_dir.path());
+    assert_eq!(target.hash_dir(old_tree.path()).unwrap(), old);
+    let patch = store_dir.path().join("patch.zip");
+    store.create_patch(&old, &new, &patch).unwrap();
+    target.apply(&patch).unwrap();
+    assert!(target.exists(&new));
+}
+
+#[test]
+fn test_blob_with_ignore_patterns() {
+    let clean = tempfile::tempdir().unwrap();
+    let dirty = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let kept: &[(&str, &[u8])] = &[
+        ("src/main.rs", b"fn main() {}"),
+        // only directories match node_modules/
+        ("docs/node_modules", b"a file, not a directory"),
+    ];
+    write_tree(clean.path(), kept);
+    write_tree(dirty.path(), kept);
+    write_tree(
+        dirty.path(),
+        &[
+            ("src/scratch.tmp", b"scratch"),
+            ("node_modules/dep/index.js", b"module"),
+            (".git/HEAD", b"ref: refs/heads/main"),
+        ],
+    );
+    let patterns = ["*.tmp", "node_modules/", ".git/"];
+    let expected =
+        create_directory_blob_file_with_ignore(store.path(), clean.path(), &patterns).unwrap();
+    let hash =
+        create_directory_blob_file_with_ignore(store.path(), dirty.path(), &patterns).unwrap();
+    assert_eq!(hash, expected);
+    assert_ne!(
+        create_directory_blob_file(store.path(), dirty.path()).unwrap(),
+        expected
+    );
+    let scratch = calculate_file_hash(dirty.path().join("src/scratch.tmp")).unwrap();
+    let store = tempfile::tempdir().unwrap();
+    create_directory_blob_file_with_ignore(store.path(), dirty.path(), &patterns).unwrap();
+    assert!(!blob_exists(&scratch, store.path()));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_record_mode_round_trip() {
+    use std::os::unix::fs::PermissionsExt;
+    let tree = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let script = tree.path().join("run.sh");
+    std::fs::write(&script, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let plain = create_directory_blob_file(server.path(), tree.path()).unwrap();
+    let ditiear = Ditiear::builder().record_mode(true).build();
+    let old = ditiear
+        .blob_directory(server.path(), tree.path())
+        .unwrap()
+        .hash;
+    // recorded modes are part of the directory blob
+    assert_ne!(old, plain);
+    ditiear.blob_directory(client.path(), tree.path()).unwrap();
+
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let new = ditiear
+        .blob_directory(server.path(), tree.path())
+        .unwrap()
+        .hash;
+    let hash = calculate_file_hash(&script).unwrap();
+    let diffs = ditiear.diff(&old, &new, server.path()).unwrap();
+    assert!(diffs.iter().any(|d| matches!(
+        d,
+        DiffCollectionType::Modify { r#type: DiffFileType::File, old, new }
+            if *old == hash && *new == hash
+    )));
+
+    let patch = out.path().join("patch.zip");
+    ditiear
+        .create_patch(&old, &new, server.path(), &patch)
+        .unwrap();
+    ditiear.apply(&patch, client.path()).unwrap();
+    let dest = out.path().join("checkout");
+    checkout(&new, client.path(), &dest).unwrap();
+    let mode = std::fs::metadata(dest.join("run.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+
+    // a setuid bit is only restored when asked for
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o4755)).unwrap();
+    let setuid = ditiear
+        .blob_directory(server.path(), tree.path())
+        .unwrap()
+        .hash;
+    let mode_after = |options: &CheckoutOptions| {
+        let dest = tempfile::tempdir().unwrap();
+        ditiear
+            .checkout(&setuid, server.path(), dest.path(), options)
+            .unwrap();
+        std::fs::metadata(dest.path().join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    };
+    assert_eq!(mode_after(&CheckoutOptions::default()), 0o755);
+    let special = CheckoutOptions {
+        special_bits: true,
+        ..CheckoutOptions::default()
+    };
+    assert_eq!(mode_after(&special), 0o4755);
+}
+
+#[test]
+fn test_empty_directories_round_trip() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(v1.path(), &[("readme.txt", b"scaffold")]);
+    write_tree(v2.path(), &[("readme.txt", b"scaffold")]);
+    std::fs::create_dir_all(v2.path().join("logs")).unwrap();
+    std::fs::create_dir_all(v2.path().join("var/cache")).unwrap();
+
+    // dropped by default
+    let plain = create_directory_blob_file(server.path(), v2.path()).unwrap();
+    assert_eq!(
+        plain,
+        create_directory_blob_file(server.path(), v1.path()).unwrap()
+    );
+
+    let ditiear = Ditiear::builder().keep_empty_dirs(true).build();
+    let old = ditiear
+        .blob_directory(server.path(), v1.path())
+        .unwrap()
+        .hash;
+    let new = ditiear
+        .blob_directory(server.path(), v2.path())
+        .unwrap()
+        .hash;
+    assert_ne!(new, plain);
+    let entries = read_directory_blob(&new, server.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries;
+    let logs = entries.iter().find(|e| e.name == "logs").unwrap();
+    assert_eq!(logs.r#type, DiffFileType::Directory);
+    let var = entries.iter().find(|e| e.name == "var").unwrap();
+    let cache = read_directory_blob(&var.hash, server.path(), Utf8Mode::Strict)
+        .unwrap()
+        .entries;
+    // every empty directory shares one blob and is placed by its parent's listing
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache[0].hash, logs.hash);
+    assert!(
+        read_directory_blob(&logs.hash, server.path(), Utf8Mode::Strict)
+            .unwrap()
+            .entries
+            .is_empty()
+    );
+
+    ditiear.blob_directory(client.path(), v1.path()).unwrap();
+    let patch = out.path().join("patch.zip");
+    ditiear
+        .create_patch(&old, &new, server.path(), &patch)
+        .unwrap();
+    ditiear.apply(&patch, client.path()).unwrap();
+    let dest = out.path().join("checkout");
+    checkout(&new, client.path(), &dest).unwrap();
+    assert!(dest.join("logs").is_dir());
+    assert!(dest.join("var/cache").is_dir());
+    assert_eq!(
+        ditiear.blob_directory(client.path(), &dest).unwrap().hash,
+        new
+    );
+}
+
+#[test]
+fn test_file_patch_round_trip() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(
+        v1.path(),
+        &[
+            ("config/app.toml", b"name = \"app\"\nversion = 1\n"),
+            ("gone.txt", b"removed"),
+        ],
+    );
+    let patch = out.path().join("files.zip");
+    create_patch_from_files(
+        vec![
+            FileChange::Modify {
+                path: "config/app.toml".to_string(),
+                old: FileSource::Path(v1.path().join("config/app.toml")),
+                new: FileSource::Bytes(bytes::Bytes::from("name = \"app\"\nversion = 2\n")),
+            },
+            FileChange::Delete {
+                path: "gone.txt".to_string(),
+            },
+            FileChange::Add {
+                path: "assets/new.bin".to_string(),
+                new: FileSource::Bytes(bytes::Bytes::from_static(&[0, 1, 2, 3])),
+            },
+        ],
+        &patch,
+    )
+    .unwrap();
+    apply_file_patch(&patch, v1.path()).unwrap();
+    write_tree(
+        v2.path(),
+        &[
+            ("config/app.toml", b"name = \"app\"\nversion = 2\n"),
+            ("assets/new.bin", &[0, 1, 2, 3]),
+        ],
+    );
+    assert_eq!(
+        hash_directory(v1.path()).unwrap().hash,
+        hash_directory(v2.path()).unwrap().hash
+    );
+
+    let hostile = out.path().join("hostile.zip");
+    create_patch_from_files(
+        vec![FileChange::Delete {
+            path: "../outside.txt".to_string(),
+        }],
+        &hostile,
+    )
+    .unwrap();
+    assert!(matches!(
+        apply_file_patch(&hostile, v1.path()),
+        Err(ZipFileError::PathTraversal(_))
+    ));
+
+    // a backslash only separates on Windows
+    let named = out.path().join("named.zip");
+    create_patch_from_files(
+        vec![FileChange::Add {
+            path: "assets/a\\b.txt".to_string(),
+            new: FileSource::Bytes(bytes::Bytes::from_static(b"slash")),
+        }],
+        &named,
+    )
+    .unwrap();
+    apply_file_patch(&named, v1.path()).unwrap();
+    let written = if cfg!(windows) {
+        v1.path().join("assets").join("a").join("b.txt")
+    } else {
+        v1.path().join("assets").join("a\\b.txt")
+    };
+    assert_eq!(std::fs::read(written).unwrap(), b"slash");
+}
+
+#[test]
+fn test_stored_signature_matches_computed() {
+    let v1 = tempfile::tempdir().unwrap();
+    let v2 = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let old = (0..6_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    let mut new = old.clone();
+    new.splice(1_500..1_500, b"inserted bytes".iter().copied());
+    new[4_000] ^= 0xff;
+    std::fs::write(v1.path().join("asset.bin"), &old).unwrap();
+    std::fs::write(v2.path().join("asset.bin"), &new).unwrap();
+    let ditiear = Ditiear::builder().store_signatures(true).build();
+    ditiear.blob_directory(store.path(), v1.path()).unwrap();
+    let hash = calculate_file_hash(v1.path().join("asset.bin")).unwrap();
+
+    let stored = read_signature(&hash, store.path()).unwrap();
+    let computed = calculate_signature(&old, stored.block_size());
+    assert_eq!(stored, computed);
+    let (old, new) = (bytes::Bytes::from(old), bytes::Bytes::from(new));
+    let patch = calculate_block_diff(old.clone(), new.clone(), &stored);
+    assert_eq!(
+        patch,
+        calculate_block_diff(old.clone(), new.clone(), &computed)
+    );
+    // the old blob is read for the changed blocks only
+    let blob = std::fs::File::open(store.path().join(&hash[..1]).join(&hash[1..])).unwrap();
+    assert_eq!(
+        calculate_block_diff_from(blob, new.clone(), &stored).unwrap(),
+        patch
+    );
+    assert_eq!(apply_binary_patch(&old, &patch).unwrap(), new.to_vec());
+    // only the blocks around the two edits are sent
+    let sent: usize = patch.iter().map(inserted_len).sum();
+    assert!(sent < new.len() / 2);
+
+    let root = create_directory_blob_file(store.path(), v2.path()).unwrap();
+    collect_garbage(&[&root], store.path()).unwrap();
+    assert!(read_signature(&hash, store.path()).is_err());
+}
+
+fn inserted_len(op: &BytesPatch) -> usize {
+    match op {
+        BytesPatch::Add { new_value, .. } => new_value.len(),
+        BytesPatch::Delete { .. } => 0,
+        BytesPatch::Replace { new_value, .. } => new_value.len(),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks_are_blobbed_as_links() {
+    use std::os::unix::fs::symlink;
+    let tree = tempfile::tempdir().unwrap();
+    let server = tempfile::tempdir().unwrap();
+    let client = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    write_tree(tree.path(), &[("lib/v1.so", b"one"), ("lib/v2.so", b"two")]);
+    symlink("v1.so", tree.path().join("lib/current.so")).unwrap();
+    // a cycle that following links would walk forever
+    symlink("..", tree.path().join("lib/parent")).unwrap();
+    let old = create_directory_blob_file(server.path(), tree.path()).unwrap();
+    assert_eq!(
+        create_directory_blob_file(client.path(), tree.path()).unwrap(),
+        old
+    );
+    assert_eq!(hash_directory(tree.path()).unwrap().hash, old);
+
+    std::fs::remove_file(tree.path().join("lib/current.so")).unwrap();
+    symlink("v2.so", tree.path().join("lib/current.so")).unwrap();
+    let new = create_directory_blob_file(server.path(), tree.path()).unwrap();
+    let diffs = compare_blob_files(&old, &new, server.path()).unwrap();
+    let modified = diffs
+        .iter()
+        .filter(|d| {
+            matches!(
+                d,
+                DiffCollectionType::Modify {
+                    r#type: DiffFileType::Symlink,
+                    ..
+                }
+            )
+        })
+        .count();
+    assert_eq!(modified, 1);
+
+    let patch = out.path().join("patch.zip");
+    create_zip_patch(diffs, server.path(), &patch).unwrap();
+    apply_patchs(patch.as_path(), client.path()).unwrap();
+    let dest = out.path().join("checkout");
+    checkout(&new, client.path(), &dest).unwrap();
+    assert_eq!(
+        std::fs::read_link(dest.join("lib/current.so")).unwrap(),
+        Path::new("v2.so")
+    );
+    assert_eq!(
+        std::fs::read_link(dest.join("lib/parent")).unwrap(),
+        Path::new("..")
+    );
+    assert_eq!(std::fs::read(dest.join("lib/current.so")).unwrap(), b"two");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_checkout_never_writes_through_links() {
+    use std::os::unix::fs::symlink;
+    let tree = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let outside = out.path().join("outside");
+    std::fs::create_dir(&outside).unwrap();
+    write_tree(tree.path(), &[("lib/v1.so", b"one")]);
+    let root = create_directory_blob_file(store.path(), tree.path()).unwrap();
+
+    // a link left in the destination is not followed
+    let dest = out.path().join("checkout");
+    std::fs::create_dir(&dest).unwrap();
+    symlink(&outside, dest.join("lib")).unwrap();
+    let error = checkout(&root, store.path(), &dest).unwrap_err();
+    assert!(matches!(error, FileParseError::PathTraversal(_)));
+    assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+    // nor is a link pointing outside the destination restored
+    for target in ["../../outside", "/etc"] {
+        let tree = tempfile::tempdir().unwrap();
+        write_tree(tree.path(), &[("lib/v1.so", b"one")]);
+        symlink(target, tree.path().join("lib/escape")).unwrap();
+        let root = create_directory_blob_file(store.path(), tree.path()).unwrap();
+        let dest = out.path().join("fresh");
+        let error = checkout(&root, store.path(), &dest).unwrap_err();
+        assert!(matches!(error, FileParseError::PathTraversal(t) if t == target));
+        assert!(!dest.join("lib/escape").exists());
+    }
+}
+
+#[test]
+fn test_recompute_keeps_versions_of_a_damaged_sidecar() {
+    let tree = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    write_tree(tree.path(), &[("a.txt", b"a"), ("sub/b.txt", b"b")]);
+    let root = create_directory_blob_file_rec(store.path(), tree.path()).unwrap();
+    let meta = store.path().join(".ditiear");
+    let sidecar = meta.join("stats");
+    let leftovers = std::fs::read_dir(&meta)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().starts_with("stats."))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    // a write cut short in the middle of a line
+    let mut content = std::fs::read(&sidecar).unwrap();
+    content.extend_from_slice(b"version 01");
+    std::fs::write(&sidecar, content).unwrap();
+    assert_eq!(
+        store_stats(store.path()).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+
+    let stats = recompute_store_stats(store.path()).unwrap();
+    assert_eq!(stats.versions(), 1);
+    assert_eq!(stats, store_stats(store.path()).unwrap());
+    assert!(blob_exists(&root, store.path()));
+}